@@ -1,14 +1,14 @@
 /// Core logic for cache files. Used by `crate::services::common::cache`.
-///
-/// TODO:
-/// * We want to try upgrading derived caches without pruning them. This will likely require the concept of a content checksum (which would just be the cache key of the object file that would be used to create the derived cache.
 use std::fs::{self, read_dir, remove_file, File};
 use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
 use std::time::{Duration, SystemTime};
 
 use anyhow::{anyhow, Result};
 use filetime::FileTime;
+use serde::{Deserialize, Serialize};
 use symbolic::common::ByteView;
 use tempfile::NamedTempFile;
 
@@ -25,7 +25,8 @@ use crate::types::Scope;
 /// yet.
 pub const MALFORMED_MARKER: &[u8] = b"malformed";
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum CacheStatus {
     /// A cache item that represents the presence of something. E.g. we succeeded in downloading an
     /// object file and cached that file.
@@ -140,50 +141,102 @@ impl Cache {
         self.cache_dir.as_deref()
     }
 
+    /// Returns whether positive items are stored compressed.
+    pub fn compress(&self) -> bool {
+        self.cache_config.compress()
+    }
+
+    /// Returns the maximum size in bytes of loaded items kept in memory.
+    pub fn max_memory(&self) -> Option<u64> {
+        self.cache_config.max_memory()
+    }
+
     pub fn cleanup(&self) -> Result<()> {
+        self.cleanup_entries().map(|_| ())
+    }
+
+    /// Removes expired items and evicts items exceeding the configured `max_size`.
+    ///
+    /// Returns all items that remain in the cache.
+    fn cleanup_entries(&self) -> Result<Vec<CacheEntry>> {
         log::info!("Cleaning up cache: {}", self.name);
         let cache_dir = self.cache_dir.clone().ok_or_else(|| {
             anyhow!("no caching configured! Did you provide a path to your config file?")
         })?;
 
+        let mut entries = Vec::new();
         let mut directories = vec![cache_dir];
         while !directories.is_empty() {
             let directory = directories.pop().unwrap();
 
-            let entries = match catch_not_found(|| read_dir(directory))? {
+            // Directories can be removed concurrently, which must not skip the size limit below.
+            let dir_entries = match catch_not_found(|| read_dir(&directory))? {
                 Some(x) => x,
                 None => {
-                    log::warn!("Directory not found");
-                    return Ok(());
+                    log::warn!("Directory not found: {}", directory.display());
+                    continue;
                 }
             };
 
-            for entry in entries {
+            for entry in dir_entries {
                 let entry = entry?;
                 let path = entry.path();
                 if path.is_dir() {
                     directories.push(path.to_owned());
-                } else if let Err(e) = self.try_cleanup_path(&path) {
-                    sentry::with_scope(
-                        |scope| scope.set_extra("path", path.display().to_string().into()),
-                        || log::error!("Failed to clean cache file: {:?}", e),
-                    );
+                } else {
+                    match self.try_cleanup_path(&path) {
+                        Ok(Some(entry)) => entries.push(entry),
+                        Ok(None) => (),
+                        Err(e) => sentry::with_scope(
+                            |scope| scope.set_extra("path", path.display().to_string().into()),
+                            || log::error!("Failed to clean cache file: {:?}", e),
+                        ),
+                    }
                 }
             }
         }
 
-        Ok(())
+        if let Some(max_size) = self.cache_config.max_size() {
+            entries = evict_lru(entries, max_size);
+        }
+
+        Ok(entries)
     }
 
-    fn try_cleanup_path(&self, path: &Path) -> Result<()> {
+    /// Removes the file at `path` if it is expired.
+    ///
+    /// Returns the item if it remains in the cache.
+    fn try_cleanup_path(&self, path: &Path) -> Result<Option<CacheEntry>> {
         log::trace!("Checking {}", path.display());
         anyhow::ensure!(path.is_file(), "not a file");
+
+        // Metadata is removed along with its item, so only orphaned files are cleaned up here.
+        if let Some(item_path) = item_path(path) {
+            if !item_path.exists() {
+                log::debug!("Removing {}", path.display());
+                catch_not_found(|| remove_file(path))?;
+            }
+            return Ok(None);
+        }
+
         if catch_not_found(|| self.check_expiry(path))?.is_none() {
             log::debug!("Removing {}", path.display());
             catch_not_found(|| remove_file(path))?;
+            CacheItemMeta::remove(path)?;
+            return Ok(None);
         }
 
-        Ok(())
+        let metadata = match catch_not_found(|| path.metadata())? {
+            Some(metadata) => metadata,
+            None => return Ok(None),
+        };
+
+        Ok(Some(CacheEntry {
+            cache: self.name,
+            path: path.to_owned(),
+            size: metadata.len(),
+            last_used: last_used(&metadata),
+        }))
     }
 
     /// Validate cache expiration of path. If cache should not be used,
@@ -283,6 +336,76 @@ impl Cache {
         })
     }
 
+    /// Lists all items of the given scope whose cache key matches the filter.
+    ///
+    /// The filter receives the file name of the item, which is derived from its cache key.
+    pub fn find_items<F>(&self, scope: &Scope, mut filter: F) -> io::Result<Vec<CacheItemInfo>>
+    where
+        F: FnMut(&str) -> bool,
+    {
+        let scope_dir = match self.cache_dir {
+            Some(ref cache_dir) => cache_dir.join(safe_path_segment(scope.as_ref())),
+            None => return Ok(Vec::new()),
+        };
+
+        let entries = match catch_not_found(|| read_dir(scope_dir))? {
+            Some(entries) => entries,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut items = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let key = entry.file_name().to_string_lossy().into_owned();
+            if key.ends_with(META_SUFFIX) || !filter(&key) {
+                continue;
+            }
+
+            let path = entry.path();
+            let metadata = match catch_not_found(|| path.metadata())? {
+                Some(metadata) if metadata.is_file() => metadata,
+                _ => continue,
+            };
+
+            let status = if metadata.len() == 0 {
+                CacheStatus::Negative
+            } else if metadata.len() == MALFORMED_MARKER.len() as u64 {
+                match catch_not_found(|| fs::read(&path))? {
+                    Some(data) => CacheStatus::from_content(&data),
+                    None => continue,
+                }
+            } else {
+                CacheStatus::Positive
+            };
+
+            let age = metadata
+                .modified()?
+                .elapsed()
+                .map_or(0, |elapsed| elapsed.as_secs());
+
+            let version = CacheItemMeta::read(&path)?.map_or(0, |meta| meta.version);
+
+            items.push(CacheItemInfo {
+                cache: self.name,
+                key,
+                status,
+                size: metadata.len(),
+                age,
+                version,
+                path,
+            });
+        }
+
+        Ok(items)
+    }
+
+    /// Removes an item listed by [`find_items`](Self::find_items) from the cache.
+    pub fn remove_item(&self, item: &CacheItemInfo) -> io::Result<()> {
+        log::debug!("Removing {}", item.path.display());
+        catch_not_found(|| remove_file(&item.path))?;
+        CacheItemMeta::remove(&item.path)
+    }
+
     /// Create a new temporary file to use in the cache.
     pub fn tempfile(&self) -> io::Result<NamedTempFile> {
         match self.tmp_dir {
@@ -295,12 +418,189 @@ impl Cache {
     }
 }
 
-#[derive(Debug, Clone, Eq, Ord, PartialEq, PartialOrd)]
+/// Information on a cache item, as listed by [`Cache::find_items`].
+#[derive(Clone, Debug, Serialize)]
+pub struct CacheItemInfo {
+    /// The name of the cache containing the item.
+    pub cache: &'static str,
+    /// The file name of the item, which is derived from its cache key.
+    pub key: String,
+    /// The status of the item.
+    pub status: CacheStatus,
+    /// The size of the item in bytes.
+    pub size: u64,
+    /// Seconds since the item was created or last used.
+    pub age: u64,
+    /// The format version of the item, see [`CacheVersions`].
+    pub version: u32,
+    /// The full path to the item.
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+/// A file in a cache that is considered for eviction.
+#[derive(Debug)]
+struct CacheEntry {
+    /// The name of the cache containing this file.
+    cache: &'static str,
+    /// The full path to the file.
+    path: PathBuf,
+    /// The size of the file in bytes.
+    size: u64,
+    /// The last time the file was used.
+    last_used: SystemTime,
+}
+
+/// Returns the last time a cache file was used.
+///
+/// Cache hits touch the `mtime` at most once per hour, see [`Cache::open_cachefile`]. The `atime`
+/// is more accurate where the filesystem records it, so the later of both is used.
+fn last_used(metadata: &fs::Metadata) -> SystemTime {
+    let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+    match metadata.accessed() {
+        Ok(accessed) => accessed.max(modified),
+        Err(_) => modified,
+    }
+}
+
+/// Removes the least recently used files until their total size is at most `max_size`.
+///
+/// Returns the entries that remain in the cache.
+fn evict_lru(mut entries: Vec<CacheEntry>, max_size: u64) -> Vec<CacheEntry> {
+    let mut total_size: u64 = entries.iter().map(|entry| entry.size).sum();
+    if total_size <= max_size {
+        return entries;
+    }
+
+    // Sort the most recently used entries first, so that evicted entries can be popped.
+    entries.sort_by(|a, b| b.last_used.cmp(&a.last_used));
+
+    while total_size > max_size {
+        let entry = match entries.pop() {
+            Some(entry) => entry,
+            None => break,
+        };
+
+        log::debug!("Evicting {}", entry.path.display());
+        let removed = catch_not_found(|| remove_file(&entry.path))
+            .and_then(|_| CacheItemMeta::remove(&entry.path));
+        match removed {
+            Ok(_) => {
+                total_size -= entry.size;
+                metric!(counter("caches.evicted") += 1, "cache" => entry.cache);
+                metric!(counter("caches.evicted.bytes") += entry.size as i64, "cache" => entry.cache);
+            }
+            Err(e) => sentry::with_scope(
+                |scope| scope.set_extra("path", entry.path.display().to_string().into()),
+                || log::error!("Failed to evict cache file: {:?}", e),
+            ),
+        }
+    }
+
+    entries
+}
+
+#[derive(Debug, Clone, Eq, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
 pub struct CacheKey {
     pub cache_key: String,
     pub scope: Scope,
 }
 
+/// Versions of the format of cache items.
+///
+/// Derived caches are stored under the [`CacheKey`] of the object file they are computed from,
+/// and record the version of their format in their [`CacheItemMeta`].  After the format changes,
+/// items of older versions can still be served while they are lazily recomputed in place from the
+/// cached object file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CacheVersions {
+    /// The version in which new items are written.
+    pub current: u32,
+    /// Older versions that can be served while the item is recomputed in the current version.
+    pub fallbacks: &'static [u32],
+}
+
+impl CacheVersions {
+    /// Returns the current version followed by all fallback versions.
+    pub fn all(self) -> impl Iterator<Item = u32> {
+        std::iter::once(self.current).chain(self.fallbacks.iter().copied())
+    }
+}
+
+/// File name suffix of the [`CacheItemMeta`] stored next to a cache item.
+///
+/// Since cache keys are safe path segments without dots, this cannot collide with other items.
+const META_SUFFIX: &str = ".meta";
+
+/// Metadata of a derived cache item, stored in a file next to the item.
+///
+/// Items without metadata have been written before their format was versioned, and are
+/// considered to be of version 0.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct CacheItemMeta {
+    /// The key of the object file this item was computed from.
+    pub source: CacheKey,
+    /// The format version of this item, see [`CacheVersions`].
+    pub version: u32,
+}
+
+impl CacheItemMeta {
+    /// Reads the metadata of the item at `path`.
+    ///
+    /// Returns `None` if the item has no metadata or it cannot be read.
+    pub fn read(path: &Path) -> io::Result<Option<Self>> {
+        let data = match catch_not_found(|| fs::read(meta_path(path)))? {
+            Some(data) => data,
+            None => return Ok(None),
+        };
+
+        Ok(serde_json::from_slice(&data).ok())
+    }
+
+    /// Writes the metadata of the item at `path`.
+    ///
+    /// The metadata is written to a temporary file next to the item first, so that it is never
+    /// read partially written.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let meta_path = meta_path(path);
+        let dir = meta_path.parent().unwrap_or_else(|| Path::new("."));
+        let mut file = NamedTempFile::new_in(dir)?;
+        serde_json::to_writer(&mut file, self)?;
+        file.persist(&meta_path).map_err(|e| e.error)?;
+        Ok(())
+    }
+
+    /// Removes the metadata of the item at `path`, if it exists.
+    pub fn remove(path: &Path) -> io::Result<()> {
+        catch_not_found(|| remove_file(meta_path(path)))?;
+        Ok(())
+    }
+}
+
+/// Returns the path of the [`CacheItemMeta`] of the item at `path`.
+fn meta_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(META_SUFFIX);
+    path.into()
+}
+
+/// Returns the path of the item if `path` points to its [`CacheItemMeta`].
+pub(crate) fn item_path(path: &Path) -> Option<PathBuf> {
+    let path = path.to_str()?;
+    path.strip_suffix(META_SUFFIX).map(PathBuf::from)
+}
+
+/// Returns the file name of a cache item in the given format version.
+///
+/// Version 0 refers to items written before cache formats were versioned, and has no suffix.
+fn versioned_name(cache_key: &str, version: u32) -> String {
+    let name = safe_path_segment(cache_key);
+    match version {
+        0 => name,
+        version => format!("{}.v{}", name, version),
+    }
+}
+
 pub fn get_scope_path(cache_dir: Option<&Path>, scope: &Scope, cache_key: &str) -> Option<PathBuf> {
     Some(
         cache_dir?
@@ -309,7 +609,19 @@ pub fn get_scope_path(cache_dir: Option<&Path>, scope: &Scope, cache_key: &str)
     )
 }
 
-fn safe_path_segment(s: &str) -> String {
+/// Returns the relative key of a cache item, used to store it outside of the local cache.
+///
+/// Just like [`get_scope_path`], this keeps items of different scopes apart.  Since other
+/// instances may run a different version, items of different format versions are kept apart, too.
+pub fn get_scope_key(scope: &Scope, cache_key: &str, version: u32) -> String {
+    format!(
+        "{}/{}",
+        safe_path_segment(scope.as_ref()),
+        versioned_name(cache_key, version)
+    )
+}
+
+pub(crate) fn safe_path_segment(s: &str) -> String {
     s.replace(".", "_") // protect against ".."
         .replace("/", "_") // protect against absolute paths
         .replace(":", "_") // not a threat on POSIX filesystems, but confuses OS X Finder
@@ -328,6 +640,7 @@ where
     }
 }
 
+#[derive(Clone, Debug)]
 pub struct Caches {
     /// Caches for object files, used by [`crate::services::objects::ObjectsActor`].
     pub objects: Cache,
@@ -343,6 +656,9 @@ pub struct Caches {
     /// Store for diagnostics data symbolicator failed to process, used by
     /// [`crate::services::symbolication::SymbolicationActor`].
     pub diagnostics: Cache,
+    /// Store for persisted symbolication requests, used by
+    /// [`crate::services::requests::RequestStore`].
+    pub requests: Cache,
 }
 
 impl Caches {
@@ -399,13 +715,31 @@ impl Caches {
                 Cache::from_config(
                     "diagnostics",
                     path,
-                    tmp_dir,
+                    tmp_dir.clone(),
                     config.caches.diagnostics.into(),
                 )?
             },
+            requests: {
+                let path = if config.requests.persist {
+                    config.cache_dir("requests")
+                } else {
+                    None
+                };
+                Cache::from_config("requests", path, tmp_dir, config.requests.into())?
+            },
         })
     }
 
+    /// Returns the caches that store items for individual object files.
+    pub fn object_caches(&self) -> [&Cache; 4] {
+        [
+            &self.object_meta,
+            &self.objects,
+            &self.symcaches,
+            &self.cficaches,
+        ]
+    }
+
     /// Clear the temporary files.
     ///
     /// We need to do this on startup of the main symbolicator process to avoid accidentally
@@ -420,11 +754,25 @@ impl Caches {
         Ok(())
     }
 
-    pub fn cleanup(&self) -> Result<()> {
-        self.objects.cleanup()?;
-        self.object_meta.cleanup()?;
-        self.symcaches.cleanup()?;
-        self.cficaches.cleanup()?;
+    /// Cleans up all caches.
+    ///
+    /// Besides the limits of each individual cache, this enforces the combined `max_size` of all
+    /// caches, which evicts the least recently used items across all caches first.
+    pub fn cleanup(&self, config: &Config) -> Result<()> {
+        let mut entries = Vec::new();
+        entries.extend(self.objects.cleanup_entries()?);
+        entries.extend(self.object_meta.cleanup_entries()?);
+        entries.extend(self.symcaches.cleanup_entries()?);
+        entries.extend(self.cficaches.cleanup_entries()?);
+
+        if let Some(max_size) = config.caches.max_size {
+            evict_lru(entries, max_size);
+        }
+
+        // Persisted requests are not a cache and do not count towards the size limit.
+        if self.requests.cache_dir().is_some() {
+            self.requests.cleanup()?;
+        }
         Ok(())
     }
 }
@@ -433,7 +781,30 @@ impl Caches {
 ///
 /// This will clean up all caches based on configured cache retention.
 pub fn cleanup(config: Config) -> Result<()> {
-    Caches::from_config(&config)?.cleanup()
+    Caches::from_config(&config)?.cleanup(&config)
+}
+
+/// Periodically cleans up all caches on a background thread.
+///
+/// This enforces cache retention and size limits while the server is running.  Does nothing
+/// unless `caches.cleanup_interval` and a `cache_dir` are configured.
+pub fn spawn_cleanup(config: Arc<Config>) -> Result<()> {
+    let interval = match config.caches.cleanup_interval {
+        Some(interval) if config.cache_dir.is_some() => interval,
+        _ => return Ok(()),
+    };
+
+    let caches = Caches::from_config(&config)?;
+    thread::Builder::new()
+        .name("symbolicator-cleanup".into())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            if let Err(e) = caches.cleanup(&config) {
+                log::error!("Failed to clean up caches: {:?}", e);
+            }
+        })?;
+
+    Ok(())
 }
 
 #[cfg(test)]
@@ -445,7 +816,7 @@ mod tests {
     use std::io::Write;
     use std::thread::sleep;
 
-    use crate::config::DerivedCacheConfig;
+    use crate::config::{CacheConfigs, DerivedCacheConfig};
 
     fn tempdir() -> io::Result<tempfile::TempDir> {
         tempfile::tempdir_in(".")
@@ -613,6 +984,180 @@ mod tests {
         Ok(())
     }
 
+    /// Sets both the `atime` and `mtime` of the file to the given number of seconds ago.
+    fn set_last_used(path: &Path, secs_ago: u64) -> Result<()> {
+        let time = FileTime::from_system_time(SystemTime::now() - Duration::from_secs(secs_ago));
+        filetime::set_file_times(path, time, time)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_size() -> Result<()> {
+        let tempdir = tempdir()?;
+        create_dir_all(tempdir.path().join("foo"))?;
+
+        let cache = Cache::from_config(
+            "test",
+            Some(tempdir.path().to_path_buf()),
+            None,
+            CacheConfig::Derived(DerivedCacheConfig {
+                max_size: Some(10),
+                ..Default::default()
+            }),
+        )?;
+
+        File::create(tempdir.path().join("foo/killthis"))?.write_all(b"hello")?;
+        File::create(tempdir.path().join("foo/keepthis"))?.write_all(b"hello")?;
+        File::create(tempdir.path().join("foo/keepthis2"))?.write_all(b"hello")?;
+        set_last_used(&tempdir.path().join("foo/killthis"), 300)?;
+        set_last_used(&tempdir.path().join("foo/keepthis"), 200)?;
+        set_last_used(&tempdir.path().join("foo/keepthis2"), 100)?;
+
+        cache.cleanup()?;
+
+        let mut basenames: Vec<_> = read_dir(tempdir.path().join("foo"))?
+            .map(|x| x.unwrap().file_name().into_string().unwrap())
+            .collect();
+
+        basenames.sort();
+
+        assert_eq!(basenames, vec!["keepthis", "keepthis2"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_global_max_size() -> Result<()> {
+        let tempdir = tempdir()?;
+        let cfg = Config {
+            cache_dir: Some(tempdir.path().to_path_buf()),
+            caches: CacheConfigs {
+                max_size: Some(10),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let caches = Caches::from_config(&cfg)?;
+
+        let killthis = tempdir.path().join("objects/killthis");
+        let keepthis = tempdir.path().join("symcaches/keepthis");
+        let keepthis2 = tempdir.path().join("objects/keepthis2");
+        File::create(&killthis)?.write_all(b"hello")?;
+        File::create(&keepthis)?.write_all(b"hello")?;
+        File::create(&keepthis2)?.write_all(b"hello")?;
+        set_last_used(&killthis, 300)?;
+        set_last_used(&keepthis, 200)?;
+        set_last_used(&keepthis2, 100)?;
+
+        caches.cleanup(&cfg)?;
+
+        assert!(!killthis.exists());
+        assert!(keepthis.exists());
+        assert!(keepthis2.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_items() -> Result<()> {
+        let tempdir = tempdir()?;
+        create_dir_all(tempdir.path().join("global"))?;
+
+        let cache = Cache::from_config(
+            "test",
+            Some(tempdir.path().to_path_buf()),
+            None,
+            CacheConfig::Derived(Default::default()),
+        )?;
+
+        File::create(tempdir.path().join("global/positive"))?.write_all(b"hello")?;
+        File::create(tempdir.path().join("global/negative"))?.write_all(b"")?;
+        File::create(tempdir.path().join("global/malformed"))?.write_all(b"malformed")?;
+        File::create(tempdir.path().join("global/other"))?.write_all(b"hello")?;
+
+        let mut items = cache.find_items(&Scope::Global, |key| key != "other")?;
+        items.sort_by(|a, b| a.key.cmp(&b.key));
+
+        let statuses: Vec<_> = items
+            .iter()
+            .map(|item| (item.key.as_str(), item.status, item.size))
+            .collect();
+        assert_eq!(
+            statuses,
+            vec![
+                ("malformed", CacheStatus::Malformed, 9),
+                ("negative", CacheStatus::Negative, 0),
+                ("positive", CacheStatus::Positive, 5),
+            ]
+        );
+
+        cache.remove_item(&items[0])?;
+        assert!(!tempdir.path().join("global/malformed").exists());
+        assert!(tempdir.path().join("global/other").exists());
+
+        // Items of other scopes are not listed.
+        let scope = Scope::Scoped("other".to_owned());
+        assert!(cache.find_items(&scope, |_| true)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_scope_key_versions() {
+        let scope = Scope::Scoped("1".to_owned());
+
+        assert_eq!(
+            get_scope_key(&scope, "source.foo/bar.pdb", 0),
+            "1/source_foo_bar_pdb"
+        );
+        assert_eq!(
+            get_scope_key(&scope, "source.foo/bar.pdb", 2),
+            "1/source_foo_bar_pdb.v2"
+        );
+    }
+
+    #[test]
+    fn test_item_meta() -> Result<()> {
+        let tempdir = tempdir()?;
+        create_dir_all(tempdir.path().join("global"))?;
+
+        let cache = Cache::from_config(
+            "test",
+            Some(tempdir.path().to_path_buf()),
+            None,
+            CacheConfig::Derived(Default::default()),
+        )?;
+
+        let path = tempdir.path().join("global/item");
+        File::create(&path)?.write_all(b"hello")?;
+        assert_eq!(CacheItemMeta::read(&path)?, None);
+
+        let meta = CacheItemMeta {
+            source: CacheKey {
+                cache_key: "item".to_owned(),
+                scope: Scope::Global,
+            },
+            version: 2,
+        };
+        meta.write(&path)?;
+        assert_eq!(CacheItemMeta::read(&path)?, Some(meta));
+
+        // Metadata is not listed as an item of its own, and removed along with the item.
+        let items = cache.find_items(&Scope::Global, |_| true)?;
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].version, 2);
+
+        cache.remove_item(&items[0])?;
+        assert!(!tempdir.path().join("global/item.meta").exists());
+
+        // Orphaned metadata is removed by cleanup.
+        File::create(tempdir.path().join("global/orphan.meta"))?;
+        cache.cleanup()?;
+        assert!(!tempdir.path().join("global/orphan.meta").exists());
+
+        Ok(())
+    }
+
     #[test]
     fn test_open_cachefile() -> Result<()> {
         // Assert that opening a cache touches the mtime but does not invalidate it.
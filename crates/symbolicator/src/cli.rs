@@ -8,7 +8,9 @@ use crate::cache;
 use crate::config::Config;
 use crate::logging;
 use crate::metrics;
+use crate::offline::{self, InputKind, OutputFormat};
 use crate::server;
+use crate::verify;
 
 fn get_crate_version() -> &'static str {
     env!("CARGO_PKG_VERSION")
@@ -34,6 +36,63 @@ enum Command {
     /// Clean local caches.
     #[structopt(name = "cleanup")]
     Cleanup,
+
+    /// Manage local caches.
+    #[structopt(name = "cache")]
+    Cache(CacheCommand),
+
+    /// Symbolicate a minidump and print the result.
+    #[structopt(name = "process-minidump")]
+    ProcessMinidump(ProcessArgs),
+
+    /// Symbolicate an Apple crash report and print the result.
+    #[structopt(name = "process-apple-crash-report")]
+    ProcessAppleCrashReport(ProcessArgs),
+
+    /// Symbolicate a JSON payload of the `/symbolicate` endpoint and print the result.
+    #[structopt(name = "symbolicate")]
+    Symbolicate(ProcessArgs),
+}
+
+/// Commands to manage local caches.
+#[derive(StructOpt)]
+enum CacheCommand {
+    /// Verify the integrity of all cache files and print a summary.
+    #[structopt(name = "verify")]
+    Verify {
+        /// Remove corrupt cache files, orphaned temporary files and unknown files.
+        #[structopt(long = "delete")]
+        delete: bool,
+    },
+}
+
+/// Arguments of the commands processing crash files.
+#[derive(StructOpt)]
+struct ProcessArgs {
+    /// Path to the file to process.
+    #[structopt(value_name = "FILE")]
+    pub file: PathBuf,
+
+    /// Path to a YAML or JSON file with a list of sources. Defaults to the configured sources.
+    #[structopt(long = "sources", value_name = "FILE")]
+    pub sources: Option<PathBuf>,
+
+    /// The output format, either `json` or `text`.
+    #[structopt(long = "format", value_name = "FORMAT", default_value = "json")]
+    pub format: OutputFormat,
+}
+
+impl ProcessArgs {
+    /// Symbolicates the file as the given kind of input.
+    fn process(&self, config: Config, kind: InputKind) -> Result<()> {
+        offline::process(
+            config,
+            kind,
+            &self.file,
+            self.sources.as_deref(),
+            self.format,
+        )
+    }
 }
 
 /// Command line interface parser.
@@ -84,6 +143,14 @@ pub fn execute() -> Result<()> {
     match cli.command {
         Command::Run => server::run(config).context("failed to start the server")?,
         Command::Cleanup => cache::cleanup(config).context("failed to clean up caches")?,
+        Command::Cache(CacheCommand::Verify { delete }) => {
+            verify::verify(config, delete).context("failed to verify caches")?
+        }
+        Command::ProcessMinidump(ref args) => args.process(config, InputKind::Minidump)?,
+        Command::ProcessAppleCrashReport(ref args) => {
+            args.process(config, InputKind::AppleCrashReport)?
+        }
+        Command::Symbolicate(ref args) => args.process(config, InputKind::Stacktraces)?,
     }
 
     Ok(())
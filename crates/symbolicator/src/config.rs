@@ -8,8 +8,9 @@ use anyhow::{Context, Result};
 use log::LevelFilter;
 use sentry::types::Dsn;
 use serde::Deserialize;
+use url::Url;
 
-use crate::sources::SourceConfig;
+use crate::sources::{DownloadLimits, RetryPolicy, S3SourceKey, SourceConfig};
 
 /// Controls the log format
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize)]
@@ -86,6 +87,22 @@ pub struct DownloadedCacheConfig {
     /// Maximum duration since creation of malformed cache item (item age).
     #[serde(with = "humantime_serde")]
     pub retry_malformed_after: Option<Duration>,
+
+    /// Maximum size of the cache in bytes.
+    ///
+    /// When exceeded, cleanup evicts the least recently used items.
+    pub max_size: Option<u64>,
+
+    /// Store object files compressed with zstd.
+    ///
+    /// Compressed object files are decompressed into a temporary file when they are loaded.
+    pub compress: bool,
+
+    /// Maximum size in bytes of decompressed copies of frequently used object files.
+    ///
+    /// Only applies if objects are stored compressed.  Objects with a decompressed copy are served
+    /// without decompressing them again.
+    pub max_decompressed: Option<u64>,
 }
 
 impl Default for DownloadedCacheConfig {
@@ -94,6 +111,9 @@ impl Default for DownloadedCacheConfig {
             max_unused_for: Some(Duration::from_secs(3600 * 24)),
             retry_misses_after: Some(Duration::from_secs(3600)),
             retry_malformed_after: Some(Duration::from_secs(3600 * 24)),
+            max_size: None,
+            compress: false,
+            max_decompressed: Some(1 << 30),
         }
     }
 }
@@ -115,6 +135,17 @@ pub struct DerivedCacheConfig {
     /// Maximum duration since creation of malformed cache item (item age).
     #[serde(with = "humantime_serde")]
     pub retry_malformed_after: Option<Duration>,
+
+    /// Maximum size of the cache in bytes.
+    ///
+    /// When exceeded, cleanup evicts the least recently used items.
+    pub max_size: Option<u64>,
+
+    /// Maximum size in bytes of loaded items kept in memory.
+    ///
+    /// Frequently used items are served from memory without accessing the file system.  Disabled
+    /// by default.
+    pub max_memory: Option<u64>,
 }
 
 impl Default for DerivedCacheConfig {
@@ -123,6 +154,8 @@ impl Default for DerivedCacheConfig {
             max_unused_for: Some(Duration::from_secs(3600 * 24 * 7)),
             retry_misses_after: Some(Duration::from_secs(3600)),
             retry_malformed_after: Some(Duration::from_secs(3600 * 24)),
+            max_size: None,
+            max_memory: None,
         }
     }
 }
@@ -144,12 +177,38 @@ impl Default for DiagnosticsCacheConfig {
     }
 }
 
+/// Configuration for persisting symbolication requests.
+///
+/// When enabled, the states of symbolication requests and their finished responses are stored
+/// in the `requests` directory under the configured `cache_dir`.  This allows clients to poll
+/// for responses even after symbolicator has been restarted.
+#[derive(Debug, Clone, Copy, Deserialize, Eq, PartialEq)]
+#[serde(default)]
+pub struct RequestsConfig {
+    /// Persist requests to disk.
+    pub persist: bool,
+
+    /// Time to keep finished requests available for polling.
+    #[serde(with = "humantime_serde")]
+    pub retention: Option<Duration>,
+}
+
+impl Default for RequestsConfig {
+    fn default() -> Self {
+        Self {
+            persist: false,
+            retention: Some(Duration::from_secs(3600)),
+        }
+    }
+}
+
 /// Struct to treat all cache configs identical in cache code.
 #[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum CacheConfig {
     Downloaded(DownloadedCacheConfig),
     Derived(DerivedCacheConfig),
     Diagnostics(DiagnosticsCacheConfig),
+    Requests(RequestsConfig),
 }
 
 impl CacheConfig {
@@ -158,6 +217,7 @@ impl CacheConfig {
             Self::Downloaded(cfg) => cfg.max_unused_for,
             Self::Derived(cfg) => cfg.max_unused_for,
             Self::Diagnostics(cfg) => cfg.retention,
+            Self::Requests(cfg) => cfg.retention,
         }
     }
 
@@ -166,6 +226,7 @@ impl CacheConfig {
             Self::Downloaded(cfg) => cfg.retry_misses_after,
             Self::Derived(cfg) => cfg.retry_misses_after,
             Self::Diagnostics(_cfg) => None,
+            Self::Requests(_cfg) => None,
         }
     }
 
@@ -174,6 +235,34 @@ impl CacheConfig {
             Self::Downloaded(cfg) => cfg.retry_malformed_after,
             Self::Derived(cfg) => cfg.retry_malformed_after,
             Self::Diagnostics(_cfg) => None,
+            Self::Requests(_cfg) => None,
+        }
+    }
+
+    pub fn max_size(&self) -> Option<u64> {
+        match self {
+            Self::Downloaded(cfg) => cfg.max_size,
+            Self::Derived(cfg) => cfg.max_size,
+            Self::Diagnostics(_cfg) => None,
+            Self::Requests(_cfg) => None,
+        }
+    }
+
+    pub fn compress(&self) -> bool {
+        match self {
+            Self::Downloaded(cfg) => cfg.compress,
+            Self::Derived(_cfg) => false,
+            Self::Diagnostics(_cfg) => false,
+            Self::Requests(_cfg) => false,
+        }
+    }
+
+    pub fn max_memory(&self) -> Option<u64> {
+        match self {
+            Self::Downloaded(cfg) => cfg.max_decompressed.filter(|_| cfg.compress),
+            Self::Derived(cfg) => cfg.max_memory,
+            Self::Diagnostics(_cfg) => None,
+            Self::Requests(_cfg) => None,
         }
     }
 }
@@ -196,6 +285,12 @@ impl From<DiagnosticsCacheConfig> for CacheConfig {
     }
 }
 
+impl From<RequestsConfig> for CacheConfig {
+    fn from(source: RequestsConfig) -> Self {
+        Self::Requests(source)
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Default)]
 #[serde(default)]
 pub struct CacheConfigs {
@@ -207,6 +302,175 @@ pub struct CacheConfigs {
     ///
     /// E.g. minidumps which caused a crash in symbolicator will be stored here.
     pub diagnostics: DiagnosticsCacheConfig,
+    /// Maximum size of all caches combined in bytes.
+    ///
+    /// This is enforced in addition to the limits of the individual caches.
+    pub max_size: Option<u64>,
+    /// Interval at which the server cleans up caches in the background.
+    ///
+    /// Defaults to `None`, which requires running `symbolicator cleanup` externally.
+    #[serde(with = "humantime_serde")]
+    pub cleanup_interval: Option<Duration>,
+}
+
+/// Configuration of a shared cache directory on a filesystem.
+#[derive(Clone, Debug, Deserialize)]
+pub struct FilesystemSharedCacheConfig {
+    /// The directory of the shared cache, for example on an NFS mount.
+    pub path: PathBuf,
+}
+
+/// Configuration of a shared cache in an S3 bucket.
+#[derive(Clone, Debug, Deserialize)]
+pub struct S3SharedCacheConfig {
+    /// Name of the S3 bucket.
+    pub bucket: String,
+
+    /// A path from the root of the bucket where caches are stored.
+    #[serde(default)]
+    pub prefix: String,
+
+    /// Authorization information for this bucket.
+    #[serde(flatten)]
+    pub source_key: Arc<S3SourceKey>,
+}
+
+/// A second-tier cache of derived caches, shared between symbolicator instances.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SharedCacheConfig {
+    /// A directory on a shared filesystem.
+    Filesystem(FilesystemSharedCacheConfig),
+    /// An S3 bucket.
+    S3(S3SharedCacheConfig),
+}
+
+/// Limits for downloads from external sources.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct DownloadLimitsConfig {
+    /// Default limits for every source, which sources can override.
+    pub per_source: DownloadLimits,
+
+    /// Limits for every host, shared by all HTTP and Sentry sources on that host.
+    pub per_host: DownloadLimits,
+}
+
+/// Circuit breaking for sources that fail repeatedly.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    /// Number of consecutive failed downloads after which a source is disabled.
+    ///
+    /// Set to `None` to never disable sources.
+    pub failure_threshold: Option<u32>,
+
+    /// How long a source remains disabled before it is tried again.
+    #[serde(with = "humantime_serde")]
+    pub cooldown: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: Some(5),
+            cooldown: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Fetching and applying source context.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct SourceContextConfig {
+    /// Maximum number of source files fetched concurrently for a single request.
+    pub max_concurrent_fetches: usize,
+
+    /// Maximum number of context lines requests can ask for before and after each frame.
+    pub max_context_lines: usize,
+}
+
+impl Default for SourceContextConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent_fetches: 10,
+            max_context_lines: 20,
+        }
+    }
+}
+
+/// Timeouts for downloads, computations and requests.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct TimeoutsConfig {
+    /// Timeout for downloading a single file from a source, which sources can override.
+    #[serde(with = "humantime_serde")]
+    pub download: Duration,
+
+    /// Timeout for listing the files of a Sentry source.
+    #[serde(with = "humantime_serde")]
+    pub list_files: Duration,
+
+    /// Timeout for fetching a single file from a source root.
+    #[serde(with = "humantime_serde")]
+    pub source_file: Duration,
+
+    /// Timeout for fetching and processing an object file, including its download.
+    #[serde(with = "humantime_serde")]
+    pub object: Duration,
+
+    /// Timeout for computing a symcache, including the download of its object files.
+    #[serde(with = "humantime_serde")]
+    pub symcache: Duration,
+
+    /// Timeout for computing a cficache, including the download of its object file.
+    #[serde(with = "humantime_serde")]
+    pub cficache: Duration,
+
+    /// Timeout for reading the modules referenced by a minidump.
+    #[serde(with = "humantime_serde")]
+    pub referenced_modules: Duration,
+
+    /// Timeout for stackwalking a minidump.
+    #[serde(with = "humantime_serde")]
+    pub stackwalking: Duration,
+
+    /// Timeout for parsing an Apple crash report.
+    #[serde(with = "humantime_serde")]
+    pub apple_crash_report: Duration,
+
+    /// Timeout for fetching, storing or removing a single item of the shared cache.
+    #[serde(with = "humantime_serde")]
+    pub shared_cache: Duration,
+
+    /// Timeout for an entire symbolication request.
+    #[serde(with = "humantime_serde")]
+    pub symbolication: Duration,
+
+    /// Time reserved for completing requests with partial results after fetching stops.
+    ///
+    /// Such requests stop fetching debug files this long before the `symbolication` timeout.
+    #[serde(with = "humantime_serde")]
+    pub partial_results_margin: Duration,
+}
+
+impl Default for TimeoutsConfig {
+    fn default() -> Self {
+        Self {
+            download: Duration::from_secs(300),
+            list_files: Duration::from_secs(30),
+            source_file: Duration::from_secs(30),
+            object: Duration::from_secs(600),
+            symcache: Duration::from_secs(1200),
+            cficache: Duration::from_secs(1200),
+            referenced_modules: Duration::from_secs(20),
+            stackwalking: Duration::from_secs(60),
+            apple_crash_report: Duration::from_secs(1200),
+            shared_cache: Duration::from_secs(300),
+            symbolication: Duration::from_secs(3600),
+            partial_results_margin: Duration::from_secs(10),
+        }
+    }
 }
 
 /// See README.md for more information on config values.
@@ -231,17 +495,49 @@ pub struct Config {
     /// Fine-tune cache expiry
     pub caches: CacheConfigs,
 
+    /// Persistence of symbolication requests across restarts.
+    pub requests: RequestsConfig,
+
+    /// A shared cache for symcaches and cficaches.
+    pub shared_cache: Option<SharedCacheConfig>,
+
     /// Enables symbol proxy mode.
     pub symstore_proxy: bool,
 
+    /// Enables the cache administration endpoints.
+    pub cache_admin: bool,
+
     /// Default list of sources and the sources used for proxy mode.
     pub sources: Arc<[SourceConfig]>,
 
     /// Allow reserved IP addresses for requests to sources.
     pub connect_to_reserved_ips: bool,
 
+    /// Endpoints of S3-compatible services that S3 sources may use instead of AWS.
+    ///
+    /// An endpoint is allowed if its scheme, host and port match one of these URLs.
+    pub allowed_s3_endpoints: Vec<Url>,
+
     /// Number of subprocesses in the internal processing pool.
     pub processing_pool_size: usize,
+
+    /// Concurrency and rate limits for downloads from sources.
+    pub download_limits: DownloadLimitsConfig,
+
+    /// Temporarily disables sources after repeated download failures.
+    pub circuit_breaker: CircuitBreakerConfig,
+
+    /// Default retry policy for downloads, which sources can override.
+    pub download_retry: RetryPolicy,
+
+    /// Timeouts for downloads, computations and requests.
+    pub timeouts: TimeoutsConfig,
+
+    /// Fetching and applying source context.
+    pub source_context: SourceContextConfig,
+
+    /// Maximum number of modules a prefetch request fetches caches for at the same time.
+    pub max_concurrent_prefetches: usize,
 }
 
 impl Config {
@@ -301,10 +597,20 @@ impl Default for Config {
             metrics: Metrics::default(),
             sentry_dsn: None,
             caches: CacheConfigs::default(),
+            requests: RequestsConfig::default(),
+            shared_cache: None,
             symstore_proxy: true,
+            cache_admin: false,
             sources: Arc::from(vec![]),
             connect_to_reserved_ips: false,
+            allowed_s3_endpoints: Vec::new(),
             processing_pool_size: num_cpus::get(),
+            download_limits: DownloadLimitsConfig::default(),
+            circuit_breaker: CircuitBreakerConfig::default(),
+            download_retry: RetryPolicy::default(),
+            timeouts: TimeoutsConfig::default(),
+            source_context: SourceContextConfig::default(),
+            max_concurrent_prefetches: 10,
         }
     }
 }
@@ -395,6 +701,253 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_cache_max_size() {
+        let cfg = Config::get(None).unwrap();
+        assert_eq!(cfg.caches.max_size, None);
+        assert_eq!(cfg.caches.cleanup_interval, None);
+
+        let yaml = r#"
+            caches:
+              derived:
+                max_size: 1073741824
+              max_size: 10737418240
+              cleanup_interval: 10m
+        "#;
+        let cfg = Config::from_reader(yaml.as_bytes()).unwrap();
+        assert_eq!(cfg.caches.derived.max_size, Some(1 << 30));
+        assert_eq!(cfg.caches.downloaded.max_size, None);
+        assert_eq!(cfg.caches.max_size, Some(10 << 30));
+        assert_eq!(cfg.caches.cleanup_interval, Some(Duration::from_secs(600)));
+    }
+
+    #[test]
+    fn test_cache_compress() {
+        let cfg = Config::get(None).unwrap();
+        assert!(!cfg.caches.downloaded.compress);
+        assert_eq!(cfg.caches.downloaded.max_decompressed, Some(1 << 30));
+
+        let yaml = r#"
+            caches:
+              downloaded:
+                compress: true
+        "#;
+        let cfg = Config::from_reader(yaml.as_bytes()).unwrap();
+        assert!(CacheConfig::from(cfg.caches.downloaded).compress());
+        assert!(!CacheConfig::from(cfg.caches.derived).compress());
+
+        // Decompressed copies are kept in memory only if objects are compressed.
+        assert_eq!(
+            CacheConfig::from(cfg.caches.downloaded).max_memory(),
+            Some(1 << 30)
+        );
+
+        let yaml = r#"
+            caches:
+              downloaded:
+                compress: true
+                max_decompressed: null
+        "#;
+        let cfg = Config::from_reader(yaml.as_bytes()).unwrap();
+        assert_eq!(CacheConfig::from(cfg.caches.downloaded).max_memory(), None);
+    }
+
+    #[test]
+    fn test_cache_max_memory() {
+        let cfg = Config::get(None).unwrap();
+        assert_eq!(cfg.caches.derived.max_memory, None);
+
+        let yaml = r#"
+            caches:
+              derived:
+                max_memory: 536870912
+        "#;
+        let cfg = Config::from_reader(yaml.as_bytes()).unwrap();
+        assert_eq!(cfg.caches.derived.max_memory, Some(512 << 20));
+        assert_eq!(
+            CacheConfig::from(cfg.caches.derived).max_memory(),
+            Some(512 << 20)
+        );
+        assert_eq!(CacheConfig::from(cfg.caches.downloaded).max_memory(), None);
+    }
+
+    #[test]
+    fn test_requests_config() {
+        let cfg = Config::get(None).unwrap();
+        assert_eq!(cfg.requests, RequestsConfig::default());
+        assert!(!cfg.requests.persist);
+
+        let yaml = r#"
+            requests:
+              persist: true
+              retention: 2h
+        "#;
+        let cfg = Config::from_reader(yaml.as_bytes()).unwrap();
+        assert!(cfg.requests.persist);
+        assert_eq!(cfg.requests.retention, Some(Duration::from_secs(7200)));
+    }
+
+    #[test]
+    fn test_shared_cache_config() {
+        let cfg = Config::get(None).unwrap();
+        assert!(cfg.shared_cache.is_none());
+
+        let yaml = r#"
+            shared_cache:
+              filesystem:
+                path: /mnt/symbolicator
+        "#;
+        let cfg = Config::from_reader(yaml.as_bytes()).unwrap();
+        match cfg.shared_cache {
+            Some(SharedCacheConfig::Filesystem(cfg)) => {
+                assert_eq!(cfg.path, Path::new("/mnt/symbolicator"))
+            }
+            other => panic!("unexpected shared cache: {:?}", other),
+        }
+
+        let yaml = r#"
+            shared_cache:
+              s3:
+                bucket: symbolicator-caches
+                region: us-east-1
+                access_key: the-access-key
+                secret_key: the-secret-key
+        "#;
+        let cfg = Config::from_reader(yaml.as_bytes()).unwrap();
+        match cfg.shared_cache {
+            Some(SharedCacheConfig::S3(cfg)) => {
+                assert_eq!(cfg.bucket, "symbolicator-caches");
+                assert_eq!(cfg.prefix, "");
+                assert_eq!(cfg.source_key.region, rusoto_core::Region::UsEast1);
+            }
+            other => panic!("unexpected shared cache: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_download_limits_config() {
+        let cfg = Config::get(None).unwrap();
+        assert_eq!(cfg.download_limits, DownloadLimitsConfig::default());
+        assert!(cfg.download_limits.per_source.is_unlimited());
+
+        let yaml = r#"
+            download_limits:
+              per_source:
+                max_concurrent: 10
+              per_host:
+                max_concurrent: 20
+                requests_per_second: 50
+        "#;
+        let cfg = Config::from_reader(yaml.as_bytes()).unwrap();
+        assert_eq!(
+            cfg.download_limits.per_source,
+            DownloadLimits {
+                max_concurrent: Some(10),
+                requests_per_second: None,
+            }
+        );
+        assert_eq!(
+            cfg.download_limits.per_host,
+            DownloadLimits {
+                max_concurrent: Some(20),
+                requests_per_second: Some(50.0),
+            }
+        );
+
+        // Rates that cannot be enforced are rejected instead of disabling the limit.
+        for rate in &["0", "-1"] {
+            let yaml = format!(
+                "download_limits:\n  per_source:\n    requests_per_second: {}\n",
+                rate
+            );
+            assert!(Config::from_reader(yaml.as_bytes()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_circuit_breaker_config() {
+        let cfg = Config::get(None).unwrap();
+        assert_eq!(cfg.circuit_breaker.failure_threshold, Some(5));
+        assert_eq!(cfg.circuit_breaker.cooldown, Duration::from_secs(60));
+
+        let yaml = r#"
+            circuit_breaker:
+              failure_threshold: null
+              cooldown: 5m
+        "#;
+        let cfg = Config::from_reader(yaml.as_bytes()).unwrap();
+        assert_eq!(cfg.circuit_breaker.failure_threshold, None);
+        assert_eq!(cfg.circuit_breaker.cooldown, Duration::from_secs(300));
+    }
+
+    #[test]
+    fn test_download_retry_config() {
+        let cfg = Config::get(None).unwrap();
+        assert_eq!(cfg.download_retry, RetryPolicy::default());
+
+        let yaml = r#"
+            download_retry:
+              max_attempts: 5
+              max_backoff: 1m
+        "#;
+        let cfg = Config::from_reader(yaml.as_bytes()).unwrap();
+        assert_eq!(
+            cfg.download_retry,
+            RetryPolicy {
+                max_attempts: Some(5),
+                initial_backoff: None,
+                max_backoff: Some(Duration::from_secs(60)),
+            }
+        );
+    }
+
+    #[test]
+    fn test_timeouts_config() {
+        let cfg = Config::get(None).unwrap();
+        assert_eq!(cfg.timeouts, TimeoutsConfig::default());
+        assert_eq!(cfg.timeouts.download, Duration::from_secs(300));
+
+        let yaml = r#"
+            timeouts:
+              download: 30m
+              symbolication: 30s
+        "#;
+        let cfg = Config::from_reader(yaml.as_bytes()).unwrap();
+        assert_eq!(cfg.timeouts.download, Duration::from_secs(1800));
+        assert_eq!(cfg.timeouts.symbolication, Duration::from_secs(30));
+        assert_eq!(cfg.timeouts.list_files, Duration::from_secs(30));
+        assert_eq!(cfg.timeouts.shared_cache, Duration::from_secs(300));
+    }
+
+    #[test]
+    fn test_allowed_s3_endpoints() {
+        let cfg = Config::get(None).unwrap();
+        assert!(cfg.allowed_s3_endpoints.is_empty());
+
+        let yaml = r#"
+            allowed_s3_endpoints:
+              - https://minio.example.com
+              - http://ceph.internal:7480
+        "#;
+        let cfg = Config::from_reader(yaml.as_bytes()).unwrap();
+        assert_eq!(cfg.allowed_s3_endpoints.len(), 2);
+        assert_eq!(cfg.allowed_s3_endpoints[1].port(), Some(7480));
+    }
+
+    #[test]
+    fn test_source_context() {
+        let cfg = Config::get(None).unwrap();
+        assert_eq!(cfg.source_context.max_context_lines, 20);
+
+        let yaml = r#"
+            source_context:
+              max_context_lines: 50
+        "#;
+        let cfg = Config::from_reader(yaml.as_bytes()).unwrap();
+        assert_eq!(cfg.source_context.max_context_lines, 50);
+        assert_eq!(cfg.source_context.max_concurrent_fetches, 10);
+    }
+
     #[test]
     fn test_unknown_fields() {
         // Unknown fields should not cause failure
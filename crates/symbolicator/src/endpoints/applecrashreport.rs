@@ -1,4 +1,4 @@
-use actix_web::{error, multipart, App, Error, HttpMessage, HttpRequest, Json, Query, State};
+use actix_web::{error, multipart, App, Error, HttpMessage, HttpRequest, Query, State};
 use futures::{compat::Stream01CompatExt, StreamExt};
 
 use crate::endpoints::symbolicate::SymbolicationRequestQueryParams;
@@ -13,7 +13,7 @@ async fn handle_apple_crash_report_request(
     state: State<Service>,
     params: Query<SymbolicationRequestQueryParams>,
     request: HttpRequest<Service>,
-) -> Result<Json<SymbolicationResponse>, Error> {
+) -> Result<SymbolicationResponse, Error> {
     sentry::start_session();
 
     let params = params.into_inner();
@@ -33,7 +33,9 @@ async fn handle_apple_crash_report_request(
         let content_disposition = field.content_disposition();
         match content_disposition.as_ref().and_then(|d| d.get_name()) {
             Some("apple_crash_report") => report = Some(read_multipart_file(field).await?),
-            Some("sources") => sources = read_multipart_sources(field).await?.into(),
+            Some("sources") => {
+                sources = super::request_sources(read_multipart_sources(field).await?)?
+            }
             Some("options") => options = read_multipart_request_options(field).await?,
             _ => (), // Always ignore unknown fields.
         }
@@ -46,7 +48,7 @@ async fn handle_apple_crash_report_request(
         symbolication.process_apple_crash_report(params.scope, report, sources, options);
 
     match symbolication.get_response(request_id, params.timeout).await {
-        Some(response) => Ok(Json(response)),
+        Some(response) => Ok(response),
         None => Err(error::ErrorInternalServerError(
             "symbolication request did not start",
         )),
@@ -98,6 +100,43 @@ mod tests {
         insta::assert_yaml_snapshot!(response);
     }
 
+    #[tokio::test]
+    async fn test_text_response() {
+        test::setup();
+
+        let service = Service::create(Config::default()).unwrap();
+        let server = TestServer::with_factory(move || crate::server::create_app(service.clone()));
+
+        let file_contents = test::read_fixture("apple_crash_report.txt");
+        let file_part = multipart::Part::bytes(file_contents).file_name("apple_crash_report.txt");
+
+        let form = multipart::Form::new()
+            .part("apple_crash_report", file_part)
+            .text("sources", "[]");
+
+        let response = Client::new()
+            .post(&server.url("/applecrashreport"))
+            .header("accept", "text/plain")
+            .multipart(form)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers()["content-type"],
+            "text/plain; charset=utf-8"
+        );
+
+        let body = response.text().await.unwrap();
+        let header = "Crashed:      Yes\n\
+                      Crash reason: SIGSEGV\n\
+                      Crash details: objc_msgSend() selector name: respondsToSelector:\n  \
+                      more information here\n";
+        assert!(body.starts_with(header), "unexpected header:\n{}", body);
+        assert!(body.contains("Thread 0 (id: 0)"));
+    }
+
     #[tokio::test]
     async fn test_unknown_field() {
         test::setup();
@@ -0,0 +1,330 @@
+use std::collections::BTreeSet;
+use std::io;
+
+use actix_web::{error, App, Error, HttpResponse, Query, State};
+use serde::Deserialize;
+use symbolic::common::{CodeId, DebugId};
+
+use crate::cache::{Cache, CacheItemInfo, CacheKey, CacheStatus, Caches};
+use crate::services::cficaches::CFICACHE_VERSIONS;
+use crate::services::symcaches::SYMCACHE_VERSIONS;
+use crate::services::Service;
+use crate::types::Scope;
+
+/// Query parameters of the cache administration endpoints.
+#[derive(Clone, Debug, Deserialize)]
+struct CacheItemsQuery {
+    #[serde(default)]
+    scope: Scope,
+    #[serde(default)]
+    debug_id: Option<String>,
+    #[serde(default)]
+    code_id: Option<String>,
+    /// Only includes items of the cache with this name.
+    #[serde(default)]
+    cache: Option<String>,
+    /// Only includes items with this status.
+    #[serde(default)]
+    status: Option<CacheStatus>,
+}
+
+/// Strips all separators from an identifier or cache key and lowercases it.
+///
+/// Cache keys contain identifiers in the format of the source's directory layout, which may be
+/// upper case or split into multiple path segments.  Normalizing both makes them comparable.
+fn normalize(s: &str) -> String {
+    s.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+impl CacheItemsQuery {
+    /// Returns the normalized identifiers to search for in cache keys.
+    fn identifiers(&self) -> Result<Vec<String>, Error> {
+        let mut identifiers = Vec::new();
+
+        if let Some(ref debug_id) = self.debug_id {
+            let debug_id = debug_id
+                .parse::<DebugId>()
+                .map_err(|_| error::ErrorBadRequest("invalid debug_id"))?;
+            // Only the UUID is contained in all layouts, some omit the age.
+            identifiers.push(normalize(&debug_id.uuid().to_simple().to_string()));
+        }
+
+        if let Some(ref code_id) = self.code_id {
+            let code_id = code_id
+                .parse::<CodeId>()
+                .map_err(|_| error::ErrorBadRequest("invalid code_id"))?;
+            identifiers.push(normalize(code_id.as_str()));
+        }
+
+        if identifiers.is_empty() {
+            return Err(error::ErrorBadRequest("debug_id or code_id is required"));
+        }
+
+        Ok(identifiers)
+    }
+}
+
+/// Returns `true` if the cache key contains one of the normalized identifiers.
+fn matches(identifiers: &[String], key: &str) -> bool {
+    let key = normalize(key);
+    identifiers.iter().any(|id| key.contains(id.as_str()))
+}
+
+/// Finds all cache items of an object matching the query.
+///
+/// This reads the file system, so it must not run on the threads of the HTTP server.
+fn find_items<'a>(
+    caches: &'a Caches,
+    query: &CacheItemsQuery,
+    identifiers: &[String],
+) -> io::Result<Vec<(&'a Cache, CacheItemInfo)>> {
+    let mut items = Vec::new();
+
+    for cache in caches.object_caches().iter() {
+        if query
+            .cache
+            .as_deref()
+            .map_or(false, |name| name != cache.name())
+        {
+            continue;
+        }
+
+        let cache_items = cache.find_items(&query.scope, |key| matches(identifiers, key))?;
+
+        for item in cache_items {
+            if query.status.map_or(true, |status| status == item.status) {
+                items.push((*cache, item));
+            }
+        }
+    }
+
+    Ok(items)
+}
+
+/// Lists the cache items of an object.
+async fn list_items(
+    state: State<Service>,
+    query: Query<CacheItemsQuery>,
+) -> Result<HttpResponse, Error> {
+    if !state.config().cache_admin {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let query = query.into_inner();
+    let identifiers = query.identifiers()?;
+    let service = Service::clone(&state);
+
+    let items = state
+        .spawn_blocking(move || {
+            let items = find_items(service.caches(), &query, &identifiers)?;
+            Ok::<_, io::Error>(items.into_iter().map(|(_, item)| item).collect::<Vec<_>>())
+        })
+        .await
+        .map_err(error::ErrorInternalServerError)??;
+
+    Ok(HttpResponse::Ok().json(items))
+}
+
+/// Returns the cache keys of an object in all local caches, regardless of the query's filters.
+///
+/// Derived caches share the cache key of the object file they are computed from, so keys found
+/// in any cache, including the long-lived object metadata, identify the derived caches of the
+/// object in the shared cache.  This reads the file system, just like [`find_items`].
+fn find_keys(
+    caches: &Caches,
+    scope: &Scope,
+    identifiers: &[String],
+) -> io::Result<BTreeSet<String>> {
+    let mut keys = BTreeSet::new();
+    for cache in caches.object_caches().iter() {
+        let items = cache.find_items(scope, |key| matches(identifiers, key))?;
+        keys.extend(items.into_iter().map(|item| item.key));
+    }
+    Ok(keys)
+}
+
+/// Removes the cache items of an object, and responds with the removed items.
+async fn remove_items(
+    state: State<Service>,
+    query: Query<CacheItemsQuery>,
+) -> Result<HttpResponse, Error> {
+    if !state.config().cache_admin {
+        return Ok(HttpResponse::NotFound().finish());
+    }
+
+    let query = query.into_inner();
+    let identifiers = query.identifiers()?;
+    let service = Service::clone(&state);
+    let (blocking_query, blocking_identifiers) = (query.clone(), identifiers.clone());
+
+    let (removed, keys) = state
+        .spawn_blocking(move || {
+            let caches = service.caches();
+            // Collect keys before removing any items, which would make them unknown.
+            let keys = find_keys(caches, &blocking_query.scope, &blocking_identifiers)?;
+
+            let mut removed = Vec::new();
+            let items = find_items(caches, &blocking_query, &blocking_identifiers)?;
+            for (cache, item) in items {
+                cache.remove_item(&item)?;
+                removed.push(item);
+            }
+            Ok::<_, io::Error>((removed, keys))
+        })
+        .await
+        .map_err(error::ErrorInternalServerError)??;
+
+    let includes = |name: &str| query.cache.as_deref().map_or(true, |cache| cache == name);
+
+    // Items kept in memory would otherwise still be served until they expire.
+    let caches = state.caches();
+    if includes(caches.symcaches.name()) {
+        state
+            .symcaches()
+            .remove_memory(&query.scope, |key| matches(&identifiers, key));
+    }
+    if includes(caches.cficaches.name()) {
+        state
+            .cficaches()
+            .remove_memory(&query.scope, |key| matches(&identifiers, key));
+    }
+    if includes(caches.objects.name()) {
+        state
+            .objects()
+            .remove_memory(&query.scope, |key| matches(&identifiers, key));
+    }
+
+    // Otherwise, the items would be fetched from the shared cache again on the next request.
+    // Only positive symcaches and CFI caches are shared, in any of their format versions.
+    let shared_caches = [
+        (caches.symcaches.name(), SYMCACHE_VERSIONS),
+        (caches.cficaches.name(), CFICACHE_VERSIONS),
+    ];
+    let shared_status = query
+        .status
+        .map_or(true, |status| status == CacheStatus::Positive);
+    if let (Some(shared_cache), true) = (state.shared_cache(), shared_status) {
+        for &(name, versions) in shared_caches.iter().filter(|(name, _)| includes(name)) {
+            for cache_key in &keys {
+                let key = CacheKey {
+                    cache_key: cache_key.clone(),
+                    scope: query.scope.clone(),
+                };
+                for version in versions.all() {
+                    shared_cache.clone().remove(name, &key, version).await;
+                }
+            }
+        }
+    }
+
+    metric!(counter("caches.admin.removed") += removed.len() as i64);
+    Ok(HttpResponse::Ok().json(removed))
+}
+
+pub fn configure(app: App<Service>) -> App<Service> {
+    app.resource("/caches/items", |r| {
+        let handler = compat_handler!(list_items, s, q);
+        r.get().with_async(handler);
+        let handler = compat_handler!(remove_items, s, q);
+        r.delete().with_async(handler);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use reqwest::{Client, StatusCode};
+
+    use crate::config::{Config, FilesystemSharedCacheConfig, SharedCacheConfig};
+    use crate::services::Service;
+    use crate::test::{self, TestServer};
+
+    #[tokio::test]
+    async fn test_disabled() {
+        test::setup();
+
+        let service = Service::create(Config::default()).unwrap();
+        let server = TestServer::with_factory(move || crate::server::create_app(service.clone()));
+
+        let url = server.url("/caches/items?debug_id=ff9f9f78-41db-88f0-cded-a9e1e9bff3b5-1");
+        let response = Client::new().get(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_list_and_remove() {
+        test::setup();
+
+        let cache_dir = test::tempdir();
+        let shared_dir = test::tempdir();
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            cache_admin: true,
+            shared_cache: Some(SharedCacheConfig::Filesystem(FilesystemSharedCacheConfig {
+                path: shared_dir.path().to_owned(),
+            })),
+            ..Default::default()
+        };
+        let service = Service::create(config).unwrap();
+        let server = TestServer::with_factory(move || crate::server::create_app(service.clone()));
+
+        let key = "microsoft_wkernel32_pdb_FF9F9F7841DB88F0CDEDA9E1E9BFF3B51_wkernel32_pd_";
+        let other = "microsoft_wntdll_pdb_FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF1_wntdll_pd_";
+        for cache in &["objects", "symcaches"] {
+            let scope_dir = cache_dir.path().join(cache).join("global");
+            fs::create_dir_all(&scope_dir).unwrap();
+            fs::write(scope_dir.join(key), b"hello").unwrap();
+            fs::write(scope_dir.join(other), b"").unwrap();
+        }
+
+        // The CFI cache only exists in the shared cache.
+        for cache in &["symcaches", "cficaches"] {
+            let shared_scope_dir = shared_dir.path().join(cache).join("global");
+            fs::create_dir_all(&shared_scope_dir).unwrap();
+            fs::write(shared_scope_dir.join(key), b"hello").unwrap();
+        }
+
+        let query = "debug_id=ff9f9f78-41db-88f0-cded-a9e1e9bff3b5-1";
+        let url = server.url(&format!("/caches/items?{}", query));
+        let response = Client::new().get(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let items: serde_json::Value = response.json().await.unwrap();
+        let mut caches: Vec<_> = items
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| {
+                assert_eq!(item["key"], key);
+                assert_eq!(item["status"], "positive");
+                assert_eq!(item["size"], 5);
+                item["cache"].as_str().unwrap().to_owned()
+            })
+            .collect();
+        caches.sort();
+        assert_eq!(caches, vec!["objects", "symcaches"]);
+
+        let url = server.url(&format!("/caches/items?{}&cache=symcaches", query));
+        let response = Client::new().delete(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let global_dir = |cache: &str| cache_dir.path().join(cache).join("global");
+        assert!(!global_dir("symcaches").join(key).exists());
+        assert!(global_dir("symcaches").join(other).exists());
+        assert!(global_dir("objects").join(key).exists());
+
+        let shared_global_dir = |cache: &str| shared_dir.path().join(cache).join("global");
+        assert!(!shared_global_dir("symcaches").join(key).exists());
+        assert!(shared_global_dir("cficaches").join(key).exists());
+
+        // Shared items are found by the keys of the object in other caches.
+        let url = server.url(&format!("/caches/items?{}&cache=cficaches", query));
+        let response = Client::new().delete(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(!shared_global_dir("cficaches").join(key).exists());
+    }
+}
@@ -0,0 +1,124 @@
+use actix_web::{http::Method, pred, App, HttpRequest, HttpResponse, Path, State};
+use failure::{Error, ResultExt};
+
+use crate::endpoints::proxy::{find_object, object_response};
+use crate::services::objects::ObjectPurpose;
+use crate::services::Service;
+use crate::sources::FileType;
+use crate::utils::paths::parse_debuginfod_build_id;
+
+/// Serves the executable or debug file with the given build id.
+async fn serve_object(
+    state: &Service,
+    request: &HttpRequest<Service>,
+    build_id: &str,
+    filetypes: &'static [FileType],
+) -> Result<HttpResponse, Error> {
+    let object_id = match parse_debuginfod_build_id(build_id) {
+        Some(object_id) if state.config().symstore_proxy => object_id,
+        _ => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    match find_object(state, filetypes, object_id, ObjectPurpose::Debug).await? {
+        Some(object_handle) => Ok(object_response(request, &object_handle)),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+async fn debuginfo(
+    state: State<Service>,
+    request: HttpRequest<Service>,
+    path: Path<(String,)>,
+) -> Result<HttpResponse, Error> {
+    serve_object(&state, &request, &path.0, &[FileType::ElfDebug]).await
+}
+
+async fn executable(
+    state: State<Service>,
+    request: HttpRequest<Service>,
+    path: Path<(String,)>,
+) -> Result<HttpResponse, Error> {
+    serve_object(&state, &request, &path.0, &[FileType::ElfCode]).await
+}
+
+/// Serves a source file from the source bundle of the given build id.
+async fn source(
+    state: State<Service>,
+    request: HttpRequest<Service>,
+    path: Path<(String, String)>,
+) -> Result<HttpResponse, Error> {
+    let (build_id, source_path) = path.into_inner();
+
+    let object_id = match parse_debuginfod_build_id(&build_id) {
+        Some(object_id) if state.config().symstore_proxy => object_id,
+        _ => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    let object_handle = match find_object(
+        &state,
+        FileType::sources(),
+        object_id,
+        ObjectPurpose::Source,
+    )
+    .await?
+    {
+        Some(object_handle) => object_handle,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    let object = match object_handle
+        .parse()
+        .context("failed to parse source bundle")?
+    {
+        Some(object) => object,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let session = object
+        .debug_session()
+        .context("failed to open source bundle")?;
+
+    // Debuginfod clients send absolute paths without the leading slash.
+    let absolute_path = format!("/{}", source_path);
+    let source = match session
+        .source_by_path(&absolute_path)
+        .context("failed to read source file")?
+    {
+        Some(source) => source,
+        None => match session
+            .source_by_path(&source_path)
+            .context("failed to read source file")?
+        {
+            Some(source) => source,
+            None => return Ok(HttpResponse::NotFound().finish()),
+        },
+    };
+
+    let mut response = HttpResponse::Ok();
+    response
+        .content_length(source.len() as u64)
+        .header("content-type", "application/octet-stream");
+
+    if request.method() == Method::HEAD {
+        return Ok(response.finish());
+    }
+
+    Ok(response.body(source.into_owned()))
+}
+
+pub fn configure(app: App<Service>) -> App<Service> {
+    app.resource("/buildid/{build_id}/debuginfo", |r| {
+        r.route()
+            .filter(pred::Any(pred::Get()).or(pred::Head()))
+            .with_async(compat_handler!(debuginfo, s, r, p));
+    })
+    .resource("/buildid/{build_id}/executable", |r| {
+        r.route()
+            .filter(pred::Any(pred::Get()).or(pred::Head()))
+            .with_async(compat_handler!(executable, s, r, p));
+    })
+    .resource("/buildid/{build_id}/source/{path:.+}", |r| {
+        r.route()
+            .filter(pred::Any(pred::Get()).or(pred::Head()))
+            .with_async(compat_handler!(source, s, r, p));
+    })
+}
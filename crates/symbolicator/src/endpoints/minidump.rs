@@ -1,4 +1,4 @@
-use actix_web::{error, multipart, App, Error, HttpMessage, HttpRequest, Json, Query, State};
+use actix_web::{error, multipart, App, Error, HttpMessage, HttpRequest, Query, State};
 use futures::{compat::Stream01CompatExt, StreamExt};
 
 use crate::endpoints::symbolicate::SymbolicationRequestQueryParams;
@@ -13,7 +13,7 @@ async fn handle_minidump_request(
     state: State<Service>,
     params: Query<SymbolicationRequestQueryParams>,
     request: HttpRequest<Service>,
-) -> Result<Json<SymbolicationResponse>, Error> {
+) -> Result<SymbolicationResponse, Error> {
     sentry::start_session();
 
     let params = params.into_inner();
@@ -33,7 +33,9 @@ async fn handle_minidump_request(
         let content_disposition = field.content_disposition();
         match content_disposition.as_ref().and_then(|d| d.get_name()) {
             Some("upload_file_minidump") => minidump = Some(read_multipart_file(field).await?),
-            Some("sources") => sources = read_multipart_sources(field).await?.into(),
+            Some("sources") => {
+                sources = super::request_sources(read_multipart_sources(field).await?)?
+            }
             Some("options") => options = read_multipart_request_options(field).await?,
             _ => (), // Always ignore unknown fields.
         }
@@ -45,7 +47,7 @@ async fn handle_minidump_request(
     let request_id = symbolication.process_minidump(params.scope, minidump, sources, options);
 
     match symbolication.get_response(request_id, params.timeout).await {
-        Some(response) => Ok(Json(response)),
+        Some(response) => Ok(response),
         None => Err(error::ErrorInternalServerError(
             "symbolication request did not start",
         )),
@@ -1,19 +1,52 @@
-use actix_web::App;
+use std::sync::Arc;
+
+use actix_web::{error, App, Error};
 
 use crate::services::Service;
+use crate::sources::SourceConfig;
 
 mod applecrashreport;
+mod caches;
+mod debuginfod;
 mod healthcheck;
 mod minidump;
+mod prefetch;
 mod proxy;
 mod requests;
 mod symbolicate;
 
+/// Validates the sources passed in a request.
+///
+/// Sources with ambient credentials would authenticate as Symbolicator, and source roots on the
+/// file system could read any file of the server, so they can only be configured on the server.
+fn request_sources(sources: Vec<SourceConfig>) -> Result<Arc<[SourceConfig]>, Error> {
+    for source in &sources {
+        if source.uses_ambient_credentials() {
+            return Err(error::ErrorBadRequest(format!(
+                "source {} may not use ambient credentials",
+                source.id()
+            )));
+        }
+
+        if source.uses_local_files() {
+            return Err(error::ErrorBadRequest(format!(
+                "source {} may not read from the file system",
+                source.id()
+            )));
+        }
+    }
+
+    Ok(sources.into())
+}
+
 /// Adds all endpoint routes to the app.
 pub fn configure(app: App<Service>) -> App<Service> {
     app.configure(applecrashreport::configure)
+        .configure(caches::configure)
+        .configure(debuginfod::configure)
         .configure(healthcheck::configure)
         .configure(minidump::configure)
+        .configure(prefetch::configure)
         .configure(proxy::configure)
         .configure(requests::configure)
         .configure(symbolicate::configure)
@@ -0,0 +1,216 @@
+use actix_web::{App, Error, HttpResponse, Json, Path, Query, State};
+use serde::Deserialize;
+
+use crate::services::prefetch::PrefetchModules;
+use crate::services::Service;
+use crate::sources::SourceConfig;
+use crate::types::{RawObjectInfo, RequestId, Scope};
+
+/// Query parameters of the prefetch request.
+#[derive(Deserialize)]
+struct PrefetchQueryParams {
+    #[serde(default)]
+    pub scope: Scope,
+}
+
+/// JSON body of the prefetch request.
+#[derive(Deserialize)]
+struct PrefetchRequestBody {
+    #[serde(default)]
+    pub sources: Option<Vec<SourceConfig>>,
+    #[serde(default)]
+    pub modules: Vec<RawObjectInfo>,
+}
+
+/// Path parameters of the prefetch poll request.
+#[derive(Deserialize)]
+struct PollPrefetchPath {
+    pub request_id: RequestId,
+}
+
+/// Starts prefetching caches of the given modules.
+async fn prefetch(
+    state: State<Service>,
+    params: Query<PrefetchQueryParams>,
+    body: Json<PrefetchRequestBody>,
+) -> Result<HttpResponse, Error> {
+    let params = params.into_inner();
+    let body = body.into_inner();
+    let sources = match body.sources {
+        Some(sources) => super::request_sources(sources)?,
+        None => state.config().default_sources(),
+    };
+
+    let prefetch = state.prefetch();
+    let request_id = prefetch.prefetch(PrefetchModules {
+        scope: params.scope,
+        sources,
+        modules: body.modules,
+    });
+
+    match prefetch.get_response(request_id) {
+        Some(response) => Ok(HttpResponse::Ok().json(response)),
+        None => Ok(HttpResponse::InternalServerError().finish()),
+    }
+}
+
+/// Polls the status of a prefetch request.
+async fn poll_prefetch(
+    state: State<Service>,
+    path: Path<PollPrefetchPath>,
+) -> Result<HttpResponse, Error> {
+    let path = path.into_inner();
+
+    match state.prefetch().get_response(path.request_id) {
+        Some(response) => Ok(HttpResponse::Ok().json(response)),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
+}
+
+pub fn configure(app: App<Service>) -> App<Service> {
+    app.resource("/prefetch", |r| {
+        r.post().with_async_config(
+            compat_handler!(prefetch, s, p, b),
+            |(_hub, _state, _params, body)| {
+                body.limit(5_000_000);
+            },
+        );
+    })
+    .resource("/prefetch/{request_id}", |r| {
+        let handler = compat_handler!(poll_prefetch, s, p);
+        r.get().with_async(handler);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use reqwest::{Client, StatusCode};
+    use serde_json::json;
+
+    use crate::config::Config;
+    use crate::services::Service;
+    use crate::test::{self, TestServer};
+
+    #[tokio::test]
+    async fn test_unknown_request() {
+        test::setup();
+
+        let service = Service::create(Config::default()).unwrap();
+        let server = TestServer::with_factory(move || crate::server::create_app(service.clone()));
+
+        let url = server.url("/prefetch/ff9f9f78-41db-88f0-cded-a9e1e9bff3b5");
+        let response = Client::new().get(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_ambient_credentials_rejected() {
+        test::setup();
+
+        let service = Service::create(Config::default()).unwrap();
+        let server = TestServer::with_factory(move || crate::server::create_app(service.clone()));
+
+        let body = json!({
+            "sources": [{
+                "type": "s3",
+                "id": "s3",
+                "bucket": "bucket",
+                "region": "us-east-1",
+                "credentials_provider": "chain",
+            }],
+            "modules": [],
+        });
+
+        let response = Client::new()
+            .post(server.url("/prefetch"))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_source_root_path_rejected() {
+        test::setup();
+
+        let service = Service::create(Config::default()).unwrap();
+        let server = TestServer::with_factory(move || crate::server::create_app(service.clone()));
+
+        let body = json!({
+            "sources": [{
+                "type": "source_root",
+                "id": "sources",
+                "prefix": "/",
+                "path": "/",
+            }],
+            "modules": [],
+        });
+
+        let response = Client::new()
+            .post(server.url("/prefetch"))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_missing() {
+        test::setup();
+
+        let service = Service::create(Config::default()).unwrap();
+        let server = TestServer::with_factory(move || crate::server::create_app(service.clone()));
+
+        let body = json!({
+            "sources": [],
+            "modules": [{
+                "type": "macho",
+                "debug_id": "502fc0a5-1ec1-3e47-9998-684fa139dca7",
+                "code_id": "502fc0a51ec13e479998684fa139dca7",
+                "image_addr": "0x100000000",
+                "image_size": 4096,
+            }],
+        });
+
+        let response = Client::new()
+            .post(server.url("/prefetch?scope=myscope"))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response: serde_json::Value = response.json().await.unwrap();
+        let request_id = response["request_id"].as_str().unwrap().to_owned();
+        assert_eq!(response["modules"].as_array().unwrap().len(), 1);
+
+        let url = server.url(&format!("/prefetch/{}", request_id));
+        let mut response = serde_json::Value::Null;
+        for _ in 0..100 {
+            response = Client::new()
+                .get(url.clone())
+                .send()
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+
+            if response["status"] == "completed" {
+                break;
+            }
+
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        assert_eq!(response["status"], "completed");
+        let module = &response["modules"][0];
+        assert_eq!(module["debug_id"], "502fc0a5-1ec1-3e47-9998-684fa139dca7");
+        assert_eq!(module["debug_status"], "missing");
+        assert_eq!(module["unwind_status"], "missing");
+    }
+}
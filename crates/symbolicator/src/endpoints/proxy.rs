@@ -8,20 +8,19 @@ use tokio01::codec::{BytesCodec, FramedRead};
 
 use crate::services::objects::{FindObject, ObjectHandle, ObjectPurpose};
 use crate::services::Service;
-use crate::types::Scope;
-use crate::utils::paths::parse_symstore_path;
-
-async fn load_object(state: &Service, path: &str) -> Result<Option<Arc<ObjectHandle>>, Error> {
-    let config = state.config();
-    if !config.symstore_proxy {
-        return Ok(None);
-    }
-
-    let (filetypes, object_id) = match parse_symstore_path(path) {
-        Some(tuple) => tuple,
-        None => return Ok(None),
-    };
+use crate::sources::FileType;
+use crate::types::{ObjectId, Scope};
+use crate::utils::paths::parse_proxy_path;
 
+/// Finds and downloads an object from the configured sources.
+///
+/// Returns `None` if the object could not be found in any of the sources.
+pub(super) async fn find_object(
+    state: &Service,
+    filetypes: &'static [FileType],
+    object_id: ObjectId,
+    purpose: ObjectPurpose,
+) -> Result<Option<Arc<ObjectHandle>>, Error> {
     log::debug!("Searching for {:?} ({:?})", object_id, filetypes);
 
     let found_object = state
@@ -29,9 +28,9 @@ async fn load_object(state: &Service, path: &str) -> Result<Option<Arc<ObjectHan
         .find(FindObject {
             filetypes,
             identifier: object_id,
-            sources: config.default_sources(),
+            sources: state.config().default_sources(),
             scope: Scope::Global,
-            purpose: ObjectPurpose::Debug,
+            purpose,
         })
         .await
         .context("failed to download object")?;
@@ -54,28 +53,57 @@ async fn load_object(state: &Service, path: &str) -> Result<Option<Arc<ObjectHan
     }
 }
 
-async fn proxy_symstore_request(
-    state: State<Service>,
-    request: HttpRequest<Service>,
-    path: Path<(String,)>,
-) -> Result<HttpResponse, Error> {
-    let object_handle = match load_object(&state, &path.0).await? {
-        Some(handle) => handle,
-        None => return Ok(HttpResponse::NotFound().finish()),
-    };
+async fn load_object(state: &Service, path: &str) -> Result<Option<Arc<ObjectHandle>>, Error> {
+    if !state.config().symstore_proxy {
+        return Ok(None);
+    }
+
+    // Paths can be ambiguous across layouts, so try every interpretation until one is found.
+    for (filetypes, object_id) in parse_proxy_path(path) {
+        let purpose = if filetypes.contains(&FileType::SourceBundle) {
+            ObjectPurpose::Source
+        } else {
+            ObjectPurpose::Debug
+        };
+
+        if let Some(object_handle) = find_object(state, filetypes, object_id, purpose).await? {
+            return Ok(Some(object_handle));
+        }
+    }
 
+    Ok(None)
+}
+
+/// Creates a response streaming the contents of a downloaded object.
+///
+/// For `HEAD` requests, only the headers are sent.
+pub(super) fn object_response<S>(
+    request: &HttpRequest<S>,
+    object_handle: &ObjectHandle,
+) -> HttpResponse {
     let mut response = HttpResponse::Ok();
     response
         .content_length(object_handle.len() as u64)
         .header("content-type", "application/octet-stream");
 
     if request.method() == Method::HEAD {
-        return Ok(response.finish());
+        return response.finish();
     }
 
     let bytes = Cursor::new(object_handle.data());
     let async_bytes = FramedRead::new(bytes, BytesCodec::new()).map(|bytes| bytes.freeze());
-    Ok(response.streaming(async_bytes))
+    response.streaming(async_bytes)
+}
+
+async fn proxy_symstore_request(
+    state: State<Service>,
+    request: HttpRequest<Service>,
+    path: Path<(String,)>,
+) -> Result<HttpResponse, Error> {
+    match load_object(&state, &path.0).await? {
+        Some(object_handle) => Ok(object_response(&request, &object_handle)),
+        None => Ok(HttpResponse::NotFound().finish()),
+    }
 }
 
 pub fn configure(app: App<Service>) -> App<Service> {
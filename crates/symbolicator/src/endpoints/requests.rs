@@ -1,8 +1,10 @@
 use actix_web::{App, Error, HttpResponse, Path, Query, State};
+use futures::{StreamExt, TryStreamExt};
+use futures01::Stream as _;
 use serde::Deserialize;
 
 use crate::services::Service;
-use crate::types::RequestId;
+use crate::types::{RequestId, SymbolicationResponse};
 
 /// Path parameters of the symbolication poll request.
 #[derive(Deserialize)]
@@ -21,24 +23,65 @@ async fn poll_request(
     state: State<Service>,
     path: Path<PollSymbolicationRequestPath>,
     query: Query<PollSymbolicationRequestQueryParams>,
-) -> Result<HttpResponse, Error> {
+) -> Result<Option<SymbolicationResponse>, Error> {
     let path = path.into_inner();
     let query = query.into_inner();
 
+    // Unknown requests respond with 404.
     let response_opt = state
         .symbolication()
         .get_response(path.request_id, query.timeout)
         .await;
 
-    Ok(match response_opt {
-        Some(response) => HttpResponse::Ok().json(response),
-        None => HttpResponse::NotFound().finish(),
-    })
+    Ok(response_opt)
+}
+
+/// Cancels a running request.
+async fn cancel_request(
+    state: State<Service>,
+    path: Path<PollSymbolicationRequestPath>,
+) -> Result<HttpResponse, Error> {
+    let path = path.into_inner();
+
+    if state.symbolication().cancel_request(path.request_id) {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Ok(HttpResponse::NotFound().finish())
+    }
+}
+
+/// Streams progress events of a running request as Server-Sent Events.
+async fn request_events(
+    state: State<Service>,
+    path: Path<PollSymbolicationRequestPath>,
+) -> Result<HttpResponse, Error> {
+    let path = path.into_inner();
+
+    let events = match state.symbolication().subscribe_progress(path.request_id) {
+        Some(events) => events,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+
+    let body = events.map(|event| -> Result<Vec<u8>, Error> {
+        let json = serde_json::to_string(&event)?;
+        Ok(format!("data: {}\n\n", json).into_bytes())
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .header("cache-control", "no-cache")
+        .streaming(body.boxed_local().compat().map(From::from)))
 }
 
 pub fn configure(app: App<Service>) -> App<Service> {
     app.resource("/requests/{request_id}", |r| {
         let handler = compat_handler!(poll_request, s, p, q);
         r.get().with_async(handler);
+        let handler = compat_handler!(cancel_request, s, p);
+        r.delete().with_async(handler);
+    })
+    .resource("/requests/{request_id}/events", |r| {
+        let handler = compat_handler!(request_events, s, p);
+        r.get().with_async(handler);
     })
 }
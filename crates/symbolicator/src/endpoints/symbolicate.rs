@@ -1,11 +1,15 @@
-use actix_web::{error, App, Error, Json, Query, State};
+use std::sync::Arc;
+
+use actix_web::http::header;
+use actix_web::{error, App, Error, HttpRequest, HttpResponse, Json, Query, Responder, State};
+use futures::future;
 use serde::Deserialize;
 
 use crate::services::symbolication::SymbolicateStacktraces;
 use crate::services::Service;
 use crate::sources::SourceConfig;
 use crate::types::{
-    RawObjectInfo, RawStacktrace, RequestOptions, Scope, Signal, SymbolicationResponse,
+    RawObjectInfo, RawStacktrace, RequestOptions, Scope, Signal, SymbolicationResponse, TextReport,
 };
 use crate::utils::sentry::ConfigureScope;
 
@@ -29,6 +33,43 @@ impl ConfigureScope for SymbolicationRequestQueryParams {
     }
 }
 
+/// Checks whether the client prefers a text response over JSON.
+fn accepts_text<S>(request: &HttpRequest<S>) -> bool {
+    let accept = match request.headers().get(header::ACCEPT) {
+        Some(accept) => accept.to_str().unwrap_or_default(),
+        None => return false,
+    };
+
+    for media_type in accept.split(',') {
+        match media_type.split(';').next().unwrap_or_default().trim() {
+            "text/plain" => return true,
+            "application/json" | "application/*" | "*/*" => return false,
+            _ => (),
+        }
+    }
+
+    false
+}
+
+/// Responds with JSON, or with a text crash report if the client accepts `text/plain`.
+///
+/// Only completed responses are rendered as text, all other responses are always JSON.
+impl Responder for SymbolicationResponse {
+    type Item = HttpResponse;
+    type Error = Error;
+
+    fn respond_to<S>(self, request: &HttpRequest<S>) -> Result<HttpResponse, Error> {
+        match self {
+            SymbolicationResponse::Completed(ref response) if accepts_text(request) => {
+                Ok(HttpResponse::Ok()
+                    .content_type("text/plain; charset=utf-8")
+                    .body(TextReport(response).to_string()))
+            }
+            _ => Json(self).respond_to(request),
+        }
+    }
+}
+
 /// JSON body of the symbolication request.
 #[derive(Deserialize)]
 struct SymbolicationRequestBody {
@@ -48,7 +89,7 @@ async fn symbolicate_frames(
     state: State<Service>,
     params: Query<SymbolicationRequestQueryParams>,
     body: Json<SymbolicationRequestBody>,
-) -> Result<Json<SymbolicationResponse>, Error> {
+) -> Result<SymbolicationResponse, Error> {
     sentry::start_session();
 
     let params = params.into_inner();
@@ -56,7 +97,7 @@ async fn symbolicate_frames(
 
     let body = body.into_inner();
     let sources = match body.sources {
-        Some(sources) => sources.into(),
+        Some(sources) => super::request_sources(sources)?,
         None => state.config().default_sources(),
     };
 
@@ -71,7 +112,81 @@ async fn symbolicate_frames(
     });
 
     match symbolication.get_response(request_id, params.timeout).await {
-        Some(response) => Ok(Json(response)),
+        Some(response) => Ok(response),
+        None => Err(error::ErrorInternalServerError(
+            "symbolication request did not start",
+        )),
+    }
+}
+
+/// A single event within a batch symbolication request.
+#[derive(Deserialize)]
+struct BatchSymbolicationEvent {
+    #[serde(default)]
+    pub signal: Option<Signal>,
+    #[serde(default)]
+    pub stacktraces: Vec<RawStacktrace>,
+    #[serde(default)]
+    pub modules: Vec<RawObjectInfo>,
+    #[serde(default)]
+    pub options: RequestOptions,
+}
+
+/// JSON body of the batch symbolication request.
+///
+/// All events in a batch share the same sources, which allows to share symcaches between them.
+#[derive(Deserialize)]
+struct BatchSymbolicationRequestBody {
+    #[serde(default)]
+    pub sources: Option<Vec<SourceConfig>>,
+    #[serde(default)]
+    pub events: Vec<BatchSymbolicationEvent>,
+}
+
+async fn symbolicate_batch(
+    state: State<Service>,
+    params: Query<SymbolicationRequestQueryParams>,
+    body: Json<BatchSymbolicationRequestBody>,
+) -> Result<Json<Vec<SymbolicationResponse>>, Error> {
+    sentry::start_session();
+
+    let params = params.into_inner();
+    params.configure_scope();
+
+    let body = body.into_inner();
+    let sources: Arc<[SourceConfig]> = match body.sources {
+        Some(sources) => super::request_sources(sources)?,
+        None => state.config().default_sources(),
+    };
+
+    let requests = body
+        .events
+        .into_iter()
+        .map(|event| SymbolicateStacktraces {
+            scope: params.scope.clone(),
+            signal: event.signal,
+            sources: sources.clone(),
+            stacktraces: event.stacktraces,
+            modules: event.modules.into_iter().map(From::from).collect(),
+            options: event.options,
+        })
+        .collect();
+
+    let symbolication = state.symbolication();
+    let request_ids = symbolication.symbolicate_stacktraces_batch(requests);
+
+    let responses = request_ids.into_iter().map(|request_id| {
+        symbolication
+            .clone()
+            .get_response(request_id, params.timeout)
+    });
+
+    match future::join_all(responses)
+        .await
+        .into_iter()
+        .collect::<Option<Vec<_>>>()
+    {
+        Some(responses) => Ok(Json(responses)),
         None => Err(error::ErrorInternalServerError(
             "symbolication request did not start",
         )),
@@ -87,4 +202,12 @@ pub fn configure(app: App<Service>) -> App<Service> {
             },
         );
     })
+    .resource("/symbolicate/batch", |r| {
+        r.post().with_async_config(
+            compat_handler!(symbolicate_batch, s, p, b),
+            |(_hub, _state, _params, body)| {
+                body.limit(50_000_000);
+            },
+        );
+    })
 }
@@ -24,11 +24,13 @@ mod config;
 mod endpoints;
 mod logging;
 mod middlewares;
+mod offline;
 mod server;
 mod services;
 mod sources;
 mod types;
 mod utils;
+mod verify;
 
 #[cfg(test)]
 mod test;
@@ -0,0 +1,219 @@
+//! Processes crash files from the command line without running the web server.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures::{FutureExt, TryFutureExt};
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::services::symbolication::SymbolicateStacktraces;
+use crate::services::Service;
+use crate::sources::SourceConfig;
+use crate::types::{
+    CompletedSymbolicationResponse, RawObjectInfo, RawStacktrace, RequestOptions, Scope, Signal,
+    SymbolicationResponse, TextReport,
+};
+
+/// The kind of crash file to process.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InputKind {
+    /// A minidump file.
+    Minidump,
+    /// An Apple crash report.
+    AppleCrashReport,
+    /// A JSON payload as sent to the `/symbolicate` endpoint.
+    Stacktraces,
+}
+
+/// The format in which the symbolicated crash is printed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutputFormat {
+    /// The JSON response of the HTTP API.
+    Json,
+    /// A human readable text crash report.
+    Text,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(OutputFormat::Json),
+            "text" => Ok(OutputFormat::Text),
+            _ => Err(anyhow!("unknown output format `{}`", s)),
+        }
+    }
+}
+
+/// JSON payload of a `/symbolicate` request.
+#[derive(Deserialize)]
+struct StacktracesPayload {
+    #[serde(default)]
+    pub signal: Option<Signal>,
+    #[serde(default)]
+    pub sources: Option<Vec<SourceConfig>>,
+    #[serde(default)]
+    pub stacktraces: Vec<RawStacktrace>,
+    #[serde(default)]
+    pub modules: Vec<RawObjectInfo>,
+    #[serde(default)]
+    pub options: RequestOptions,
+}
+
+/// Loads a list of sources from a YAML or JSON file.
+fn load_sources(path: &Path) -> Result<Arc<[SourceConfig]>> {
+    let file = fs::File::open(path).context("failed to open sources file")?;
+    let sources: Vec<SourceConfig> =
+        serde_yaml::from_reader(file).context("failed to parse sources file")?;
+    Ok(sources.into())
+}
+
+/// Entry function for the process commands.
+///
+/// This symbolicates the given crash file in-process and prints the result to stdout.  Sources
+/// are read from the `sources` file if given, and otherwise default to the configured sources.
+pub fn process(
+    config: Config,
+    kind: InputKind,
+    path: &Path,
+    sources: Option<&Path>,
+    format: OutputFormat,
+) -> Result<()> {
+    let data = fs::read(path).context("failed to read input file")?;
+    let sources = match sources {
+        Some(sources) => Some(load_sources(sources)?),
+        None => None,
+    };
+
+    let response = symbolicate(config, kind, data, sources)?;
+
+    let stdout = io::stdout();
+    write_response(&mut stdout.lock(), &response, format).context("failed to write response")
+}
+
+/// Symbolicates a crash file in-process and returns the completed response.
+fn symbolicate(
+    config: Config,
+    kind: InputKind,
+    data: Vec<u8>,
+    sources: Option<Arc<[SourceConfig]>>,
+) -> Result<CompletedSymbolicationResponse> {
+    // Downloads run on the tokio runtime, which needs to be entered to create the services.
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("symbolicator")
+        .enable_all()
+        .build()
+        .context("failed to create runtime")?;
+    let _guard = runtime.enter();
+
+    let default_sources = config.default_sources();
+    let service = Service::create(config).context("failed to create service state")?;
+    let symbolication = service.symbolication();
+
+    // Symbolication requests are spawned on a current thread runtime, just like in the web
+    // server, so they must be created from within the runtime.
+    let future = async move {
+        let request_id = match kind {
+            InputKind::Minidump => symbolication.process_minidump(
+                Scope::Global,
+                data,
+                sources.unwrap_or(default_sources),
+                RequestOptions::default(),
+            ),
+            InputKind::AppleCrashReport => symbolication.process_apple_crash_report(
+                Scope::Global,
+                data,
+                sources.unwrap_or(default_sources),
+                RequestOptions::default(),
+            ),
+            InputKind::Stacktraces => {
+                let payload: StacktracesPayload = serde_json::from_slice(&data)
+                    .context("failed to parse symbolication request")?;
+                let sources = sources
+                    .or_else(|| payload.sources.map(Into::into))
+                    .unwrap_or(default_sources);
+
+                symbolication.symbolicate_stacktraces(SymbolicateStacktraces {
+                    scope: Scope::Global,
+                    signal: payload.signal,
+                    sources,
+                    stacktraces: payload.stacktraces,
+                    modules: payload.modules.into_iter().map(From::from).collect(),
+                    options: payload.options,
+                })
+            }
+        };
+
+        Ok::<_, anyhow::Error>(symbolication.get_response(request_id, None).await)
+    };
+
+    let response = tokio01::runtime::current_thread::Runtime::new()
+        .context("failed to create runtime")?
+        .block_on(future.boxed_local().compat())?;
+
+    match response {
+        Some(SymbolicationResponse::Completed(response)) => Ok(*response),
+        Some(SymbolicationResponse::Failed { message }) => {
+            bail!("symbolication failed: {}", message)
+        }
+        Some(SymbolicationResponse::Timeout) => bail!("symbolication timed out"),
+        Some(_) | None => bail!("symbolication failed"),
+    }
+}
+
+/// Writes the symbolicated crash in the given format.
+fn write_response<W: Write>(
+    mut writer: W,
+    response: &CompletedSymbolicationResponse,
+    format: OutputFormat,
+) -> Result<()> {
+    match format {
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, response)?;
+            writeln!(writer)?;
+        }
+        OutputFormat::Text => write!(writer, "{}", TextReport(response))?,
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test;
+
+    #[test]
+    fn test_process_minidump() {
+        test::setup();
+
+        let cache_dir = test::tempdir();
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            ..Default::default()
+        };
+
+        // Without sources, the minidump is only stackwalked.
+        let data = test::read_fixture("windows.dmp");
+        let response = symbolicate(config, InputKind::Minidump, data, Some(Arc::new([]))).unwrap();
+        assert_eq!(response.crashed, Some(true));
+        assert!(!response.stacktraces.is_empty());
+
+        let mut output = Vec::new();
+        write_response(&mut output, &response, OutputFormat::Json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(json["crashed"], true);
+
+        let mut output = Vec::new();
+        write_response(&mut output, &response, OutputFormat::Text).unwrap();
+        assert!(String::from_utf8(output).unwrap().contains("Thread 0"));
+    }
+}
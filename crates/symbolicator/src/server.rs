@@ -1,6 +1,7 @@
 use actix_web::{server::HttpServer, App};
 use anyhow::{Context, Result};
 
+use crate::cache;
 use crate::config::Config;
 use crate::endpoints;
 use crate::middlewares;
@@ -35,6 +36,7 @@ pub fn run(config: Config) -> Result<()> {
     // Enter the tokio runtime before creating the services.
     let _guard = runtime.enter();
     let service = Service::create(config).context("failed to create service state")?;
+    cache::spawn_cleanup(service.config()).context("failed to start cache cleanup")?;
 
     log::info!("Starting http server: {}", bind);
     HttpServer::new(move || create_app(service.clone()))
@@ -42,6 +42,18 @@ impl BcSymbolMapHandle {
     }
 }
 
+/// Checks whether a positive item of the auxiliary DIF cache can be parsed.
+///
+/// Since the UUID of PLists is not known here, they are parsed with a nil UUID.
+pub(crate) fn verify_auxdif(data: &[u8]) -> Result<(), Error> {
+    if BcSymbolMap::test(data) {
+        BcSymbolMap::parse(data).context("Failed to parse BCSymbolMap")?;
+    } else {
+        UuidMapping::parse_plist(DebugId::nil(), data).context("Failed to parse PList")?;
+    }
+    Ok(())
+}
+
 /// The handle to be returned by [`CacheItemRequest`].
 ///
 /// This trait requires us to return a handle regardless of positive, negative or malformed
@@ -64,6 +76,7 @@ struct FetchFileRequest {
     uuid: DebugId,
     download_svc: Arc<DownloadService>,
     cache: Arc<Cacher<FetchFileRequest>>,
+    timeout: Duration,
 }
 
 impl FetchFileRequest {
@@ -143,7 +156,7 @@ impl CacheItemRequest for FetchFileRequest {
         Box::pin(
             future_metrics!(
                 "auxdifs",
-                Some((Duration::from_secs(600),Error::msg("Timeout fetching aux DIF"))),
+                Some((self.timeout, Error::msg("Timeout fetching aux DIF"))),
                 fut.compat(),
                 "source_type" => source_name,
             )
@@ -170,13 +183,15 @@ impl CacheItemRequest for FetchFileRequest {
 pub struct BitcodeService {
     cache: Arc<Cacher<FetchFileRequest>>,
     download_svc: Arc<DownloadService>,
+    timeout: Duration,
 }
 
 impl BitcodeService {
-    pub fn new(difs_cache: Cache, download_svc: Arc<DownloadService>) -> Self {
+    pub fn new(difs_cache: Cache, download_svc: Arc<DownloadService>, timeout: Duration) -> Self {
         Self {
-            cache: Arc::new(Cacher::new(difs_cache)),
+            cache: Arc::new(Cacher::new(difs_cache, None)),
             download_svc,
+            timeout,
         }
     }
 
@@ -279,6 +294,7 @@ impl BitcodeService {
                 uuid,
                 download_svc: self.download_svc.clone(),
                 cache: self.cache.clone(),
+                timeout: self.timeout,
             };
             let job = self
                 .cache
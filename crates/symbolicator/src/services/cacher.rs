@@ -2,18 +2,24 @@ use std::collections::BTreeMap;
 use std::fmt;
 use std::io;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
 
 use futures::channel::oneshot;
-use futures::future::{self, FutureExt, Shared, TryFutureExt};
+use futures::future::{self, AbortHandle, Abortable, FutureExt, Shared, TryFutureExt};
 use parking_lot::Mutex;
 use sentry::{Hub, SentryFutureExt};
 use symbolic::common::ByteView;
 use tempfile::NamedTempFile;
 
-use crate::cache::{get_scope_path, Cache, CacheKey, CacheStatus};
+use crate::cache::{get_scope_path, Cache, CacheItemMeta, CacheKey, CacheStatus, CacheVersions};
+use crate::services::cancellation::{Cancellation, CancellationFutureExt};
+use crate::services::deadline::{Deadline, DeadlineFutureExt, SharedDeadline};
+use crate::services::progress::{Progress, ProgressFutureExt};
+use crate::services::shared_cache::SharedCacheService;
 use crate::types::Scope;
-use crate::utils::futures::{spawn_compat, BoxedFuture, CallOnDrop};
+use crate::utils::futures::{spawn_compat, BoxedFuture, CallOnDrop, ThreadPool};
 
 /// Result from [`Cacher::compute_memoized`].
 type CacheResultFuture<T, E> = BoxedFuture<Result<Arc<T>, Arc<E>>>;
@@ -22,7 +28,186 @@ type CacheResultFuture<T, E> = BoxedFuture<Result<Arc<T>, Arc<E>>>;
 // newtype around it.
 type ComputationChannel<T, E> = Shared<oneshot::Receiver<Result<Arc<T>, Arc<E>>>>;
 
-type ComputationMap<T, E> = Arc<Mutex<BTreeMap<CacheKey, ComputationChannel<T, E>>>>;
+type ComputationMap<T, E> = Arc<Mutex<BTreeMap<CacheKey, Computation<T, E>>>>;
+
+/// How long items are served from memory before they are looked up in the file system again.
+///
+/// This applies expiry of the file system cache and keeps the last use of cache files current.
+const MEMORY_TTL: Duration = Duration::from_secs(300);
+
+/// Aborts a running computation when dropped, unless it has been detached.
+///
+/// Every caller waiting for a computation holds a reference to this guard.  Callers that stop
+/// waiting without their request being cancelled detach the computation, so that it runs to
+/// completion and its result is cached.  Only if all callers have been cancelled, the computation
+/// is aborted once the guard is dropped.
+#[derive(Debug)]
+struct ComputationGuard {
+    abort_handle: AbortHandle,
+    detached: Arc<AtomicBool>,
+    /// Cancellation handle owned by the computation and seen by nested computations.
+    cancellation: Cancellation,
+}
+
+impl ComputationGuard {
+    /// Keeps the computation running after all callers have stopped waiting.
+    fn detach(&self) {
+        self.detached.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for ComputationGuard {
+    fn drop(&mut self) {
+        if !self.detached.load(Ordering::Relaxed) {
+            // Cancel before aborting, so that nested computations are aborted as well.
+            self.cancellation.cancel();
+            self.abort_handle.abort();
+        }
+    }
+}
+
+/// A running computation in the [`ComputationMap`].
+#[derive(Debug)]
+struct Computation<T, E> {
+    channel: ComputationChannel<T, E>,
+    /// The guard held by all waiting callers.
+    ///
+    /// If this can no longer be upgraded and the computation has not been detached, it is being
+    /// aborted and must not be joined by new callers.
+    guard: Weak<ComputationGuard>,
+    /// Whether the computation has been detached, see [`ComputationGuard::detach`].
+    detached: Arc<AtomicBool>,
+    /// An item of an older version served while this computation refreshes it.
+    fallback: Option<Arc<T>>,
+    /// The earliest deadline of all requests waiting for this computation.
+    deadline: SharedDeadline,
+}
+
+impl<T, E> Computation<T, E> {
+    /// Joins this computation if it is still running.
+    ///
+    /// Detached computations can no longer be aborted, so callers joining them get no guard.
+    fn join(&self) -> Option<(ComputationChannel<T, E>, Option<Arc<ComputationGuard>>)> {
+        match self.guard.upgrade() {
+            Some(guard) => Some((self.channel.clone(), Some(guard))),
+            None if self.detached.load(Ordering::Relaxed) => Some((self.channel.clone(), None)),
+            None => None,
+        }
+    }
+}
+
+/// An item in the [`MemoryCache`].
+#[derive(Debug)]
+struct MemoryEntry<T> {
+    item: Arc<T>,
+    size: u64,
+    inserted: Instant,
+    /// The tick of the last use of this entry.
+    last_used: u64,
+}
+
+/// A bounded in-memory cache of loaded items.
+///
+/// When the total size of the items exceeds the maximum size, the least recently used items are
+/// evicted.  Items expire after [`MEMORY_TTL`].
+#[derive(Debug)]
+struct MemoryCache<T> {
+    entries: BTreeMap<CacheKey, MemoryEntry<T>>,
+    /// Keys of all entries ordered by their last use.
+    lru: BTreeMap<u64, CacheKey>,
+    /// A counter ordering uses of entries.
+    tick: u64,
+    size: u64,
+    max_size: u64,
+}
+
+impl<T> MemoryCache<T> {
+    fn new(max_size: u64) -> Self {
+        MemoryCache {
+            entries: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            size: 0,
+            max_size,
+        }
+    }
+
+    /// Returns the item if it is in memory and marks it as used.
+    fn get(&mut self, key: &CacheKey) -> Option<Arc<T>> {
+        if self.entries.get(key)?.inserted.elapsed() >= MEMORY_TTL {
+            self.remove(key);
+            return None;
+        }
+
+        self.tick += 1;
+        let entry = self.entries.get_mut(key)?;
+        self.lru.remove(&entry.last_used);
+        self.lru.insert(self.tick, key.clone());
+        entry.last_used = self.tick;
+
+        Some(entry.item.clone())
+    }
+
+    /// Inserts an item and returns the number of evicted items.
+    fn insert(&mut self, key: CacheKey, item: Arc<T>, size: u64) -> usize {
+        if size > self.max_size {
+            return 0;
+        }
+
+        self.remove(&key);
+        self.tick += 1;
+        self.lru.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            MemoryEntry {
+                item,
+                size,
+                inserted: Instant::now(),
+                last_used: self.tick,
+            },
+        );
+        self.size += size;
+
+        let mut evicted = 0;
+        while self.size > self.max_size {
+            let oldest = match self.lru.values().next() {
+                Some(oldest) => oldest.clone(),
+                None => break,
+            };
+            self.remove(&oldest);
+            evicted += 1;
+        }
+
+        evicted
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_used);
+            self.size -= entry.size;
+        }
+    }
+
+    /// Removes all items of the given scope whose cache key matches the filter, and returns the
+    /// number of removed items.
+    fn remove_matching<F>(&mut self, scope: &Scope, mut filter: F) -> usize
+    where
+        F: FnMut(&str) -> bool,
+    {
+        let keys: Vec<_> = self
+            .entries
+            .keys()
+            .filter(|key| key.scope == *scope && filter(&key.cache_key))
+            .cloned()
+            .collect();
+
+        for key in &keys {
+            self.remove(key);
+        }
+
+        keys.len()
+    }
+}
 
 /// Manages a filesystem cache of any kind of data that can be serialized into bytes and read from
 /// it:
@@ -34,13 +219,20 @@ type ComputationMap<T, E> = Arc<Mutex<BTreeMap<CacheKey, ComputationChannel<T, E
 /// Transparently performs cache lookups, downloads and cache stores via the [`CacheItemRequest`]
 /// trait and associated types.
 ///
-/// Internally deduplicates concurrent cache lookups (in-memory).
+/// Internally deduplicates concurrent cache lookups (in-memory).  If configured with a
+/// `max_memory`, frequently used items are additionally kept in memory.
 #[derive(Debug)]
 pub struct Cacher<T: CacheItemRequest> {
     config: Cache,
 
-    /// Used for deduplicating cache lookups.
+    /// An optional second-tier cache shared with other instances.
+    shared_cache: Option<Arc<SharedCacheService>>,
+
+    /// Used for deduplicating cache lookups and refreshes.
     current_computations: ComputationMap<T::Item, T::Error>,
+
+    /// Loaded items kept in memory, if enabled.
+    memory: Option<Arc<Mutex<MemoryCache<T::Item>>>>,
 }
 
 impl<T: CacheItemRequest> Clone for Cacher<T> {
@@ -48,19 +240,32 @@ impl<T: CacheItemRequest> Clone for Cacher<T> {
         // https://github.com/rust-lang/rust/issues/26925
         Cacher {
             config: self.config.clone(),
+            shared_cache: self.shared_cache.clone(),
             current_computations: self.current_computations.clone(),
+            memory: self.memory.clone(),
         }
     }
 }
 
 impl<T: CacheItemRequest> Cacher<T> {
-    pub fn new(config: Cache) -> Self {
+    pub fn new(config: Cache, shared_cache: Option<Arc<SharedCacheService>>) -> Self {
+        let memory = config
+            .max_memory()
+            .map(|max_size| Arc::new(Mutex::new(MemoryCache::new(max_size))));
+
         Cacher {
             config,
+            shared_cache,
             current_computations: Arc::new(Mutex::new(BTreeMap::new())),
+            memory,
         }
     }
 
+    /// Returns the file system cache of this cacher.
+    pub fn cache(&self) -> &Cache {
+        &self.config
+    }
+
     pub fn tempfile(&self) -> io::Result<NamedTempFile> {
         self.config.tempfile()
     }
@@ -117,7 +322,7 @@ impl AsRef<Path> for CachePath {
     }
 }
 
-pub trait CacheItemRequest: 'static + Send {
+pub trait CacheItemRequest: 'static + Send + Clone {
     type Item: 'static + Send + Sync;
 
     // XXX: Probably should have our own concrete error type for cacheactor instead of forcing our
@@ -127,10 +332,53 @@ pub trait CacheItemRequest: 'static + Send {
     /// Returns the key by which this item is cached.
     fn get_cache_key(&self) -> CacheKey;
 
+    /// Returns the format versions of this item, if it is derived from an object file.
+    ///
+    /// Versioned items are computed in the current version and record it in their
+    /// [`CacheItemMeta`].  If an item exists in one of the fallback versions, it is served while
+    /// the current version is computed in the background.
+    fn versions(&self) -> Option<CacheVersions> {
+        None
+    }
+
+    /// Returns the key of the object file this item is computed from.
+    ///
+    /// This is recorded in the [`CacheItemMeta`] of versioned items.  Defaults to the key of the
+    /// item itself.
+    fn get_source_key(&self) -> CacheKey {
+        self.get_cache_key()
+    }
+
     /// Invoked to compute an instance of this item and put it at the given location in the file
     /// system. This is used to populate the cache for a previously missing element.
     fn compute(&self, path: &Path) -> BoxedFuture<Result<CacheStatus, Self::Error>>;
 
+    /// Returns a copy of a loaded item to keep in memory, along with its size on the heap.
+    ///
+    /// Items are only kept in memory if the cache is configured with a `max_memory`.  The copy is
+    /// served to all requests with the same cache key through [`from_memory`](Self::from_memory),
+    /// so it should only hold data that does not depend on the request that loaded it.
+    fn to_memory(_item: &Self::Item) -> Option<(Self::Item, u64)> {
+        None
+    }
+
+    /// Returns the item for this request from a copy kept in memory.
+    ///
+    /// The copy may have been loaded by another request with the same cache key, so data specific
+    /// to a request has to be rebuilt here.  Returns `None` if the copy can no longer be served, in
+    /// which case the item is looked up in the file system cache again.
+    fn from_memory(&self, item: Arc<Self::Item>) -> Option<Arc<Self::Item>> {
+        Some(item)
+    }
+
+    /// Returns the thread pool on which items are loaded from the file system cache.
+    ///
+    /// Items are loaded on the current thread by default.  Requests that do expensive work in
+    /// [`load`](Self::load), such as decompressing the item, should load on a thread pool instead.
+    fn load_threadpool(&self) -> Option<&ThreadPool> {
+        None
+    }
+
     /// Determines whether this item should be loaded.
     ///
     /// If this returns `false` the cache will re-computed and be overwritten with the new
@@ -139,6 +387,12 @@ pub trait CacheItemRequest: 'static + Send {
         true
     }
 
+    /// Determines whether an item of one of the fallback [`versions`](Self::versions) can be
+    /// served while it is recomputed.
+    fn should_load_fallback(&self, _data: &[u8]) -> bool {
+        false
+    }
+
     /// Loads an existing element from the cache.
     fn load(
         &self,
@@ -154,18 +408,19 @@ impl<T: CacheItemRequest> Cacher<T> {
     ///
     /// Returns `Ok(None)` if the cache item needs to be re-computed, otherwise reads the
     /// cached data from disk and returns the cached item as returned by
-    /// [`CacheItemRequest::load`].
+    /// [`CacheItemRequest::load`].  The flag is set if the item is of a fallback version and needs
+    /// to be refreshed.
     ///
     /// # Errors
     ///
     /// If there is an I/O error reading the cache [`CacheItemRequest::Error`] is returned.
     fn lookup_cache(
-        &self,
+        config: &Cache,
         request: &T,
         key: &CacheKey,
         path: &Path,
-    ) -> Result<Option<T::Item>, T::Error> {
-        let name = self.config.name();
+    ) -> Result<Option<(T::Item, bool)>, T::Error> {
+        let name = config.name();
         sentry::configure_scope(|scope| {
             scope.set_extra(
                 &format!("cache.{}.cache_path", name),
@@ -173,16 +428,47 @@ impl<T: CacheItemRequest> Cacher<T> {
             );
         });
 
-        let byteview = match self.config.open_cachefile(path)? {
+        let byteview = match config.open_cachefile(path)? {
             Some(x) => x,
             None => return Ok(None),
         };
 
         let status = CacheStatus::from_content(&byteview);
-        if status == CacheStatus::Positive && !request.should_load(&byteview) {
-            log::trace!("Discarding {} at path {:?}", name, path);
-            metric!(counter(&format!("caches.{}.file.discarded", name)) += 1);
-            return Ok(None);
+        let mut stale = false;
+        if status == CacheStatus::Positive {
+            let current = match request.versions() {
+                None => true,
+                Some(versions) => {
+                    let version = match CacheItemMeta::read(path)? {
+                        Some(meta) if meta.source == *key => Some(meta.version),
+                        Some(_) => None,
+                        None => Some(0),
+                    };
+
+                    if version == Some(versions.current) {
+                        true
+                    } else if version.map_or(false, |v| versions.fallbacks.contains(&v))
+                        && request.should_load_fallback(&byteview)
+                    {
+                        metric!(
+                            counter(&format!("caches.{}.file.fallback", name)) += 1,
+                            "version" => &version.unwrap_or_default().to_string(),
+                        );
+                        stale = true;
+                        false
+                    } else {
+                        log::trace!("Discarding {} of unknown version at {:?}", name, path);
+                        metric!(counter(&format!("caches.{}.file.discarded", name)) += 1);
+                        return Ok(None);
+                    }
+                }
+            };
+
+            if current && !request.should_load(&byteview) {
+                log::trace!("Discarding {} at path {:?}", name, path);
+                metric!(counter(&format!("caches.{}.file.discarded", name)) += 1);
+                return Ok(None);
+            }
         }
 
         // This is also reported for "negative cache hits": When we cached the 404 response from a
@@ -196,103 +482,344 @@ impl<T: CacheItemRequest> Cacher<T> {
         let path = path.to_path_buf();
         log::trace!("Loading {} at path {:?}", name, path);
         let item = request.load(key.scope.clone(), status, byteview, CachePath::Cached(path));
-        Ok(Some(item))
+        Ok(Some((item, stale)))
     }
 
     /// Compute an item.
     ///
-    /// If the item is in the file system cache, it is returned immediately. Otherwise, it
-    /// is computed using [`T::compute`](CacheItemRequest::compute), and then persisted to
-    /// the cache.
+    /// If the item is in the file system cache, it is returned immediately.  If it is of one of the
+    /// fallback versions, it is returned along with a flag to refresh it, see
+    /// [`spawn_refresh`](Self::spawn_refresh).  Otherwise, it is computed, see
+    /// [`compute_current`](Self::compute_current).
     ///
     /// This method does not take care of ensuring the computation only happens once even
     /// for concurrent requests, see the public [`Cacher::compute_memoized`] for this.
-    fn compute(&self, request: T, key: CacheKey) -> BoxedFuture<Result<T::Item, T::Error>> {
+    fn compute(&self, request: T, key: CacheKey) -> BoxedFuture<Result<(T::Item, bool), T::Error>> {
         // cache_path is None when caching is disabled.
         let cache_path = get_scope_path(self.config.cache_dir(), &key.scope, &key.cache_key);
-        if let Some(ref path) = cache_path {
-            if let Some(item) = tryf!(self.lookup_cache(&request, &key, &path)) {
-                return Box::pin(future::ok(item));
+        let slf = self.clone();
+
+        let future = async move {
+            if let Some(ref path) = cache_path {
+                if let Some(item) = slf.load_cache(&request, &key, path).await? {
+                    return Ok(item);
+                }
+            }
+
+            let item = slf.compute_current(request, key, cache_path).await?;
+            Ok((item, false))
+        };
+
+        Box::pin(future)
+    }
+
+    /// Looks up an item in the file system cache, see [`lookup_cache`](Self::lookup_cache).
+    ///
+    /// The item is loaded on the [`load_threadpool`](CacheItemRequest::load_threadpool) of the
+    /// request if it has one, and on the current thread otherwise.
+    async fn load_cache(
+        &self,
+        request: &T,
+        key: &CacheKey,
+        path: &Path,
+    ) -> Result<Option<(T::Item, bool)>, T::Error> {
+        let threadpool = match request.load_threadpool() {
+            Some(threadpool) => threadpool,
+            None => return Self::lookup_cache(&self.config, request, key, path),
+        };
+
+        let config = self.config.clone();
+        let request = request.clone();
+        let key = key.clone();
+        let path = path.to_owned();
+        let lookup = async move { Self::lookup_cache(&config, &request, &key, &path) };
+
+        threadpool
+            .spawn_handle(lookup.bind_hub(Hub::current()))
+            .await
+            .unwrap_or_else(|_| {
+                let message = "cache lookup was cancelled";
+                Err(io::Error::new(io::ErrorKind::Interrupted, message).into())
+            })
+    }
+
+    /// Recomputes an item of a fallback version in the background.
+    ///
+    /// The refresh is registered as a computation, so that it is deduplicated with other
+    /// computations of the same item.  Until it completes, requests joining it are served the
+    /// `fallback` item instead of waiting.
+    fn spawn_refresh(&self, request: T, key: CacheKey, fallback: Arc<T::Item>) {
+        let mut current_computations = self.current_computations.lock();
+        if let Some(computation) = current_computations.get(&key) {
+            if computation.join().is_some() {
+                return;
             }
         }
 
         let name = self.config.name();
-        let key = request.get_cache_key();
+        metric!(counter(&format!("caches.{}.file.refresh", name)) += 1);
+
+        // Refreshes are not bound to any request, so they always run to completion.
+        let deadline = SharedDeadline::default();
+        let (channel, guard) = self.create_channel(request, key.clone(), true, deadline.clone());
+        guard.detach();
+
+        let computation = Computation {
+            channel,
+            guard: Arc::downgrade(&guard),
+            detached: guard.detached.clone(),
+            fallback: Some(fallback),
+            deadline,
+        };
+        current_computations.insert(key, computation);
+    }
+
+    /// Computes an item in the current version.
+    ///
+    /// The item is fetched from the shared cache if configured, or computed using
+    /// [`T::compute`](CacheItemRequest::compute), and then persisted to the cache at
+    /// `cache_path`.  Computed items are also uploaded to the shared cache.
+    fn compute_current(
+        &self,
+        request: T,
+        key: CacheKey,
+        cache_path: Option<PathBuf>,
+    ) -> BoxedFuture<Result<T::Item, T::Error>> {
+        let name = self.config.name();
+        let versions = request.versions();
+        let version = versions.map_or(0, |versions| versions.current);
 
         // A file was not found. If this spikes, it's possible that the filesystem cache
         // just got pruned.
         metric!(counter(&format!("caches.{}.file.miss", name)) += 1);
 
         let temp_file = tryf!(self.tempfile());
+        let shared_cache = self.shared_cache.clone();
 
-        let future =
-            request
-                .compute(temp_file.path())
-                .and_then(move |status: CacheStatus| async move {
-                    if let Some(ref cache_path) = cache_path {
-                        sentry::configure_scope(|scope| {
-                            scope.set_extra(
-                                &format!("cache.{}.cache_path", name),
-                                cache_path.to_string_lossy().into(),
-                            );
-                        });
-
-                        log::trace!("Creating {} at path {:?}", name, cache_path);
-                    }
-
+        let future = async move {
+            // Only positive items are shared.
+            let mut shared = false;
+            if let Some(ref shared_cache) = shared_cache {
+                if shared_cache
+                    .clone()
+                    .fetch(name, &key, version, temp_file.path())
+                    .await
+                {
                     let byteview = ByteView::open(temp_file.path())?;
+                    shared = CacheStatus::from_content(&byteview) == CacheStatus::Positive
+                        && request.should_load(&byteview);
+                }
+            }
 
-                    metric!(
-                        counter(&format!("caches.{}.file.write", name)) += 1,
-                        "status" => status.as_ref(),
-                    );
-                    metric!(
-                        time_raw(&format!("caches.{}.file.size", name)) = byteview.len() as u64,
-                        "hit" => "false"
+            let status = if shared {
+                CacheStatus::Positive
+            } else {
+                request.compute(temp_file.path()).await?
+            };
+
+            if let Some(ref cache_path) = cache_path {
+                sentry::configure_scope(|scope| {
+                    scope.set_extra(
+                        &format!("cache.{}.cache_path", name),
+                        cache_path.to_string_lossy().into(),
                     );
+                });
+
+                log::trace!("Creating {} at path {:?}", name, cache_path);
+            }
+
+            let byteview = ByteView::open(temp_file.path())?;
 
-                    let path = match cache_path {
-                        Some(ref cache_path) => {
-                            status.persist_item(cache_path, temp_file)?;
-                            CachePath::Cached(cache_path.to_path_buf())
+            metric!(
+                counter(&format!("caches.{}.file.write", name)) += 1,
+                "status" => status.as_ref(),
+            );
+            metric!(
+                time_raw(&format!("caches.{}.file.size", name)) = byteview.len() as u64,
+                "hit" => "false"
+            );
+
+            if let Some(shared_cache) = shared_cache {
+                if !shared && status == CacheStatus::Positive {
+                    shared_cache.store(name, &key, version, byteview.clone());
+                }
+            }
+
+            let path = match cache_path {
+                Some(ref cache_path) => {
+                    status.persist_item(cache_path, temp_file)?;
+                    // Only positive items have a format, see `lookup_cache`.
+                    match (versions, status) {
+                        (Some(_), CacheStatus::Positive) => {
+                            let meta = CacheItemMeta {
+                                source: request.get_source_key(),
+                                version,
+                            };
+                            meta.write(cache_path)?;
                         }
-                        None => CachePath::Temp(temp_file.into_temp_path()),
-                    };
+                        (Some(_), _) => CacheItemMeta::remove(cache_path)?,
+                        (None, _) => (),
+                    }
+                    CachePath::Cached(cache_path.to_path_buf())
+                }
+                None => CachePath::Temp(temp_file.into_temp_path()),
+            };
 
-                    Ok(request.load(key.scope.clone(), status, byteview, path))
-                });
+            Ok::<_, T::Error>(request.load(key.scope.clone(), status, byteview, path))
+        };
 
         Box::pin(future)
     }
 
+    /// Returns the item from memory if it is kept there.
+    fn get_memory(&self, key: &CacheKey) -> Option<Arc<T::Item>> {
+        let memory = self.memory.as_ref()?;
+        let name = self.config.name();
+
+        match memory.lock().get(key) {
+            Some(item) => {
+                metric!(counter(&format!("caches.{}.memory.hit", name)) += 1);
+                Some(item)
+            }
+            None => {
+                metric!(counter(&format!("caches.{}.memory.miss", name)) += 1);
+                None
+            }
+        }
+    }
+
+    /// Keeps a loaded item in memory if enabled and the item supports it.
+    fn insert_memory(&self, key: CacheKey, item: &Arc<T::Item>) {
+        let memory = match self.memory {
+            Some(ref memory) => memory,
+            None => return,
+        };
+
+        let (item, size) = match T::to_memory(item) {
+            Some(copy) => copy,
+            None => return,
+        };
+
+        let name = self.config.name();
+        let mut memory = memory.lock();
+        let evicted = memory.insert(key, Arc::new(item), size);
+
+        if evicted > 0 {
+            metric!(counter(&format!("caches.{}.memory.evicted", name)) += evicted as i64);
+        }
+        metric!(gauge(&format!("caches.{}.memory.size", name)) = memory.size);
+    }
+
+    /// Removes items of the given scope whose cache key matches the filter from memory.
+    ///
+    /// This must be called when items are removed from the file system cache, since items in
+    /// memory would otherwise still be served until they expire.
+    pub fn remove_memory<F>(&self, scope: &Scope, filter: F) -> usize
+    where
+        F: FnMut(&str) -> bool,
+    {
+        let memory = match self.memory {
+            Some(ref memory) => memory,
+            None => return 0,
+        };
+
+        let name = self.config.name();
+        let mut memory = memory.lock();
+        let removed = memory.remove_matching(scope, filter);
+        metric!(gauge(&format!("caches.{}.memory.size", name)) = memory.size);
+        removed
+    }
+
     /// Creates a shareable channel that computes an item.
-    fn create_channel(&self, request: T, key: CacheKey) -> ComputationChannel<T::Item, T::Error> {
+    ///
+    /// If `refresh` is set, the item is computed in the current version without looking it up in
+    /// the file system cache first.
+    ///
+    /// The computation is aborted as soon as the returned guard and all of its clones are
+    /// dropped, unless the guard has been detached.  It runs with the given shared `deadline`,
+    /// which callers joining the computation lower to their own deadline.
+    fn create_channel(
+        &self,
+        request: T,
+        key: CacheKey,
+        refresh: bool,
+        deadline: SharedDeadline,
+    ) -> (ComputationChannel<T::Item, T::Error>, Arc<ComputationGuard>) {
         let (sender, receiver) = oneshot::channel();
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+        let cancellation = Cancellation::new();
+        let guard = Arc::new(ComputationGuard {
+            abort_handle,
+            detached: Arc::new(AtomicBool::new(false)),
+            cancellation: cancellation.clone(),
+        });
 
         let slf = self.clone();
         let current_computations = self.current_computations.clone();
+        let weak_guard = Arc::downgrade(&guard);
         let remove_computation_token = CallOnDrop::new(clone!(key, || {
-            current_computations.lock().remove(&key);
+            // An aborted computation might already have been replaced by a new one.
+            let mut current_computations = current_computations.lock();
+            if let Some(computation) = current_computations.get(&key) {
+                if Weak::ptr_eq(&computation.guard, &weak_guard) {
+                    current_computations.remove(&key);
+                }
+            }
         }));
 
-        // Run the computation and wrap the result in Arcs to make them clonable.
+        // Refreshes are not started by a request, so they report no progress.
+        let progress = if refresh {
+            Progress::default()
+        } else {
+            Progress::current()
+        };
+
+        // Run the computation and wrap the result in Arcs to make them clonable.  Progress of the
+        // computation is only reported to the request that started it.  Computations are shared, so
+        // they run without the cancellation of that request and with the earliest deadline of all
+        // waiting requests.  The computation owns its cancellation handle, which is only cancelled
+        // once the computation is aborted, so nested computations are aborted along with this one
+        // but never by a single caller.
         let channel = async move {
-            let result = match slf.compute(request, key).await {
-                Ok(ok) => Ok(Arc::new(ok)),
-                Err(err) => Err(Arc::new(err)),
+            let result = if refresh {
+                let cache_path = get_scope_path(slf.config.cache_dir(), &key.scope, &key.cache_key);
+                let future = slf.compute_current(request.clone(), key.clone(), cache_path);
+                future.await.map(|item| (item, false))
+            } else {
+                slf.compute(request.clone(), key.clone()).await
+            };
+
+            let (result, stale) = match result {
+                Ok((ok, stale)) => {
+                    let item = Arc::new(ok);
+                    slf.insert_memory(key.clone(), &item);
+                    (Ok(item), stale)
+                }
+                Err(err) => (Err(Arc::new(err)), false),
             };
             // Drop the token first to evict from the map.  This ensures that callers either
             // get a channel that will receive data, or they create a new channel.
             drop(remove_computation_token);
+
+            // Refresh items of older versions only after this computation has been removed, so
+            // that the refresh can take its place.
+            if let Ok(ref item) = result {
+                if stale {
+                    slf.spawn_refresh(request, key, item.clone());
+                }
+            }
             sender.send(result).ok();
         }
-        .bind_hub(Hub::new_from_top(Hub::current()));
+        .bind_hub(Hub::new_from_top(Hub::current()))
+        .bind_progress(progress)
+        .bind_shared_deadline(deadline)
+        .bind_cancellation(cancellation);
 
         // TODO: This spawns into the current_thread runtime of the caller. Consider more explicit
         // resource allocation here to separate CPU intensive work from I/O work.
-        spawn_compat(channel);
+        spawn_compat(Abortable::new(channel, abort_registration));
 
-        receiver.shared()
+        (receiver.shared(), guard)
     }
 
     /// Computes an item by loading from or populating the cache.
@@ -303,6 +830,11 @@ impl<T: CacheItemRequest> Cacher<T> {
     /// The computation itself is done by [`T::compute`](CacheItemRequest::compute), but only if it
     /// was not already in the cache.
     ///
+    /// Once all futures returned for a computation are dropped before it completes and all of
+    /// their requests have been cancelled, the computation is aborted.  If any caller stops
+    /// waiting for another reason, such as a timeout, the computation keeps running in the
+    /// background and its result is cached.
+    ///
     /// # Errors
     ///
     /// Cache computation can fail, in which case [`T::compute`](CacheItemRequest::compute)
@@ -314,29 +846,442 @@ impl<T: CacheItemRequest> Cacher<T> {
         let key = request.get_cache_key();
         let name = self.config.name();
 
-        let channel = {
+        if let Some(item) = self.get_memory(&key) {
+            if let Some(item) = request.from_memory(item) {
+                return Box::pin(future::ok(item));
+            }
+        }
+
+        let (channel, guard) = {
             let mut current_computations = self.current_computations.lock();
-            if let Some(channel) = current_computations.get(&key) {
+            let running = current_computations
+                .get(&key)
+                .and_then(|computation| Some((computation.join()?, computation)));
+            if let Some((running, computation)) = running {
                 // A concurrent cache lookup was deduplicated.
                 metric!(counter(&format!("caches.{}.channel.hit", name)) += 1);
-                channel.clone()
+                // Items being refreshed are served in their previous version in the meantime.
+                if let Some(ref item) = computation.fallback {
+                    return Box::pin(future::ok(item.clone()));
+                }
+                computation.deadline.join(Deadline::current());
+                running
             } else {
                 // A concurrent cache lookup is considered new. This does not imply a cache miss.
+                // This replaces computations that are being aborted.
                 metric!(counter(&format!("caches.{}.channel.miss", name)) += 1);
-                let channel = self.create_channel(request, key.clone());
-                let evicted = current_computations.insert(key.clone(), channel.clone());
-                debug_assert!(evicted.is_none());
-                channel
+                let deadline = SharedDeadline::new(Deadline::current());
+                let (channel, guard) =
+                    self.create_channel(request, key.clone(), false, deadline.clone());
+                let computation = Computation {
+                    channel: channel.clone(),
+                    guard: Arc::downgrade(&guard),
+                    detached: guard.detached.clone(),
+                    fallback: None,
+                    deadline,
+                };
+                current_computations.insert(key.clone(), computation);
+                (channel, Some(guard))
             }
         };
 
-        let future = channel.unwrap_or_else(move |_cancelled_error| {
-            let message = format!("{} computation channel dropped", name);
-            Err(Arc::new(
-                io::Error::new(io::ErrorKind::Interrupted, message).into(),
-            ))
+        // Detach the computation if this caller stops waiting without being cancelled.
+        let cancellation = Cancellation::current();
+        let waiter = CallOnDrop::new(move || {
+            if let Some(guard) = guard {
+                if !cancellation.is_cancelled() {
+                    guard.detach();
+                }
+            }
         });
 
+        let future = async move {
+            let result = channel.await;
+            drop(waiter);
+            result.unwrap_or_else(move |_cancelled_error| {
+                let message = format!("{} computation channel dropped", name);
+                Err(Arc::new(
+                    io::Error::new(io::ErrorKind::Interrupted, message).into(),
+                ))
+            })
+        };
+
         Box::pin(future)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::AtomicUsize;
+
+    use tokio::sync::Notify;
+
+    use crate::config::{CacheConfig, DerivedCacheConfig};
+    use crate::test;
+    use crate::utils::futures::delay;
+
+    /// A cache item that is computed as `"new"` in version 1 and counts its computations.
+    #[derive(Clone, Debug)]
+    struct TestCacheItem {
+        key: CacheKey,
+        computations: Arc<AtomicUsize>,
+    }
+
+    impl CacheItemRequest for TestCacheItem {
+        type Item = String;
+        type Error = io::Error;
+
+        fn get_cache_key(&self) -> CacheKey {
+            self.key.clone()
+        }
+
+        fn get_source_key(&self) -> CacheKey {
+            CacheKey {
+                cache_key: "some_object".to_owned(),
+                scope: self.key.scope.clone(),
+            }
+        }
+
+        fn versions(&self) -> Option<CacheVersions> {
+            Some(CacheVersions {
+                current: 1,
+                fallbacks: &[0],
+            })
+        }
+
+        fn compute(&self, path: &Path) -> BoxedFuture<Result<CacheStatus, Self::Error>> {
+            let path = path.to_owned();
+            let computations = self.computations.clone();
+            Box::pin(async move {
+                delay(Duration::from_millis(50)).await;
+                std::fs::write(path, b"new")?;
+                computations.fetch_add(1, Ordering::Relaxed);
+                Ok(CacheStatus::Positive)
+            })
+        }
+
+        fn should_load_fallback(&self, _data: &[u8]) -> bool {
+            true
+        }
+
+        fn load(
+            &self,
+            _scope: Scope,
+            _status: CacheStatus,
+            data: ByteView<'static>,
+            _path: CachePath,
+        ) -> Self::Item {
+            String::from_utf8_lossy(&data).into_owned()
+        }
+    }
+
+    /// A cache item that computes once it is released and reports whether it finished.
+    ///
+    /// If the computation is aborted, the outcome sender is dropped without a value.
+    #[derive(Clone, Debug)]
+    struct SlowCacheItem {
+        key: CacheKey,
+        started: Arc<Notify>,
+        release: Arc<Notify>,
+        outcome: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+    }
+
+    impl CacheItemRequest for SlowCacheItem {
+        type Item = String;
+        type Error = io::Error;
+
+        fn get_cache_key(&self) -> CacheKey {
+            self.key.clone()
+        }
+
+        fn compute(&self, path: &Path) -> BoxedFuture<Result<CacheStatus, Self::Error>> {
+            let path = path.to_owned();
+            let started = self.started.clone();
+            let release = self.release.clone();
+            let outcome = self.outcome.lock().take();
+            Box::pin(async move {
+                started.notify_one();
+                release.notified().await;
+                std::fs::write(path, b"slow")?;
+                if let Some(outcome) = outcome {
+                    outcome.send(()).ok();
+                }
+                Ok(CacheStatus::Positive)
+            })
+        }
+
+        fn load(
+            &self,
+            _scope: Scope,
+            _status: CacheStatus,
+            data: ByteView<'static>,
+            _path: CachePath,
+        ) -> Self::Item {
+            String::from_utf8_lossy(&data).into_owned()
+        }
+    }
+
+    /// A cache item kept in memory, which is served along with the name of its requester.
+    #[derive(Clone, Debug)]
+    struct MemoryCacheItem {
+        key: CacheKey,
+        requester: &'static str,
+        computations: Arc<AtomicUsize>,
+    }
+
+    impl CacheItemRequest for MemoryCacheItem {
+        type Item = (String, &'static str);
+        type Error = io::Error;
+
+        fn get_cache_key(&self) -> CacheKey {
+            self.key.clone()
+        }
+
+        fn compute(&self, path: &Path) -> BoxedFuture<Result<CacheStatus, Self::Error>> {
+            let path = path.to_owned();
+            let computations = self.computations.clone();
+            Box::pin(async move {
+                std::fs::write(path, b"data")?;
+                computations.fetch_add(1, Ordering::Relaxed);
+                Ok(CacheStatus::Positive)
+            })
+        }
+
+        fn to_memory(item: &Self::Item) -> Option<(Self::Item, u64)> {
+            Some(((item.0.clone(), ""), item.0.len() as u64))
+        }
+
+        fn from_memory(&self, item: Arc<Self::Item>) -> Option<Arc<Self::Item>> {
+            Some(Arc::new((item.0.clone(), self.requester)))
+        }
+
+        fn load(
+            &self,
+            _scope: Scope,
+            _status: CacheStatus,
+            data: ByteView<'static>,
+            _path: CachePath,
+        ) -> Self::Item {
+            (String::from_utf8_lossy(&data).into_owned(), self.requester)
+        }
+    }
+
+    /// Starts computing a [`SlowCacheItem`] and stops waiting for it once it has started.
+    ///
+    /// Returns whether the computation finished in the background.
+    async fn stop_waiting(cancel: bool) -> bool {
+        let cache_dir = test::tempdir();
+        let cache = Cache::from_config(
+            "test",
+            Some(cache_dir.path().to_owned()),
+            None,
+            CacheConfig::Derived(Default::default()),
+        )
+        .unwrap();
+
+        let (sender, receiver) = oneshot::channel();
+        let item = SlowCacheItem {
+            key: CacheKey {
+                cache_key: "slow_item".to_owned(),
+                scope: Scope::Global,
+            },
+            started: Arc::new(Notify::new()),
+            release: Arc::new(Notify::new()),
+            outcome: Arc::new(Mutex::new(Some(sender))),
+        };
+
+        test::spawn_compat(move || async move {
+            let cacher = Cacher::new(cache, None);
+            let cancellation = Cancellation::new();
+            let waiter = async { cacher.compute_memoized(item.clone()).await }
+                .bind_cancellation(cancellation.clone());
+
+            if cancel {
+                cancellation.cancel();
+            }
+
+            // Stop waiting as soon as the computation has started, then let it continue.
+            let started = item.started.notified();
+            match future::select(Box::pin(waiter), Box::pin(started)).await {
+                future::Either::Left(_) => panic!("computation finished before it was released"),
+                future::Either::Right((_, waiter)) => drop(waiter),
+            }
+            item.release.notify_one();
+
+            receiver.await.is_ok()
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_waiter_detaches() {
+        test::setup();
+
+        // The computation keeps running for the next request.
+        assert!(stop_waiting(false).await);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_waiter_aborts() {
+        test::setup();
+
+        assert!(!stop_waiting(true).await);
+    }
+
+    #[tokio::test]
+    async fn test_fallback_version() {
+        test::setup();
+
+        // An item exists in the fallback version 0.
+        let cache_dir = test::tempdir();
+        let scope_dir = cache_dir.path().join("global");
+        std::fs::create_dir_all(&scope_dir).unwrap();
+        std::fs::write(scope_dir.join("some_item"), b"old").unwrap();
+
+        let cache = Cache::from_config(
+            "test",
+            Some(cache_dir.path().to_owned()),
+            None,
+            CacheConfig::Derived(Default::default()),
+        )
+        .unwrap();
+
+        let key = CacheKey {
+            cache_key: "some_item".to_owned(),
+            scope: Scope::Global,
+        };
+
+        let computations = Arc::new(AtomicUsize::new(0));
+        let item = TestCacheItem {
+            key,
+            computations: computations.clone(),
+        };
+
+        let results = test::spawn_compat(move || async move {
+            let cacher = Cacher::new(cache, None);
+            let mut results = Vec::new();
+            // The second request joins the refresh and is served the fallback as well.
+            for _ in 0..2 {
+                let result = cacher.compute_memoized(item.clone()).await.unwrap();
+                results.push(result.as_str().to_owned());
+            }
+            delay(Duration::from_millis(200)).await;
+            let result = cacher.compute_memoized(item).await.unwrap();
+            results.push(result.as_str().to_owned());
+            results
+        })
+        .await;
+
+        // The fallback is served immediately, and then replaced in place by the current version.
+        assert_eq!(results, vec!["old", "old", "new"]);
+        assert_eq!(computations.load(Ordering::Relaxed), 1);
+        assert_eq!(std::fs::read(scope_dir.join("some_item")).unwrap(), b"new");
+
+        let meta = CacheItemMeta::read(&scope_dir.join("some_item")).unwrap();
+        assert_eq!(
+            meta,
+            Some(CacheItemMeta {
+                source: CacheKey {
+                    cache_key: "some_object".to_owned(),
+                    scope: Scope::Global,
+                },
+                version: 1
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_memory_rebuilt_per_request() {
+        test::setup();
+
+        let cache_dir = test::tempdir();
+        let cache = Cache::from_config(
+            "test",
+            Some(cache_dir.path().to_owned()),
+            None,
+            CacheConfig::Derived(DerivedCacheConfig {
+                max_memory: Some(1024),
+                ..Default::default()
+            }),
+        )
+        .unwrap();
+
+        let key = CacheKey {
+            cache_key: "memory_item".to_owned(),
+            scope: Scope::Global,
+        };
+        let computations = Arc::new(AtomicUsize::new(0));
+        let first = MemoryCacheItem {
+            key: key.clone(),
+            requester: "first",
+            computations: computations.clone(),
+        };
+        let second = MemoryCacheItem {
+            key,
+            requester: "second",
+            computations: computations.clone(),
+        };
+
+        let results = test::spawn_compat(move || async move {
+            let cacher = Cacher::new(cache, None);
+            let first = cacher.compute_memoized(first).await.unwrap();
+            let second = cacher.compute_memoized(second).await.unwrap();
+            (first, second)
+        })
+        .await;
+
+        // The second request is served from memory, but not with the data of the first request.
+        assert_eq!(*results.0, ("data".to_owned(), "first"));
+        assert_eq!(*results.1, ("data".to_owned(), "second"));
+        assert_eq!(computations.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_memory_cache_lru() {
+        let key = |name: &str| CacheKey {
+            cache_key: name.to_owned(),
+            scope: Scope::Global,
+        };
+
+        let mut memory = MemoryCache::new(10);
+        assert_eq!(memory.insert(key("a"), Arc::new("a"), 4), 0);
+        assert_eq!(memory.insert(key("b"), Arc::new("b"), 4), 0);
+
+        // Using "a" makes "b" the least recently used item.
+        assert_eq!(memory.get(&key("a")).as_deref(), Some(&"a"));
+        assert_eq!(memory.insert(key("c"), Arc::new("c"), 4), 1);
+        assert_eq!(memory.size, 8);
+
+        assert!(memory.get(&key("b")).is_none());
+        assert!(memory.get(&key("a")).is_some());
+        assert!(memory.get(&key("c")).is_some());
+
+        // Items larger than the cache are never kept.
+        assert_eq!(memory.insert(key("d"), Arc::new("d"), 11), 0);
+        assert!(memory.get(&key("d")).is_none());
+        assert_eq!(memory.size, 8);
+    }
+
+    #[test]
+    fn test_memory_cache_remove_matching() {
+        let key = |name: &str, scope: Scope| CacheKey {
+            cache_key: name.to_owned(),
+            scope,
+        };
+        let scoped = Scope::Scoped("myscope".to_owned());
+
+        let mut memory = MemoryCache::new(10);
+        memory.insert(key("a_1", Scope::Global), Arc::new("a"), 2);
+        memory.insert(key("a_1", scoped.clone()), Arc::new("a"), 2);
+        memory.insert(key("b_1", Scope::Global), Arc::new("b"), 2);
+
+        let removed = memory.remove_matching(&Scope::Global, |key| key.starts_with("a_"));
+        assert_eq!(removed, 1);
+        assert_eq!(memory.size, 4);
+        assert!(memory.get(&key("a_1", Scope::Global)).is_none());
+        assert!(memory.get(&key("a_1", scoped)).is_some());
+        assert!(memory.get(&key("b_1", Scope::Global)).is_some());
+    }
+}
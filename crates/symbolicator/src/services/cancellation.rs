@@ -0,0 +1,123 @@
+//! Explicit cancellation of symbolication requests.
+//!
+//! Every symbolication request has a [`Cancellation`] handle, which is set when a client cancels
+//! the request with `DELETE /requests/{request_id}`.  Like a [`Progress`](super::progress::Progress)
+//! handle, it is bound to the request future and retrieved with [`Cancellation::current`].
+//!
+//! The [`Cacher`](super::cacher::Cacher) uses it to tell cancelled requests apart from requests
+//! that stopped waiting for any other reason, such as a timeout.  Shared computations are only
+//! aborted if all requests waiting on them have been cancelled.  Otherwise they keep running, so
+//! that their result is cached for the next request.
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+thread_local! {
+    static CURRENT: RefCell<Cancellation> = RefCell::new(Cancellation::default());
+}
+
+/// Handle to cancel a symbolication request.
+///
+/// The default handle is not associated with any request and is never cancelled.
+#[derive(Clone, Debug, Default)]
+pub struct Cancellation {
+    inner: Option<Arc<AtomicBool>>,
+}
+
+impl Cancellation {
+    /// Creates a new cancellation handle for a request.
+    pub fn new() -> Self {
+        Self {
+            inner: Some(Arc::new(AtomicBool::new(false))),
+        }
+    }
+
+    /// Returns the cancellation handle bound to the currently executing future.
+    pub fn current() -> Self {
+        CURRENT.with(|current| current.borrow().clone())
+    }
+
+    /// Marks the request as cancelled.
+    pub fn cancel(&self) {
+        if let Some(ref inner) = self.inner {
+            inner.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns `true` if the request has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        match self.inner {
+            Some(ref inner) => inner.load(Ordering::Relaxed),
+            None => false,
+        }
+    }
+
+    /// Runs the callback with this handle as the current handle.
+    fn run<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let previous = CURRENT.with(|current| current.replace(self.clone()));
+        let result = f();
+        CURRENT.with(|current| current.replace(previous));
+        result
+    }
+}
+
+/// A future bound to a [`Cancellation`], see [`CancellationFutureExt::bind_cancellation`].
+#[derive(Debug)]
+pub struct CancellationFuture<F> {
+    cancellation: Cancellation,
+    inner: F,
+}
+
+impl<F> Future for CancellationFuture<F>
+where
+    F: Future,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let cancellation = self.cancellation.clone();
+        // https://doc.rust-lang.org/std/pin/index.html#pinning-is-structural-for-field
+        let future = unsafe { self.map_unchecked_mut(|s| &mut s.inner) };
+        cancellation.run(|| future.poll(ctx))
+    }
+}
+
+pub trait CancellationFutureExt: Sized {
+    /// Binds a cancellation handle to this future, making it [`Cancellation::current`] while
+    /// polling.
+    fn bind_cancellation(self, cancellation: Cancellation) -> CancellationFuture<Self> {
+        CancellationFuture {
+            cancellation,
+            inner: self,
+        }
+    }
+}
+
+impl<F> CancellationFutureExt for F where F: Future {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind_cancellation() {
+        let cancellation = Cancellation::new();
+        cancellation.cancel();
+
+        let current = async { Cancellation::current().is_cancelled() }
+            .bind_cancellation(cancellation)
+            .await;
+        assert!(current);
+
+        // Outside of the bound future, nothing is cancelled.
+        assert!(!Cancellation::current().is_cancelled());
+        assert!(!Cancellation::default().is_cancelled());
+    }
+}
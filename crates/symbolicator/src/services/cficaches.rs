@@ -13,11 +13,12 @@ use symbolic::{
 };
 use thiserror::Error;
 
-use crate::cache::{Cache, CacheKey, CacheStatus};
+use crate::cache::{Cache, CacheKey, CacheStatus, CacheVersions};
 use crate::services::cacher::{CacheItemRequest, CachePath, Cacher};
 use crate::services::objects::{
     FindObject, ObjectError, ObjectHandle, ObjectMetaHandle, ObjectPurpose, ObjectsActor,
 };
+use crate::services::shared_cache::SharedCacheService;
 use crate::sources::{FileType, SourceConfig};
 use crate::types::{
     AllObjectCandidates, ObjectFeatures, ObjectId, ObjectType, ObjectUseInfo, Scope,
@@ -47,19 +48,43 @@ pub enum CfiCacheError {
     Canceled,
 }
 
+/// The format versions of cficaches.
+///
+/// Bump the current version when the format written by symbolic changes.  If symbolic can still
+/// read the previous format, add the previous version to the fallbacks, so that existing items
+/// are served while they are upgraded in place.
+///
+/// # Versions
+///
+/// - 0: Initial version, including items written before the version was recorded.
+/// - 1: Items are always in the latest CFI cache format of symbolic.  Items of version 0 may be in
+///   an older format, which is served until they are recomputed if symbolic can still read it.
+pub const CFICACHE_VERSIONS: CacheVersions = CacheVersions {
+    current: 1,
+    fallbacks: &[0],
+};
+
 #[derive(Clone, Debug)]
 pub struct CfiCacheActor {
     cficaches: Arc<Cacher<FetchCfiCacheInternal>>,
     objects: ObjectsActor,
     threadpool: ThreadPool,
+    timeout: Duration,
 }
 
 impl CfiCacheActor {
-    pub fn new(cache: Cache, objects: ObjectsActor, threadpool: ThreadPool) -> Self {
+    pub fn new(
+        cache: Cache,
+        shared_cache: Option<Arc<SharedCacheService>>,
+        objects: ObjectsActor,
+        threadpool: ThreadPool,
+        timeout: Duration,
+    ) -> Self {
         CfiCacheActor {
-            cficaches: Arc::new(Cacher::new(cache)),
+            cficaches: Arc::new(Cacher::new(cache, shared_cache)),
             objects,
             threadpool,
+            timeout,
         }
     }
 }
@@ -105,6 +130,20 @@ struct FetchCfiCacheInternal {
     meta_handle: Arc<ObjectMetaHandle>,
     candidates: AllObjectCandidates,
     threadpool: ThreadPool,
+    timeout: Duration,
+}
+
+impl FetchCfiCacheInternal {
+    /// Returns the candidates of this request, including the status of the CFI cache.
+    fn candidates(&self, status: CacheStatus) -> AllObjectCandidates {
+        let mut candidates = self.candidates.clone();
+        candidates.set_unwind(
+            self.meta_handle.source_id().clone(),
+            &self.meta_handle.uri(),
+            ObjectUseInfo::from_derived_status(status, self.meta_handle.status()),
+        );
+        candidates
+    }
 }
 
 impl CacheItemRequest for FetchCfiCacheInternal {
@@ -115,6 +154,10 @@ impl CacheItemRequest for FetchCfiCacheInternal {
         self.meta_handle.cache_key()
     }
 
+    fn get_source_key(&self) -> CacheKey {
+        self.meta_handle.cache_key()
+    }
+
     /// Extracts the Call Frame Information (CFI) from an object file.
     ///
     /// The extracted CFI is written to `path` in symbolic's
@@ -155,7 +198,7 @@ impl CacheItemRequest for FetchCfiCacheInternal {
         Box::pin(
             future_metrics!(
                 "cficaches",
-                Some((Duration::from_secs(1200), CfiCacheError::Timeout)),
+                Some((self.timeout, CfiCacheError::Timeout)),
                 result.compat(),
                 "num_sources" => &num_sources.to_string()
             )
@@ -163,12 +206,64 @@ impl CacheItemRequest for FetchCfiCacheInternal {
         )
     }
 
+    fn versions(&self) -> Option<CacheVersions> {
+        Some(CFICACHE_VERSIONS)
+    }
+
+    fn to_memory(item: &Self::Item) -> Option<(Self::Item, u64)> {
+        // Stackwalking reads CFI caches from the file system in a separate process, so only items
+        // that are permanently cached can be served from memory.  The copy shares the mapped data,
+        // and the candidates are rebuilt for every request in `from_memory`.
+        let path = match (item.status, &item.path) {
+            (CacheStatus::Positive, CachePath::Cached(path)) => path.clone(),
+            _ => return None,
+        };
+
+        let size = item.data.len() + std::mem::size_of::<CfiCacheFile>();
+        let copy = CfiCacheFile {
+            object_type: item.object_type,
+            identifier: item.identifier.clone(),
+            scope: item.scope.clone(),
+            data: item.data.clone(),
+            features: item.features,
+            status: item.status,
+            path: CachePath::Cached(path),
+            candidates: AllObjectCandidates::default(),
+        };
+
+        Some((copy, size as u64))
+    }
+
+    fn from_memory(&self, item: Arc<Self::Item>) -> Option<Arc<Self::Item>> {
+        // The file may have been removed by a cleanup in the meantime.
+        if !item.path.exists() {
+            return None;
+        }
+
+        Some(Arc::new(CfiCacheFile {
+            object_type: self.request.object_type,
+            identifier: self.request.identifier.clone(),
+            scope: item.scope.clone(),
+            data: item.data.clone(),
+            features: item.features,
+            status: item.status,
+            path: CachePath::Cached(item.path.to_path_buf()),
+            candidates: self.candidates(item.status),
+        }))
+    }
+
     fn should_load(&self, data: &[u8]) -> bool {
         CfiCache::from_bytes(ByteView::from_slice(data))
             .map(|cficache| cficache.is_latest())
             .unwrap_or(false)
     }
 
+    fn should_load_fallback(&self, data: &[u8]) -> bool {
+        // Items of older versions are served while they are being upgraded, so this only checks
+        // whether the format can still be read.
+        verify_cficache(data).is_ok()
+    }
+
     fn load(
         &self,
         scope: Scope,
@@ -176,12 +271,7 @@ impl CacheItemRequest for FetchCfiCacheInternal {
         data: ByteView<'static>,
         path: CachePath,
     ) -> Self::Item {
-        let mut candidates = self.candidates.clone();
-        candidates.set_unwind(
-            self.meta_handle.source_id().clone(),
-            &self.meta_handle.uri(),
-            ObjectUseInfo::from_derived_status(status, self.meta_handle.status()),
-        );
+        let candidates = self.candidates(status);
 
         CfiCacheFile {
             object_type: self.request.object_type,
@@ -196,6 +286,12 @@ impl CacheItemRequest for FetchCfiCacheInternal {
     }
 }
 
+/// Checks whether a cficache can be parsed.
+pub(crate) fn verify_cficache(data: &[u8]) -> Result<(), CfiCacheError> {
+    CfiCache::from_bytes(ByteView::from_slice(data))?;
+    Ok(())
+}
+
 /// Information for fetching the symbols for this cficache
 #[derive(Debug, Clone)]
 pub struct FetchCfiCache {
@@ -206,6 +302,14 @@ pub struct FetchCfiCache {
 }
 
 impl CfiCacheActor {
+    /// Removes CFI caches of the given scope whose cache key matches the filter from memory.
+    pub fn remove_memory<F>(&self, scope: &Scope, filter: F) -> usize
+    where
+        F: FnMut(&str) -> bool,
+    {
+        self.cficaches.remove_memory(scope, filter)
+    }
+
     /// Fetches the CFI cache file for a given code module.
     ///
     /// The code object can be identified by a combination of the code-id, debug-id and
@@ -236,6 +340,7 @@ impl CfiCacheActor {
                         objects_actor: self.objects.clone(),
                         meta_handle,
                         threadpool: self.threadpool.clone(),
+                        timeout: self.timeout,
                         candidates: found_result.candidates,
                     })
                     .await
@@ -0,0 +1,222 @@
+//! Deadlines of symbolication requests.
+//!
+//! Clients can limit how long a symbolication request may take with
+//! [`RequestOptions::timeout`](crate::types::RequestOptions::timeout).  The resulting [`Deadline`]
+//! is bound to the request future like a [`Progress`](super::progress::Progress) handle, and
+//! retrieved with [`Deadline::current`] by stackwalking and other work of the request to cap
+//! their own timeouts.
+//!
+//! Computations in the cachers are shared between requests, so they run with a [`SharedDeadline`]
+//! that expires with the earliest deadline of all requests waiting for them, including requests
+//! that join while the computation is running.  Requests with
+//! [`partial_results`](crate::types::RequestOptions::partial_results) stop waiting for debug files
+//! at the deadline and complete with the debug files available by then, but are still bound by
+//! the `timeouts.symbolication` configuration of the server.
+
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+thread_local! {
+    static CURRENT: Cell<Deadline> = Cell::new(Deadline::default());
+}
+
+/// The point in time by which a request must have finished.
+///
+/// The default deadline is not associated with any request and never expires.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Deadline {
+    instant: Option<Instant>,
+}
+
+impl Deadline {
+    /// Creates a deadline that expires after `timeout`, or never if there is no timeout.
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            instant: timeout.map(|timeout| Instant::now() + timeout),
+        }
+    }
+
+    /// Returns the deadline bound to the currently executing future.
+    pub fn current() -> Self {
+        CURRENT.with(|current| current.get())
+    }
+
+    /// Returns the time left until the deadline, or `None` if there is no deadline.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.instant
+            .map(|instant| instant.saturating_duration_since(Instant::now()))
+    }
+
+    /// Returns `timeout`, shortened to the time left until the deadline.
+    pub fn cap(&self, timeout: Duration) -> Duration {
+        match self.remaining() {
+            Some(remaining) => timeout.min(remaining),
+            None => timeout,
+        }
+    }
+
+    /// Returns the earlier of both deadlines.
+    pub fn min(self, other: Deadline) -> Self {
+        let instant = match (self.instant, other.instant) {
+            (Some(instant), Some(other)) => Some(instant.min(other)),
+            (instant, other) => instant.or(other),
+        };
+        Self { instant }
+    }
+
+    /// Runs the callback with this deadline as the current deadline.
+    fn run<F, R>(self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let previous = CURRENT.with(|current| current.replace(self));
+        let result = f();
+        CURRENT.with(|current| current.set(previous));
+        result
+    }
+}
+
+/// A future bound to a [`Deadline`], see [`DeadlineFutureExt::bind_deadline`].
+#[derive(Debug)]
+pub struct DeadlineFuture<F> {
+    deadline: Deadline,
+    inner: F,
+}
+
+impl<F> Future for DeadlineFuture<F>
+where
+    F: Future,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let deadline = self.deadline;
+        // https://doc.rust-lang.org/std/pin/index.html#pinning-is-structural-for-field
+        let future = unsafe { self.map_unchecked_mut(|s| &mut s.inner) };
+        deadline.run(|| future.poll(ctx))
+    }
+}
+
+/// A deadline shared between all requests waiting for a computation.
+///
+/// Requests that join the computation lower the deadline to their own, so the computation is
+/// bound by the earliest deadline of its waiters.
+#[derive(Clone, Debug, Default)]
+pub struct SharedDeadline {
+    deadline: Arc<Mutex<Deadline>>,
+}
+
+impl SharedDeadline {
+    /// Creates a shared deadline that initially expires with `deadline`.
+    pub fn new(deadline: Deadline) -> Self {
+        Self {
+            deadline: Arc::new(Mutex::new(deadline)),
+        }
+    }
+
+    /// Lowers the shared deadline to `deadline` if that expires earlier.
+    pub fn join(&self, deadline: Deadline) {
+        let mut current = self.deadline.lock();
+        *current = current.min(deadline);
+    }
+
+    /// Returns the current value of the shared deadline.
+    pub fn get(&self) -> Deadline {
+        *self.deadline.lock()
+    }
+}
+
+/// A future bound to a [`SharedDeadline`], see [`DeadlineFutureExt::bind_shared_deadline`].
+#[derive(Debug)]
+pub struct SharedDeadlineFuture<F> {
+    deadline: SharedDeadline,
+    inner: F,
+}
+
+impl<F> Future for SharedDeadlineFuture<F>
+where
+    F: Future,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let deadline = self.deadline.get();
+        // https://doc.rust-lang.org/std/pin/index.html#pinning-is-structural-for-field
+        let future = unsafe { self.map_unchecked_mut(|s| &mut s.inner) };
+        deadline.run(|| future.poll(ctx))
+    }
+}
+
+pub trait DeadlineFutureExt: Sized {
+    /// Binds a deadline to this future, making it [`Deadline::current`] while polling.
+    fn bind_deadline(self, deadline: Deadline) -> DeadlineFuture<Self> {
+        DeadlineFuture {
+            deadline,
+            inner: self,
+        }
+    }
+
+    /// Binds a shared deadline to this future, making its current value [`Deadline::current`]
+    /// while polling.
+    fn bind_shared_deadline(self, deadline: SharedDeadline) -> SharedDeadlineFuture<Self> {
+        SharedDeadlineFuture {
+            deadline,
+            inner: self,
+        }
+    }
+}
+
+impl<F> DeadlineFutureExt for F where F: Future {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cap() {
+        let timeout = Duration::from_secs(60);
+        assert_eq!(Deadline::default().cap(timeout), timeout);
+
+        let deadline = Deadline::new(Some(Duration::from_secs(10)));
+        assert!(deadline.cap(timeout) <= Duration::from_secs(10));
+        assert!(deadline.cap(timeout) > Duration::from_secs(9));
+        assert_eq!(deadline.cap(Duration::from_secs(1)), Duration::from_secs(1));
+
+        let expired = Deadline::new(Some(Duration::from_secs(0)));
+        assert_eq!(expired.cap(timeout), Duration::from_secs(0));
+    }
+
+    #[tokio::test]
+    async fn test_bind_deadline() {
+        let deadline = Deadline::new(Some(Duration::from_secs(10)));
+
+        let current = async { Deadline::current() }.bind_deadline(deadline).await;
+        assert_eq!(current, deadline);
+
+        // Outside of the bound future, there is no deadline.
+        assert_eq!(Deadline::current(), Deadline::default());
+    }
+
+    #[tokio::test]
+    async fn test_shared_deadline() {
+        let shared = SharedDeadline::new(Deadline::default());
+        let early = Deadline::new(Some(Duration::from_secs(10)));
+        let late = Deadline::new(Some(Duration::from_secs(60)));
+
+        shared.join(late);
+        shared.join(early);
+        shared.join(Deadline::default());
+        assert_eq!(shared.get(), early);
+
+        // Futures observe deadlines joined after they were bound.
+        let future = async { Deadline::current() }.bind_shared_deadline(shared.clone());
+        shared.join(Deadline::new(Some(Duration::from_secs(1))));
+        assert!(future.await.cap(Duration::from_secs(60)) <= Duration::from_secs(1));
+    }
+}
@@ -16,9 +16,8 @@ use url::Url;
 
 use super::locations::SourceLocation;
 use super::{DownloadError, DownloadStatus, RemoteDif, RemoteDifUri};
-use crate::sources::{FileType, GcsSourceConfig, GcsSourceKey};
+use crate::sources::{FileType, GcsCredentialsProvider, GcsSourceConfig, GcsSourceKey};
 use crate::types::ObjectId;
-use crate::utils::futures as future_utils;
 
 /// An LRU cache for GCS OAuth tokens.
 type GcsTokenCache = lru::LruCache<Arc<GcsSourceKey>, Arc<GcsToken>>;
@@ -33,6 +32,13 @@ type GcsTokenCache = lru::LruCache<Arc<GcsSourceKey>, Arc<GcsToken>>;
 /// metrics.
 const GCS_TOKEN_CACHE_SIZE: usize = 100;
 
+/// The OAuth endpoint of Google to exchange grants for tokens.
+const TOKEN_URL: &str = "https://www.googleapis.com/oauth2/v4/token";
+
+/// The endpoint of the metadata server returning tokens of the default service account.
+const METADATA_TOKEN_URL: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+
 /// The GCS-specific [`RemoteDif`].
 #[derive(Debug, Clone)]
 pub struct GcsRemoteDif {
@@ -83,9 +89,36 @@ struct OAuth2Grant {
     assertion: String,
 }
 
+#[derive(Serialize)]
+struct RefreshTokenGrant<'a> {
+    grant_type: &'a str,
+    client_id: &'a str,
+    client_secret: &'a str,
+    refresh_token: &'a str,
+}
+
 #[derive(Deserialize)]
 struct GcsTokenResponse {
     access_token: String,
+    #[serde(default)]
+    expires_in: Option<i64>,
+}
+
+/// The contents of an application default credentials file.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ApplicationCredentials {
+    /// A service account key, as downloaded from the Cloud Console.
+    ServiceAccount {
+        private_key: String,
+        client_email: String,
+    },
+    /// A user account, as created by `gcloud auth application-default login`.
+    AuthorizedUser {
+        client_id: String,
+        client_secret: String,
+        refresh_token: String,
+    },
 }
 
 #[derive(Debug)]
@@ -104,6 +137,10 @@ pub enum GcsError {
     Jwt(#[from] jsonwebtoken::errors::Error),
     #[error("failed to send authentication request")]
     Auth(#[source] reqwest::Error),
+    #[error("failed to read application default credentials")]
+    ApplicationCredentials(#[source] std::io::Error),
+    #[error("invalid application default credentials")]
+    InvalidApplicationCredentials(#[source] serde_json::Error),
 }
 
 /// Returns the JWT key parsed from a string.
@@ -117,50 +154,75 @@ fn key_from_string(key: &str) -> Result<EncodingKey, jsonwebtoken::errors::Error
     EncodingKey::from_rsa_pem(buffer.as_bytes())
 }
 
-/// Computes a JWT authentication assertion for the given GCS bucket.
-fn get_auth_jwt(source_key: &GcsSourceKey, expiration: i64) -> Result<String, GcsError> {
+/// Computes a JWT authentication assertion for the given service account.
+fn get_auth_jwt(
+    private_key: &str,
+    client_email: &str,
+    expiration: i64,
+) -> Result<String, GcsError> {
     let header = jsonwebtoken::Header::new(jsonwebtoken::Algorithm::RS256);
 
     let jwt_claims = JwtClaims {
-        issuer: source_key.client_email.clone(),
+        issuer: client_email.to_owned(),
         scope: "https://www.googleapis.com/auth/devstorage.read_only".into(),
-        audience: "https://www.googleapis.com/oauth2/v4/token".into(),
+        audience: TOKEN_URL.into(),
         expiration,
         issued_at: Utc::now().timestamp(),
     };
 
-    let key = key_from_string(&source_key.private_key)?;
+    let key = key_from_string(private_key)?;
 
     Ok(jsonwebtoken::encode(&header, &jwt_claims, &key)?)
 }
 
+/// Reads the application default credentials file.
+///
+/// The file is located with the `GOOGLE_APPLICATION_CREDENTIALS` environment variable, falling back
+/// to the location used by `gcloud`.
+async fn read_application_credentials() -> Result<ApplicationCredentials, GcsError> {
+    let path = match std::env::var_os("GOOGLE_APPLICATION_CREDENTIALS") {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let home = std::env::var_os("HOME").ok_or_else(|| {
+                let error = std::io::Error::new(std::io::ErrorKind::NotFound, "HOME is not set");
+                GcsError::ApplicationCredentials(error)
+            })?;
+            PathBuf::from(home).join(".config/gcloud/application_default_credentials.json")
+        }
+    };
+
+    let data = tokio::fs::read(&path)
+        .await
+        .map_err(GcsError::ApplicationCredentials)?;
+    serde_json::from_slice(&data).map_err(GcsError::InvalidApplicationCredentials)
+}
+
 /// Downloader implementation that supports the [`GcsSourceConfig`] source.
 #[derive(Debug)]
 pub struct GcsDownloader {
     token_cache: Mutex<GcsTokenCache>,
     client: reqwest::Client,
+    /// Client for the metadata server, which is exempt from the restrictions of `client`.
+    metadata_client: reqwest::Client,
 }
 
 impl GcsDownloader {
-    pub fn new(client: Client) -> Self {
+    pub fn new(client: Client, metadata_client: Client) -> Self {
         Self {
             token_cache: Mutex::new(GcsTokenCache::new(GCS_TOKEN_CACHE_SIZE)),
             client,
+            metadata_client,
         }
     }
 
-    /// Requests a new GCS OAuth token.
-    async fn request_new_token(&self, source_key: &GcsSourceKey) -> Result<GcsToken, GcsError> {
-        let expires_at = Utc::now() + Duration::minutes(58);
-        let auth_jwt = get_auth_jwt(source_key, expires_at.timestamp() + 30)?;
-
-        let request = self
-            .client
-            .post("https://www.googleapis.com/oauth2/v4/token")
-            .form(&OAuth2Grant {
-                grant_type: "urn:ietf:params:oauth:grant-type:jwt-bearer".into(),
-                assertion: auth_jwt,
-            });
+    /// Sends a request for a GCS OAuth token.
+    ///
+    /// Tokens without a lifetime in the response are assumed to expire after an hour.
+    async fn send_token_request(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<GcsToken, GcsError> {
+        let requested_at = Utc::now();
 
         let response = request.send().await.map_err(|err| {
             log::debug!("Failed to authenticate against gcs: {}", err);
@@ -172,12 +234,79 @@ impl GcsDownloader {
             .await
             .map_err(GcsError::Auth)?;
 
+        // Leave some time to use the token before it expires.
+        let lifetime = match token.expires_in {
+            Some(expires_in) => Duration::seconds(expires_in) - Duration::minutes(2),
+            None => Duration::minutes(58),
+        };
+
         Ok(GcsToken {
             access_token: token.access_token,
-            expires_at,
+            expires_at: requested_at + lifetime,
         })
     }
 
+    /// Requests a new GCS OAuth token for a service account.
+    async fn request_service_account_token(
+        &self,
+        private_key: &str,
+        client_email: &str,
+    ) -> Result<GcsToken, GcsError> {
+        let expiration = Utc::now() + Duration::minutes(58);
+        let auth_jwt = get_auth_jwt(private_key, client_email, expiration.timestamp() + 30)?;
+
+        let request = self.client.post(TOKEN_URL).form(&OAuth2Grant {
+            grant_type: "urn:ietf:params:oauth:grant-type:jwt-bearer".into(),
+            assertion: auth_jwt,
+        });
+
+        self.send_token_request(request).await
+    }
+
+    /// Requests a new GCS OAuth token with the credentials of the given key.
+    async fn request_new_token(&self, source_key: &GcsSourceKey) -> Result<GcsToken, GcsError> {
+        match source_key.credentials_provider {
+            GcsCredentialsProvider::Static => {
+                self.request_service_account_token(
+                    &source_key.private_key,
+                    &source_key.client_email,
+                )
+                .await
+            }
+            GcsCredentialsProvider::ApplicationDefault => {
+                match read_application_credentials().await? {
+                    ApplicationCredentials::ServiceAccount {
+                        private_key,
+                        client_email,
+                    } => {
+                        self.request_service_account_token(&private_key, &client_email)
+                            .await
+                    }
+                    ApplicationCredentials::AuthorizedUser {
+                        client_id,
+                        client_secret,
+                        refresh_token,
+                    } => {
+                        let request = self.client.post(TOKEN_URL).form(&RefreshTokenGrant {
+                            grant_type: "refresh_token",
+                            client_id: &client_id,
+                            client_secret: &client_secret,
+                            refresh_token: &refresh_token,
+                        });
+                        self.send_token_request(request).await
+                    }
+                }
+            }
+            GcsCredentialsProvider::Metadata => {
+                let request = self
+                    .metadata_client
+                    .get(METADATA_TOKEN_URL)
+                    .header("Metadata-Flavor", "Google");
+                self.send_token_request(request).await
+            }
+        }
+    }
+
     /// Resolves a valid GCS OAuth token.
     ///
     /// If the cache contains a valid token, then this token is returned. Otherwise, a new token is
@@ -219,12 +348,11 @@ impl GcsDownloader {
             .map_err(|_| GcsError::InvalidUrl)?
             .extend(&[&file_source.source.bucket, "o", &key]);
 
-        let response = future_utils::retry(|| {
-            self.client
-                .get(url.clone())
-                .header("authorization", format!("Bearer {}", token.access_token))
-                .send()
-        });
+        let response = self
+            .client
+            .get(url.clone())
+            .header("authorization", format!("Bearer {}", token.access_token))
+            .send();
 
         match response.await {
             Ok(response) => {
@@ -237,6 +365,22 @@ impl GcsDownloader {
                     let stream = response.bytes_stream().map_err(DownloadError::Reqwest);
 
                     super::download_stream(file_source, stream, destination).await
+                } else if let Some(error) = DownloadError::throttled(&response) {
+                    log::trace!(
+                        "Throttled by GCS {} (from {}): {}",
+                        &key,
+                        &file_source.source.bucket,
+                        response.status()
+                    );
+                    Err(error)
+                } else if response.status().is_server_error() {
+                    log::trace!(
+                        "Server error from GCS {} (from {}): {}",
+                        &key,
+                        &file_source.source.bucket,
+                        response.status()
+                    );
+                    Err(DownloadError::BadStatus(response.status()))
                 } else {
                     log::trace!(
                         "Unexpected status code from GCS {} (from {}): {}",
@@ -247,7 +391,7 @@ impl GcsDownloader {
                     Ok(DownloadStatus::NotFound)
                 }
             }
-            Err(e) => {
+            Err(e) if e.is_builder() => {
                 log::trace!(
                     "Skipping response from GCS {} (from {}): {} ({:?})",
                     &key,
@@ -257,6 +401,15 @@ impl GcsDownloader {
                 );
                 Ok(DownloadStatus::NotFound)
             }
+            Err(e) => {
+                log::trace!(
+                    "Failed to fetch from GCS {} (from {}): {}",
+                    &key,
+                    &file_source.source.bucket,
+                    &e
+                );
+                Err(DownloadError::Reqwest(e))
+            }
         }
     }
 
@@ -297,6 +450,7 @@ mod tests {
             None
         } else {
             Some(GcsSourceKey {
+                credentials_provider: GcsCredentialsProvider::Static,
                 private_key,
                 client_email,
             })
@@ -330,7 +484,7 @@ mod tests {
         test::setup();
 
         let source = gcs_source(gcs_source_key!());
-        let downloader = GcsDownloader::new(Client::new());
+        let downloader = GcsDownloader::new(Client::new(), Client::new());
 
         let object_id = ObjectId {
             code_id: Some("e514c9464eed3be5943a2c61d9241fad".parse().unwrap()),
@@ -354,7 +508,7 @@ mod tests {
         test::setup();
 
         let source = gcs_source(gcs_source_key!());
-        let downloader = GcsDownloader::new(Client::new());
+        let downloader = GcsDownloader::new(Client::new(), Client::new());
 
         let tempdir = test::tempdir();
         let target_path = tempdir.path().join("myfile");
@@ -381,7 +535,7 @@ mod tests {
         test::setup();
 
         let source = gcs_source(gcs_source_key!());
-        let downloader = GcsDownloader::new(Client::new());
+        let downloader = GcsDownloader::new(Client::new(), Client::new());
 
         let tempdir = test::tempdir();
         let target_path = tempdir.path().join("myfile");
@@ -403,12 +557,13 @@ mod tests {
         test::setup();
 
         let broken_credentials = GcsSourceKey {
+            credentials_provider: GcsCredentialsProvider::Static,
             private_key: "".to_owned(),
             client_email: "".to_owned(),
         };
 
         let source = gcs_source(broken_credentials);
-        let downloader = GcsDownloader::new(Client::new());
+        let downloader = GcsDownloader::new(Client::new(), Client::new());
 
         let tempdir = test::tempdir();
         let target_path = tempdir.path().join("myfile");
@@ -424,6 +579,22 @@ mod tests {
         assert!(!target_path.exists());
     }
 
+    #[test]
+    fn test_application_credentials() {
+        let json = r#"{
+            "client_id": "123.apps.googleusercontent.com",
+            "client_secret": "secret",
+            "refresh_token": "token",
+            "type": "authorized_user"
+        }"#;
+
+        let credentials: ApplicationCredentials = serde_json::from_str(json).unwrap();
+        assert!(matches!(
+            credentials,
+            ApplicationCredentials::AuthorizedUser { ref refresh_token, .. } if refresh_token == "token"
+        ));
+    }
+
     #[test]
     fn test_key_from_string() {
         let creds = gcs_source_key!();
@@ -441,6 +612,7 @@ mod tests {
     #[test]
     fn test_gcs_remote_dif_uri() {
         let source_key = Arc::new(GcsSourceKey {
+            credentials_provider: GcsCredentialsProvider::Static,
             private_key: String::from("ABC"),
             client_email: String::from("someone@example.com"),
         });
@@ -0,0 +1,368 @@
+//! Health tracking and circuit breaking of sources.
+//!
+//! Downloads from a source that is down or whose credentials have expired would otherwise each
+//! wait for an error.  After a number of consecutive failures, the circuit
+//! breaker of the source opens and downloads fail immediately for a cooldown period.  After the
+//! cooldown, a single download probes the source and closes the circuit again if it succeeds.
+//!
+//! Sources passed in requests may reuse the IDs of other sources, so health is tracked per source
+//! ID and [endpoint](RemoteDif::endpoint).  Cancelled downloads and sources throttling downloads do
+//! not count as failures, but timed out downloads do, see [`DownloadError::is_source_failure`].
+//! Sources that have not been downloaded from within a cooldown period are forgotten.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+use crate::config::CircuitBreakerConfig;
+use crate::sources::SourceId;
+
+use super::{DownloadError, RemoteDif};
+
+/// Identifies a source by its ID and endpoint.
+type HealthKey = (SourceId, String);
+
+/// Weight of the latest download in the moving averages of error rate and latency.
+const AVERAGE_WEIGHT: f64 = 0.1;
+
+/// Health of a single source.
+#[derive(Debug, Default)]
+struct SourceHealth {
+    /// Number of failed downloads since the last successful download.
+    consecutive_failures: u32,
+    /// Moving average of the ratio of failed downloads.
+    error_rate: f64,
+    /// Moving average of the download duration.
+    latency: Duration,
+    /// Downloads are rejected until this time, and until a probe succeeds afterwards.
+    disabled_until: Option<Instant>,
+    /// When the download probing the source after its cooldown started.
+    probe: Option<Instant>,
+    /// When the last download from the source finished.
+    updated: Option<Instant>,
+}
+
+impl SourceHealth {
+    /// Returns `true` if the source has neither been downloaded from nor been disabled within the
+    /// last cooldown period.
+    fn is_idle(&self, now: Instant, cooldown: Duration) -> bool {
+        let expired = |instant: Instant| now.saturating_duration_since(instant) >= cooldown;
+        self.probe.is_none()
+            && self.disabled_until.map_or(true, expired)
+            && self.updated.map_or(true, expired)
+    }
+}
+
+/// Tracks the health of sources and disables them after repeated failures.
+#[derive(Debug)]
+pub struct SourceHealthTracker {
+    config: CircuitBreakerConfig,
+    sources: Mutex<BTreeMap<HealthKey, SourceHealth>>,
+}
+
+impl SourceHealthTracker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        SourceHealthTracker {
+            config,
+            sources: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Checks whether a download from the given source may start.
+    ///
+    /// Returns [`DownloadError::SourceDisabled`] while the circuit breaker of the source is open.
+    /// Once the cooldown has elapsed, a single download is let through to probe the source.
+    pub fn check(&self, source: &RemoteDif) -> Result<(), DownloadError> {
+        let key = (source.source_id().clone(), source.endpoint());
+        let mut sources = self.sources.lock();
+        let health = match sources.get_mut(&key) {
+            Some(health) => health,
+            None => return Ok(()),
+        };
+
+        let disabled_until = match health.disabled_until {
+            Some(disabled_until) => disabled_until,
+            None => return Ok(()),
+        };
+
+        // Probes that have not finished within the cooldown were most likely dropped.
+        let now = Instant::now();
+        let probing = health.probe.map_or(false, |probe| {
+            now.duration_since(probe) < self.config.cooldown
+        });
+
+        if now < disabled_until || probing {
+            metric!(
+                counter("service.download.source.rejected") += 1,
+                "source_type" => source.source_type_name()
+            );
+            return Err(DownloadError::SourceDisabled);
+        }
+
+        health.probe = Some(now);
+        Ok(())
+    }
+
+    /// Records the result of a download from a source.
+    pub fn record<T>(
+        &self,
+        source: &RemoteDif,
+        result: &Result<T, DownloadError>,
+        latency: Duration,
+    ) {
+        let threshold = match self.config.failure_threshold {
+            Some(threshold) => threshold.max(1),
+            None => return,
+        };
+
+        let failed = match result {
+            Ok(_) => false,
+            Err(error) if error.is_source_failure() => true,
+            // Local errors do not tell anything about the health of the source.
+            Err(_) => return,
+        };
+
+        let source_id = source.source_id();
+        let source_type = source.source_type_name();
+        let key = (source_id.clone(), source.endpoint());
+        let now = Instant::now();
+        let mut sources = self.sources.lock();
+
+        // Evict idle sources, so that sources passed in past requests do not accumulate.
+        if !sources.contains_key(&key) {
+            let cooldown = self.config.cooldown;
+            let idle: Vec<_> = sources
+                .iter()
+                .filter(|(_, health)| health.is_idle(now, cooldown))
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in idle {
+                sources.remove(&key);
+            }
+        }
+
+        let health = sources.entry(key).or_default();
+        health.updated = Some(now);
+
+        let outcome = if failed { 1.0 } else { 0.0 };
+        health.error_rate += AVERAGE_WEIGHT * (outcome - health.error_rate);
+        health.latency =
+            health.latency.mul_f64(1.0 - AVERAGE_WEIGHT) + latency.mul_f64(AVERAGE_WEIGHT);
+
+        metric!(
+            time_raw("service.download.source.error_rate") = (health.error_rate * 100.0) as u64,
+            "source_type" => source_type
+        );
+        metric!(
+            timer("service.download.source.latency") = health.latency,
+            "source_type" => source_type
+        );
+
+        if !failed {
+            if health.disabled_until.is_some() {
+                log::info!("Re-enabling source {}", source_id);
+                metric!(
+                    counter("service.download.source.enabled") += 1,
+                    "source_type" => source_type
+                );
+            }
+
+            health.consecutive_failures = 0;
+            health.disabled_until = None;
+            health.probe = None;
+        } else {
+            health.consecutive_failures += 1;
+            let probe_failed = health.probe.take().is_some();
+            let first_disable =
+                health.disabled_until.is_none() && health.consecutive_failures >= threshold;
+
+            if probe_failed || first_disable {
+                log::warn!(
+                    "Disabling source {} for {:?} after {} consecutive failures \
+                     (error rate {:.0}%, latency {:?})",
+                    source_id,
+                    self.config.cooldown,
+                    health.consecutive_failures,
+                    health.error_rate * 100.0,
+                    health.latency,
+                );
+                metric!(
+                    counter("service.download.source.disabled") += 1,
+                    "source_type" => source_type
+                );
+                health.disabled_until = Some(now + self.config.cooldown);
+            }
+        }
+
+        let disabled = sources
+            .values()
+            .filter(|health| health.disabled_until.is_some())
+            .count();
+        metric!(gauge("service.download.sources.disabled") = disabled as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+
+    use crate::services::download::http::HttpRemoteDif;
+    use crate::services::download::{DownloadStatus, SourceLocation};
+    use crate::sources::SourceConfig;
+    use crate::test;
+
+    fn record(tracker: &SourceHealthTracker, source: &RemoteDif, failed: bool) {
+        let result = if failed {
+            Err(DownloadError::BadStatus(reqwest::StatusCode::BAD_GATEWAY))
+        } else {
+            Ok(DownloadStatus::NotFound)
+        };
+        let latency = Duration::from_millis(10);
+        tracker.record(source, &result, latency);
+    }
+
+    /// Returns a [`RemoteDif`] with the same source ID as [`test::remote_dif`] at another URL.
+    fn remote_dif_at(url: &str) -> RemoteDif {
+        match test::microsoft_symsrv() {
+            SourceConfig::Http(source) => {
+                let mut source = (*source).clone();
+                source.url = url.parse().unwrap();
+                HttpRemoteDif::new(Arc::new(source), SourceLocation::new("hello.txt")).into()
+            }
+            _ => panic!("unexpected source"),
+        }
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let tracker = SourceHealthTracker::new(CircuitBreakerConfig {
+            failure_threshold: Some(2),
+            cooldown: Duration::from_millis(100),
+        });
+
+        let source = test::remote_dif();
+        record(&tracker, &source, true);
+        assert!(tracker.check(&source).is_ok());
+        record(&tracker, &source, true);
+        assert!(matches!(
+            tracker.check(&source),
+            Err(DownloadError::SourceDisabled)
+        ));
+
+        // After the cooldown, a single probe is let through.
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(tracker.check(&source).is_ok());
+        assert!(tracker.check(&source).is_err());
+
+        // A failed probe disables the source again.
+        record(&tracker, &source, true);
+        assert!(tracker.check(&source).is_err());
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(tracker.check(&source).is_ok());
+        record(&tracker, &source, false);
+        assert!(tracker.check(&source).is_ok());
+        assert!(tracker.check(&source).is_ok());
+    }
+
+    #[test]
+    fn test_local_errors() {
+        let tracker = SourceHealthTracker::new(CircuitBreakerConfig {
+            failure_threshold: Some(1),
+            cooldown: Duration::from_secs(60),
+        });
+
+        let source = test::remote_dif();
+        let error = std::io::Error::new(std::io::ErrorKind::Other, "disk full");
+        let result = Err(DownloadError::Write(error));
+        tracker.record(&source, &result, Duration::from_millis(10));
+        assert!(tracker.check(&source).is_ok());
+    }
+
+    #[test]
+    fn test_cancelled_and_throttled() {
+        let tracker = SourceHealthTracker::new(CircuitBreakerConfig {
+            failure_threshold: Some(1),
+            cooldown: Duration::from_secs(60),
+        });
+
+        let source = test::remote_dif();
+        let latency = Duration::from_millis(10);
+        tracker.record(&source, &Err(DownloadError::Canceled), latency);
+        assert!(tracker.check(&source).is_ok());
+
+        let throttled = DownloadError::Throttled {
+            status: reqwest::StatusCode::TOO_MANY_REQUESTS,
+            retry_after: None,
+        };
+        let result = Err(DownloadError::Retried {
+            retries: 3,
+            error: Box::new(throttled),
+        });
+        tracker.record(&source, &result, latency);
+        assert!(tracker.check(&source).is_ok());
+    }
+
+    #[test]
+    fn test_timeout() {
+        let tracker = SourceHealthTracker::new(CircuitBreakerConfig {
+            failure_threshold: Some(1),
+            cooldown: Duration::from_secs(60),
+        });
+
+        let source = test::remote_dif();
+        let latency = Duration::from_secs(10);
+        tracker.record(&source, &Err::<(), _>(DownloadError::Timeout), latency);
+        assert!(matches!(
+            tracker.check(&source),
+            Err(DownloadError::SourceDisabled)
+        ));
+    }
+
+    #[test]
+    fn test_evict_idle() {
+        let tracker = SourceHealthTracker::new(CircuitBreakerConfig {
+            failure_threshold: Some(1),
+            cooldown: Duration::from_millis(100),
+        });
+
+        let source = test::remote_dif();
+        let other = remote_dif_at("https://symbols.example.com/");
+
+        record(&tracker, &source, false);
+        assert_eq!(tracker.sources.lock().len(), 1);
+
+        // The healthy source is evicted once idle.
+        std::thread::sleep(Duration::from_millis(100));
+        record(&tracker, &other, true);
+        assert_eq!(tracker.sources.lock().len(), 1);
+
+        // Disabled sources are kept until their cooldown has elapsed.
+        record(
+            &tracker,
+            &remote_dif_at("https://other.example.com/"),
+            false,
+        );
+        assert!(tracker.check(&other).is_err());
+        assert_eq!(tracker.sources.lock().len(), 2);
+    }
+
+    #[test]
+    fn test_same_id_different_endpoint() {
+        let tracker = SourceHealthTracker::new(CircuitBreakerConfig {
+            failure_threshold: Some(1),
+            cooldown: Duration::from_secs(60),
+        });
+
+        let source = test::remote_dif();
+        let other = remote_dif_at("https://symbols.example.com/");
+        assert_eq!(source.source_id(), other.source_id());
+
+        record(&tracker, &source, true);
+        assert!(tracker.check(&source).is_err());
+        assert!(tracker.check(&other).is_ok());
+    }
+}
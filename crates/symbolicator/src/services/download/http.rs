@@ -13,7 +13,6 @@ use url::Url;
 use super::{DownloadError, DownloadStatus, RemoteDif, RemoteDifUri, SourceLocation, USER_AGENT};
 use crate::sources::{FileType, HttpSourceConfig};
 use crate::types::ObjectId;
-use crate::utils::futures as future_utils;
 
 /// The HTTP-specific [`RemoteDif`].
 #[derive(Debug, Clone)]
@@ -68,17 +67,15 @@ impl HttpDownloader {
         };
 
         log::debug!("Fetching debug file from {}", download_url);
-        let response = future_utils::retry(|| {
-            let mut builder = self.client.get(download_url.clone());
+        let mut builder = self.client.get(download_url.clone());
 
-            for (key, value) in file_source.source.headers.iter() {
-                if let Ok(key) = header::HeaderName::from_bytes(key.as_bytes()) {
-                    builder = builder.header(key, value.as_str());
-                }
+        for (key, value) in file_source.source.headers.iter() {
+            if let Ok(key) = header::HeaderName::from_bytes(key.as_bytes()) {
+                builder = builder.header(key, value.as_str());
             }
+        }
 
-            builder.header(header::USER_AGENT, USER_AGENT).send()
-        });
+        let response = builder.header(header::USER_AGENT, USER_AGENT).send();
 
         match response.await {
             Ok(response) => {
@@ -87,6 +84,12 @@ impl HttpDownloader {
                     let stream = response.bytes_stream().map_err(DownloadError::Reqwest);
 
                     super::download_stream(file_source, stream, destination).await
+                } else if let Some(error) = DownloadError::throttled(&response) {
+                    log::trace!("Throttled by {}: {}", download_url, response.status());
+                    Err(error)
+                } else if response.status().is_server_error() {
+                    log::trace!("Server error from {}: {}", download_url, response.status());
+                    Err(DownloadError::BadStatus(response.status()))
                 } else {
                     log::trace!(
                         "Unexpected status code from {}: {}",
@@ -96,10 +99,14 @@ impl HttpDownloader {
                     Ok(DownloadStatus::NotFound)
                 }
             }
-            Err(e) => {
+            Err(e) if e.is_builder() => {
                 log::trace!("Skipping response from {}: {}", download_url, e);
                 Ok(DownloadStatus::NotFound) // must be wrong type
             }
+            Err(e) => {
+                log::trace!("Failed to fetch from {}: {}", download_url, e);
+                Err(DownloadError::Reqwest(e))
+            }
         }
     }
 
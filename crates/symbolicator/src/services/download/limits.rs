@@ -0,0 +1,401 @@
+//! Concurrency and rate limits for downloads.
+//!
+//! Downloads are limited per source and per host.  A download waits until it may start instead
+//! of failing.  Waiting downloads are served in the order they arrived, so that a single large
+//! request cannot starve others using the same source.
+//!
+//! Sources passed in requests may reuse IDs and set their own limits.  The limits of such sources
+//! cannot exceed the configured `per_source` limits, or the limits of a configured source with the
+//! same ID.  Every source ID has a single limit, so that varying the limits does not lift them.
+//! Limits that are no longer in use are evicted.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::DownloadLimitsConfig;
+use crate::sources::{DownloadLimits, SourceConfig, SourceId};
+
+use super::RemoteDif;
+
+/// A token bucket limiting the rate at which downloads start.
+#[derive(Debug)]
+struct TokenBucket {
+    /// Tokens added per second.
+    rate: f64,
+    /// Maximum number of tokens.
+    capacity: f64,
+    /// Available tokens, negative if tokens have been reserved ahead of time.
+    tokens: f64,
+    /// When `tokens` was last updated.
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        let capacity = rate.max(1.0);
+
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    /// Adds the tokens accumulated until `now`.
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Takes a token and returns how long to wait from `now` until it is available.
+    ///
+    /// Tokens are handed out in the order they are reserved.
+    fn reserve(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= 1.0;
+
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+
+    /// Returns `true` if the bucket is full at `now`, so that it behaves like a new bucket.
+    fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+}
+
+/// Concurrency and rate limit of a single source or host.
+#[derive(Debug)]
+struct Limit {
+    /// The limits this was created with.
+    limits: DownloadLimits,
+    /// The number of permits of the semaphore.
+    max_concurrent: usize,
+    semaphore: Option<Arc<Semaphore>>,
+    bucket: Option<Mutex<TokenBucket>>,
+}
+
+impl Limit {
+    fn new(limits: DownloadLimits) -> Self {
+        let max_concurrent = limits.max_concurrent.unwrap_or_default().max(1);
+
+        Limit {
+            limits,
+            max_concurrent,
+            semaphore: limits
+                .max_concurrent
+                .map(|_| Arc::new(Semaphore::new(max_concurrent))),
+            bucket: limits
+                .requests_per_second
+                .map(|rate| Mutex::new(TokenBucket::new(rate, Instant::now()))),
+        }
+    }
+
+    /// Returns `true` if no downloads hold or wait for this limit, and its rate is not exhausted.
+    ///
+    /// Such limits can be dropped, since a new limit would behave the same.
+    fn is_idle(&self, now: Instant) -> bool {
+        let permits_free = self.semaphore.as_ref().map_or(true, |semaphore| {
+            semaphore.available_permits() == self.max_concurrent
+        });
+        let bucket_full = self
+            .bucket
+            .as_ref()
+            .map_or(true, |bucket| bucket.lock().is_full(now));
+
+        permits_free && bucket_full
+    }
+
+    /// Waits until a download may start.
+    ///
+    /// The returned permit must be held for the duration of the download.
+    async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        // Semaphores are never closed, so acquiring a permit cannot fail.
+        let permit = match self.semaphore {
+            Some(ref semaphore) => semaphore.clone().acquire_owned().await.ok(),
+            None => None,
+        };
+
+        // Reserve a token only once the download may run, so that queued downloads do not
+        // consume the rate of others.
+        if let Some(ref bucket) = self.bucket {
+            let delay = bucket.lock().reserve(Instant::now());
+            if delay > Duration::from_secs(0) {
+                tokio::time::sleep(delay).await;
+            }
+        }
+
+        permit
+    }
+}
+
+/// Returns `true` if no download holds or waits for the limit, see [`Limit::is_idle`].
+///
+/// Limits referenced outside of the map are being acquired.
+fn is_unused(limit: &Arc<Limit>, now: Instant) -> bool {
+    Arc::strong_count(limit) == 1 && limit.is_idle(now)
+}
+
+/// Returns the limit for `key` with the given limits.
+///
+/// There is a single limit per key.  If the limits of a key change, its limit is replaced once it
+/// is unused, since a limit must not change while downloads hold it.  Until then, the previous
+/// limits apply.  When a new limit is created, idle limits are evicted, so that limits of sources
+/// passed in past requests do not accumulate.
+fn get_limit<K: Ord + Clone>(
+    limits: &Mutex<BTreeMap<K, Arc<Limit>>>,
+    key: K,
+    config: DownloadLimits,
+) -> Option<Arc<Limit>> {
+    if config.is_unlimited() {
+        return None;
+    }
+
+    let now = Instant::now();
+    let mut limits = limits.lock();
+    if let Some(limit) = limits.get(&key) {
+        if limit.limits == config || !is_unused(limit, now) {
+            return Some(limit.clone());
+        }
+    }
+
+    let idle: Vec<_> = limits
+        .iter()
+        .filter(|(_, limit)| is_unused(limit, now))
+        .map(|(key, _)| key.clone())
+        .collect();
+    for key in idle {
+        limits.remove(&key);
+    }
+
+    let limit = Arc::new(Limit::new(config));
+    limits.insert(key, limit.clone());
+    Some(limit)
+}
+
+/// Permission to run a download, released when dropped.
+#[derive(Debug)]
+pub struct DownloadPermit {
+    _permits: Vec<OwnedSemaphorePermit>,
+}
+
+/// Limits concurrency and rate of downloads from sources and hosts.
+#[derive(Debug)]
+pub struct DownloadLimiter {
+    config: DownloadLimitsConfig,
+    /// The limits of sources in the configuration file of Symbolicator.
+    configured: Vec<(SourceId, DownloadLimits)>,
+    sources: Mutex<BTreeMap<SourceId, Arc<Limit>>>,
+    hosts: Mutex<BTreeMap<String, Arc<Limit>>>,
+}
+
+impl DownloadLimiter {
+    pub fn new(config: DownloadLimitsConfig, sources: &[SourceConfig]) -> Self {
+        DownloadLimiter {
+            config,
+            configured: sources
+                .iter()
+                .map(|source| (source.id().clone(), source.limits()))
+                .collect(),
+            sources: Mutex::new(BTreeMap::new()),
+            hosts: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Returns the limits of a source.
+    ///
+    /// Sources from the configuration file may set any limits.  Limits of other sources fall back
+    /// to the `per_source` limits and cannot exceed them.  Sources that reuse the ID of a
+    /// configured source cannot exceed the limits of that source either.
+    fn source_limits(&self, source: &RemoteDif) -> DownloadLimits {
+        let limits = source.limits();
+        let configured = self
+            .configured
+            .iter()
+            .find(|(id, _)| id == source.source_id())
+            .map(|(_, configured)| *configured);
+
+        match configured {
+            Some(configured) if configured == limits => limits.or(self.config.per_source),
+            Some(configured) => limits.clamp(configured.or(self.config.per_source)),
+            None => limits.clamp(self.config.per_source),
+        }
+    }
+
+    /// Waits until a download from the given source may start.
+    ///
+    /// The time spent waiting is reported as `service.download.queue_time`.
+    pub async fn acquire(&self, source: &RemoteDif) -> DownloadPermit {
+        let start = Instant::now();
+
+        let source_limits = self.source_limits(source);
+        let source_limit = get_limit(&self.sources, source.source_id().clone(), source_limits);
+        let host_limit = source
+            .host()
+            .and_then(|host| get_limit(&self.hosts, host.to_owned(), self.config.per_host));
+
+        // Always acquire the source before the host, so that downloads cannot deadlock.
+        let mut permits = Vec::new();
+        for limit in source_limit.iter().chain(host_limit.iter()) {
+            permits.extend(limit.acquire().await);
+        }
+
+        metric!(
+            timer("service.download.queue_time") = start.elapsed(),
+            "source_type" => source.source_type_name()
+        );
+
+        DownloadPermit { _permits: permits }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test;
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, start);
+        assert_eq!(bucket.reserve(start), Duration::from_secs(0));
+        assert_eq!(bucket.reserve(start), Duration::from_secs(0));
+        assert_eq!(bucket.reserve(start), Duration::from_millis(500));
+        assert_eq!(bucket.reserve(start), Duration::from_millis(1000));
+        assert!(!bucket.is_full(start));
+
+        // Tokens are added over time, after paying back the reserved ones.
+        let later = start + Duration::from_secs(1);
+        assert_eq!(bucket.reserve(later), Duration::from_millis(500));
+
+        let much_later = start + Duration::from_secs(10);
+        assert!(bucket.is_full(much_later));
+        assert_eq!(bucket.reserve(much_later), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_source_limits_clamped() {
+        let per_source = DownloadLimits {
+            max_concurrent: Some(4),
+            requests_per_second: Some(10.0),
+        };
+        let config = DownloadLimitsConfig {
+            per_source,
+            ..Default::default()
+        };
+
+        // Sources without limits use the defaults.
+        let source = test::remote_dif();
+        let limiter = DownloadLimiter::new(config, &[]);
+        assert_eq!(limiter.source_limits(&source), per_source);
+
+        // Sources only known from requests cannot raise the defaults.
+        let source = test::remote_dif_with_limits(DownloadLimits {
+            max_concurrent: Some(100),
+            requests_per_second: Some(1.0),
+        });
+        let expected = DownloadLimits {
+            max_concurrent: Some(4),
+            requests_per_second: Some(1.0),
+        };
+        assert_eq!(limiter.source_limits(&source), expected);
+
+        // Configured sources can.
+        let configured = test::microsoft_symsrv_with_limits(DownloadLimits {
+            max_concurrent: Some(100),
+            requests_per_second: Some(1.0),
+        });
+        let limiter = DownloadLimiter::new(config, &[configured]);
+        let expected = DownloadLimits {
+            max_concurrent: Some(100),
+            requests_per_second: Some(1.0),
+        };
+        assert_eq!(limiter.source_limits(&source), expected);
+
+        // Sources from requests that reuse the ID of a configured source cannot exceed its limits.
+        let source = test::remote_dif_with_limits(DownloadLimits {
+            max_concurrent: Some(1000),
+            requests_per_second: Some(0.5),
+        });
+        let expected = DownloadLimits {
+            max_concurrent: Some(100),
+            requests_per_second: Some(0.5),
+        };
+        assert_eq!(limiter.source_limits(&source), expected);
+    }
+
+    #[tokio::test]
+    async fn test_idle_limits_evicted() {
+        let limits = DownloadLimits {
+            max_concurrent: Some(1),
+            requests_per_second: None,
+        };
+        let map = Mutex::new(BTreeMap::new());
+
+        let limit = get_limit(&map, "a", limits).unwrap();
+        let permit = limit.acquire().await;
+        drop(limit);
+
+        // The limit held by a download is kept.
+        get_limit(&map, "b", limits).unwrap();
+        assert_eq!(map.lock().len(), 2);
+
+        // Once released, it is evicted when the next limit is created.
+        drop(permit);
+        get_limit(&map, "c", limits).unwrap();
+        let keys: Vec<_> = map.lock().keys().copied().collect();
+        assert_eq!(keys, vec!["c"]);
+
+        // Different limits of the same key share the limit while it is held.
+        let other = DownloadLimits {
+            max_concurrent: Some(2),
+            requests_per_second: None,
+        };
+        let a = get_limit(&map, "a", limits).unwrap();
+        let permit = a.acquire().await;
+        let b = get_limit(&map, "a", other).unwrap();
+        assert!(Arc::ptr_eq(&a, &b));
+        assert_eq!(b.limits, limits);
+
+        // Once unused, the limit is replaced with the new limits.
+        drop((a, b, permit));
+        let c = get_limit(&map, "a", other).unwrap();
+        assert_eq!(c.limits, other);
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrent() {
+        let limiter = DownloadLimiter::new(
+            DownloadLimitsConfig {
+                per_host: DownloadLimits {
+                    max_concurrent: Some(1),
+                    requests_per_second: None,
+                },
+                ..Default::default()
+            },
+            &[],
+        );
+
+        let source = test::remote_dif();
+        let permit = limiter.acquire(&source).await;
+
+        let timeout = Duration::from_millis(50);
+        let queued = tokio::time::timeout(timeout, limiter.acquire(&source)).await;
+        assert!(queued.is_err());
+
+        drop(permit);
+        let queued = tokio::time::timeout(timeout, limiter.acquire(&source)).await;
+        assert!(queued.is_ok());
+    }
+}
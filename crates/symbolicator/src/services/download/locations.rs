@@ -5,13 +5,14 @@
 
 use std::fmt;
 use std::path::Path;
+use std::time::Duration;
 
 use anyhow::{Error, Result};
 use serde::{Deserialize, Serialize};
 use url::Url;
 
 use crate::cache::CacheKey;
-use crate::sources::SourceId;
+use crate::sources::{DownloadLimits, RetryPolicy, SourceId};
 use crate::types::Scope;
 use crate::utils::sentry::ConfigureScope;
 
@@ -174,6 +175,69 @@ impl RemoteDif {
         }
     }
 
+    /// Returns the download limits configured on the source.
+    pub fn limits(&self) -> DownloadLimits {
+        match self {
+            RemoteDif::Sentry(_) => DownloadLimits::default(),
+            RemoteDif::Http(ref x) => x.source.files.limits,
+            RemoteDif::S3(ref x) => x.source.files.limits,
+            RemoteDif::Gcs(ref x) => x.source.files.limits,
+            RemoteDif::Filesystem(ref x) => x.source.files.limits,
+        }
+    }
+
+    /// Returns the retry policy configured on the source.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        match self {
+            RemoteDif::Sentry(ref x) => x.source.retry,
+            RemoteDif::Http(ref x) => x.source.files.retry,
+            RemoteDif::S3(ref x) => x.source.files.retry,
+            RemoteDif::Gcs(ref x) => x.source.files.retry,
+            RemoteDif::Filesystem(ref x) => x.source.files.retry,
+        }
+    }
+
+    /// Returns the download timeout configured on the source.
+    pub fn timeout(&self) -> Option<Duration> {
+        match self {
+            RemoteDif::Sentry(_) => None,
+            RemoteDif::Http(ref x) => x.source.files.timeout,
+            RemoteDif::S3(ref x) => x.source.files.timeout,
+            RemoteDif::Gcs(ref x) => x.source.files.timeout,
+            RemoteDif::Filesystem(ref x) => x.source.files.timeout,
+        }
+    }
+
+    /// Returns the host of the server this object file is downloaded from.
+    ///
+    /// This is only defined for servers run by customers or Sentry, including S3-compatible
+    /// services with a custom endpoint. Cloud storage and the local file system return `None`.
+    pub fn host(&self) -> Option<&str> {
+        match self {
+            RemoteDif::Sentry(ref x) => x.source.url.host_str(),
+            RemoteDif::Http(ref x) => x.source.url.host_str(),
+            RemoteDif::S3(ref x) => x.source.endpoint.as_ref().and_then(Url::host_str),
+            RemoteDif::Gcs(_) | RemoteDif::Filesystem(_) => None,
+        }
+    }
+
+    /// Returns where the source is located, such as its URL or bucket.
+    ///
+    /// Sources passed in requests may reuse IDs, so this tells different sources with the same ID
+    /// apart.
+    pub fn endpoint(&self) -> String {
+        match self {
+            RemoteDif::Sentry(ref x) => x.source.url.to_string(),
+            RemoteDif::Http(ref x) => x.source.url.to_string(),
+            RemoteDif::S3(ref x) => match x.source.endpoint {
+                Some(ref endpoint) => format!("{}#{}", endpoint, x.source.bucket),
+                None => format!("s3://{}", x.source.bucket),
+            },
+            RemoteDif::Gcs(ref x) => format!("gs://{}", x.source.bucket),
+            RemoteDif::Filesystem(ref x) => x.source.path.display().to_string(),
+        }
+    }
+
     pub fn source_type_name(&self) -> &'static str {
         match *self {
             RemoteDif::Sentry(..) => "sentry",
@@ -5,32 +5,46 @@
 
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use ::sentry::{Hub, SentryFutureExt};
 use futures::prelude::*;
+use rusoto_core::request::TlsError;
 use thiserror::Error;
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
 
+use crate::services::deadline::Deadline;
+use crate::services::progress::{Progress, ProgressEvent, ProgressFutureExt};
 use crate::utils::futures::{m, measure};
 use crate::utils::paths::get_directory_paths;
 
 mod filesystem;
 mod gcs;
-mod http;
+mod health;
+pub(crate) mod http;
+mod limits;
 mod locations;
+mod retry;
 mod s3;
 mod sentry;
+mod source_root;
 
 use crate::config::Config;
-pub use crate::sources::{DirectoryLayout, FileType, SourceConfig, SourceFilters};
+pub use crate::sources::{
+    DirectoryLayout, FileType, SourceConfig, SourceFilters, SourceRootSourceConfig,
+};
+use crate::sources::{RetryPolicy, SourceId};
 pub use crate::types::ObjectId;
 pub use locations::{RemoteDif, RemoteDifUri, SourceLocation};
+pub use s3::S3CredentialsProvider;
 
 /// HTTP User-Agent string to use.
 const USER_AGENT: &str = concat!("symbolicator/", env!("CARGO_PKG_VERSION"));
 
+/// Number of downloaded bytes between two download progress events.
+const PROGRESS_INTERVAL: u64 = 1024 * 1024;
+
 /// Errors happening while downloading from sources.
 #[derive(Debug, Error)]
 pub enum DownloadError {
@@ -44,10 +58,103 @@ pub enum DownloadError {
     Write(#[source] std::io::Error),
     #[error("download was cancelled")]
     Canceled,
+    #[error("download took too long")]
+    Timeout,
     #[error("failed to fetch data from GCS")]
     Gcs(#[from] gcs::GcsError),
     #[error("failed to fetch data from Sentry")]
     Sentry(#[from] sentry::SentryError),
+    #[error("failed to fetch data from S3")]
+    S3(#[source] rusoto_core::RusotoError<rusoto_s3::GetObjectError>),
+    #[error("unexpected status code: {0}")]
+    BadStatus(reqwest::StatusCode),
+    #[error("source temporarily disabled after repeated failures")]
+    SourceDisabled,
+    #[error("endpoint is not allowed: {0}")]
+    EndpointNotAllowed(url::Url),
+    #[error("server asked to retry later: {status}")]
+    Throttled {
+        status: reqwest::StatusCode,
+        retry_after: Option<Duration>,
+    },
+    #[error("failed after {retries} retries")]
+    Retried {
+        retries: u32,
+        #[source]
+        error: Box<DownloadError>,
+    },
+}
+
+impl DownloadError {
+    /// Returns `true` if the error is caused by the source rather than locally.
+    ///
+    /// Cancelled downloads and throttling with status `429` are not failures of the source.  Status
+    /// `503` still counts, since it is also returned by sources that are down, and so do downloads
+    /// that time out.
+    pub fn is_source_failure(&self) -> bool {
+        match self {
+            DownloadError::Retried { error, .. } => error.is_source_failure(),
+            DownloadError::Throttled { status, .. } => {
+                *status != reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            _ => !matches!(
+                self,
+                DownloadError::BadDestination(_)
+                    | DownloadError::Write(_)
+                    | DownloadError::Canceled
+                    | DownloadError::SourceDisabled
+                    | DownloadError::EndpointNotAllowed(_)
+            ),
+        }
+    }
+
+    /// Returns `true` if the error is temporary and the download should be retried.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            DownloadError::Reqwest(error) => !error.is_builder(),
+            DownloadError::Sentry(sentry::SentryError::Reqwest(error)) => !error.is_builder(),
+            DownloadError::Sentry(sentry::SentryError::BadStatusCode(status)) => {
+                status.is_server_error()
+            }
+            // Rejected credentials do not change when retrying.
+            DownloadError::S3(rusoto_core::RusotoError::Unknown(response)) => {
+                response.status.is_server_error()
+            }
+            DownloadError::S3(_)
+            | DownloadError::BadStatus(_)
+            | DownloadError::Throttled { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Returns how long the server asked to wait before retrying.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            DownloadError::Throttled { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// Returns an error if the server asks to retry later with status `429` or `503`.
+    fn throttled(response: &reqwest::Response) -> Option<Self> {
+        let status = response.status();
+        if status != reqwest::StatusCode::TOO_MANY_REQUESTS
+            && status != reqwest::StatusCode::SERVICE_UNAVAILABLE
+        {
+            return None;
+        }
+
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(retry::parse_retry_after);
+
+        Some(DownloadError::Throttled {
+            status,
+            retry_after,
+        })
+    }
 }
 
 /// Completion status of a successful download request.
@@ -61,34 +168,90 @@ pub enum DownloadStatus {
 
 /// A service which can download files from a [`SourceConfig`].
 ///
-/// The service is rather simple on the outside but will one day control
-/// rate limits and the concurrency it uses.
+/// The service is rather simple on the outside but limits the concurrency and rate of downloads
+/// from each source and host, see [`DownloadLimitsConfig`](crate::config::DownloadLimitsConfig).
+/// Sources that fail repeatedly are disabled temporarily, see
+/// [`CircuitBreakerConfig`](crate::config::CircuitBreakerConfig).
 #[derive(Debug)]
 pub struct DownloadService {
     config: Arc<Config>,
     worker: tokio::runtime::Handle,
+    limiter: limits::DownloadLimiter,
+    health: health::SourceHealthTracker,
     sentry: sentry::SentryDownloader,
     http: http::HttpDownloader,
     s3: s3::S3Downloader,
     gcs: gcs::GcsDownloader,
     fs: filesystem::FilesystemDownloader,
+    source_root: source_root::SourceRootDownloader,
 }
 
 impl DownloadService {
     /// Creates a new downloader that runs all downloads in the given remote thread.
-    pub fn new(config: Arc<Config>) -> Arc<Self> {
+    ///
+    /// Fails if the HTTP client for S3 sources cannot be created.
+    pub fn new(config: Arc<Config>) -> Result<Arc<Self>, TlsError> {
         let trusted_client = crate::utils::http::create_client(&config, true);
         let restricted_client = crate::utils::http::create_client(&config, false);
+        let allowed_s3_endpoints = config.allowed_s3_endpoints.clone();
 
-        Arc::new(Self {
+        Ok(Arc::new(Self {
+            limiter: limits::DownloadLimiter::new(config.download_limits, &config.sources),
+            health: health::SourceHealthTracker::new(config.circuit_breaker),
             config,
             worker: tokio::runtime::Handle::current(),
-            sentry: sentry::SentryDownloader::new(trusted_client),
+            sentry: sentry::SentryDownloader::new(trusted_client.clone()),
             http: http::HttpDownloader::new(restricted_client.clone()),
-            s3: s3::S3Downloader::new(),
-            gcs: gcs::GcsDownloader::new(restricted_client),
+            s3: s3::S3Downloader::new(allowed_s3_endpoints)?,
+            gcs: gcs::GcsDownloader::new(restricted_client.clone(), trusted_client),
             fs: filesystem::FilesystemDownloader::new(),
-        })
+            source_root: source_root::SourceRootDownloader::new(restricted_client),
+        }))
+    }
+
+    /// Returns the source with the given ID from the configuration file of Symbolicator.
+    fn configured_source(&self, source_id: &SourceId) -> Option<&SourceConfig> {
+        self.config
+            .sources
+            .iter()
+            .find(|source| source.id() == source_id)
+    }
+
+    /// Returns the download timeout of a source.
+    ///
+    /// Sources from the configuration file may set any timeout.  Timeouts of other sources fall
+    /// back to the `download` timeout and cannot exceed it, or the timeout of a configured source
+    /// with the same ID.
+    fn download_timeout(&self, source_id: &SourceId, timeout: Option<Duration>) -> Duration {
+        let default = self.config.timeouts.download;
+        let configured = self.configured_source(source_id).map(SourceConfig::timeout);
+
+        match configured {
+            Some(configured) if configured == timeout => timeout.unwrap_or(default),
+            configured => {
+                let max = configured.flatten().unwrap_or(default);
+                timeout.map_or(max, |timeout| timeout.min(max))
+            }
+        }
+    }
+
+    /// Returns the retry policy of a source.
+    ///
+    /// Sources from the configuration file may set any policy.  Policies of other sources fall
+    /// back to the `download_retry` policy and cannot make more attempts or back off for shorter
+    /// than it.  Sources that reuse the ID of a configured source cannot exceed the policy of that
+    /// source either.
+    fn retry_policy(&self, source_id: &SourceId, policy: RetryPolicy) -> RetryPolicy {
+        let configured = self
+            .configured_source(source_id)
+            .map(SourceConfig::retry_policy);
+
+        let defaults = self.config.download_retry;
+        match configured {
+            Some(configured) if configured == policy => policy.or(defaults),
+            Some(configured) => policy.clamp(configured.or(defaults).or(retry::DEFAULT_POLICY)),
+            None => policy.clamp(defaults.or(retry::DEFAULT_POLICY)),
+        }
     }
 
     /// Dispatches downloading of the given file to the appropriate source.
@@ -113,29 +276,75 @@ impl DownloadService {
     /// The downloaded file is saved into `destination`. The file will be created if it does not
     /// exist and truncated if it does. In case of any error, the file's contents is considered
     /// garbage.
+    pub async fn download(
+        self: Arc<Self>,
+        source: RemoteDif,
+        destination: PathBuf,
+    ) -> Result<DownloadStatus, DownloadError> {
+        let (status, _) = self.download_with_retries(source, destination).await?;
+        Ok(status)
+    }
+
+    /// Like [`download`](Self::download), but also returns how many times the download was
+    /// retried.
     //
     // NB: This takes `Arc<Self>` since it needs to spawn into the worker pool internally. Spawning
     // requires futures to be `'static`, which means there cannot be any references to an externally
     // owned downloader.
-    pub async fn download(
+    pub async fn download_with_retries(
         self: Arc<Self>,
         source: RemoteDif,
         destination: PathBuf,
-    ) -> Result<DownloadStatus, DownloadError> {
+    ) -> Result<(DownloadStatus, u32), DownloadError> {
         let hub = Hub::current();
+        let progress = Progress::current();
+        let deadline = Deadline::current();
         let slf = self.clone();
 
-        // NB: Enter the tokio 1 runtime, which is required to create the timeout.
-        // See: https://docs.rs/tokio/1.0.1/tokio/runtime/struct.Runtime.html#method.enter
-        let _guard = self.worker.enter();
-        let job = slf.dispatch_download(source, destination).bind_hub(hub);
-        let job = tokio::time::timeout(Duration::from_secs(300), job);
-        let job = measure("service.download", m::timed_result, job);
+        // Downloads over the limits of the source wait here. The timeout only starts once the
+        // download is allowed to run, but never extends past the deadline of the request.
+        let job = async move {
+            slf.health.check(&source)?;
+            let mut permit = Some(slf.limiter.acquire(&source).await);
+
+            let source_type = source.source_type_name();
+            let policy = slf.retry_policy(source.source_id(), source.retry_policy());
+            let timeout = slf.download_timeout(source.source_id(), source.timeout());
+            let start = Instant::now();
+
+            // Every retry waits for the limits of the source again, so that retries count towards
+            // the concurrency and rate of the source.
+            let job = retry::retry(policy, source_type, "download", || {
+                let permit = permit.take();
+                let slf = slf.clone();
+                let source = source.clone();
+                let destination = destination.clone();
+                async move {
+                    let _permit = match permit {
+                        Some(permit) => permit,
+                        None => slf.limiter.acquire(&source).await,
+                    };
+                    slf.dispatch_download(source, destination).await
+                }
+            })
+            .bind_hub(hub)
+            .bind_progress(progress);
+            let job = tokio::time::timeout(deadline.cap(timeout), job);
+
+            // Map timeouts into DownloadError::Timeout.
+            let result = match measure("service.download", m::timed_result, job).await {
+                Ok(result) => result,
+                Err(_) => Err(DownloadError::Timeout),
+            };
+
+            slf.health.record(&source, &result, start.elapsed());
+            result
+        };
 
         // Map all SpawnError variants into DownloadError::Canceled.
         match self.worker.spawn(job).await {
-            Ok(Ok(result)) => result,
-            Ok(Err(_)) | Err(_) => Err(DownloadError::Canceled),
+            Ok(result) => result,
+            Err(_) => Err(DownloadError::Canceled),
         }
     }
 
@@ -161,28 +370,71 @@ impl DownloadService {
                 // This `async move` ensures that the `list_files` future completes before `slf`
                 // goes out of scope, which ensures 'static lifetime for `spawn` below.
                 let job = async move {
-                    slf.sentry
-                        .list_files(cfg, object_id, config)
-                        .bind_hub(hub)
-                        .await
+                    let policy = slf.retry_policy(&cfg.id, cfg.retry);
+                    retry::retry(policy, "sentry", "list_files", || {
+                        slf.sentry
+                            .list_files(cfg.clone(), object_id.clone(), config.clone())
+                    })
+                    .bind_hub(hub)
+                    .await
+                    .map(|(files, _)| files)
                 };
 
                 // NB: Enter the tokio 1 runtime, which is required to create the timeout.
                 // See: https://docs.rs/tokio/1.0.1/tokio/runtime/struct.Runtime.html#method.enter
                 let _guard = self.worker.enter();
-                let job = tokio::time::timeout(Duration::from_secs(30), job);
+                let timeout = Deadline::current().cap(self.config.timeouts.list_files);
+                let job = tokio::time::timeout(timeout, job);
                 let job = measure("service.download.list_files", m::timed_result, job);
 
-                // Map all SpawnError variants into DownloadError::Canceled.
+                // Map timeouts into DownloadError::Timeout and all SpawnError variants into
+                // DownloadError::Canceled.
                 match self.worker.spawn(job).await {
                     Ok(Ok(result)) => result,
-                    Ok(Err(_)) | Err(_) => Err(DownloadError::Canceled),
+                    Ok(Err(_)) => Err(DownloadError::Timeout),
+                    Err(_) => Err(DownloadError::Canceled),
                 }
             }
             SourceConfig::Http(cfg) => Ok(self.http.list_files(cfg, filetypes, object_id)),
             SourceConfig::S3(cfg) => Ok(self.s3.list_files(cfg, filetypes, object_id)),
             SourceConfig::Gcs(cfg) => Ok(self.gcs.list_files(cfg, filetypes, object_id)),
             SourceConfig::Filesystem(cfg) => Ok(self.fs.list_files(cfg, filetypes, object_id)),
+            // Source roots only provide source files, see `fetch_source_file`.
+            SourceConfig::SourceRoot(_) => Ok(Vec::new()),
+        }
+    }
+
+    /// Fetches a single source file by its absolute path from a source root.
+    ///
+    /// Returns `None` if the source root does not contain the file.
+    pub async fn fetch_source_file(
+        self: Arc<Self>,
+        source: Arc<SourceRootSourceConfig>,
+        abs_path: String,
+    ) -> Result<Option<String>, DownloadError> {
+        let hub = Hub::current();
+        let slf = self.clone();
+
+        let job = async move {
+            slf.source_root
+                .fetch_source_file(source, &abs_path)
+                .bind_hub(hub)
+                .await
+        };
+
+        // NB: Enter the tokio 1 runtime, which is required to create the timeout.
+        // See: https://docs.rs/tokio/1.0.1/tokio/runtime/struct.Runtime.html#method.enter
+        let _guard = self.worker.enter();
+        let timeout = Deadline::current().cap(self.config.timeouts.source_file);
+        let job = tokio::time::timeout(timeout, job);
+        let job = measure("service.download.source_file", m::timed_result, job);
+
+        // Map timeouts into DownloadError::Timeout and all SpawnError variants into
+        // DownloadError::Canceled.
+        match self.worker.spawn(job).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(DownloadError::Timeout),
+            Err(_) => Err(DownloadError::Canceled),
         }
     }
 }
@@ -196,19 +448,41 @@ async fn download_stream(
     destination: PathBuf,
 ) -> Result<DownloadStatus, DownloadError> {
     // All file I/O in this function is blocking!
-    log::trace!("Downloading from {}", source.into());
+    let source = source.into();
+    log::trace!("Downloading from {}", source);
     let mut file = File::create(&destination)
         .await
         .map_err(DownloadError::BadDestination)?;
     futures::pin_mut!(stream);
 
+    let progress = Progress::current();
+    let location = source.uri();
+    let report = |downloaded_bytes, finished| {
+        progress.emit(ProgressEvent::Download {
+            location: location.clone(),
+            downloaded_bytes,
+            finished,
+        })
+    };
+
+    let mut downloaded_bytes = 0;
+    let mut reported_bytes = 0;
+    report(downloaded_bytes, false);
+
     while let Some(chunk) = stream.next().await {
         let chunk = chunk?;
         file.write_all(chunk.as_ref())
             .await
             .map_err(DownloadError::Write)?;
+
+        downloaded_bytes += chunk.as_ref().len() as u64;
+        if downloaded_bytes - reported_bytes >= PROGRESS_INTERVAL {
+            report(downloaded_bytes, false);
+            reported_bytes = downloaded_bytes;
+        }
     }
     file.flush().await.map_err(DownloadError::Write)?;
+    report(downloaded_bytes, true);
     Ok(DownloadStatus::Completed)
 }
 
@@ -281,7 +555,7 @@ mod tests {
             ..Config::default()
         });
 
-        let service = DownloadService::new(config);
+        let service = DownloadService::new(config).unwrap();
         let dest2 = dest.clone();
 
         // Jump through some hoops here, to prove that we can .await the service.
@@ -305,7 +579,7 @@ mod tests {
         };
 
         let config = Arc::new(Config::default());
-        let svc = DownloadService::new(config);
+        let svc = DownloadService::new(config).unwrap();
         let file_list = svc
             .list_files(source.clone(), FileType::all(), objid, Hub::current())
             .await
@@ -315,4 +589,112 @@ mod tests {
         let item = &file_list[0];
         assert_eq!(item.source_id(), source.id());
     }
+
+    #[tokio::test]
+    async fn test_retry_policy() {
+        let configured_policy = RetryPolicy {
+            max_attempts: Some(10),
+            initial_backoff: None,
+            max_backoff: Some(Duration::from_secs(5)),
+        };
+        let configured = match test::microsoft_symsrv() {
+            SourceConfig::Http(source) => {
+                let mut source = (*source).clone();
+                source.files.retry = configured_policy;
+                SourceConfig::Http(Arc::new(source))
+            }
+            _ => panic!("unexpected source"),
+        };
+
+        let config = Arc::new(Config {
+            sources: Arc::from(vec![configured]),
+            download_retry: RetryPolicy {
+                max_attempts: Some(5),
+                ..RetryPolicy::default()
+            },
+            ..Config::default()
+        });
+        let service = DownloadService::new(config).unwrap();
+
+        // Configured sources keep their policy.
+        let id = SourceId::new("microsoft");
+        assert_eq!(
+            service.retry_policy(&id, configured_policy),
+            RetryPolicy {
+                max_attempts: Some(10),
+                initial_backoff: None,
+                max_backoff: Some(Duration::from_secs(5)),
+            }
+        );
+
+        // Sources passed in requests cannot exceed the configured policies.
+        let unbounded = RetryPolicy {
+            max_attempts: Some(1000),
+            initial_backoff: Some(Duration::from_secs(0)),
+            max_backoff: Some(Duration::from_secs(0)),
+        };
+        assert_eq!(
+            service.retry_policy(&SourceId::new("request"), unbounded),
+            RetryPolicy {
+                max_attempts: Some(5),
+                initial_backoff: Some(Duration::from_millis(500)),
+                max_backoff: Some(Duration::from_secs(30)),
+            }
+        );
+        assert_eq!(
+            service.retry_policy(&id, unbounded),
+            RetryPolicy {
+                max_attempts: Some(10),
+                initial_backoff: Some(Duration::from_millis(500)),
+                max_backoff: Some(Duration::from_secs(5)),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_download_timeout() {
+        let configured = match test::microsoft_symsrv() {
+            SourceConfig::Http(source) => {
+                let mut source = (*source).clone();
+                source.files.timeout = Some(Duration::from_secs(3600));
+                SourceConfig::Http(Arc::new(source))
+            }
+            _ => panic!("unexpected source"),
+        };
+
+        let config = Arc::new(Config {
+            sources: Arc::from(vec![configured]),
+            ..Config::default()
+        });
+        let service = DownloadService::new(config).unwrap();
+        let id = SourceId::new("microsoft");
+        let other = SourceId::new("request");
+        let long = Some(Duration::from_secs(7200));
+        let short = Some(Duration::from_secs(10));
+
+        // Configured sources keep their timeout.
+        let configured = Some(Duration::from_secs(3600));
+        assert_eq!(
+            service.download_timeout(&id, configured),
+            Duration::from_secs(3600)
+        );
+
+        // Sources passed in requests cannot exceed the configured timeouts.
+        assert_eq!(
+            service.download_timeout(&other, long),
+            Duration::from_secs(300)
+        );
+        assert_eq!(
+            service.download_timeout(&other, short),
+            Duration::from_secs(10)
+        );
+        assert_eq!(
+            service.download_timeout(&other, None),
+            Duration::from_secs(300)
+        );
+        assert_eq!(
+            service.download_timeout(&id, long),
+            Duration::from_secs(3600)
+        );
+    }
 }
@@ -0,0 +1,220 @@
+//! Retries of downloads that fail temporarily.
+//!
+//! Failed attempts are retried with exponential backoff.  The backoff is randomized, so that
+//! downloads which failed at the same time do not all retry at the same time.  If the server
+//! asks to retry later with a `Retry-After` header, its delay is used instead.
+
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use crate::sources::RetryPolicy;
+
+use super::DownloadError;
+
+/// Default maximum number of attempts, including the first one.
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+
+/// Default delay before the first retry.
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Default maximum delay between two attempts.
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The retry policy used for settings that are set neither on the source nor in the config.
+pub const DEFAULT_POLICY: RetryPolicy = RetryPolicy {
+    max_attempts: Some(DEFAULT_MAX_ATTEMPTS),
+    initial_backoff: Some(DEFAULT_INITIAL_BACKOFF),
+    max_backoff: Some(DEFAULT_MAX_BACKOFF),
+};
+
+/// Parses the value of a `Retry-After` header.
+///
+/// The header contains either a number of seconds or an HTTP date.
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    let delay = date.with_timezone(&Utc) - Utc::now();
+    Some(delay.to_std().unwrap_or_else(|_| Duration::from_secs(0)))
+}
+
+/// Returns a random delay between half and all of `backoff`.
+fn jitter(backoff: Duration) -> Duration {
+    // The standard hasher is randomly seeded, which is good enough for jitter.
+    let random = RandomState::new().build_hasher().finish();
+    let fraction = random as f64 / u64::MAX as f64;
+    backoff.mul_f64(0.5 + fraction / 2.0)
+}
+
+/// Returns the delay before the next attempt after `retries` previous retries.
+fn backoff(policy: &RetryPolicy, retries: u32, error: &DownloadError) -> Duration {
+    let initial_backoff = policy.initial_backoff.unwrap_or(DEFAULT_INITIAL_BACKOFF);
+    let max_backoff = policy.max_backoff.unwrap_or(DEFAULT_MAX_BACKOFF);
+
+    let delay = match error.retry_after() {
+        Some(retry_after) => retry_after,
+        None => 1u32
+            .checked_shl(retries)
+            .and_then(|factor| initial_backoff.checked_mul(factor))
+            .map_or(max_backoff, jitter),
+    };
+
+    delay.min(max_backoff)
+}
+
+/// Runs `task` and retries it according to `policy` while it fails temporarily.
+///
+/// Returns the result of the task along with the number of retries it took.  If the task still
+/// fails after retries, the last error is wrapped in [`DownloadError::Retried`].  Every retry is
+/// reported as `service.download.retry`, and every task that succeeds after retries as
+/// `service.download.retry.succeeded`.
+pub async fn retry<T, F, Fut>(
+    policy: RetryPolicy,
+    source_type: &'static str,
+    operation: &'static str,
+    mut task: F,
+) -> Result<(T, u32), DownloadError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, DownloadError>>,
+{
+    let max_attempts = policy.max_attempts.unwrap_or(DEFAULT_MAX_ATTEMPTS).max(1);
+    let mut retries = 0;
+
+    loop {
+        let error = match task().await {
+            Ok(value) => {
+                if retries > 0 {
+                    metric!(
+                        counter("service.download.retry.succeeded") += 1,
+                        "source_type" => source_type,
+                        "operation" => operation
+                    );
+                }
+                return Ok((value, retries));
+            }
+            Err(error) => error,
+        };
+
+        if !error.is_retryable() || retries + 1 >= max_attempts {
+            return Err(match retries {
+                0 => error,
+                _ => DownloadError::Retried {
+                    retries,
+                    error: Box::new(error),
+                },
+            });
+        }
+
+        let delay = backoff(&policy, retries, &error);
+        log::debug!("Retrying {} in {:?}: {}", operation, delay, error);
+        metric!(
+            counter("service.download.retry") += 1,
+            "source_type" => source_type,
+            "operation" => operation
+        );
+
+        retries += 1;
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use reqwest::StatusCode;
+
+    fn throttled(retry_after: Option<Duration>) -> DownloadError {
+        DownloadError::Throttled {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            retry_after,
+        }
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::from_secs(0))
+        );
+
+        let date = (Utc::now() + chrono::Duration::seconds(60)).to_rfc2822();
+        let delay = parse_retry_after(&date).unwrap();
+        assert!(delay > Duration::from_secs(55));
+        assert!(delay <= Duration::from_secs(60));
+
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: None,
+            initial_backoff: Some(Duration::from_secs(1)),
+            max_backoff: Some(Duration::from_secs(10)),
+        };
+
+        let error = throttled(None);
+        let delay = backoff(&policy, 2, &error);
+        assert!(delay >= Duration::from_secs(2));
+        assert!(delay <= Duration::from_secs(4));
+        assert_eq!(backoff(&policy, 40, &error), Duration::from_secs(10));
+
+        let error = throttled(Some(Duration::from_secs(7)));
+        assert_eq!(backoff(&policy, 0, &error), Duration::from_secs(7));
+        let error = throttled(Some(Duration::from_secs(3600)));
+        assert_eq!(backoff(&policy, 0, &error), Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let policy = RetryPolicy {
+            max_attempts: Some(3),
+            initial_backoff: Some(Duration::from_millis(1)),
+            max_backoff: None,
+        };
+
+        let attempts = &AtomicU32::new(0);
+        let result = retry(policy, "http", "download", move || async move {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(throttled(Some(Duration::from_millis(1)))),
+                _ => Ok(()),
+            }
+        })
+        .await;
+        assert!(matches!(result, Ok(((), 1))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        let attempts = &AtomicU32::new(0);
+        let result: Result<((), u32), _> = retry(policy, "http", "download", move || async move {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(throttled(None))
+        })
+        .await;
+        assert!(matches!(
+            result,
+            Err(DownloadError::Retried { retries: 2, .. })
+        ));
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        let attempts = &AtomicU32::new(0);
+        let result: Result<((), u32), _> = retry(policy, "http", "download", move || async move {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(DownloadError::Canceled)
+        })
+        .await;
+        assert!(matches!(result, Err(DownloadError::Canceled)));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+}
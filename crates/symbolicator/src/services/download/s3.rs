@@ -4,19 +4,44 @@
 
 use std::any::type_name;
 use std::fmt;
+use std::future::Future;
 use std::path::PathBuf;
+use std::pin::Pin;
 use std::sync::Arc;
 
 use futures::TryStreamExt;
 use parking_lot::Mutex;
-use rusoto_s3::S3;
+use rusoto_core::request::{BufferedHttpResponse, TlsError};
+use rusoto_core::signature::SignedRequest;
+use rusoto_core::{ByteStream, Region, RusotoError};
+use rusoto_credential::{
+    AutoRefreshingProvider, AwsCredentials, ContainerProvider, CredentialsError,
+    EnvironmentProvider, InstanceMetadataProvider, ProfileProvider, ProvideAwsCredentials,
+    StaticProvider,
+};
+use rusoto_s3::{GetObjectError, S3};
+use rusoto_sts::WebIdentityProvider;
+use url::Url;
 
 use super::locations::SourceLocation;
 use super::{DownloadError, DownloadStatus, RemoteDif, RemoteDifUri};
-use crate::sources::{FileType, S3SourceConfig, S3SourceKey};
+use crate::sources::{AwsCredentialsProvider, FileType, S3SourceConfig, S3SourceKey};
 use crate::types::ObjectId;
 
-type ClientCache = lru::LruCache<Arc<S3SourceKey>, Arc<rusoto_s3::S3Client>>;
+/// Returns `true` if S3 rejected the request because of the source's credentials.
+///
+/// Missing objects are reported as `403 AccessDenied` if the `ListBucket` permission is not
+/// granted, so those cannot be told apart from missing files. All other authentication errors,
+/// such as unknown keys, bad signatures or expired tokens, are errors of the source.
+fn is_rejected(response: &BufferedHttpResponse) -> bool {
+    let body = String::from_utf8_lossy(&response.body);
+    match response.status.as_u16() {
+        401 | 403 => !body.contains("<Code>AccessDenied</Code>"),
+        _ => body.contains("<Code>ExpiredToken</Code>"),
+    }
+}
+
+type ClientCache = lru::LruCache<Arc<S3SourceKey>, rusoto_core::Client>;
 
 /// Maximum number of cached S3 clients.
 ///
@@ -29,6 +54,10 @@ type ClientCache = lru::LruCache<Arc<S3SourceKey>, Arc<rusoto_s3::S3Client>>;
 /// metrics.
 const S3_CLIENT_CACHE_SIZE: usize = 100;
 
+/// Boxed future returned by implementations of [`ProvideAwsCredentials`].
+type CredentialsFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<AwsCredentials, CredentialsError>> + Send + 'a>>;
+
 /// The S3-specific [`RemoteDif`].
 #[derive(Debug, Clone)]
 pub struct S3RemoteDif {
@@ -59,54 +88,255 @@ impl S3RemoteDif {
         self.source.bucket.clone()
     }
 
+    /// Returns the region to send requests to, which points to the custom endpoint if there is one.
+    fn region(&self) -> Region {
+        let region = &self.source.source_key.region;
+        match self.source.endpoint {
+            Some(ref endpoint) => Region::Custom {
+                name: region.name().to_owned(),
+                endpoint: endpoint.as_str().trim_end_matches('/').to_owned(),
+            },
+            None => region.clone(),
+        }
+    }
+
+    /// Returns `true` if the bucket is addressed in the host name of a custom endpoint.
+    fn is_virtual_hosted(&self) -> bool {
+        self.source.endpoint.is_some() && !self.source.path_style
+    }
+
+    /// Returns the URL of the custom endpoint that requests are sent to, if there is one.
+    ///
+    /// When the bucket is addressed in the host name, it is prepended to the host of the endpoint.
+    /// Returns `None` for bucket names that are not valid in a host name.
+    fn endpoint_url(&self) -> Option<Url> {
+        let mut endpoint = self.source.endpoint.clone()?;
+        if self.is_virtual_hosted() {
+            let bucket = &self.source.bucket;
+            if !is_valid_bucket_name(bucket) {
+                return None;
+            }
+
+            let host = format!("{}.{}", bucket, endpoint.host_str()?);
+            endpoint.set_host(Some(&host)).ok()?;
+        }
+
+        Some(endpoint)
+    }
+
     /// Returns the `s3://` URI from which to download this object file.
     pub fn uri(&self) -> RemoteDifUri {
         RemoteDifUri::from_parts("s3", &self.source.bucket, &self.key())
     }
 }
 
+/// Returns `true` if the bucket name follows the S3 naming rules.
+///
+/// Bucket names are between 3 and 63 characters long, consist of lowercase letters, digits, dots
+/// and hyphens, begin and end with a letter or digit, and do not contain adjacent dots.
+fn is_valid_bucket_name(bucket: &str) -> bool {
+    let bytes = bucket.as_bytes();
+    let is_alphanumeric = |b: &u8| b.is_ascii_lowercase() || b.is_ascii_digit();
+
+    (3..=63).contains(&bytes.len())
+        && bytes.first().map_or(false, is_alphanumeric)
+        && bytes.last().map_or(false, is_alphanumeric)
+        && bytes
+            .iter()
+            .all(|b| is_alphanumeric(b) || *b == b'.' || *b == b'-')
+        && !bucket.contains("..")
+}
+
+/// Returns a provider assuming a role with a web identity token, if one is configured.
+///
+/// This is how EKS provides credentials to service accounts, configured with the
+/// `AWS_WEB_IDENTITY_TOKEN_FILE` and `AWS_ROLE_ARN` environment variables.  The token is read again
+/// for every request to STS, since it is rotated by the orchestrator.
+fn web_identity_from_env() -> Option<WebIdentityProvider> {
+    std::env::var_os("AWS_WEB_IDENTITY_TOKEN_FILE")?;
+    std::env::var_os("AWS_ROLE_ARN")?;
+    Some(WebIdentityProvider::from_k8s_env())
+}
+
+/// Credentials from the environment of Symbolicator, see [`AwsCredentialsProvider::Chain`].
+///
+/// This follows the `ChainProvider` of rusoto, but also supports web identity tokens.  These are
+/// checked before the instance metadata, which would otherwise return the role of the node.
+pub struct AmbientProvider {
+    environment: EnvironmentProvider,
+    profile: Option<ProfileProvider>,
+    web_identity: Option<WebIdentityProvider>,
+    container: ContainerProvider,
+    instance_metadata: InstanceMetadataProvider,
+}
+
+impl AmbientProvider {
+    fn new() -> Self {
+        Self {
+            environment: EnvironmentProvider::default(),
+            profile: ProfileProvider::new().ok(),
+            web_identity: web_identity_from_env(),
+            container: ContainerProvider::new(),
+            instance_metadata: InstanceMetadataProvider::new(),
+        }
+    }
+
+    async fn find_credentials(&self) -> Result<AwsCredentials, CredentialsError> {
+        if let Ok(credentials) = self.environment.credentials().await {
+            return Ok(credentials);
+        }
+
+        if let Some(ref profile) = self.profile {
+            if let Ok(credentials) = profile.credentials().await {
+                return Ok(credentials);
+            }
+        }
+
+        if let Some(ref web_identity) = self.web_identity {
+            return web_identity.credentials().await;
+        }
+
+        if let Ok(credentials) = self.container.credentials().await {
+            return Ok(credentials);
+        }
+
+        self.instance_metadata.credentials().await.map_err(|_| {
+            CredentialsError::new("no credentials found in the environment of symbolicator")
+        })
+    }
+}
+
+impl ProvideAwsCredentials for AmbientProvider {
+    fn credentials<'a, 'f>(&'a self) -> CredentialsFuture<'f>
+    where
+        'a: 'f,
+        Self: 'f,
+    {
+        Box::pin(self.find_credentials())
+    }
+}
+
+/// Provides the credentials of an [`S3SourceKey`].
+pub enum S3CredentialsProvider {
+    Static(StaticProvider),
+    Ambient(AutoRefreshingProvider<AmbientProvider>),
+}
+
+impl S3CredentialsProvider {
+    pub fn new(key: &S3SourceKey) -> Result<Self, CredentialsError> {
+        Ok(match key.credentials_provider {
+            AwsCredentialsProvider::Static => Self::Static(StaticProvider::new_minimal(
+                key.access_key.clone(),
+                key.secret_key.clone(),
+            )),
+            AwsCredentialsProvider::Chain => {
+                Self::Ambient(AutoRefreshingProvider::new(AmbientProvider::new())?)
+            }
+        })
+    }
+}
+
+impl ProvideAwsCredentials for S3CredentialsProvider {
+    fn credentials<'a, 'f>(&'a self) -> CredentialsFuture<'f>
+    where
+        'a: 'f,
+        Self: 'f,
+    {
+        match self {
+            Self::Static(provider) => provider.credentials(),
+            Self::Ambient(provider) => provider.credentials(),
+        }
+    }
+}
+
+/// Returns `true` if the scheme, host and port of `endpoint` match one of the `allowed` URLs.
+fn is_endpoint_allowed(endpoint: &Url, allowed: &[Url]) -> bool {
+    allowed.iter().any(|allowed| {
+        allowed.scheme() == endpoint.scheme()
+            && allowed.host_str() == endpoint.host_str()
+            && allowed.port_or_known_default() == endpoint.port_or_known_default()
+    })
+}
+
 /// Downloader implementation that supports the [`S3SourceConfig`] source.
 pub struct S3Downloader {
     http_client: Arc<rusoto_core::HttpClient>,
     client_cache: Mutex<ClientCache>,
+    allowed_endpoints: Vec<Url>,
 }
 
 impl fmt::Debug for S3Downloader {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct(type_name::<Self>())
             .field("http_client", &format_args!("rusoto_core::HttpClient"))
-            .field("client_cache", &self.client_cache)
+            .field("client_cache", &self.client_cache.lock().len())
+            .field("allowed_endpoints", &self.allowed_endpoints)
             .finish()
     }
 }
 
 impl S3Downloader {
-    pub fn new() -> Self {
-        Self {
-            http_client: Arc::new(rusoto_core::HttpClient::new().unwrap()),
+    pub fn new(allowed_endpoints: Vec<Url>) -> Result<Self, TlsError> {
+        Ok(Self {
+            http_client: Arc::new(rusoto_core::HttpClient::new()?),
             client_cache: Mutex::new(ClientCache::new(S3_CLIENT_CACHE_SIZE)),
-        }
+            allowed_endpoints,
+        })
     }
 
-    fn get_s3_client(&self, key: &Arc<S3SourceKey>) -> Arc<rusoto_s3::S3Client> {
+    /// Returns a client signing requests with the given key.
+    ///
+    /// The client is not bound to a region, so that it can be shared by all buckets using the key.
+    fn get_client(&self, key: &Arc<S3SourceKey>) -> Result<rusoto_core::Client, CredentialsError> {
         let mut container = self.client_cache.lock();
         if let Some(client) = container.get(&*key) {
             metric!(counter("source.s3.client.cached") += 1);
-            client.clone()
+            Ok(client.clone())
         } else {
             metric!(counter("source.s3.client.create") += 1);
 
-            let s3 = Arc::new(rusoto_s3::S3Client::new_with(
-                self.http_client.clone(),
-                rusoto_credential::StaticProvider::new_minimal(
-                    key.access_key.clone(),
-                    key.secret_key.clone(),
-                ),
-                key.region.clone(),
-            ));
-
-            container.put(key.clone(), s3.clone());
-            s3
+            let provider = S3CredentialsProvider::new(key)?;
+            let client = rusoto_core::Client::new_with(provider, self.http_client.clone());
+
+            container.put(key.clone(), client.clone());
+            Ok(client)
+        }
+    }
+
+    /// Fetches an object, returning its body.
+    async fn get_object(
+        &self,
+        file_source: &S3RemoteDif,
+    ) -> Result<Option<ByteStream>, RusotoError<GetObjectError>> {
+        let client = self
+            .get_client(&file_source.source.source_key)
+            .map_err(RusotoError::Credentials)?;
+        let region = file_source.region();
+        let bucket = file_source.bucket();
+        let key = file_source.key();
+
+        if !file_source.is_virtual_hosted() {
+            let request = rusoto_s3::GetObjectRequest {
+                key,
+                bucket,
+                ..Default::default()
+            };
+            let s3 = rusoto_s3::S3Client::new_with_client(client, region);
+            return Ok(s3.get_object(request).await?.body);
+        }
+
+        // The S3 client always addresses buckets in the path, so requests addressing the bucket in
+        // the host name are signed and dispatched directly.
+        let mut request = SignedRequest::new("GET", "s3", &region, &format!("/{}", key));
+        let hostname = format!("{}.{}", bucket, request.hostname());
+        request.set_hostname(Some(hostname));
+
+        let response = client.sign_and_dispatch(request).await?;
+        if response.status.is_success() {
+            Ok(Some(response.body))
+        } else {
+            let response = response.buffer().await.map_err(RusotoError::HttpDispatch)?;
+            Err(RusotoError::Unknown(response))
         }
     }
 
@@ -119,18 +349,55 @@ impl S3Downloader {
         let bucket = file_source.bucket();
         log::debug!("Fetching from s3: {} (from {})", &key, &bucket);
 
-        let source_key = &file_source.source.source_key;
-        let result = self
-            .get_s3_client(&source_key)
-            .get_object(rusoto_s3::GetObjectRequest {
-                key: key.clone(),
-                bucket: bucket.clone(),
-                ..Default::default()
-            })
-            .await;
+        // Custom endpoints could be used to reach internal services, so only allowed ones are used.
+        // This is checked again for the final host, which contains the bucket name when the bucket
+        // is addressed in the host name.
+        if let Some(ref endpoint) = file_source.source.endpoint {
+            if !is_endpoint_allowed(endpoint, &self.allowed_endpoints) {
+                log::debug!("Skipping s3://{}/{}: endpoint not allowed", bucket, &key);
+                return Err(DownloadError::EndpointNotAllowed(endpoint.clone()));
+            }
+
+            match file_source.endpoint_url() {
+                Some(url) if is_endpoint_allowed(&url, &self.allowed_endpoints) => (),
+                Some(url) => {
+                    log::debug!("Skipping s3://{}/{}: endpoint not allowed", bucket, &key);
+                    return Err(DownloadError::EndpointNotAllowed(url));
+                }
+                None => {
+                    log::debug!("Skipping s3://{}/{}: invalid bucket name", bucket, &key);
+                    return Err(DownloadError::EndpointNotAllowed(endpoint.clone()));
+                }
+            }
+        }
+
+        let result = self.get_object(&file_source).await;
 
         let response = match result {
             Ok(response) => response,
+            // Failing to reach S3 or to obtain credentials is an error of the source.
+            Err(err @ RusotoError::HttpDispatch(_)) | Err(err @ RusotoError::Credentials(_)) => {
+                log::debug!("Failed to fetch from s3://{}/{}: {}", bucket, &key, err);
+                return Err(DownloadError::S3(err));
+            }
+            Err(RusotoError::Unknown(response)) if response.status.is_server_error() => {
+                log::debug!(
+                    "Server error from s3://{}/{}: {}",
+                    bucket,
+                    &key,
+                    response.status
+                );
+                return Err(DownloadError::S3(RusotoError::Unknown(response)));
+            }
+            Err(RusotoError::Unknown(response)) if is_rejected(&response) => {
+                log::debug!(
+                    "Request to s3://{}/{} rejected: {}",
+                    bucket,
+                    &key,
+                    response.status
+                );
+                return Err(DownloadError::S3(RusotoError::Unknown(response)));
+            }
             Err(err) => {
                 // For missing files, Amazon returns different status codes based on the given
                 // permissions.
@@ -142,7 +409,7 @@ impl S3Downloader {
             }
         };
 
-        let stream = match response.body {
+        let stream = match response {
             Some(body) => body.map_err(DownloadError::Io),
             None => {
                 log::debug!("Empty response from s3:{}{}", bucket, &key);
@@ -195,6 +462,7 @@ mod tests {
         } else {
             Some(S3SourceKey {
                 region: rusoto_core::Region::UsEast1,
+                credentials_provider: AwsCredentialsProvider::Static,
                 access_key,
                 secret_key,
             })
@@ -219,6 +487,8 @@ mod tests {
             bucket: S3_BUCKET.to_owned(),
             prefix: String::new(),
             source_key: Arc::new(source_key),
+            endpoint: None,
+            path_style: false,
             files: CommonSourceConfig::with_layout(DirectoryLayoutType::Unified),
         })
     }
@@ -317,7 +587,7 @@ mod tests {
         test::setup();
 
         let source = s3_source(s3_source_key!());
-        let downloader = S3Downloader::new();
+        let downloader = S3Downloader::new(Vec::new()).unwrap();
 
         let object_id = ObjectId {
             code_id: Some("502fc0a51ec13e479998684fa139dca7".parse().unwrap()),
@@ -344,7 +614,7 @@ mod tests {
         setup_bucket(source_key.clone()).await;
 
         let source = s3_source(source_key);
-        let downloader = S3Downloader::new();
+        let downloader = S3Downloader::new(Vec::new()).unwrap();
 
         let tempdir = test::tempdir();
         let target_path = tempdir.path().join("myfile");
@@ -373,7 +643,7 @@ mod tests {
         setup_bucket(source_key.clone()).await;
 
         let source = s3_source(source_key);
-        let downloader = S3Downloader::new();
+        let downloader = S3Downloader::new(Vec::new()).unwrap();
 
         let tempdir = test::tempdir();
         let target_path = tempdir.path().join("myfile");
@@ -396,11 +666,12 @@ mod tests {
 
         let broken_key = S3SourceKey {
             region: rusoto_core::Region::UsEast1,
+            credentials_provider: AwsCredentialsProvider::Static,
             access_key: "".to_owned(),
             secret_key: "".to_owned(),
         };
         let source = s3_source(broken_key);
-        let downloader = S3Downloader::new();
+        let downloader = S3Downloader::new(Vec::new()).unwrap();
 
         let tempdir = test::tempdir();
         let target_path = tempdir.path().join("myfile");
@@ -408,14 +679,15 @@ mod tests {
         let source_location = SourceLocation::new("does/not/exist");
         let file_source = S3RemoteDif::new(source, source_location);
 
-        let download_status = downloader
+        let result = downloader
             .download_source(file_source, target_path.clone())
-            .await
-            .unwrap();
+            .await;
 
-        // We anticipate 403 for regularly missing files if the ListBucket permission is not
-        // granted, therefore return `NotFound` instead of an authentication error.
-        assert_eq!(download_status, DownloadStatus::NotFound);
+        // Only 403 "access denied" is anticipated for missing files. Invalid credentials are an
+        // error of the source.
+        let error = result.unwrap_err();
+        assert!(matches!(error, DownloadError::S3(_)));
+        assert!(error.is_source_failure());
         assert!(!target_path.exists());
     }
 
@@ -423,6 +695,7 @@ mod tests {
     fn test_s3_remote_dif_uri() {
         let source_key = Arc::new(S3SourceKey {
             region: rusoto_core::Region::UsEast1,
+            credentials_provider: AwsCredentialsProvider::Static,
             access_key: String::from("abc"),
             secret_key: String::from("123"),
         });
@@ -431,6 +704,8 @@ mod tests {
             bucket: String::from("bucket"),
             prefix: String::from("prefix"),
             source_key,
+            endpoint: None,
+            path_style: false,
             files: CommonSourceConfig::with_layout(DirectoryLayoutType::Unified),
         });
         let location = SourceLocation::new("a/key/with spaces");
@@ -441,4 +716,120 @@ mod tests {
             RemoteDifUri::new("s3://bucket/prefix/a/key/with%20spaces")
         );
     }
+
+    #[test]
+    fn test_s3_remote_dif_custom_endpoint() {
+        let source_key = Arc::new(S3SourceKey {
+            region: rusoto_core::Region::UsEast1,
+            credentials_provider: AwsCredentialsProvider::Static,
+            access_key: String::from("abc"),
+            secret_key: String::from("123"),
+        });
+        let source = Arc::new(S3SourceConfig {
+            id: SourceId::new("s3-id"),
+            bucket: String::from("bucket"),
+            prefix: String::from("prefix"),
+            source_key,
+            endpoint: Some("https://minio.example.com:9000/".parse().unwrap()),
+            path_style: true,
+            files: CommonSourceConfig::with_layout(DirectoryLayoutType::Unified),
+        });
+        let location = SourceLocation::new("a/key");
+
+        let dif = S3RemoteDif::new(source, location);
+        assert!(!dif.is_virtual_hosted());
+        assert_eq!(
+            dif.region(),
+            Region::Custom {
+                name: String::from("us-east-1"),
+                endpoint: String::from("https://minio.example.com:9000"),
+            }
+        );
+    }
+
+    #[test]
+    fn test_s3_remote_dif_virtual_hosted() {
+        let source_key = Arc::new(S3SourceKey {
+            region: rusoto_core::Region::UsEast1,
+            credentials_provider: AwsCredentialsProvider::Static,
+            access_key: String::from("abc"),
+            secret_key: String::from("123"),
+        });
+        let source = |bucket: &str| {
+            Arc::new(S3SourceConfig {
+                id: SourceId::new("s3-id"),
+                bucket: String::from(bucket),
+                prefix: String::from("prefix"),
+                source_key: source_key.clone(),
+                endpoint: Some("https://minio.example.com:9000/".parse().unwrap()),
+                path_style: false,
+                files: CommonSourceConfig::with_layout(DirectoryLayoutType::Unified),
+            })
+        };
+
+        let dif = S3RemoteDif::new(source("bucket"), SourceLocation::new("a/key"));
+        assert!(dif.is_virtual_hosted());
+        assert_eq!(
+            dif.endpoint_url(),
+            Some("https://bucket.minio.example.com:9000/".parse().unwrap())
+        );
+
+        let dif = S3RemoteDif::new(source("evil.com/"), SourceLocation::new("a/key"));
+        assert_eq!(dif.endpoint_url(), None);
+    }
+
+    #[test]
+    fn test_is_valid_bucket_name() {
+        assert!(is_valid_bucket_name("bucket"));
+        assert!(is_valid_bucket_name("my-bucket.v2"));
+        assert!(is_valid_bucket_name("123"));
+
+        assert!(!is_valid_bucket_name("ab"));
+        assert!(!is_valid_bucket_name(&"a".repeat(64)));
+        assert!(!is_valid_bucket_name("Bucket"));
+        assert!(!is_valid_bucket_name("-bucket"));
+        assert!(!is_valid_bucket_name("bucket."));
+        assert!(!is_valid_bucket_name("my..bucket"));
+        assert!(!is_valid_bucket_name("internal.host:8080/"));
+        assert!(!is_valid_bucket_name("user@host"));
+    }
+
+    #[test]
+    fn test_is_endpoint_allowed() {
+        let allowed: Vec<Url> = vec![
+            "https://minio.example.com/".parse().unwrap(),
+            "http://ceph.internal:7480/".parse().unwrap(),
+        ];
+
+        let endpoint = |s: &str| s.parse::<Url>().unwrap();
+        assert!(is_endpoint_allowed(
+            &endpoint("https://minio.example.com"),
+            &allowed
+        ));
+        assert!(is_endpoint_allowed(
+            &endpoint("https://minio.example.com:443/"),
+            &allowed
+        ));
+        assert!(is_endpoint_allowed(
+            &endpoint("http://ceph.internal:7480"),
+            &allowed
+        ));
+
+        assert!(!is_endpoint_allowed(
+            &endpoint("http://minio.example.com"),
+            &allowed
+        ));
+        assert!(!is_endpoint_allowed(
+            &endpoint("http://ceph.internal"),
+            &allowed
+        ));
+        assert!(!is_endpoint_allowed(
+            &endpoint("http://169.254.169.254"),
+            &allowed
+        ));
+        assert!(!is_endpoint_allowed(
+            &endpoint("https://minio.example.com"),
+            &[]
+        ));
+    }
 }
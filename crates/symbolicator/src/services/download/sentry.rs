@@ -19,7 +19,7 @@ use super::{DownloadError, DownloadStatus, RemoteDif, RemoteDifUri, USER_AGENT};
 use crate::config::Config;
 use crate::sources::SentrySourceConfig;
 use crate::types::ObjectId;
-use crate::utils::futures::{self as future_utils, m, measure};
+use crate::utils::futures::{m, measure};
 
 /// The Sentry-specific [`RemoteDif`].
 #[derive(Debug, Clone)]
@@ -112,7 +112,7 @@ impl SentryDownloader {
     async fn fetch_sentry_json(
         &self,
         query: &SearchQuery,
-    ) -> Result<Vec<SearchResult>, SentryError> {
+    ) -> Result<Vec<SearchResult>, DownloadError> {
         let response = self
             .client
             .get(query.index_url.clone())
@@ -120,14 +120,18 @@ impl SentryDownloader {
             .header("User-Agent", USER_AGENT)
             .bearer_auth(&query.token)
             .send()
-            .await?;
+            .await
+            .map_err(SentryError::Reqwest)?;
 
         if response.status().is_success() {
             log::trace!("Success fetching index from Sentry");
-            Ok(response.json().await?)
+            Ok(response.json().await.map_err(SentryError::Reqwest)?)
+        } else if let Some(error) = DownloadError::throttled(&response) {
+            log::warn!("Sentry asked to retry later: {}", response.status());
+            Err(error)
         } else {
             log::warn!("Sentry returned status code {}", response.status());
-            Err(SentryError::BadStatusCode(response.status()))
+            Err(SentryError::BadStatusCode(response.status()).into())
         }
     }
 
@@ -160,7 +164,7 @@ impl SentryDownloader {
             "Fetching list of Sentry debug files from {}",
             &query.index_url
         );
-        let entries = future_utils::retry(|| self.fetch_sentry_json(&query)).await?;
+        let entries = self.fetch_sentry_json(&query).await?;
 
         if cache_duration > Duration::from_secs(0) {
             self.index_cache
@@ -216,10 +220,10 @@ impl SentryDownloader {
         file_source: SentryRemoteDif,
         destination: PathBuf,
     ) -> Result<DownloadStatus, DownloadError> {
-        let retries = future_utils::retry(|| {
-            self.download_source_once(file_source.clone(), destination.clone())
-        });
-        match retries.await {
+        match self
+            .download_source_once(file_source.clone(), destination)
+            .await
+        {
             Ok(status) => {
                 log::debug!(
                     "Fetched debug file from {}: {:?}",
@@ -257,6 +261,12 @@ impl SentryDownloader {
                     let stream = response.bytes_stream().map_err(DownloadError::Reqwest);
 
                     super::download_stream(source, stream, destination).await
+                } else if let Some(error) = DownloadError::throttled(&response) {
+                    log::trace!("Throttled by {}: {}", source.url(), response.status());
+                    Err(error)
+                } else if response.status().is_server_error() {
+                    log::trace!("Server error from {}: {}", source.url(), response.status());
+                    Err(DownloadError::BadStatus(response.status()))
                 } else {
                     log::trace!(
                         "Unexpected status code from {}: {}",
@@ -266,10 +276,14 @@ impl SentryDownloader {
                     Ok(DownloadStatus::NotFound)
                 }
             }
-            Err(e) => {
+            Err(e) if e.is_builder() => {
                 log::trace!("Skipping response from {}: {}", source.url(), e);
                 Ok(DownloadStatus::NotFound) // must be wrong type
             }
+            Err(e) => {
+                log::trace!("Failed to fetch from {}: {}", source.url(), e);
+                Err(DownloadError::Reqwest(e))
+            }
         }
     }
 }
@@ -286,6 +300,7 @@ mod tests {
             id: SourceId::new("test"),
             url: Url::parse("https://example.net/endpoint/").unwrap(),
             token: "token".into(),
+            retry: Default::default(),
         };
         let file_source = SentryRemoteDif::new(Arc::new(source), SentryFileId("abc123".into()));
         let url = file_source.url();
@@ -298,6 +313,7 @@ mod tests {
             id: SourceId::new("test"),
             url: Url::parse("https://example.net/endpoint/").unwrap(),
             token: "token".into(),
+            retry: Default::default(),
         };
         let file_source = SentryRemoteDif::new(Arc::new(source), SentryFileId("abc123".into()));
         let uri = file_source.uri();
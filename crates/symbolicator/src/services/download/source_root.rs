@@ -0,0 +1,262 @@
+//! Support to fetch source files from source roots.
+//!
+//! Specifically this supports the [`SourceRootSourceConfig`] source.  As opposed to the other
+//! downloaders, this does not download debug files, but reads single source files into memory.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use reqwest::{header, Client, StatusCode};
+use url::Url;
+
+use super::{DownloadError, SourceLocation, USER_AGENT};
+use crate::sources::{SourceRoot, SourceRootSourceConfig};
+
+/// The maximum size of a source file in bytes.
+///
+/// Larger files are skipped, since they are unlikely to be source code and are read into memory.
+const MAX_SOURCE_FILE_SIZE: u64 = 10 * 1024 * 1024;
+
+/// The location of a source file within a source root.
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum SourceFileLocation {
+    Path(PathBuf),
+    Url(Url),
+}
+
+/// Resolves the absolute path of a source file to its location within the source root.
+///
+/// Returns `None` if the path is not below the prefix of the source root, or if it would escape
+/// the root through `..` segments.
+fn resolve(source: &SourceRootSourceConfig, abs_path: &str) -> Option<SourceFileLocation> {
+    let separators = &['/', '\\'][..];
+    let relative = abs_path.strip_prefix(source.prefix.as_str())?;
+    if !source.prefix.ends_with(separators) && !relative.starts_with(separators) {
+        return None;
+    }
+
+    let mut segments = Vec::new();
+    for segment in relative.split(separators) {
+        match segment {
+            "" | "." => continue,
+            ".." => return None,
+            segment => segments.push(segment),
+        }
+    }
+
+    if segments.is_empty() {
+        return None;
+    }
+
+    let location = SourceLocation::new(segments.join("/"));
+    match source.root {
+        SourceRoot::Path(ref root) => Some(SourceFileLocation::Path(root.join(location.path()))),
+        SourceRoot::Url(ref template) => {
+            let base = match source.revision {
+                Some(ref revision) => template.replace("{revision}", revision),
+                None if template.contains("{revision}") => return None,
+                None => template.clone(),
+            };
+
+            let base = Url::parse(&base).ok()?;
+            location.to_url(&base).ok().map(SourceFileLocation::Url)
+        }
+    }
+}
+
+/// Reads a source file from the file system.
+///
+/// Returns `None` if the path is not a regular file or exceeds [`MAX_SOURCE_FILE_SIZE`].  Special
+/// files, such as devices or files in `/proc`, are not read since their size is unknown.
+fn read_source_file(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        log::debug!("Skipping source file {:?}: not a regular file", path);
+        return Ok(None);
+    } else if metadata.len() > MAX_SOURCE_FILE_SIZE {
+        log::debug!("Skipping source file {:?}: exceeds the maximum size", path);
+        metric!(counter("source_root.too_large") += 1);
+        return Ok(None);
+    }
+
+    // The file may still grow after its size has been checked.
+    let mut data = Vec::with_capacity(metadata.len() as usize);
+    file.take(MAX_SOURCE_FILE_SIZE + 1).read_to_end(&mut data)?;
+    if data.len() as u64 > MAX_SOURCE_FILE_SIZE {
+        metric!(counter("source_root.too_large") += 1);
+        return Ok(None);
+    }
+
+    Ok(Some(data))
+}
+
+fn into_string(data: Vec<u8>) -> String {
+    match String::from_utf8(data) {
+        Ok(string) => string,
+        Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+    }
+}
+
+/// Downloader implementation that supports the [`SourceRootSourceConfig`] source.
+#[derive(Debug)]
+pub struct SourceRootDownloader {
+    client: Client,
+}
+
+impl SourceRootDownloader {
+    pub fn new(client: Client) -> Self {
+        Self { client }
+    }
+
+    /// Fetches the source file with the given absolute path from a source root.
+    ///
+    /// Returns `None` if the file does not exist in this source root.
+    pub async fn fetch_source_file(
+        &self,
+        source: Arc<SourceRootSourceConfig>,
+        abs_path: &str,
+    ) -> Result<Option<String>, DownloadError> {
+        match resolve(&source, abs_path) {
+            Some(SourceFileLocation::Path(path)) => {
+                log::debug!("Fetching source file from {:?}", path);
+                let result = tokio::task::spawn_blocking(move || read_source_file(&path))
+                    .await
+                    .map_err(|_| DownloadError::Canceled)?;
+
+                match result {
+                    Ok(data) => Ok(data.map(into_string)),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                    Err(e) => Err(DownloadError::Io(e)),
+                }
+            }
+            Some(SourceFileLocation::Url(url)) => {
+                log::debug!("Fetching source file from {}", url);
+                let mut builder = self.client.get(url.clone());
+
+                for (key, value) in source.headers.iter() {
+                    if let Ok(key) = header::HeaderName::from_bytes(key.as_bytes()) {
+                        builder = builder.header(key, value.as_str());
+                    }
+                }
+
+                let mut response = builder
+                    .header(header::USER_AGENT, USER_AGENT)
+                    .send()
+                    .await
+                    .map_err(DownloadError::Reqwest)?;
+
+                let status = response.status();
+                if status == StatusCode::NOT_FOUND {
+                    log::trace!("Source file not found at {}", url);
+                    return Ok(None);
+                } else if !status.is_success() {
+                    log::debug!("Unexpected status code from {}: {}", url, status);
+                    metric!(
+                        counter("source_root.bad_status") += 1,
+                        "status" => status.as_str()
+                    );
+                    return Err(DownloadError::BadStatus(status));
+                }
+
+                if response.content_length().unwrap_or(0) > MAX_SOURCE_FILE_SIZE {
+                    log::debug!("Source file at {} exceeds the maximum size", url);
+                    metric!(counter("source_root.too_large") += 1);
+                    return Ok(None);
+                }
+
+                let mut data = Vec::new();
+                while let Some(chunk) = response.chunk().await.map_err(DownloadError::Reqwest)? {
+                    if data.len() as u64 + chunk.len() as u64 > MAX_SOURCE_FILE_SIZE {
+                        log::debug!("Source file at {} exceeds the maximum size", url);
+                        metric!(counter("source_root.too_large") += 1);
+                        return Ok(None);
+                    }
+                    data.extend_from_slice(&chunk);
+                }
+
+                Ok(Some(into_string(data)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::sources::SourceId;
+    use crate::test;
+
+    fn source_root(
+        prefix: &str,
+        root: SourceRoot,
+        revision: Option<&str>,
+    ) -> SourceRootSourceConfig {
+        SourceRootSourceConfig {
+            id: SourceId::new("source-root"),
+            prefix: prefix.to_owned(),
+            root,
+            revision: revision.map(str::to_owned),
+            headers: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_resolve_path() {
+        let source = source_root("/build/src", SourceRoot::Path("/mnt/src".into()), None);
+
+        assert_eq!(
+            resolve(&source, "/build/src/foo/bar.c"),
+            Some(SourceFileLocation::Path("/mnt/src/foo/bar.c".into()))
+        );
+        assert_eq!(resolve(&source, "/build/srcfoo/bar.c"), None);
+        assert_eq!(resolve(&source, "/build/src/../secret"), None);
+        assert_eq!(resolve(&source, "/other/foo/bar.c"), None);
+    }
+
+    #[test]
+    fn test_resolve_url() {
+        let root = SourceRoot::Url("https://git.example.com/raw/{revision}/".into());
+        let source = source_root("C:\\build\\", root.clone(), Some("f00ba4"));
+
+        assert_eq!(
+            resolve(&source, "C:\\build\\foo\\bar baz.c"),
+            Some(SourceFileLocation::Url(
+                "https://git.example.com/raw/f00ba4/foo/bar%20baz.c"
+                    .parse()
+                    .unwrap()
+            ))
+        );
+
+        // Without a revision, the template cannot be expanded.
+        let source = source_root("C:\\build\\", root, None);
+        assert_eq!(resolve(&source, "C:\\build\\foo\\bar.c"), None);
+    }
+
+    #[test]
+    fn test_read_source_file() {
+        let dir = test::tempdir();
+
+        let path = dir.path().join("small.c");
+        std::fs::write(&path, b"int main() {}").unwrap();
+        assert_eq!(
+            read_source_file(&path).unwrap(),
+            Some(b"int main() {}".to_vec())
+        );
+
+        let path = dir.path().join("large.c");
+        let file = File::create(&path).unwrap();
+        file.set_len(MAX_SOURCE_FILE_SIZE + 1).unwrap();
+        assert_eq!(read_source_file(&path).unwrap(), None);
+
+        // Directories and special files are skipped.
+        assert_eq!(read_source_file(dir.path()).unwrap(), None);
+        if Path::new("/dev/zero").exists() {
+            assert_eq!(read_source_file(Path::new("/dev/zero")).unwrap(), None);
+        }
+    }
+}
@@ -36,9 +36,15 @@ use crate::utils::futures::ThreadPool;
 
 pub mod bitcode;
 pub mod cacher;
+pub mod cancellation;
 pub mod cficaches;
+pub mod deadline;
 pub mod download;
 pub mod objects;
+pub mod prefetch;
+pub mod progress;
+pub mod requests;
+pub mod shared_cache;
 pub mod symbolication;
 pub mod symcaches;
 
@@ -46,6 +52,9 @@ use self::bitcode::BitcodeService;
 use self::cficaches::CfiCacheActor;
 use self::download::DownloadService;
 use self::objects::ObjectsActor;
+use self::prefetch::PrefetchActor;
+use self::requests::RequestStore;
+use self::shared_cache::SharedCacheService;
 use self::symbolication::SymbolicationActor;
 use self::symcaches::SymCacheActor;
 
@@ -56,10 +65,22 @@ pub struct Service {
     symbolication: SymbolicationActor,
     /// Actor for downloading and caching objects (no symcaches or cficaches)
     objects: ObjectsActor,
+    /// Actor for prefetching symcaches and cficaches
+    prefetch: PrefetchActor,
+    /// Actor for symcaches, used for cache administration.
+    symcaches: SymCacheActor,
+    /// Actor for CFI caches, used for cache administration.
+    cficaches: CfiCacheActor,
     /// The config object.
     config: Arc<Config>,
     /// The download service.
     downloader: Arc<DownloadService>,
+    /// The local caches, used for cache administration.
+    caches: Arc<Caches>,
+    /// The shared cache, used for cache administration.
+    shared_cache: Option<Arc<SharedCacheService>>,
+    /// The `tokio 1` runtime, used to run blocking file system I/O.
+    worker: tokio::runtime::Handle,
 }
 
 impl Service {
@@ -70,31 +91,78 @@ impl Service {
         let spawnpool = procspawn::Pool::new(config.processing_pool_size)
             .context("failed to create process pool")?;
 
-        let downloader = DownloadService::new(config.clone());
+        let downloader =
+            DownloadService::new(config.clone()).context("failed to create downloader")?;
         let caches = Caches::from_config(&config).context("failed to create local caches")?;
         caches
             .clear_tmp(&config)
             .context("failed to clear tmp caches")?;
-        let objects = ObjectsActor::new(caches.object_meta, caches.objects, downloader.clone());
-        let bitcode = BitcodeService::new(caches.auxdifs, downloader.clone());
-        let symcaches =
-            SymCacheActor::new(caches.symcaches, objects.clone(), bitcode, cpu_pool.clone());
-        let cficaches = CfiCacheActor::new(caches.cficaches, objects.clone(), cpu_pool.clone());
+        let objects = ObjectsActor::new(
+            caches.object_meta.clone(),
+            caches.objects.clone(),
+            downloader.clone(),
+            config.timeouts.object,
+            cpu_pool.clone(),
+        );
+        let bitcode = BitcodeService::new(
+            caches.auxdifs.clone(),
+            downloader.clone(),
+            config.timeouts.object,
+        );
+        let shared_cache = config
+            .shared_cache
+            .clone()
+            .map(|shared_cache| SharedCacheService::new(shared_cache, config.timeouts.shared_cache))
+            .transpose()
+            .context("failed to create shared cache")?;
+        let symcaches = SymCacheActor::new(
+            caches.symcaches.clone(),
+            shared_cache.clone(),
+            objects.clone(),
+            bitcode,
+            cpu_pool.clone(),
+            config.timeouts.symcache,
+        );
+        let cficaches = CfiCacheActor::new(
+            caches.cficaches.clone(),
+            shared_cache.clone(),
+            objects.clone(),
+            cpu_pool.clone(),
+            config.timeouts.cficache,
+        );
+
+        let prefetch = PrefetchActor::new(
+            symcaches.clone(),
+            cficaches.clone(),
+            config.max_concurrent_prefetches,
+        );
+
+        let requests = RequestStore::new(caches.requests.clone());
 
         let symbolication = SymbolicationActor::new(
             objects.clone(),
-            symcaches,
-            cficaches,
-            caches.diagnostics,
+            downloader.clone(),
+            symcaches.clone(),
+            cficaches.clone(),
+            caches.diagnostics.clone(),
+            requests,
             cpu_pool,
             spawnpool,
+            config.timeouts,
+            config.source_context,
         );
 
         Ok(Self {
             symbolication,
             objects,
+            prefetch,
+            symcaches,
+            cficaches,
             config,
             downloader,
+            caches: Arc::new(caches),
+            shared_cache,
+            worker: tokio::runtime::Handle::current(),
         })
     }
 
@@ -106,7 +174,36 @@ impl Service {
         &self.objects
     }
 
+    pub fn prefetch(&self) -> &PrefetchActor {
+        &self.prefetch
+    }
+
+    pub fn symcaches(&self) -> &SymCacheActor {
+        &self.symcaches
+    }
+
+    pub fn cficaches(&self) -> &CfiCacheActor {
+        &self.cficaches
+    }
+
     pub fn config(&self) -> Arc<Config> {
         self.config.clone()
     }
+
+    pub fn caches(&self) -> &Caches {
+        &self.caches
+    }
+
+    pub fn shared_cache(&self) -> Option<&Arc<SharedCacheService>> {
+        self.shared_cache.as_ref()
+    }
+
+    /// Runs blocking file system I/O in the `tokio 1` runtime, off the HTTP server's threads.
+    pub fn spawn_blocking<F, R>(&self, f: F) -> tokio::task::JoinHandle<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        self.worker.spawn_blocking(f)
+    }
 }
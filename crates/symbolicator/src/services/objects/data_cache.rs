@@ -9,28 +9,33 @@
 use std::cmp;
 use std::fmt;
 use std::fs;
-use std::io::{self, Seek, SeekFrom};
-use std::path::Path;
-use std::time::Duration;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::time::Instant;
 
 use futures::compat::Future01CompatExt;
 use futures::future::{FutureExt, TryFutureExt};
 use sentry::{Hub, SentryFutureExt};
 use symbolic::common::ByteView;
 use symbolic::debuginfo::{Archive, Object};
-use tempfile::tempfile_in;
+use tempfile::{tempfile_in, NamedTempFile};
 
 use crate::cache::{CacheKey, CacheStatus};
+use crate::logging::LogError;
 use crate::services::cacher::{CacheItemRequest, CachePath};
-use crate::services::download::{DownloadStatus, RemoteDif};
+use crate::services::download::{DownloadError, DownloadStatus, RemoteDif};
 use crate::types::{ObjectId, Scope};
-use crate::utils::compression::decompress_object_file;
-use crate::utils::futures::BoxedFuture;
+use crate::utils::compression::{compress_zstd, decompress_object_file, decompress_zstd, is_zstd};
+use crate::utils::futures::{BoxedFuture, ThreadPool};
 use crate::utils::sentry::ConfigureScope;
 
 use super::meta_cache::FetchFileMetaRequest;
 use super::ObjectError;
 
+/// The zstd compression level of object files stored compressed.
+const COMPRESSION_LEVEL: i32 = 3;
+
 /// This requests the file content of a single file at a specific path/url.
 /// The attributes for this are the same as for `FetchFileMetaRequest`, hence the newtype
 #[derive(Clone, Debug)]
@@ -55,6 +60,9 @@ pub struct ObjectHandle {
     /// malformed marker.
     pub(super) data: ByteView<'static>,
     pub(super) status: CacheStatus,
+
+    /// Whether the object was stored compressed and `data` is a decompressed copy.
+    pub(super) decompressed: bool,
 }
 
 impl ObjectHandle {
@@ -117,6 +125,105 @@ impl fmt::Display for ObjectHandle {
     }
 }
 
+/// Decompresses an object file stored compressed into the given temporary file.
+fn decompress_object(data: &[u8], file: fs::File) -> io::Result<ByteView<'static>> {
+    let start = Instant::now();
+    let view = ByteView::map_file(decompress_zstd(data, file)?)?;
+    metric!(timer("objects.decompression.duration") = start.elapsed());
+    Ok(view)
+}
+
+/// Checks whether a positive item of the object cache can be loaded and parsed.
+pub(crate) fn verify_object(data: ByteView<'static>) -> Result<(), ObjectError> {
+    let data = if is_zstd(&data) {
+        decompress_object(&data, tempfile::tempfile()?)?
+    } else {
+        data
+    };
+
+    Object::parse(&data)?;
+    Ok(())
+}
+
+/// Decompresses a downloaded object file, extracts the requested object and persists it.
+///
+/// If `compress` is set, the object is stored compressed with zstd.  See
+/// [`FetchFileDataRequest::compute`] for the returned status.
+fn process_object(
+    download_file: &NamedTempFile,
+    download_dir: PathBuf,
+    path: &Path,
+    object_id: &ObjectId,
+    compress: bool,
+) -> Result<CacheStatus, ObjectError> {
+    let decompress_result = decompress_object_file(download_file, tempfile_in(download_dir)?);
+
+    // Treat decompression errors as malformed files. It is more likely that
+    // the error comes from a corrupt file than a local file system error.
+    let mut decompressed = match decompress_result {
+        Ok(decompressed) => decompressed,
+        Err(_) => return Ok(CacheStatus::Malformed),
+    };
+
+    // Seek back to the start and parse this object so we can deal with it.
+    // Since objects in Sentry (and potentially also other sources) might be
+    // multi-arch files (e.g. FatMach), we parse as Archive and try to
+    // extract the wanted file.
+    decompressed.seek(SeekFrom::Start(0))?;
+    let view = ByteView::map_file(decompressed)?;
+    let archive = match Archive::parse(&view) {
+        Ok(archive) => archive,
+        Err(_) => return Ok(CacheStatus::Malformed),
+    };
+    let data = if archive.is_multi() {
+        let object_opt = archive
+            .objects()
+            .filter_map(Result::ok)
+            .find(|object| object_id.match_object(object));
+
+        let object = match object_opt {
+            Some(object) => object,
+            None => {
+                if archive.objects().any(|r| r.is_err()) {
+                    return Ok(CacheStatus::Malformed);
+                } else {
+                    return Ok(CacheStatus::Negative);
+                }
+            }
+        };
+
+        object.data()
+    } else {
+        // Attempt to parse the object to capture errors. The result can be
+        // discarded as the object's data is the entire ByteView.
+        if archive.object_by_index(0).is_err() {
+            return Ok(CacheStatus::Malformed);
+        }
+
+        view.as_slice()
+    };
+
+    let mut persist_file = fs::File::create(path)?;
+    if compress {
+        let start = Instant::now();
+        let compressed = compress_zstd(data, &mut persist_file, COMPRESSION_LEVEL)?;
+        metric!(timer("objects.compression.duration") = start.elapsed());
+        metric!(counter("objects.compression.uncompressed_bytes") += data.len() as i64);
+        metric!(counter("objects.compression.compressed_bytes") += compressed as i64);
+    } else {
+        persist_file.write_all(data)?;
+    }
+
+    Ok(CacheStatus::Positive)
+}
+
+impl FetchFileDataRequest {
+    /// Decompresses an object file stored compressed into a temporary file.
+    fn decompress(&self, data: &[u8]) -> io::Result<ByteView<'static>> {
+        decompress_object(data, self.0.data_cache.tempfile()?.into_file())
+    }
+}
+
 impl CacheItemRequest for FetchFileDataRequest {
     type Item = ObjectHandle;
     type Error = ObjectError;
@@ -130,7 +237,8 @@ impl CacheItemRequest for FetchFileDataRequest {
     /// If the object file was successfully downloaded it is first decompressed.  If it is
     /// an archive containing multiple objects, then next the object matching the code or
     /// debug ID of our request is extracted first.  Finally the object is parsed with
-    /// symbolic to ensure it is not malformed.
+    /// symbolic to ensure it is not malformed.  If the cache is configured to compress, the
+    /// object is stored compressed with zstd.
     ///
     /// If there is an error with downloading or decompression then an `Err` of
     /// [`ObjectError`] is returned.  However if only the final object file parsing failed
@@ -152,15 +260,19 @@ impl CacheItemRequest for FetchFileDataRequest {
 
         let file_id = self.0.file_source.clone();
         let downloader = self.0.download_svc.clone();
+        let retries = self.0.retries.clone();
+        let compress = self.0.data_cache.cache().compress();
         let download_file = tryf!(self.0.data_cache.tempfile());
         let download_dir =
             tryf!(download_file.path().parent().ok_or(ObjectError::NoTempDir)).to_owned();
+        let threadpool = self.0.threadpool.clone();
 
         let future = async move {
-            let status = downloader
-                .download(file_id, download_file.path().to_owned())
+            let (status, download_retries) = downloader
+                .download_with_retries(file_id, download_file.path().to_owned())
                 .await
                 .map_err(Self::Error::from)?;
+            retries.store(download_retries, Ordering::Relaxed);
 
             match status {
                 DownloadStatus::NotFound => {
@@ -173,62 +285,28 @@ impl CacheItemRequest for FetchFileDataRequest {
             }
 
             log::trace!("Finished download of {}", cache_key);
-            let decompress_result =
-                decompress_object_file(&download_file, tempfile_in(download_dir)?);
 
-            // Treat decompression errors as malformed files. It is more likely that
-            // the error comes from a corrupt file than a local file system error.
-            let mut decompressed = match decompress_result {
-                Ok(decompressed) => decompressed,
-                Err(_) => return Ok(CacheStatus::Malformed),
+            // Decompressing, parsing and compressing objects is CPU intensive.
+            let process = async move {
+                process_object(&download_file, download_dir, &path, &object_id, compress)
             };
 
-            // Seek back to the start and parse this object so we can deal with it.
-            // Since objects in Sentry (and potentially also other sources) might be
-            // multi-arch files (e.g. FatMach), we parse as Archive and try to
-            // extract the wanted file.
-            decompressed.seek(SeekFrom::Start(0))?;
-            let view = ByteView::map_file(decompressed)?;
-            let archive = match Archive::parse(&view) {
-                Ok(archive) => archive,
-                Err(_) => return Ok(CacheStatus::Malformed),
-            };
-            let mut persist_file = fs::File::create(&path)?;
-            if archive.is_multi() {
-                let object_opt = archive
-                    .objects()
-                    .filter_map(Result::ok)
-                    .find(|object| object_id.match_object(object));
-
-                let object = match object_opt {
-                    Some(object) => object,
-                    None => {
-                        if archive.objects().any(|r| r.is_err()) {
-                            return Ok(CacheStatus::Malformed);
-                        } else {
-                            return Ok(CacheStatus::Negative);
-                        }
-                    }
-                };
-
-                io::copy(&mut object.data(), &mut persist_file)?;
-            } else {
-                // Attempt to parse the object to capture errors. The result can be
-                // discarded as the object's data is the entire ByteView.
-                if archive.object_by_index(0).is_err() {
-                    return Ok(CacheStatus::Malformed);
-                }
-
-                io::copy(&mut view.as_ref(), &mut persist_file)?;
-            }
-
-            Ok(CacheStatus::Positive)
+            threadpool
+                .spawn_handle(process.bind_hub(Hub::current()))
+                .await
+                .unwrap_or_else(|_| {
+                    let message = "object processing was cancelled";
+                    Err(io::Error::new(io::ErrorKind::Interrupted, message).into())
+                })
         };
 
         let result = future
             .boxed_local()
             .map_err(|e| {
-                sentry::capture_error(&e);
+                // Skipped downloads from disabled sources are expected and would flood Sentry.
+                if !matches!(e.download_error(), Some(DownloadError::SourceDisabled)) {
+                    sentry::capture_error(&e);
+                }
                 e
             })
             .bind_hub(Hub::current());
@@ -238,7 +316,7 @@ impl CacheItemRequest for FetchFileDataRequest {
         Box::pin(
             future_metrics!(
                 "objects",
-                Some((Duration::from_secs(600), ObjectError::Timeout)),
+                Some((self.0.data_timeout, ObjectError::Timeout)),
                 result.compat(),
                 "source_type" => type_name,
             )
@@ -246,13 +324,41 @@ impl CacheItemRequest for FetchFileDataRequest {
         )
     }
 
+    fn to_memory(item: &Self::Item) -> Option<(Self::Item, u64)> {
+        // Decompressed copies are kept, so that hot objects are not decompressed on every load.
+        // They already live in a temporary file that is deleted once the last handle is dropped.
+        match (item.status, item.decompressed) {
+            (CacheStatus::Positive, true) => Some((item.clone(), item.data.len() as u64)),
+            _ => None,
+        }
+    }
+
+    fn load_threadpool(&self) -> Option<&ThreadPool> {
+        // Compressed objects are decompressed when they are loaded.
+        Some(&self.0.threadpool)
+    }
+
     fn load(
         &self,
         scope: Scope,
-        status: CacheStatus,
-        data: ByteView<'static>,
+        mut status: CacheStatus,
+        mut data: ByteView<'static>,
         _: CachePath,
     ) -> Self::Item {
+        // Object files are stored compressed if configured.  Since objects never start with the
+        // zstd magic, this also loads objects stored before the configuration changed.
+        let decompressed = status == CacheStatus::Positive && is_zstd(&data);
+        if decompressed {
+            data = match self.decompress(&data) {
+                Ok(decompressed) => decompressed,
+                Err(err) => {
+                    log::error!("Failed to decompress object file: {}", LogError(&err));
+                    status = CacheStatus::Malformed;
+                    ByteView::from_slice(b"")
+                }
+            };
+        }
+
         let object_handle = ObjectHandle {
             object_id: self.0.object_id.clone(),
             scope,
@@ -262,6 +368,7 @@ impl CacheItemRequest for FetchFileDataRequest {
 
             status,
             data,
+            decompressed,
         };
 
         object_handle.configure_scope();
@@ -9,7 +9,9 @@
 
 use std::fs;
 use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 use symbolic::common::ByteView;
 use symbolic::debuginfo::Object;
@@ -19,10 +21,16 @@ use crate::services::cacher::{CacheItemRequest, CachePath, Cacher};
 use crate::services::download::{RemoteDif, RemoteDifUri};
 use crate::sources::SourceId;
 use crate::types::{ObjectFeatures, ObjectId, Scope};
-use crate::utils::futures::BoxedFuture;
+use crate::utils::futures::{BoxedFuture, ThreadPool};
 
 use super::{FetchFileDataRequest, ObjectError, ObjectHandle};
 
+/// Checks whether a positive item of the object meta cache can be parsed.
+pub(crate) fn verify_object_meta(data: &[u8]) -> Result<(), serde_json::Error> {
+    serde_json::from_slice::<ObjectFeatures>(data)?;
+    Ok(())
+}
+
 /// This requests metadata of a single file at a specific path/url.
 #[derive(Clone, Debug)]
 pub(super) struct FetchFileMetaRequest {
@@ -37,6 +45,15 @@ pub(super) struct FetchFileMetaRequest {
     // state for computing.
     pub(super) data_cache: Arc<Cacher<FetchFileDataRequest>>,
     pub(super) download_svc: Arc<crate::services::download::DownloadService>,
+    /// Timeout for fetching the object file data, see [`TimeoutsConfig::object`].
+    ///
+    /// [`TimeoutsConfig::object`]: crate::config::TimeoutsConfig::object
+    pub(super) data_timeout: Duration,
+    /// Number of retries of the download made for this request, see
+    /// [`ObjectMetaHandle::retries`].
+    pub(super) retries: Arc<AtomicU32>,
+    /// Thread pool on which object files are processed and loaded.
+    pub(super) threadpool: ThreadPool,
 }
 
 /// Handle to local metadata file of an object.
@@ -51,6 +68,10 @@ pub struct ObjectMetaHandle {
     pub(super) file_source: RemoteDif,
     pub(super) features: ObjectFeatures,
     pub(super) status: CacheStatus,
+    /// Number of retries it took to download the object.
+    ///
+    /// This is only set if the object was downloaded to compute this handle, otherwise it is `0`.
+    pub(super) retries: u32,
 }
 
 impl ObjectMetaHandle {
@@ -74,6 +95,10 @@ impl ObjectMetaHandle {
         self.status
     }
 
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
     pub fn scope(&self) -> &Scope {
         &self.scope
     }
@@ -139,7 +164,7 @@ impl CacheItemRequest for FetchFileMetaRequest {
     }
 
     fn should_load(&self, data: &[u8]) -> bool {
-        serde_json::from_slice::<ObjectFeatures>(data).is_ok()
+        verify_object_meta(data).is_ok()
     }
 
     /// Returns the [`ObjectMetaHandle`] at the given cache key.
@@ -173,6 +198,7 @@ impl CacheItemRequest for FetchFileMetaRequest {
             file_source: self.file_source.clone(),
             features,
             status,
+            retries: self.retries.load(Ordering::Relaxed),
         }
     }
 }
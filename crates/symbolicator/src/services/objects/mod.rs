@@ -3,6 +3,7 @@ use std::error::Error;
 use std::fmt;
 use std::io;
 use std::sync::Arc;
+use std::time::Duration;
 
 use ::sentry::Hub;
 use backtrace::Backtrace;
@@ -16,6 +17,7 @@ use crate::services::cacher::Cacher;
 use crate::services::download::{DownloadError, DownloadService, RemoteDif, RemoteDifUri};
 use crate::sources::{FileType, SourceConfig, SourceId};
 use crate::types::{AllObjectCandidates, ObjectCandidate, ObjectDownloadInfo, ObjectId, Scope};
+use crate::utils::futures::ThreadPool;
 
 use data_cache::FetchFileDataRequest;
 use meta_cache::FetchFileMetaRequest;
@@ -23,6 +25,9 @@ use meta_cache::FetchFileMetaRequest;
 pub use data_cache::ObjectHandle;
 pub use meta_cache::ObjectMetaHandle;
 
+pub(crate) use data_cache::verify_object;
+pub(crate) use meta_cache::verify_object_meta;
+
 mod data_cache;
 mod meta_cache;
 
@@ -61,6 +66,17 @@ impl fmt::Display for ObjectError {
     }
 }
 
+impl ObjectError {
+    /// Returns the download error that caused this error, if any.
+    pub fn download_error(&self) -> Option<&DownloadError> {
+        match self {
+            ObjectError::Download(ref error) => Some(error),
+            ObjectError::Caching(ref error) => error.download_error(),
+            _ => None,
+        }
+    }
+}
+
 impl fmt::Debug for ObjectError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self)?;
@@ -169,17 +185,36 @@ pub struct ObjectsActor {
     meta_cache: Arc<Cacher<FetchFileMetaRequest>>,
     data_cache: Arc<Cacher<FetchFileDataRequest>>,
     download_svc: Arc<DownloadService>,
+    data_timeout: Duration,
+    threadpool: ThreadPool,
 }
 
 impl ObjectsActor {
-    pub fn new(meta_cache: Cache, data_cache: Cache, download_svc: Arc<DownloadService>) -> Self {
+    pub fn new(
+        meta_cache: Cache,
+        data_cache: Cache,
+        download_svc: Arc<DownloadService>,
+        data_timeout: Duration,
+        threadpool: ThreadPool,
+    ) -> Self {
         ObjectsActor {
-            meta_cache: Arc::new(Cacher::new(meta_cache)),
-            data_cache: Arc::new(Cacher::new(data_cache)),
+            meta_cache: Arc::new(Cacher::new(meta_cache, None)),
+            data_cache: Arc::new(Cacher::new(data_cache, None)),
             download_svc,
+            data_timeout,
+            threadpool,
         }
     }
 
+    /// Removes decompressed copies of objects of the given scope whose cache key matches the
+    /// filter from memory.
+    pub fn remove_memory<F>(&self, scope: &Scope, filter: F) -> usize
+    where
+        F: FnMut(&str) -> bool,
+    {
+        self.data_cache.remove_memory(scope, filter)
+    }
+
     /// Returns the requested object file.
     ///
     /// This fetches the requested object, re-downloading it from the source if it is no
@@ -194,6 +229,9 @@ impl ObjectsActor {
             object_id: file_handle.object_id.clone(),
             data_cache: self.data_cache.clone(),
             download_svc: self.download_svc.clone(),
+            data_timeout: self.data_timeout,
+            retries: Default::default(),
+            threadpool: self.threadpool.clone(),
         });
 
         self.data_cache
@@ -296,6 +334,8 @@ impl ObjectsActor {
             let scope = scope.clone();
             let data_cache = self.data_cache.clone();
             let download_svc = self.download_svc.clone();
+            let data_timeout = self.data_timeout;
+            let threadpool = self.threadpool.clone();
             let meta_cache = self.meta_cache.clone();
 
             let query = async move {
@@ -310,6 +350,9 @@ impl ObjectsActor {
                     object_id,
                     data_cache,
                     download_svc,
+                    data_timeout,
+                    retries: Default::default(),
+                    threadpool,
                 };
                 meta_cache
                     .compute_memoized(request)
@@ -444,6 +487,7 @@ fn create_candidate_info(
             let download = match meta_handle.status {
                 CacheStatus::Positive => ObjectDownloadInfo::Ok {
                     features: meta_handle.features(),
+                    retries: meta_handle.retries(),
                 },
                 CacheStatus::Negative => ObjectDownloadInfo::NotFound,
                 CacheStatus::Malformed => ObjectDownloadInfo::Malformed,
@@ -457,11 +501,21 @@ fn create_candidate_info(
             }
         }
         Err(wrapped_error) => {
-            let details = wrapped_error.error.to_string();
+            let download_error = wrapped_error.error.download_error();
+
+            // Tell users explicitly why a source has been skipped.
+            let details = match download_error {
+                Some(error @ DownloadError::SourceDisabled) => error.to_string(),
+                _ => wrapped_error.error.to_string(),
+            };
+            let retries = match download_error {
+                Some(DownloadError::Retried { retries, .. }) => *retries,
+                _ => 0,
+            };
             ObjectCandidate {
                 source: wrapped_error.file_source.source_id().clone(),
                 location: wrapped_error.file_source.uri(),
-                download: ObjectDownloadInfo::Error { details },
+                download: ObjectDownloadInfo::Error { details, retries },
                 unwind: Default::default(),
                 debug: Default::default(),
             }
@@ -0,0 +1,185 @@
+//! Prefetching of symcaches and CFI caches for known modules.
+//!
+//! A prefetch request fetches the derived caches of a list of modules in the background, for
+//! instance ahead of a release.  It uses the same memoized computations as symbolication
+//! requests, so that symbolication requests for the same modules wait for the running prefetch
+//! instead of starting their own computation.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{future, stream, StreamExt as _};
+use parking_lot::Mutex;
+use sentry::{Hub, SentryFutureExt};
+
+use crate::cache::CacheStatus;
+use crate::services::cficaches::{CfiCacheActor, FetchCfiCache};
+use crate::services::symbolication::object_id_from_object_info;
+use crate::services::symcaches::{FetchSymCache, SymCacheActor};
+use crate::sources::SourceConfig;
+use crate::types::{
+    ObjectFileStatus, PrefetchModule, PrefetchResponse, RawObjectInfo, RequestId, Scope,
+};
+use crate::utils::futures::{delay, spawn_compat};
+
+/// How long the status of a finished prefetch request can be polled.
+const RETENTION: Duration = Duration::from_secs(3600);
+
+/// The state of a single prefetch request.
+#[derive(Debug)]
+struct PrefetchState {
+    modules: Vec<PrefetchModule>,
+    /// Whether all caches of this request have been fetched.
+    finished: bool,
+}
+
+type PrefetchMap = Arc<Mutex<BTreeMap<RequestId, Arc<Mutex<PrefetchState>>>>>;
+
+/// Modules to prefetch.
+#[derive(Debug, Clone)]
+pub struct PrefetchModules {
+    pub scope: Scope,
+    pub sources: Arc<[SourceConfig]>,
+    pub modules: Vec<RawObjectInfo>,
+}
+
+#[derive(Clone, Debug)]
+pub struct PrefetchActor {
+    symcaches: SymCacheActor,
+    cficaches: CfiCacheActor,
+    prefetches: PrefetchMap,
+    max_concurrent: usize,
+}
+
+impl PrefetchActor {
+    pub fn new(symcaches: SymCacheActor, cficaches: CfiCacheActor, max_concurrent: usize) -> Self {
+        PrefetchActor {
+            symcaches,
+            cficaches,
+            prefetches: Arc::new(Mutex::new(BTreeMap::new())),
+            max_concurrent,
+        }
+    }
+
+    /// Starts fetching symcaches and CFI caches of all modules in the background.
+    ///
+    /// Returns the id with which the status of the modules can be polled.
+    pub fn prefetch(&self, request: PrefetchModules) -> RequestId {
+        let PrefetchModules {
+            scope,
+            sources,
+            modules,
+        } = request;
+
+        metric!(counter("prefetch.modules") += modules.len() as i64);
+
+        let state = Arc::new(Mutex::new(PrefetchState {
+            modules: modules
+                .iter()
+                .map(|raw| PrefetchModule {
+                    debug_status: None,
+                    unwind_status: None,
+                    raw: raw.clone(),
+                })
+                .collect(),
+            finished: false,
+        }));
+
+        // Assume that there are no UUID4 collisions in practice.
+        let request_id = RequestId::new(uuid::Uuid::new_v4());
+        self.prefetches.lock().insert(request_id, state.clone());
+
+        let futures = modules.into_iter().enumerate().map(|(index, object_info)| {
+            let symcache_future = {
+                let symcaches = self.symcaches.clone();
+                let state = state.clone();
+                let request = FetchSymCache {
+                    object_type: object_info.ty,
+                    identifier: object_id_from_object_info(&object_info),
+                    sources: sources.clone(),
+                    scope: scope.clone(),
+                };
+
+                async move {
+                    let status = match symcaches.fetch(request).await {
+                        Ok(symcache) => match symcache.parse() {
+                            Ok(Some(_)) => ObjectFileStatus::Found,
+                            Ok(None) => ObjectFileStatus::Missing,
+                            Err(e) => (&e).into(),
+                        },
+                        Err(e) => (&*e).into(),
+                    };
+
+                    state.lock().modules[index].debug_status = Some(status);
+                }
+            };
+
+            let cficache_future = {
+                let cficaches = self.cficaches.clone();
+                let state = state.clone();
+                let request = FetchCfiCache {
+                    object_type: object_info.ty,
+                    identifier: object_id_from_object_info(&object_info),
+                    sources: sources.clone(),
+                    scope: scope.clone(),
+                };
+
+                async move {
+                    let status = match cficaches.fetch(request).await {
+                        Ok(cfi_cache) => match cfi_cache.status() {
+                            CacheStatus::Positive => ObjectFileStatus::Found,
+                            CacheStatus::Negative => ObjectFileStatus::Missing,
+                            CacheStatus::Malformed => ObjectFileStatus::Malformed,
+                        },
+                        Err(e) => (&*e).into(),
+                    };
+
+                    state.lock().modules[index].unwind_status = Some(status);
+                }
+            };
+
+            future::join(symcache_future, cficache_future)
+                .bind_hub(Hub::new_from_top(Hub::current()))
+        });
+
+        let futures: Vec<_> = futures.collect();
+        let max_concurrent = self.max_concurrent.max(1);
+        let prefetches = self.prefetches.clone();
+        let start = Instant::now();
+
+        spawn_compat(async move {
+            stream::iter(futures)
+                .buffer_unordered(max_concurrent)
+                .for_each(|_| future::ready(()))
+                .await;
+            state.lock().finished = true;
+            metric!(timer("prefetch.duration") = start.elapsed());
+
+            // Keep the status available for polling for a while, then forget the request.
+            delay(RETENTION).await;
+            prefetches.lock().remove(&request_id);
+        });
+
+        request_id
+    }
+
+    /// Returns the current status of a prefetch request, or `None` if it is unknown.
+    pub fn get_response(&self, request_id: RequestId) -> Option<PrefetchResponse> {
+        let state = self.prefetches.lock().get(&request_id)?.clone();
+        let state = state.lock();
+        let modules = state.modules.clone();
+
+        Some(if state.finished {
+            PrefetchResponse::Completed {
+                request_id,
+                modules,
+            }
+        } else {
+            PrefetchResponse::Pending {
+                request_id,
+                modules,
+            }
+        })
+    }
+}
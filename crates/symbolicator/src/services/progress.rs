@@ -0,0 +1,289 @@
+//! Progress reporting for symbolication requests.
+//!
+//! Every symbolication request has a [`Progress`] handle, which collects [`ProgressEvent`]s while
+//! the request moves through the [`SymbolicationActor`].  Clients can subscribe to these events to
+//! follow long-running requests.
+//!
+//! Similar to the sentry [`Hub`](sentry::Hub), the progress handle is bound to a future with
+//! [`ProgressFutureExt::bind_progress`] and can be retrieved anywhere within that future using
+//! [`Progress::current`].  This allows to report events from deep within the services, such as
+//! downloads, without passing the handle through every function.
+//!
+//! [`SymbolicationActor`]: crate::services::symbolication::SymbolicationActor
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::channel::mpsc;
+use futures::{stream, Stream, StreamExt};
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::services::download::RemoteDifUri;
+use crate::types::{ObjectFileStatus, RawObjectInfo};
+
+thread_local! {
+    static CURRENT: RefCell<Progress> = RefCell::new(Progress::default());
+}
+
+/// A processing phase of a symbolication request.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProcessingPhase {
+    /// Parsing the minidump to find referenced modules.
+    ReferencedModules,
+    /// Fetching CFI caches for stackwalking.
+    LoadCfiCaches,
+    /// Stackwalking the minidump.
+    Stackwalk,
+    /// Parsing an Apple crash report.
+    ParseAppleCrashReport,
+    /// Fetching symcaches for symbolication.
+    FetchSymCaches,
+    /// Symbolicating stack frames.
+    Symbolicate,
+    /// Fetching source files and applying source context.
+    SourceContext,
+}
+
+/// The use of a module a [`ProgressEvent::Module`] refers to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ModuleUse {
+    /// The module was used for unwinding.
+    Unwind,
+    /// The module was used for symbolication.
+    Debug,
+}
+
+/// An event reported while processing a symbolication request.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProgressEvent {
+    /// The request entered a new processing phase.
+    Phase { phase: ProcessingPhase },
+    /// The status of a module has been determined.
+    Module {
+        #[serde(rename = "use")]
+        module_use: ModuleUse,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        code_file: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        debug_id: Option<String>,
+        status: ObjectFileStatus,
+    },
+    /// A download made progress.
+    Download {
+        location: RemoteDifUri,
+        downloaded_bytes: u64,
+        finished: bool,
+    },
+    /// The request has finished and its response can be polled.
+    Finished,
+}
+
+impl ProgressEvent {
+    /// Creates a [`ProgressEvent::Module`] for the given module.
+    pub fn module(module_use: ModuleUse, info: &RawObjectInfo, status: ObjectFileStatus) -> Self {
+        Self::Module {
+            module_use,
+            code_file: info.code_file.clone(),
+            debug_id: info.debug_id.clone(),
+            status,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ProgressState {
+    /// All events emitted so far, replayed to new subscribers.
+    history: Vec<ProgressEvent>,
+    /// Subscribers receiving new events.
+    subscribers: Vec<mpsc::UnboundedSender<ProgressEvent>>,
+    /// Whether the request has finished.
+    finished: bool,
+}
+
+/// Handle to report and subscribe to progress of a symbolication request.
+///
+/// The default handle is not associated with any request and discards all events.
+#[derive(Clone, Debug, Default)]
+pub struct Progress {
+    inner: Option<Arc<Mutex<ProgressState>>>,
+}
+
+impl Progress {
+    /// Creates a new progress handle for a request.
+    pub fn new() -> Self {
+        Self {
+            inner: Some(Arc::new(Mutex::new(ProgressState::default()))),
+        }
+    }
+
+    /// Returns the progress handle bound to the currently executing future.
+    pub fn current() -> Self {
+        CURRENT.with(|current| current.borrow().clone())
+    }
+
+    /// Runs the callback with this progress handle as the current handle.
+    fn run<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce() -> R,
+    {
+        let previous = CURRENT.with(|current| current.replace(self.clone()));
+        let result = f();
+        CURRENT.with(|current| current.replace(previous));
+        result
+    }
+
+    /// Reports an event to all subscribers.
+    pub fn emit(&self, event: ProgressEvent) {
+        let inner = match self.inner {
+            Some(ref inner) => inner,
+            None => return,
+        };
+
+        let mut state = inner.lock();
+        if state.finished {
+            return;
+        }
+
+        state
+            .subscribers
+            .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+        state.history.push(event);
+    }
+
+    /// Reports that the request entered a new phase.
+    pub fn phase(&self, phase: ProcessingPhase) {
+        self.emit(ProgressEvent::Phase { phase });
+    }
+
+    /// Reports that the request has finished and closes all subscriptions.
+    pub fn finish(&self) {
+        self.emit(ProgressEvent::Finished);
+
+        if let Some(ref inner) = self.inner {
+            let mut state = inner.lock();
+            state.finished = true;
+            state.subscribers.clear();
+        }
+    }
+
+    /// Subscribes to events of this request.
+    ///
+    /// The stream first yields all events that have been reported so far, and then follows new
+    /// events until the request has finished.
+    pub fn subscribe(&self) -> impl Stream<Item = ProgressEvent> {
+        let (history, receiver) = match self.inner {
+            Some(ref inner) => {
+                let mut state = inner.lock();
+                let (sender, receiver) = mpsc::unbounded();
+                if !state.finished {
+                    state.subscribers.push(sender);
+                }
+                (state.history.clone(), Some(receiver))
+            }
+            None => (Vec::new(), None),
+        };
+
+        stream::iter(history).chain(stream::iter(receiver).flatten())
+    }
+}
+
+/// A future bound to a [`Progress`] handle, see [`ProgressFutureExt::bind_progress`].
+#[derive(Debug)]
+pub struct ProgressFuture<F> {
+    progress: Progress,
+    inner: F,
+}
+
+impl<F> Future for ProgressFuture<F>
+where
+    F: Future,
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context<'_>) -> Poll<Self::Output> {
+        let progress = self.progress.clone();
+        // https://doc.rust-lang.org/std/pin/index.html#pinning-is-structural-for-field
+        let future = unsafe { self.map_unchecked_mut(|s| &mut s.inner) };
+        progress.run(|| future.poll(ctx))
+    }
+}
+
+pub trait ProgressFutureExt: Sized {
+    /// Binds a progress handle to this future, making it [`Progress::current`] while polling.
+    fn bind_progress(self, progress: Progress) -> ProgressFuture<Self> {
+        ProgressFuture {
+            progress,
+            inner: self,
+        }
+    }
+}
+
+impl<F> ProgressFutureExt for F where F: Future {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn phases(events: &[ProgressEvent]) -> Vec<ProcessingPhase> {
+        events
+            .iter()
+            .filter_map(|event| match event {
+                ProgressEvent::Phase { phase } => Some(*phase),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_replay_history() {
+        let progress = Progress::new();
+        progress.phase(ProcessingPhase::FetchSymCaches);
+
+        let subscription = progress.subscribe();
+        progress.phase(ProcessingPhase::Symbolicate);
+        progress.finish();
+
+        let events: Vec<_> = subscription.collect().await;
+        assert_eq!(
+            phases(&events),
+            [
+                ProcessingPhase::FetchSymCaches,
+                ProcessingPhase::Symbolicate
+            ]
+        );
+        assert!(matches!(events.last(), Some(ProgressEvent::Finished)));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_after_finish() {
+        let progress = Progress::new();
+        progress.phase(ProcessingPhase::Stackwalk);
+        progress.finish();
+
+        let events: Vec<_> = progress.subscribe().collect().await;
+        assert_eq!(events.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_bind_progress() {
+        let progress = Progress::new();
+
+        async { Progress::current().phase(ProcessingPhase::Symbolicate) }
+            .bind_progress(progress.clone())
+            .await;
+        progress.finish();
+
+        // Outside of the bound future, events are discarded.
+        Progress::current().phase(ProcessingPhase::Stackwalk);
+
+        let events: Vec<_> = progress.subscribe().collect().await;
+        assert_eq!(phases(&events), [ProcessingPhase::Symbolicate]);
+    }
+}
@@ -0,0 +1,216 @@
+//! Persistence of symbolication requests.
+//!
+//! Symbolication requests are tracked in memory by the
+//! [`SymbolicationActor`](crate::services::symbolication::SymbolicationActor) while they are
+//! running and shortly after.  The [`RequestStore`] additionally writes the state of every request
+//! to disk, so that clients can still poll for responses after symbolicator has been restarted.
+//! File system access is blocking, so it runs on the blocking threads of the `tokio 1` runtime.
+
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cache::Cache;
+use crate::logging::LogError;
+use crate::types::{RequestId, SymbolicationResponse};
+
+/// The state of a request as persisted on disk.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+enum PersistedRequest {
+    /// The request was started, but has not finished yet.
+    Running,
+    /// The request has finished with the given response.
+    Finished { response: SymbolicationResponse },
+}
+
+/// Checks whether a persisted request can be parsed.
+pub(crate) fn verify_request(data: &[u8]) -> Result<(), serde_json::Error> {
+    serde_json::from_slice::<PersistedRequest>(data)?;
+    Ok(())
+}
+
+/// Stores the states and responses of symbolication requests on disk.
+///
+/// If persistence is disabled in the configuration, all operations are no-ops.
+#[derive(Clone, Debug)]
+pub struct RequestStore {
+    cache: Cache,
+    worker: tokio::runtime::Handle,
+}
+
+impl RequestStore {
+    /// Creates a new store that runs file system access in the current `tokio 1` runtime.
+    pub fn new(cache: Cache) -> Self {
+        Self {
+            cache,
+            worker: tokio::runtime::Handle::current(),
+        }
+    }
+
+    fn path(&self, request_id: RequestId) -> Option<PathBuf> {
+        Some(self.cache.cache_dir()?.join(request_id.to_string()))
+    }
+
+    fn write_blocking(&self, request_id: RequestId, request: &PersistedRequest) -> io::Result<()> {
+        let path = match self.path(request_id) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let mut file = self.cache.tempfile()?;
+        serde_json::to_writer(&mut file, request)?;
+        file.flush()?;
+        file.persist(path).map_err(|e| e.error)?;
+
+        Ok(())
+    }
+
+    async fn write(&self, request_id: RequestId, request: PersistedRequest) {
+        if self.cache.cache_dir().is_none() {
+            return;
+        }
+
+        let slf = self.clone();
+        let result = self
+            .worker
+            .spawn_blocking(move || slf.write_blocking(request_id, &request))
+            .await
+            .unwrap_or_else(|error| Err(io::Error::new(io::ErrorKind::Other, error)));
+
+        if let Err(error) = result {
+            log::error!(
+                "Failed to persist request {}: {}",
+                request_id,
+                LogError(&error)
+            );
+        }
+    }
+
+    /// Records that a request has been started.
+    pub async fn mark_running(&self, request_id: RequestId) {
+        self.write(request_id, PersistedRequest::Running).await
+    }
+
+    /// Records the final response of a request.
+    pub async fn store_response(&self, request_id: RequestId, response: &SymbolicationResponse) {
+        let request = PersistedRequest::Finished {
+            response: response.clone(),
+        };
+
+        self.write(request_id, request).await
+    }
+
+    /// Loads the response of a persisted request.
+    ///
+    /// Requests that were still running when their state was persisted can no longer make progress,
+    /// since they are not tracked by this process.  They resolve to a failure.  Returns `None` if
+    /// the request is unknown or has expired.
+    pub async fn load_response(&self, request_id: RequestId) -> Option<SymbolicationResponse> {
+        let path = self.path(request_id)?;
+        let slf = self.clone();
+        self.worker
+            .spawn_blocking(move || slf.load_response_blocking(request_id, path))
+            .await
+            .unwrap_or_default()
+    }
+
+    fn load_response_blocking(
+        &self,
+        request_id: RequestId,
+        path: PathBuf,
+    ) -> Option<SymbolicationResponse> {
+        let data = match self.cache.open_cachefile(&path) {
+            Ok(data) => data?,
+            Err(error) => {
+                log::error!(
+                    "Failed to load request {}: {}",
+                    request_id,
+                    LogError(&error)
+                );
+                return None;
+            }
+        };
+
+        match serde_json::from_slice(&data) {
+            Ok(PersistedRequest::Finished { response }) => Some(response),
+            Ok(PersistedRequest::Running) => Some(SymbolicationResponse::Failed {
+                message: "symbolication request was interrupted by a restart".to_owned(),
+            }),
+            Err(error) => {
+                log::error!(
+                    "Failed to load request {}: {}",
+                    request_id,
+                    LogError(&error)
+                );
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::RequestsConfig;
+    use crate::test;
+    use crate::types::CompletedSymbolicationResponse;
+
+    fn request_store(cache_dir: &test::TempDir) -> RequestStore {
+        let cache = Cache::from_config(
+            "requests",
+            Some(cache_dir.path().join("requests")),
+            Some(cache_dir.path().join("tmp")),
+            RequestsConfig::default().into(),
+        )
+        .unwrap();
+
+        RequestStore::new(cache)
+    }
+
+    #[tokio::test]
+    async fn test_finished_request() {
+        let cache_dir = test::tempdir();
+        let store = request_store(&cache_dir);
+        let request_id = RequestId::new(uuid::Uuid::new_v4());
+
+        store.mark_running(request_id).await;
+        let response = CompletedSymbolicationResponse::default();
+        store
+            .store_response(
+                request_id,
+                &SymbolicationResponse::Completed(Box::new(response)),
+            )
+            .await;
+
+        let loaded = store.load_response(request_id).await;
+        assert!(matches!(loaded, Some(SymbolicationResponse::Completed(_))));
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_request() {
+        let cache_dir = test::tempdir();
+        let store = request_store(&cache_dir);
+        let request_id = RequestId::new(uuid::Uuid::new_v4());
+
+        store.mark_running(request_id).await;
+
+        // A new store on the same directory simulates a restart.
+        let store = request_store(&cache_dir);
+        let loaded = store.load_response(request_id).await;
+        assert!(matches!(loaded, Some(SymbolicationResponse::Failed { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_request() {
+        let cache_dir = test::tempdir();
+        let store = request_store(&cache_dir);
+
+        let loaded = store
+            .load_response(RequestId::new(uuid::Uuid::new_v4()))
+            .await;
+        assert!(loaded.is_none());
+    }
+}
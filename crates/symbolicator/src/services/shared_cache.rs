@@ -0,0 +1,486 @@
+//! A second-tier cache for derived caches, shared between symbolicator instances.
+//!
+//! Before computing a symcache or cficache, the [`Cacher`](super::cacher::Cacher) looks for the
+//! item in the shared cache, and it uploads freshly computed items afterwards.  This way, a fleet
+//! of symbolicator instances only needs to compute every derived cache once.
+//!
+//! Items in the shared cache use the same [`CacheKey`]s as the local caches, which keeps items
+//! of different scopes apart.  Only positive cache items are shared.
+
+use std::any::type_name;
+use std::fmt;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::{future, stream, TryStreamExt};
+use rusoto_core::request::TlsError;
+use rusoto_core::{ByteStream, RusotoError};
+use rusoto_credential::CredentialsError;
+use rusoto_s3::{DeleteObjectError, GetObjectError, PutObjectError, S3};
+use symbolic::common::ByteView;
+use thiserror::Error;
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+
+use crate::cache::{get_scope_key, CacheKey};
+use crate::config::{FilesystemSharedCacheConfig, S3SharedCacheConfig, SharedCacheConfig};
+use crate::logging::LogError;
+use crate::services::download::S3CredentialsProvider;
+use crate::types::Scope;
+use crate::utils::futures::{m, measure};
+
+/// Errors creating or accessing the shared cache.
+#[derive(Debug, Error)]
+pub enum SharedCacheError {
+    #[error("failed to access shared cache")]
+    Io(#[from] io::Error),
+
+    #[error("failed to fetch from S3")]
+    S3Fetch(#[source] RusotoError<GetObjectError>),
+
+    #[error("failed to upload to S3")]
+    S3Store(#[source] RusotoError<PutObjectError>),
+
+    #[error("failed to remove from S3")]
+    S3Remove(#[source] RusotoError<DeleteObjectError>),
+
+    #[error("failed to create S3 client")]
+    S3Client(#[source] TlsError),
+
+    #[error("failed to load S3 credentials")]
+    S3Credentials(#[source] CredentialsError),
+}
+
+/// The shared cache in an S3 bucket.
+struct S3SharedCache {
+    config: S3SharedCacheConfig,
+    client: rusoto_s3::S3Client,
+}
+
+impl fmt::Debug for S3SharedCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct(type_name::<Self>())
+            .field("config", &self.config)
+            .field("client", &format_args!("rusoto_s3::S3Client"))
+            .finish()
+    }
+}
+
+impl S3SharedCache {
+    fn new(config: S3SharedCacheConfig) -> Result<Self, SharedCacheError> {
+        let client = rusoto_s3::S3Client::new_with(
+            rusoto_core::HttpClient::new().map_err(SharedCacheError::S3Client)?,
+            S3CredentialsProvider::new(&config.source_key)
+                .map_err(SharedCacheError::S3Credentials)?,
+            config.source_key.region.clone(),
+        );
+
+        Ok(Self { config, client })
+    }
+
+    /// Returns the key of an item within the bucket.
+    fn object_key(&self, key: &str) -> String {
+        let prefix = self.config.prefix.trim_matches('/');
+        if prefix.is_empty() {
+            key.to_owned()
+        } else {
+            format!("{}/{}", prefix, key)
+        }
+    }
+
+    async fn fetch(&self, key: &str, destination: &Path) -> Result<bool, SharedCacheError> {
+        let result = self
+            .client
+            .get_object(rusoto_s3::GetObjectRequest {
+                bucket: self.config.bucket.clone(),
+                key: self.object_key(key),
+                ..Default::default()
+            })
+            .await;
+
+        let response = match result {
+            Ok(response) => response,
+            Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => return Ok(false),
+            Err(RusotoError::Unknown(response)) if response.status == 404 => return Ok(false),
+            Err(err) => return Err(SharedCacheError::S3Fetch(err)),
+        };
+
+        let mut stream = match response.body {
+            Some(body) => body,
+            None => return Ok(false),
+        };
+
+        let mut file = File::create(destination).await?;
+        while let Some(chunk) = stream.try_next().await? {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+
+        Ok(true)
+    }
+
+    async fn store(&self, key: &str, data: ByteView<'static>) -> Result<(), SharedCacheError> {
+        self.client
+            .put_object(rusoto_s3::PutObjectRequest {
+                bucket: self.config.bucket.clone(),
+                key: self.object_key(key),
+                content_length: Some(data.len() as i64),
+                body: Some(byte_stream(data)),
+                ..Default::default()
+            })
+            .await
+            .map_err(SharedCacheError::S3Store)?;
+
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), SharedCacheError> {
+        self.client
+            .delete_object(rusoto_s3::DeleteObjectRequest {
+                bucket: self.config.bucket.clone(),
+                key: self.object_key(key),
+                ..Default::default()
+            })
+            .await
+            .map_err(SharedCacheError::S3Remove)?;
+
+        Ok(())
+    }
+}
+
+/// Returns the key of an item of the given cache and format version in the shared cache.
+///
+/// Just like [`get_scope_key`], this includes the scope and format version of the item.
+fn shared_key(cache: &str, key: &CacheKey, version: u32) -> String {
+    format!(
+        "{}/{}",
+        cache,
+        get_scope_key(&key.scope, &key.cache_key, version)
+    )
+}
+
+/// Size of the chunks in which items are uploaded to S3.
+const UPLOAD_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Streams the contents of a cache item in chunks, without copying the whole item at once.
+fn byte_stream(data: ByteView<'static>) -> ByteStream {
+    let size = data.len();
+    let chunks = stream::unfold(0, move |offset| {
+        let end = (offset + UPLOAD_CHUNK_SIZE).min(data.len());
+        let chunk = match data.get(offset..end) {
+            Some(chunk) if !chunk.is_empty() => Bytes::copy_from_slice(chunk),
+            _ => return future::ready(None),
+        };
+        future::ready(Some((Ok::<_, io::Error>(chunk), end)))
+    });
+    ByteStream::new_with_size(chunks, size)
+}
+
+/// The storage backend of the shared cache.
+#[derive(Debug)]
+enum SharedCacheBackend {
+    Filesystem(FilesystemSharedCacheConfig),
+    S3(S3SharedCache),
+}
+
+impl SharedCacheBackend {
+    /// Copies the item to `destination` and returns whether it exists in the shared cache.
+    async fn fetch(&self, key: &str, destination: &Path) -> Result<bool, SharedCacheError> {
+        match self {
+            Self::Filesystem(config) => match fs::copy(config.path.join(key), destination).await {
+                Ok(_) => Ok(true),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+                Err(e) => Err(e.into()),
+            },
+            Self::S3(cache) => cache.fetch(key, destination).await,
+        }
+    }
+
+    /// Stores the item in the shared cache, replacing a previous item.
+    async fn store(&self, key: &str, data: ByteView<'static>) -> Result<(), SharedCacheError> {
+        match self {
+            Self::Filesystem(config) => {
+                let path = config.path.join(key);
+                // Write to a temporary file next to the item first, so that other instances never
+                // read partially written items.
+                let result = tokio::task::spawn_blocking(move || {
+                    let dir = path.parent().unwrap_or(&path);
+                    std::fs::create_dir_all(dir)?;
+                    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+                    file.write_all(&data)?;
+                    file.persist(&path).map_err(|e| e.error)?;
+                    Ok::<_, SharedCacheError>(())
+                })
+                .await;
+
+                match result {
+                    Ok(result) => result,
+                    Err(e) => Err(io::Error::new(io::ErrorKind::Other, e).into()),
+                }
+            }
+            Self::S3(cache) => cache.store(key, data).await,
+        }
+    }
+
+    /// Removes the item from the shared cache, if it exists.
+    async fn remove(&self, key: &str) -> Result<(), SharedCacheError> {
+        match self {
+            Self::Filesystem(config) => match fs::remove_file(config.path.join(key)).await {
+                Ok(()) => Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e.into()),
+            },
+            Self::S3(cache) => cache.remove(key).await,
+        }
+    }
+}
+
+/// Service to fetch and store derived caches in the shared cache.
+#[derive(Debug)]
+pub struct SharedCacheService {
+    backend: SharedCacheBackend,
+    worker: tokio::runtime::Handle,
+    /// Timeout for fetching, storing or removing a single item.
+    timeout: Duration,
+}
+
+impl SharedCacheService {
+    /// Creates a new shared cache that runs all I/O in the current tokio runtime.
+    ///
+    /// Every fetch, store and removal of an item is limited by `timeout`.
+    pub fn new(
+        config: SharedCacheConfig,
+        timeout: Duration,
+    ) -> Result<Arc<Self>, SharedCacheError> {
+        let backend = match config {
+            SharedCacheConfig::Filesystem(config) => SharedCacheBackend::Filesystem(config),
+            SharedCacheConfig::S3(config) => SharedCacheBackend::S3(S3SharedCache::new(config)?),
+        };
+
+        Ok(Arc::new(Self {
+            backend,
+            worker: tokio::runtime::Handle::current(),
+            timeout,
+        }))
+    }
+
+    /// Fetches an item of the given cache and format version into `destination`.
+    ///
+    /// Returns `true` if the item was found.  Errors are logged and treated as a miss, since the
+    /// item can always be computed instead.
+    pub async fn fetch(
+        self: Arc<Self>,
+        cache: &'static str,
+        key: &CacheKey,
+        version: u32,
+        destination: &Path,
+    ) -> bool {
+        let shared_key = shared_key(cache, key, version);
+        let destination = destination.to_owned();
+        let slf = self.clone();
+
+        let job = async move { slf.backend.fetch(&shared_key, &destination).await };
+
+        // NB: Enter the tokio 1 runtime, which is required to create the timeout.
+        // See: https://docs.rs/tokio/1.0.1/tokio/runtime/struct.Runtime.html#method.enter
+        let _guard = self.worker.enter();
+        let job = tokio::time::timeout(self.timeout, job);
+        let job = measure("service.shared_cache.fetch", m::timed_result, job);
+
+        let found = match self.worker.spawn(job).await {
+            Ok(Ok(Ok(found))) => found,
+            Ok(Ok(Err(e))) => {
+                log::error!(
+                    "Failed to fetch {} from shared cache: {}",
+                    key,
+                    LogError(&e)
+                );
+                false
+            }
+            Ok(Err(_)) => {
+                log::warn!("Timed out fetching {} from shared cache", key);
+                false
+            }
+            Err(_) => false,
+        };
+
+        if found {
+            metric!(counter(&format!("caches.{}.shared_cache.hit", cache)) += 1);
+        } else {
+            metric!(counter(&format!("caches.{}.shared_cache.miss", cache)) += 1);
+        }
+
+        found
+    }
+
+    /// Uploads an item of the given cache and format version in the background.
+    pub fn store(
+        self: Arc<Self>,
+        cache: &'static str,
+        key: &CacheKey,
+        version: u32,
+        data: ByteView<'static>,
+    ) {
+        let shared_key = shared_key(cache, key, version);
+        let key = key.clone();
+        let slf = self.clone();
+
+        let job = async move { slf.backend.store(&shared_key, data).await };
+
+        // NB: Enter the tokio 1 runtime, which is required to create the timeout.
+        // See: https://docs.rs/tokio/1.0.1/tokio/runtime/struct.Runtime.html#method.enter
+        let _guard = self.worker.enter();
+        let job = tokio::time::timeout(self.timeout, job);
+        let job = measure("service.shared_cache.store", m::timed_result, job);
+
+        self.worker.spawn(async move {
+            match job.await {
+                Ok(Ok(())) => {
+                    metric!(counter(&format!("caches.{}.shared_cache.store", cache)) += 1);
+                }
+                Ok(Err(e)) => {
+                    log::error!("Failed to store {} in shared cache: {}", key, LogError(&e));
+                }
+                Err(_) => log::warn!("Timed out storing {} in shared cache", key),
+            }
+        });
+    }
+
+    /// Removes an item of the given cache and format version from the shared cache.
+    ///
+    /// Returns `false` if the item could not be removed.
+    pub async fn remove(
+        self: Arc<Self>,
+        cache: &'static str,
+        key: &CacheKey,
+        version: u32,
+    ) -> bool {
+        let shared_key = shared_key(cache, key, version);
+        let slf = self.clone();
+
+        let job = async move { slf.backend.remove(&shared_key).await };
+
+        // NB: Enter the tokio 1 runtime, which is required to create the timeout.
+        // See: https://docs.rs/tokio/1.0.1/tokio/runtime/struct.Runtime.html#method.enter
+        let _guard = self.worker.enter();
+        let job = tokio::time::timeout(self.timeout, job);
+        let job = measure("service.shared_cache.remove", m::timed_result, job);
+
+        match self.worker.spawn(job).await {
+            Ok(Ok(Ok(()))) => {
+                metric!(counter(&format!("caches.{}.shared_cache.remove", cache)) += 1);
+                true
+            }
+            Ok(Ok(Err(e))) => {
+                log::error!(
+                    "Failed to remove {} from shared cache: {}",
+                    key,
+                    LogError(&e)
+                );
+                false
+            }
+            Ok(Err(_)) => {
+                log::warn!("Timed out removing {} from shared cache", key);
+                false
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_key() {
+        let key = CacheKey {
+            cache_key: "item".to_owned(),
+            scope: Scope::Global,
+        };
+        assert_eq!(shared_key("symcaches", &key, 2), "symcaches/global/item.v2");
+    }
+
+    #[tokio::test]
+    async fn test_byte_stream() {
+        let data: Vec<u8> = (0..UPLOAD_CHUNK_SIZE * 5 / 2).map(|i| i as u8).collect();
+        let chunks: Vec<Bytes> = byte_stream(ByteView::from_vec(data.clone()))
+            .try_collect()
+            .await
+            .unwrap();
+
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks.concat(), data);
+    }
+
+    #[tokio::test]
+    async fn test_filesystem_roundtrip() {
+        let shared_dir = tempfile::tempdir().unwrap();
+        let local_dir = tempfile::tempdir().unwrap();
+
+        let service = SharedCacheService::new(
+            SharedCacheConfig::Filesystem(FilesystemSharedCacheConfig {
+                path: shared_dir.path().to_owned(),
+            }),
+            Duration::from_secs(300),
+        )
+        .unwrap();
+
+        let key = CacheKey {
+            cache_key: "abc".to_owned(),
+            scope: Scope::Scoped("myscope".to_owned()),
+        };
+        let other_scope = CacheKey {
+            cache_key: "abc".to_owned(),
+            scope: Scope::Global,
+        };
+        let destination = local_dir.path().join("item");
+
+        assert!(
+            !service
+                .clone()
+                .fetch("symcaches", &key, 0, &destination)
+                .await
+        );
+
+        service
+            .backend
+            .store("symcaches/myscope/abc", ByteView::from_slice(b"hello"))
+            .await
+            .unwrap();
+
+        assert!(
+            service
+                .clone()
+                .fetch("symcaches", &key, 0, &destination)
+                .await
+        );
+        assert_eq!(std::fs::read(&destination).unwrap(), b"hello");
+
+        // Items are isolated by scope, cache and version.
+        assert!(
+            !service
+                .clone()
+                .fetch("symcaches", &other_scope, 0, &destination)
+                .await
+        );
+        assert!(
+            !service
+                .clone()
+                .fetch("symcaches", &key, 1, &destination)
+                .await
+        );
+        assert!(
+            !service
+                .clone()
+                .fetch("cficaches", &key, 0, &destination)
+                .await
+        );
+
+        assert!(service.clone().remove("symcaches", &key, 0).await);
+        assert!(!service.fetch("symcaches", &key, 0, &destination).await);
+    }
+}
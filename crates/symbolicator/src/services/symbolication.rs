@@ -1,9 +1,11 @@
+use std::borrow::Cow;
 use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryInto;
 use std::future::Future;
 use std::io::{Cursor, Write};
 use std::iter::FromIterator;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
@@ -11,7 +13,8 @@ use anyhow::Context;
 use apple_crash_report_parser::AppleCrashReport;
 use bytes::Bytes;
 use chrono::{DateTime, TimeZone, Utc};
-use futures::{channel::oneshot, future, FutureExt as _};
+use futures::future::LocalBoxFuture;
+use futures::{channel::oneshot, future, stream, FutureExt as _, Stream, StreamExt as _};
 use parking_lot::Mutex;
 use regex::Regex;
 use sentry::protocol::SessionStatus;
@@ -30,11 +33,19 @@ use symbolic::minidump::processor::{
 use thiserror::Error;
 
 use crate::cache::CacheStatus;
+use crate::config::{SourceContextConfig, TimeoutsConfig};
 use crate::logging::LogError;
+use crate::services::cancellation::{Cancellation, CancellationFutureExt};
 use crate::services::cficaches::{CfiCacheActor, CfiCacheError, CfiCacheFile, FetchCfiCache};
+use crate::services::deadline::{Deadline, DeadlineFutureExt};
+use crate::services::download::DownloadService;
 use crate::services::objects::{FindObject, ObjectError, ObjectPurpose, ObjectsActor};
+use crate::services::progress::{
+    ModuleUse, ProcessingPhase, Progress, ProgressEvent, ProgressFutureExt,
+};
+use crate::services::requests::RequestStore;
 use crate::services::symcaches::{FetchSymCache, SymCacheActor, SymCacheError, SymCacheFile};
-use crate::sources::{FileType, SourceConfig};
+use crate::sources::{FileType, SourceConfig, SourceId};
 use crate::types::ObjectFeatures;
 use crate::types::{
     AllObjectCandidates, CompleteObjectInfo, CompleteStacktrace, CompletedSymbolicationResponse,
@@ -95,6 +106,10 @@ type ComputationChannel = future::Shared<oneshot::Receiver<(Instant, Symbolicati
 
 type ComputationMap = Arc<Mutex<BTreeMap<RequestId, ComputationChannel>>>;
 
+type ProgressMap = Arc<Mutex<BTreeMap<RequestId, Progress>>>;
+
+type CancellationMap = Arc<Mutex<BTreeMap<RequestId, (future::AbortHandle, Cancellation)>>>;
+
 #[derive(Debug, Clone)]
 pub struct SymCacheLookupResult<'a> {
     module_index: usize,
@@ -412,35 +427,52 @@ impl ModuleListBuilder {
 #[derive(Clone, Debug)]
 pub struct SymbolicationActor {
     objects: ObjectsActor,
+    downloader: Arc<DownloadService>,
     symcaches: SymCacheActor,
     cficaches: CfiCacheActor,
     diagnostics_cache: crate::cache::Cache,
     threadpool: ThreadPool,
     requests: ComputationMap,
+    progress: ProgressMap,
+    cancellations: CancellationMap,
+    request_store: RequestStore,
     spawnpool: Arc<procspawn::Pool>,
+    timeouts: TimeoutsConfig,
+    source_context: SourceContextConfig,
 }
 
 impl SymbolicationActor {
     pub fn new(
         objects: ObjectsActor,
+        downloader: Arc<DownloadService>,
         symcaches: SymCacheActor,
         cficaches: CfiCacheActor,
         diagnostics_cache: crate::cache::Cache,
+        request_store: RequestStore,
         threadpool: ThreadPool,
         spawnpool: procspawn::Pool,
+        timeouts: TimeoutsConfig,
+        source_context: SourceContextConfig,
     ) -> Self {
         SymbolicationActor {
             objects,
+            downloader,
             symcaches,
             cficaches,
             diagnostics_cache,
             threadpool,
             requests: Arc::new(Mutex::new(BTreeMap::new())),
+            progress: Arc::new(Mutex::new(BTreeMap::new())),
+            cancellations: Arc::new(Mutex::new(BTreeMap::new())),
+            request_store,
             spawnpool: Arc::new(spawnpool),
+            timeouts,
+            source_context,
         }
     }
 
-    fn create_symbolication_request<F>(&self, f: F) -> RequestId
+    /// Spawns a symbolication request, which must finish within the given `timeout`.
+    fn create_symbolication_request<F>(&self, timeout: Option<Duration>, f: F) -> RequestId
     where
         F: Future<Output = Result<CompletedSymbolicationResponse, SymbolicationError>> + 'static,
     {
@@ -452,21 +484,43 @@ impl SymbolicationActor {
         let requests = self.requests.clone();
         let request_id = RequestId::new(uuid::Uuid::new_v4());
         requests.lock().insert(request_id, receiver.shared());
+        let request_store = self.request_store.clone();
+
+        let progress = Progress::new();
+        let progress_map = self.progress.clone();
+        progress_map.lock().insert(request_id, progress.clone());
+
+        let (f, abort_handle) = future::abortable(f);
+        let cancellation = Cancellation::new();
+        let cancellations = self.cancellations.clone();
+        cancellations
+            .lock()
+            .insert(request_id, (abort_handle, cancellation.clone()));
+        let finished_cancellations = cancellations.clone();
+
         let drop_hub = hub.clone();
+        let drop_progress = progress.clone();
         let token = CallOnDrop::new(move || {
             requests.lock().remove(&request_id);
+            progress_map.lock().remove(&request_id);
+            cancellations.lock().remove(&request_id);
+            drop_progress.finish();
             // we consider every premature drop of the future as fatal crash, which works fine
             // since ending a session consumes it and its not possible to double-end.
             drop_hub.end_session_with_status(SessionStatus::Crashed);
         });
 
         let request_future = async move {
-            let response = match f.await {
-                Ok(response) => {
+            request_store.mark_running(request_id).await;
+            let result = f.await;
+            finished_cancellations.lock().remove(&request_id);
+
+            let response = match result {
+                Ok(Ok(response)) => {
                     sentry::end_session_with_status(SessionStatus::Exited);
                     SymbolicationResponse::Completed(Box::new(response))
                 }
-                Err(error) => {
+                Ok(Err(error)) => {
                     // a timeout is an abnormal session exit, all other errors are considered "crashed"
                     let status = match &error {
                         SymbolicationError::Timeout => SessionStatus::Abnormal,
@@ -478,9 +532,19 @@ impl SymbolicationActor {
                     log::error!("Symbolication error: {:?}", anyhow::Error::new(error));
                     response
                 }
+                Err(future::Aborted) => {
+                    // the client asked to cancel, which is a regular exit of the session
+                    sentry::end_session_with_status(SessionStatus::Exited);
+                    metric!(counter("symbolication.request_cancelled") += 1);
+                    SymbolicationResponse::Failed {
+                        message: "symbolication request was cancelled".to_owned(),
+                    }
+                }
             };
 
+            request_store.store_response(request_id, &response).await;
             sender.send((Instant::now(), response)).ok();
+            Progress::current().finish();
 
             // Wait before removing the channel from the computation map to allow clients to
             // poll the status.
@@ -488,7 +552,10 @@ impl SymbolicationActor {
 
             drop(token);
         }
-        .bind_hub(hub);
+        .bind_hub(hub)
+        .bind_progress(progress)
+        .bind_deadline(Deadline::new(timeout))
+        .bind_cancellation(cancellation);
 
         // TODO: This spawns into the current_thread runtime of the caller, which usually is the web
         // handler. This doesn't block the web request, but it congests the threads that should only
@@ -534,7 +601,7 @@ async fn wrap_response_channel(
     }
 }
 
-fn object_id_from_object_info(object_info: &RawObjectInfo) -> ObjectId {
+pub(crate) fn object_id_from_object_info(object_info: &RawObjectInfo) -> ObjectId {
     ObjectId {
         debug_id: match object_info.debug_id.as_deref() {
             None | Some("") => None,
@@ -592,26 +659,27 @@ struct SourceObjectEntry {
 
 struct SourceLookup {
     inner: Vec<SourceObjectEntry>,
+    /// Source files fetched from source roots, by their absolute path.
+    files: BTreeMap<String, String>,
 }
 
 impl SourceLookup {
     pub async fn fetch_sources(
         self,
         objects: ObjectsActor,
+        downloader: Arc<DownloadService>,
         scope: Scope,
         sources: Arc<[SourceConfig]>,
-        response: &CompletedSymbolicationResponse,
+        config: SourceContextConfig,
+        frames: &[&RawFrame],
     ) -> Result<Self, SymbolicationError> {
         let mut referenced_objects = BTreeSet::new();
-        let stacktraces = &response.stacktraces;
 
-        for stacktrace in stacktraces {
-            for frame in &stacktrace.frames {
-                if let Some(i) =
-                    self.get_object_index_by_addr(frame.raw.instruction_addr.0, frame.raw.addr_mode)
-                {
-                    referenced_objects.insert(i);
-                }
+        for frame in frames {
+            if let Some(i) =
+                self.get_object_index_by_addr(frame.instruction_addr.0, frame.addr_mode)
+            {
+                referenced_objects.insert(i);
             }
         }
 
@@ -661,9 +729,102 @@ impl SourceLookup {
             });
         }
 
-        Ok(SourceLookup {
+        let mut lookup = SourceLookup {
             inner: future::join_all(futures).await,
-        })
+            files: BTreeMap::new(),
+        };
+
+        let files = lookup
+            .fetch_source_files(downloader, &sources, config, frames)
+            .await;
+        lookup.files = files;
+
+        Ok(lookup)
+    }
+
+    /// Fetches source files from source roots for all frames without a source in a source bundle.
+    ///
+    /// At most `config.max_concurrent_fetches` files are fetched at the same time.
+    async fn fetch_source_files(
+        &self,
+        downloader: Arc<DownloadService>,
+        sources: &[SourceConfig],
+        config: SourceContextConfig,
+        frames: &[&RawFrame],
+    ) -> BTreeMap<String, String> {
+        let source_roots: Vec<_> = sources
+            .iter()
+            .filter_map(|source| match source {
+                SourceConfig::SourceRoot(source) => Some(source.clone()),
+                _ => None,
+            })
+            .collect();
+
+        if source_roots.is_empty() {
+            return BTreeMap::new();
+        }
+
+        let futures = self
+            .missing_source_files(frames)
+            .into_iter()
+            .map(|abs_path| {
+                let downloader = downloader.clone();
+                let source_roots = source_roots.clone();
+
+                async move {
+                    // The first source root containing the file wins.
+                    for source in source_roots {
+                        let result = downloader
+                            .clone()
+                            .fetch_source_file(source, abs_path.clone())
+                            .await;
+
+                        match result {
+                            Ok(Some(contents)) => return Some((abs_path, contents)),
+                            Ok(None) => (),
+                            Err(e) => log::debug!(
+                                "Failed to fetch source file {}: {}",
+                                abs_path,
+                                LogError(&e)
+                            ),
+                        }
+                    }
+
+                    None
+                }
+            });
+
+        stream::iter(futures)
+            .buffer_unordered(config.max_concurrent_fetches.max(1))
+            .filter_map(future::ready)
+            .collect()
+            .await
+    }
+
+    /// Returns the absolute paths of all frames for which the source bundles have no source.
+    fn missing_source_files(&self, frames: &[&RawFrame]) -> BTreeSet<String> {
+        let debug_sessions = self.prepare_debug_sessions();
+        let mut missing = BTreeSet::new();
+
+        for frame in frames {
+            let abs_path = match (&frame.abs_path, frame.lineno) {
+                (&Some(ref abs_path), Some(_)) => abs_path,
+                _ => continue,
+            };
+
+            let bundled = self.get_bundled_source(
+                &debug_sessions,
+                frame.instruction_addr.0,
+                frame.addr_mode,
+                abs_path,
+            );
+
+            if bundled.is_none() {
+                missing.insert(abs_path.clone());
+            }
+        }
+
+        missing
     }
 
     pub fn prepare_debug_sessions(&self) -> Vec<Option<ObjectDebugSession<'_>>> {
@@ -687,9 +848,10 @@ impl SourceLookup {
         lineno: u32,
         n: usize,
     ) -> Option<(Vec<String>, String, Vec<String>)> {
-        let index = self.get_object_index_by_addr(addr, addr_mode)?;
-        let session = debug_sessions[index].as_ref()?;
-        let source = session.source_by_path(abs_path).ok()??;
+        let source = match self.get_bundled_source(debug_sessions, addr, addr_mode, abs_path) {
+            Some(source) => source,
+            None => Cow::Borrowed(self.files.get(abs_path)?.as_str()),
+        };
 
         let lineno = lineno as usize;
         let start_line = lineno.saturating_sub(n);
@@ -706,6 +868,19 @@ impl SourceLookup {
         Some((pre_context, context, post_context))
     }
 
+    /// Returns the source file from the source bundle of the module containing the address.
+    fn get_bundled_source<'a>(
+        &self,
+        debug_sessions: &'a [Option<ObjectDebugSession<'_>>],
+        addr: u64,
+        addr_mode: AddrMode,
+        abs_path: &str,
+    ) -> Option<Cow<'a, str>> {
+        let index = self.get_object_index_by_addr(addr, addr_mode)?;
+        let session = debug_sessions[index].as_ref()?;
+        session.source_by_path(abs_path).ok()?
+    }
+
     fn get_object_index_by_addr(&self, addr: u64, addr_mode: AddrMode) -> Option<usize> {
         match addr_mode {
             AddrMode::Abs => {
@@ -771,12 +946,145 @@ impl FromIterator<CompleteObjectInfo> for SourceLookup {
                     source_object: None,
                 })
                 .collect(),
+            files: BTreeMap::new(),
         };
         rv.sort();
         rv
     }
 }
 
+type SymCacheResult = Result<Arc<SymCacheFile>, Arc<SymCacheError>>;
+
+type SharedSymCacheFuture = future::Shared<LocalBoxFuture<'static, SymCacheResult>>;
+
+/// Identifies a symcache fetch independently of where the module was loaded.
+///
+/// Image addresses are deliberately not part of this key, since the same module is commonly
+/// mounted at different addresses across processes.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+struct SharedSymCacheKey {
+    scope: Scope,
+    sources: Vec<SourceId>,
+    ty: ObjectType,
+    code_id: Option<String>,
+    code_file: Option<String>,
+    debug_id: Option<String>,
+    debug_file: Option<String>,
+}
+
+impl SharedSymCacheKey {
+    fn new(scope: &Scope, sources: &[SourceConfig], object_info: &RawObjectInfo) -> Self {
+        SharedSymCacheKey {
+            scope: scope.clone(),
+            sources: sources.iter().map(|source| source.id().clone()).collect(),
+            ty: object_info.ty,
+            code_id: object_info.code_id.clone(),
+            code_file: object_info.code_file.clone(),
+            debug_id: object_info.debug_id.clone(),
+            debug_file: object_info.debug_file.clone(),
+        }
+    }
+}
+
+/// Deduplicates symcache fetches across the events of a batch symbolication request.
+///
+/// Every module is fetched at most once per batch, and all events referencing that module share
+/// the same [`SymCacheFile`].  Batches usually share most of their modules between events.  Each
+/// batch request creates its own instance, so nothing is shared between different requests.
+#[derive(Clone)]
+struct SharedSymCaches {
+    symcaches: SymCacheActor,
+    fetches: Arc<Mutex<BTreeMap<SharedSymCacheKey, SharedSymCacheFuture>>>,
+}
+
+impl SharedSymCaches {
+    fn new(symcaches: SymCacheActor) -> Self {
+        SharedSymCaches {
+            symcaches,
+            fetches: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// Fetches the symcache for a module, or joins an existing fetch for the same module.
+    fn fetch(
+        &self,
+        object_info: &RawObjectInfo,
+        sources: Arc<[SourceConfig]>,
+        scope: Scope,
+    ) -> SharedSymCacheFuture {
+        let key = SharedSymCacheKey::new(&scope, &sources, object_info);
+        let mut fetches = self.fetches.lock();
+
+        if let Some(shared) = fetches.get(&key) {
+            metric!(counter("symbolication.shared_symcaches.hit") += 1);
+            return shared.clone();
+        }
+
+        let symcaches = self.symcaches.clone();
+        let request = FetchSymCache {
+            object_type: object_info.ty,
+            identifier: object_id_from_object_info(object_info),
+            sources,
+            scope,
+        };
+
+        let shared = async move { symcaches.fetch(request).await }
+            .boxed_local()
+            .shared();
+        fetches.insert(key, shared.clone());
+        shared
+    }
+}
+
+/// Waits for a debug file fetch until `deadline`, returning `None` if the deadline passes first.
+///
+/// Without a deadline, this simply awaits the fetch.  Otherwise, the fetch runs in the background
+/// without a deadline of its own.  If the deadline passes, the fetch keeps running so that its
+/// results are cached for later requests.  If this future is dropped before, the fetch is aborted.
+async fn fetch_until<F>(deadline: Deadline, fetch: F) -> Option<F::Output>
+where
+    F: Future + 'static,
+{
+    let remaining = match deadline.remaining() {
+        Some(remaining) => remaining,
+        None => return Some(fetch.await),
+    };
+
+    let (sender, receiver) = oneshot::channel();
+    let (fetch, abort_handle) = future::abortable(async move {
+        sender.send(fetch.await).ok();
+    });
+    spawn_compat(
+        fetch
+            .bind_hub(Hub::new_from_top(Hub::current()))
+            .bind_progress(Progress::current())
+            .bind_deadline(Deadline::default())
+            .bind_cancellation(Cancellation::current()),
+    );
+
+    let abort_token = CallOnDrop::new(move || abort_handle.abort());
+    match timeout_compat(remaining, receiver).await {
+        Ok(result) => result.ok(),
+        Err(_) => {
+            metric!(counter("symbolication.fetch_detached") += 1);
+            abort_token.disarm();
+            None
+        }
+    }
+}
+
+/// Returns the deadline at which fetching debug files stops for the given request.
+///
+/// Only requests with [`partial_results`](RequestOptions::partial_results) stop fetching, all
+/// others wait until all debug files have been fetched or the request times out.
+fn fetch_deadline(options: &RequestOptions) -> Deadline {
+    if options.partial_results {
+        Deadline::current()
+    } else {
+        Deadline::default()
+    }
+}
+
 struct SymCacheEntry {
     module_index: usize,
     object_info: CompleteObjectInfo,
@@ -828,8 +1136,9 @@ impl SymCacheLookup {
 
     async fn fetch_symcaches(
         self,
-        symcache_actor: SymCacheActor,
+        symcaches: SharedSymCaches,
         request: SymbolicateStacktraces,
+        deadline: Deadline,
     ) -> Self {
         let mut referenced_objects = BTreeSet::new();
         let stacktraces = request.stacktraces;
@@ -845,34 +1154,34 @@ impl SymCacheLookup {
         }
 
         let mut futures = Vec::new();
+        let progress = Progress::current();
 
         for mut entry in self.inner.into_iter() {
             let is_used = referenced_objects.contains(&entry.module_index);
             let sources = request.sources.clone();
             let scope = request.scope.clone();
-            let symcache_actor = symcache_actor.clone();
+            let symcaches = symcaches.clone();
+            let progress = progress.clone();
 
             futures.push(async move {
                 if !is_used {
                     entry.object_info.debug_status = ObjectFileStatus::Unused;
                     return entry;
                 }
-                let symcache_result = symcache_actor
-                    .fetch(FetchSymCache {
-                        object_type: entry.object_info.raw.ty,
-                        identifier: object_id_from_object_info(&entry.object_info.raw),
-                        sources,
-                        scope,
-                    })
-                    .await;
+                let symcache_result = fetch_until(
+                    deadline,
+                    symcaches.fetch(&entry.object_info.raw, sources, scope),
+                )
+                .await;
 
                 let (symcache, status) = match symcache_result {
-                    Ok(symcache) => match symcache.parse() {
+                    Some(Ok(symcache)) => match symcache.parse() {
                         Ok(Some(_)) => (Some(symcache), ObjectFileStatus::Found),
                         Ok(None) => (Some(symcache), ObjectFileStatus::Missing),
                         Err(e) => (None, (&e).into()),
                     },
-                    Err(e) => (None, (&*e).into()),
+                    Some(Err(e)) => (None, (&*e).into()),
+                    None => (None, ObjectFileStatus::Timeout),
                 };
 
                 entry.object_info.arch = Default::default();
@@ -883,6 +1192,12 @@ impl SymCacheLookup {
                     entry.object_info.candidates.merge(symcache.candidates());
                 }
 
+                progress.emit(ProgressEvent::module(
+                    ModuleUse::Debug,
+                    &entry.object_info.raw,
+                    status,
+                ));
+
                 entry.symcache = symcache;
                 entry.object_info.debug_status = status;
                 entry
@@ -1102,6 +1417,7 @@ fn symbolicate_frame(
                     language => Some(language),
                 },
                 trust: frame.trust,
+                in_app: frame.in_app,
             },
         });
     }
@@ -1113,6 +1429,42 @@ fn symbolicate_frame(
     Ok(rv)
 }
 
+/// Returns the frames that receive source context as `(stacktrace, frame)` index pairs.
+///
+/// Only frames with an absolute path and line number are returned, filtered according to the
+/// context options in [`RequestOptions`].
+fn get_context_frames(
+    response: &CompletedSymbolicationResponse,
+    options: &RequestOptions,
+) -> Vec<(usize, usize)> {
+    let crashing_thread = response
+        .stacktraces
+        .iter()
+        .position(|trace| trace.is_requesting == Some(true))
+        .unwrap_or(0);
+
+    let mut context_frames = Vec::new();
+    for (trace_index, trace) in response.stacktraces.iter().enumerate() {
+        if options.context_max_frames.is_some() && trace_index != crashing_thread {
+            continue;
+        }
+
+        let frames = trace
+            .frames
+            .iter()
+            .enumerate()
+            .filter(|(_, frame)| !options.context_in_app_only || frame.raw.in_app == Some(true))
+            .filter(|(_, frame)| frame.raw.abs_path.is_some() && frame.raw.lineno.is_some())
+            .take(options.context_max_frames.unwrap_or(usize::MAX));
+
+        for (frame_index, _) in frames {
+            context_frames.push((trace_index, frame_index));
+        }
+    }
+
+    context_frames
+}
+
 fn symbolicate_stacktrace(
     thread: RawStacktrace,
     caches: &SymCacheLookup,
@@ -1208,10 +1560,44 @@ impl SymbolicationActor {
         self,
         request: SymbolicateStacktraces,
     ) -> Result<CompletedSymbolicationResponse, SymbolicationError> {
-        let serialize_dif_candidates = request.options.dif_candidates;
+        let symcaches = SharedSymCaches::new(self.symcaches.clone());
+        self.do_symbolicate_shared(request, symcaches).await
+    }
 
-        let f = self.do_symbolicate_impl(request);
-        let f = timeout_compat(Duration::from_secs(3600), f);
+    /// Returns the deadline of a request along with the hard limit of its runtime.
+    ///
+    /// Requests with partial results stop fetching at their deadline and then complete the
+    /// response, so their deadline is at least `timeouts.partial_results_margin` before the hard
+    /// limit of the server.  All other requests fail at their deadline.
+    fn request_deadline(&self, partial_results: bool) -> (Deadline, Duration) {
+        let timeout = self.timeouts.symbolication;
+        if partial_results {
+            let fetch_timeout = timeout
+                .checked_sub(self.timeouts.partial_results_margin)
+                .unwrap_or_else(|| Duration::from_secs(0));
+            let deadline = Deadline::new(Some(Deadline::current().cap(fetch_timeout)));
+            (deadline, timeout)
+        } else {
+            let timeout = Deadline::current().cap(timeout);
+            (Deadline::new(Some(timeout)), timeout)
+        }
+    }
+
+    async fn do_symbolicate_shared(
+        self,
+        request: SymbolicateStacktraces,
+        symcaches: SharedSymCaches,
+    ) -> Result<CompletedSymbolicationResponse, SymbolicationError> {
+        let serialize_dif_candidates = request.options.dif_candidates;
+        let partial_results = request.options.partial_results;
+
+        // With partial results, fetching stops at the deadline and the request completes with
+        // the symcaches available by then.  The server timeout remains the hard limit.
+        let (deadline, timeout) = self.request_deadline(partial_results);
+        let f = self
+            .do_symbolicate_impl(request, symcaches)
+            .bind_deadline(deadline);
+        let f = timeout_compat(timeout, f);
         let f = measure("symbolicate", m::timed_result, f);
 
         let mut response = f
@@ -1229,17 +1615,28 @@ impl SymbolicationActor {
     async fn do_symbolicate_impl(
         self,
         request: SymbolicateStacktraces,
+        symcaches: SharedSymCaches,
     ) -> Result<CompletedSymbolicationResponse, anyhow::Error> {
         let symcache_lookup: SymCacheLookup = request.modules.iter().cloned().collect();
         let source_lookup: SourceLookup = request.modules.iter().cloned().collect();
         let stacktraces = request.stacktraces.clone();
         let sources = request.sources.clone();
+        let mut options = request.options.clone();
         let scope = request.scope.clone();
         let signal = request.signal;
 
+        // Requests cannot blow up responses with arbitrarily large source context.
+        options.context_lines = options
+            .context_lines
+            .min(self.source_context.max_context_lines);
+
+        let progress = Progress::current();
+        progress.phase(ProcessingPhase::FetchSymCaches);
+        let deadline = fetch_deadline(&options);
         let symcache_lookup = symcache_lookup
-            .fetch_symcaches(self.symcaches, request)
+            .fetch_symcaches(symcaches, request, deadline)
             .await;
+        progress.phase(ProcessingPhase::Symbolicate);
 
         let future = async move {
             let stacktraces: Vec<_> = stacktraces
@@ -1285,34 +1682,54 @@ impl SymbolicationActor {
             .await
             .context("Symbolication future cancelled")?;
 
+        // Without source context, there is no need to fetch source bundles at all.
+        if options.context_lines == 0 {
+            return Ok(response);
+        }
+
+        progress.phase(ProcessingPhase::SourceContext);
+        let context_frames = get_context_frames(&response, &options);
+        let frames: Vec<_> = context_frames
+            .iter()
+            .map(|&(trace_index, frame_index)| {
+                &response.stacktraces[trace_index].frames[frame_index].raw
+            })
+            .collect();
+
         let source_lookup = source_lookup
-            .fetch_sources(self.objects, scope, sources, &response)
+            .fetch_sources(
+                self.objects,
+                self.downloader,
+                scope,
+                sources,
+                self.source_context,
+                &frames,
+            )
             .await?;
 
         let future = async move {
             let debug_sessions = source_lookup.prepare_debug_sessions();
 
-            for trace in &mut response.stacktraces {
-                for frame in &mut trace.frames {
-                    let (abs_path, lineno) = match (&frame.raw.abs_path, frame.raw.lineno) {
-                        (&Some(ref abs_path), Some(lineno)) => (abs_path, lineno),
-                        _ => continue,
-                    };
+            for (trace_index, frame_index) in context_frames {
+                let frame = &mut response.stacktraces[trace_index].frames[frame_index];
+                let (abs_path, lineno) = match (&frame.raw.abs_path, frame.raw.lineno) {
+                    (&Some(ref abs_path), Some(lineno)) => (abs_path, lineno),
+                    _ => continue,
+                };
 
-                    let result = source_lookup.get_context_lines(
-                        &debug_sessions,
-                        frame.raw.instruction_addr.0,
-                        frame.raw.addr_mode,
-                        abs_path,
-                        lineno,
-                        5,
-                    );
+                let result = source_lookup.get_context_lines(
+                    &debug_sessions,
+                    frame.raw.instruction_addr.0,
+                    frame.raw.addr_mode,
+                    abs_path,
+                    lineno,
+                    options.context_lines,
+                );
 
-                    if let Some((pre_context, context_line, post_context)) = result {
-                        frame.raw.pre_context = pre_context;
-                        frame.raw.context_line = Some(context_line);
-                        frame.raw.post_context = post_context;
-                    }
+                if let Some((pre_context, context_line, post_context)) = result {
+                    frame.raw.pre_context = pre_context;
+                    frame.raw.context_line = Some(context_line);
+                    frame.raw.post_context = post_context;
                 }
             }
             response
@@ -1325,7 +1742,32 @@ impl SymbolicationActor {
     }
 
     pub fn symbolicate_stacktraces(&self, request: SymbolicateStacktraces) -> RequestId {
-        self.create_symbolication_request(self.clone().do_symbolicate(request))
+        let timeout = request.options.timeout;
+        self.create_symbolication_request(timeout, self.clone().do_symbolicate(request))
+    }
+
+    /// Symbolicates a batch of requests, sharing symcaches between them.
+    ///
+    /// Each request is tracked individually and can be polled with its own [`RequestId`], returned
+    /// in the same order as the requests.  Modules referenced by more than one request in the
+    /// batch are fetched only once, as long as the requests use the same scope and sources.
+    pub fn symbolicate_stacktraces_batch(
+        &self,
+        requests: Vec<SymbolicateStacktraces>,
+    ) -> Vec<RequestId> {
+        metric!(time_raw("symbolication.batch_size") = requests.len() as u64);
+
+        let symcaches = SharedSymCaches::new(self.symcaches.clone());
+        requests
+            .into_iter()
+            .map(|request| {
+                let timeout = request.options.timeout;
+                let future = self
+                    .clone()
+                    .do_symbolicate_shared(request, symcaches.clone());
+                self.create_symbolication_request(timeout, future)
+            })
+            .collect()
     }
 
     /// Polls the status for a started symbolication task.
@@ -1342,6 +1784,12 @@ impl SymbolicationActor {
         match channel_opt {
             Some(channel) => Some(wrap_response_channel(request_id, timeout, channel).await),
             None => {
+                // Requests from before a restart are only available if persistence is enabled.
+                if let Some(response) = self.request_store.load_response(request_id).await {
+                    metric!(counter("symbolication.request_id_restored") += 1);
+                    return Some(response);
+                }
+
                 // This is okay to occur during deploys, but if it happens all the time we have a state
                 // bug somewhere. Could be a misconfigured load balancer (supposed to be pinned to
                 // scopes).
@@ -1350,6 +1798,51 @@ impl SymbolicationActor {
             }
         }
     }
+
+    /// Cancels a running symbolication task.
+    ///
+    /// This drops the computation of the request, including downloads and cache computations
+    /// that only cancelled requests are waiting on.  The request then resolves with a failure
+    /// response.  Returns `false` if the request is not known to this instance or has already
+    /// finished.
+    pub fn cancel_request(&self, request_id: RequestId) -> bool {
+        match self.cancellations.lock().remove(&request_id) {
+            Some((abort_handle, cancellation)) => {
+                // Mark the request first, so that computations see it when they are dropped.
+                cancellation.cancel();
+                abort_handle.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Subscribes to progress events of a running symbolication task.
+    ///
+    /// Returns `None` if the request is not known to this instance.  Once the request has
+    /// finished, the stream ends with [`ProgressEvent::Finished`].
+    pub fn subscribe_progress(
+        &self,
+        request_id: RequestId,
+    ) -> Option<impl Stream<Item = ProgressEvent>> {
+        let progress = self.progress.lock().get(&request_id).cloned()?;
+        Some(progress.subscribe())
+    }
+}
+
+/// Interval in which a procspawn subprocess checks for cancellation of its request.
+const PROCSPAWN_CANCEL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Creates a flag to kill a procspawn subprocess when its request is cancelled.
+///
+/// The flag is set once the returned token is dropped, which happens when the future waiting
+/// for the subprocess is dropped.  It is passed to [`SymbolicationActor::join_procspawn`].
+fn procspawn_cancellation() -> (Arc<AtomicBool>, CallOnDrop) {
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let token = CallOnDrop::new(clone!(cancelled, || {
+        cancelled.store(true, Ordering::Relaxed)
+    }));
+    (cancelled, token)
 }
 
 type CfiCacheResult = (CodeModuleId, Result<Arc<CfiCacheFile>, Arc<CfiCacheError>>);
@@ -1454,6 +1947,8 @@ impl SymbolicationActor {
     ) -> Result<Vec<(CodeModuleId, RawObjectInfo)>, anyhow::Error> {
         let pool = self.spawnpool.clone();
         let diagnostics_cache = self.diagnostics_cache.clone();
+        let (cancelled, _cancel_token) = procspawn_cancellation();
+        let timeout = Deadline::current().cap(self.timeouts.referenced_modules);
         let lazy = async move {
             let spawn_time = std::time::SystemTime::now();
             let spawn_result = pool.spawn(
@@ -1486,7 +1981,8 @@ impl SymbolicationActor {
 
             Self::join_procspawn(
                 spawn_result,
-                Duration::from_secs(20),
+                timeout,
+                &cancelled,
                 "minidump.modules.spawn.error",
                 &minidump,
                 diagnostics_cache,
@@ -1504,9 +2000,13 @@ impl SymbolicationActor {
     /// This handles the procspawn result, makes sure to appropriately log any failures and
     /// save the minidump for debugging.  Returns a simple result converted to the
     /// [`SymbolicationError`].
+    ///
+    /// If the `cancelled` flag is set while waiting, the subprocess is killed.  See
+    /// [`procspawn_cancellation`].
     fn join_procspawn<T, E>(
-        handle: procspawn::JoinHandle<Result<procspawn::serde::Json<T>, E>>,
+        mut handle: procspawn::JoinHandle<Result<procspawn::serde::Json<T>, E>>,
         timeout: Duration,
+        cancelled: &AtomicBool,
         metric: &str,
         minidump: &[u8],
         minidump_cache: crate::cache::Cache,
@@ -1515,7 +2015,22 @@ impl SymbolicationActor {
         T: Serialize + DeserializeOwned,
         E: Into<anyhow::Error> + Serialize + DeserializeOwned,
     {
-        match handle.join_timeout(timeout) {
+        let deadline = Instant::now() + timeout;
+        let result = loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match handle.join_timeout(remaining.min(PROCSPAWN_CANCEL_INTERVAL)) {
+                Err(perr) if perr.is_timeout() && Instant::now() < deadline => {
+                    if cancelled.load(Ordering::Relaxed) {
+                        handle.kill().ok();
+                        metric!(counter(metric) += 1, "reason" => "request-cancelled");
+                        anyhow::bail!("minidump processing cancelled");
+                    }
+                }
+                result => break result,
+            }
+        };
+
+        match result {
             Ok(Ok(procspawn::serde::Json(out))) => Ok(out),
             Ok(Err(err)) => Err(err.into()),
             Err(perr) => {
@@ -1578,23 +2093,43 @@ impl SymbolicationActor {
         scope: Scope,
         requests: Vec<(CodeModuleId, RawObjectInfo)>,
         sources: Arc<[SourceConfig]>,
+        deadline: Deadline,
     ) -> Vec<CfiCacheResult> {
         let mut futures = Vec::with_capacity(requests.len());
+        let progress = Progress::current();
 
         for (code_id, object_info) in requests {
             let sources = sources.clone();
             let scope = scope.clone();
+            let progress = progress.clone();
+
+            let cficaches = self.cficaches.clone();
+            let request = FetchCfiCache {
+                object_type: object_info.ty,
+                identifier: object_id_from_object_info(&object_info),
+                sources,
+                scope,
+            };
 
             let fut = async move {
-                let result = self
-                    .cficaches
-                    .fetch(FetchCfiCache {
-                        object_type: object_info.ty,
-                        identifier: object_id_from_object_info(&object_info),
-                        sources,
-                        scope,
-                    })
-                    .await;
+                let result = fetch_until(deadline, async move { cficaches.fetch(request).await })
+                    .await
+                    .unwrap_or_else(|| Err(Arc::new(CfiCacheError::Timeout)));
+
+                let status = match result {
+                    Ok(ref cfi_cache) => match cfi_cache.status() {
+                        CacheStatus::Positive => ObjectFileStatus::Found,
+                        CacheStatus::Negative => ObjectFileStatus::Missing,
+                        CacheStatus::Malformed => ObjectFileStatus::Malformed,
+                    },
+                    Err(ref err) => ObjectFileStatus::from(err.as_ref()),
+                };
+                progress.emit(ProgressEvent::module(
+                    ModuleUse::Unwind,
+                    &object_info,
+                    status,
+                ));
+
                 (code_id, result)
             };
 
@@ -1635,6 +2170,8 @@ impl SymbolicationActor {
 
         let pool = self.spawnpool.clone();
         let diagnostics_cache = self.diagnostics_cache.clone();
+        let (cancelled, _cancel_token) = procspawn_cancellation();
+        let timeout = Deadline::current().cap(self.timeouts.stackwalking);
         let lazy = async move {
             let spawn_time = std::time::SystemTime::now();
             let spawn_result = pool.spawn(
@@ -1714,7 +2251,8 @@ impl SymbolicationActor {
 
             let (modules, stacktraces, minidump_state) = Self::join_procspawn(
                 spawn_result,
-                Duration::from_secs(60),
+                timeout,
+                &cancelled,
                 "minidump.stackwalk.spawn.error",
                 &minidump,
                 diagnostics_cache,
@@ -1751,22 +2289,47 @@ impl SymbolicationActor {
         sources: Arc<[SourceConfig]>,
         options: RequestOptions,
     ) -> Result<(SymbolicateStacktraces, MinidumpState), SymbolicationError> {
+        let partial_results = options.partial_results;
+        let (deadline, timeout) = self.request_deadline(partial_results);
+
+        // With partial results, only fetching CFI caches stops at the deadline.  Stackwalking
+        // always runs to completion, since there is no response without it.
+        let stackwalk_deadline = if partial_results {
+            Deadline::default()
+        } else {
+            deadline
+        };
+
         let future = async move {
             let minidump = Bytes::from(minidump);
+            let progress = Progress::current();
 
+            progress.phase(ProcessingPhase::ReferencedModules);
             let referenced_modules = self
                 .get_referenced_modules_from_minidump(minidump.clone())
+                .bind_deadline(stackwalk_deadline)
                 .await?;
 
+            progress.phase(ProcessingPhase::LoadCfiCaches);
+            let cfi_deadline = fetch_deadline(&options);
             let cfi_caches = self
-                .load_cfi_caches(scope.clone(), referenced_modules, sources.clone())
+                .load_cfi_caches(
+                    scope.clone(),
+                    referenced_modules,
+                    sources.clone(),
+                    cfi_deadline,
+                )
                 .await;
 
+            progress.phase(ProcessingPhase::Stackwalk);
             self.stackwalk_minidump_with_cfi(scope, minidump, sources, options, cfi_caches)
+                .bind_deadline(stackwalk_deadline)
                 .await
-        };
+        }
+        .bind_deadline(deadline);
 
-        let future = timeout_compat(Duration::from_secs(3600), future);
+        // With partial results, the server timeout remains the hard limit.
+        let future = timeout_compat(timeout, future);
         let future = measure("minidump_stackwalk", m::timed_result, future);
         future
             .await
@@ -1800,6 +2363,7 @@ impl SymbolicationActor {
         options: RequestOptions,
     ) -> RequestId {
         self.create_symbolication_request(
+            options.timeout,
             self.clone()
                 .do_process_minidump(scope, minidump, sources, options),
         )
@@ -1959,7 +2523,8 @@ impl SymbolicationActor {
                 .context("Parse applecrashreport future cancelled")
         };
 
-        let future = timeout_compat(Duration::from_secs(1200), future);
+        let timeout = Deadline::current().cap(self.timeouts.apple_crash_report);
+        let future = timeout_compat(timeout, future);
         let future = measure("parse_apple_crash_report", m::timed_result, future);
         future
             .await
@@ -1974,6 +2539,7 @@ impl SymbolicationActor {
         sources: Arc<[SourceConfig]>,
         options: RequestOptions,
     ) -> Result<CompletedSymbolicationResponse, SymbolicationError> {
+        Progress::current().phase(ProcessingPhase::ParseAppleCrashReport);
         let (request, state) = self
             .parse_apple_crash_report(scope, report, sources, options)
             .await?;
@@ -1990,12 +2556,11 @@ impl SymbolicationActor {
         sources: Arc<[SourceConfig]>,
         options: RequestOptions,
     ) -> RequestId {
-        self.create_symbolication_request(self.clone().do_process_apple_crash_report(
-            scope,
-            apple_crash_report,
-            sources,
-            options,
-        ))
+        self.create_symbolication_request(
+            options.timeout,
+            self.clone()
+                .do_process_apple_crash_report(scope, apple_crash_report, sources, options),
+        )
     }
 }
 
@@ -2061,8 +2626,11 @@ mod tests {
 
     use std::fs;
 
-    use crate::config::Config;
+    use futures::StreamExt;
+
+    use crate::config::{Config, RequestsConfig, TimeoutsConfig};
     use crate::services::Service;
+    use crate::sources::{HttpSourceConfig, SourceId, SourceRoot, SourceRootSourceConfig};
     use crate::test;
 
     /// Setup tests and create a test service.
@@ -2111,6 +2679,7 @@ mod tests {
             })],
             options: RequestOptions {
                 dif_candidates: true,
+                ..RequestOptions::default()
             },
         }
     }
@@ -2244,6 +2813,211 @@ mod tests {
         .await;
     }
 
+    #[tokio::test]
+    async fn test_symbolicate_batch() {
+        let (service, _cache_dir) = setup_service();
+        let (_symsrv, source) = test::symbol_server();
+
+        let symbolication = service.symbolication();
+        let responses = test::spawn_compat(move || async move {
+            let requests = vec![
+                get_symbolication_request(vec![source.clone()]),
+                get_symbolication_request(vec![source]),
+            ];
+
+            let mut responses = Vec::new();
+            for request_id in symbolication.symbolicate_stacktraces_batch(requests) {
+                let response = symbolication.clone().get_response(request_id, None).await;
+                responses.push(response.unwrap());
+            }
+            responses
+        })
+        .await;
+
+        assert_eq!(responses.len(), 2);
+        assert_eq!(
+            serde_json::to_value(&responses[0]).unwrap(),
+            serde_json::to_value(&responses[1]).unwrap()
+        );
+        assert_snapshot!(responses[0]);
+    }
+
+    #[tokio::test]
+    async fn test_get_response_after_restart() {
+        test::setup();
+
+        let cache_dir = test::tempdir();
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            requests: RequestsConfig {
+                persist: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let service = Service::create(config.clone()).unwrap();
+        let request_id = test::spawn_compat(move || async move {
+            let request = get_symbolication_request(vec![]);
+            let request_id = service.symbolication().symbolicate_stacktraces(request);
+            service
+                .symbolication()
+                .get_response(request_id, None)
+                .await
+                .unwrap();
+            request_id
+        })
+        .await;
+
+        // A new service on the same cache directory no longer tracks the request in memory.
+        let service = Service::create(config).unwrap();
+        let response = test::spawn_compat(move || async move {
+            service.symbolication().get_response(request_id, None).await
+        })
+        .await;
+
+        assert!(matches!(
+            response,
+            Some(SymbolicationResponse::Completed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_request() {
+        test::setup();
+
+        let (service, _cache_dir) = setup_service();
+        let (_symsrv, source) = test::symbol_server();
+
+        let response = test::spawn_compat(move || async move {
+            let request = get_symbolication_request(vec![source]);
+            let symbolication = service.symbolication();
+            let request_id = symbolication.symbolicate_stacktraces(request);
+            assert!(symbolication.cancel_request(request_id));
+            // A request can only be cancelled once.
+            assert!(!symbolication.cancel_request(request_id));
+            symbolication.get_response(request_id, None).await
+        })
+        .await;
+
+        assert!(matches!(
+            response,
+            Some(SymbolicationResponse::Failed { message }) if message.contains("cancelled")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_until_deadline() {
+        test::setup();
+
+        let finished = Arc::new(AtomicBool::new(false));
+        let fetched = finished.clone();
+
+        let result = test::spawn_compat(move || async move {
+            let deadline = Deadline::new(Some(Duration::from_millis(10)));
+            let fetch = async move {
+                delay(Duration::from_millis(50)).await;
+                fetched.store(true, Ordering::SeqCst);
+            };
+
+            let result = fetch_until(deadline, fetch).await;
+            // The fetch continues in the background after the deadline.
+            delay(Duration::from_millis(100)).await;
+            result
+        })
+        .await;
+
+        assert!(result.is_none());
+        assert!(finished.load(Ordering::SeqCst));
+
+        let result = test::spawn_compat(|| async move {
+            let deadline = Deadline::new(Some(Duration::from_secs(10)));
+            fetch_until(deadline, async { 42 }).await
+        })
+        .await;
+        assert_eq!(result, Some(42));
+    }
+
+    #[tokio::test]
+    async fn test_partial_results_after_deadline() {
+        test::setup();
+
+        let cache_dir = test::tempdir();
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            connect_to_reserved_ips: true,
+            timeouts: TimeoutsConfig {
+                symbolication: Duration::from_secs(2),
+                partial_results_margin: Duration::from_secs(1),
+                ..TimeoutsConfig::default()
+            },
+            ..Default::default()
+        };
+        let service = Service::create(config).unwrap();
+
+        // A symbol server that responds long after the request has to complete.
+        let app = warp::any().and_then(|| async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok::<_, warp::Rejection>(warp::reply())
+        });
+        let server = test::Server::new(app);
+        let source = SourceConfig::Http(Arc::new(HttpSourceConfig {
+            id: SourceId::new("slow"),
+            url: server.url("download/"),
+            headers: Default::default(),
+            files: Default::default(),
+        }));
+
+        let response = test::spawn_compat(move || async move {
+            let mut request = get_symbolication_request(vec![source]);
+            request.options.partial_results = true;
+            let symbolication = service.symbolication();
+            let request_id = symbolication.symbolicate_stacktraces(request);
+            symbolication.get_response(request_id, None).await
+        })
+        .await;
+
+        // Fetching stops before the server timeout, so the response still completes.
+        let response = match response {
+            Some(SymbolicationResponse::Completed(response)) => response,
+            other => panic!("unexpected response: {:?}", other),
+        };
+        assert_ne!(response.modules[0].debug_status, ObjectFileStatus::Found);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_progress() {
+        test::setup();
+
+        let (service, _cache_dir) = setup_service();
+        let events = test::spawn_compat(move || async move {
+            let request = get_symbolication_request(vec![]);
+            let symbolication = service.symbolication();
+            let request_id = symbolication.symbolicate_stacktraces(request);
+            let events = symbolication.subscribe_progress(request_id).unwrap();
+            events.collect::<Vec<_>>().await
+        })
+        .await;
+
+        let phases: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                ProgressEvent::Phase { phase } => Some(*phase),
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(
+            phases,
+            [
+                ProcessingPhase::FetchSymCaches,
+                ProcessingPhase::Symbolicate,
+                ProcessingPhase::SourceContext,
+            ]
+        );
+        assert!(matches!(events.last(), Some(ProgressEvent::Finished)));
+    }
+
     async fn stackwalk_minidump(path: &str) -> anyhow::Result<()> {
         let (service, _cache_dir) = setup_service();
         let (_symsrv, source) = test::symbol_server();
@@ -2257,6 +3031,7 @@ mod tests {
                 Arc::new([source]),
                 RequestOptions {
                     dif_candidates: true,
+                    ..RequestOptions::default()
                 },
             );
             symbolication.get_response(request_id, None).await
@@ -2303,6 +3078,7 @@ mod tests {
                 Arc::new([source]),
                 RequestOptions {
                     dif_candidates: true,
+                    ..RequestOptions::default()
                 },
             );
 
@@ -2384,6 +3160,151 @@ mod tests {
         assert!(lookup_result.symcache.is_none());
     }
 
+    #[tokio::test]
+    async fn test_source_root_context() {
+        let (service, _cache_dir) = setup_service();
+
+        let source_dir = test::tempdir();
+        fs::write(
+            source_dir.path().join("foo.c"),
+            "line1\nline2\nline3\nline4\nline5\n",
+        )
+        .unwrap();
+
+        let source = SourceConfig::SourceRoot(Arc::new(SourceRootSourceConfig {
+            id: SourceId::new("sources"),
+            prefix: "/build".to_owned(),
+            root: SourceRoot::Path(source_dir.path().to_owned()),
+            revision: None,
+            headers: Default::default(),
+        }));
+
+        let frame = RawFrame {
+            abs_path: Some("/build/foo.c".to_owned()),
+            lineno: Some(3),
+            ..Default::default()
+        };
+
+        // There are no modules with source bundles, so the source root is used.
+        let lookup = SourceLookup::from_iter(vec![])
+            .fetch_sources(
+                service.objects.clone(),
+                service.downloader.clone(),
+                Scope::Global,
+                Arc::from(vec![source]),
+                Default::default(),
+                &[&frame],
+            )
+            .await
+            .unwrap();
+
+        let context = lookup.get_context_lines(&[], 0, AddrMode::Abs, "/build/foo.c", 3, 2);
+        assert_eq!(
+            context,
+            Some((
+                vec!["line2".to_owned()],
+                "line3".to_owned(),
+                vec!["line4".to_owned(), "line5".to_owned()]
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_source_root_context_sequential() {
+        let (service, _cache_dir) = setup_service();
+
+        let source_dir = test::tempdir();
+        fs::write(source_dir.path().join("foo.c"), "foo\n").unwrap();
+        fs::write(source_dir.path().join("bar.c"), "bar\n").unwrap();
+
+        let source = SourceConfig::SourceRoot(Arc::new(SourceRootSourceConfig {
+            id: SourceId::new("sources"),
+            prefix: "/build".to_owned(),
+            root: SourceRoot::Path(source_dir.path().to_owned()),
+            revision: None,
+            headers: Default::default(),
+        }));
+
+        let frame = |abs_path: &str| RawFrame {
+            abs_path: Some(abs_path.to_owned()),
+            lineno: Some(1),
+            ..Default::default()
+        };
+        let frames = vec![frame("/build/foo.c"), frame("/build/bar.c")];
+        let frames: Vec<_> = frames.iter().collect();
+
+        // All files are fetched, even if only one is fetched at a time.
+        let lookup = SourceLookup::from_iter(vec![])
+            .fetch_sources(
+                service.objects.clone(),
+                service.downloader.clone(),
+                Scope::Global,
+                Arc::from(vec![source]),
+                SourceContextConfig {
+                    max_concurrent_fetches: 1,
+                },
+                &frames,
+            )
+            .await
+            .unwrap();
+
+        for name in &["foo", "bar"] {
+            let abs_path = format!("/build/{}.c", name);
+            let context = lookup.get_context_lines(&[], 0, AddrMode::Abs, &abs_path, 1, 0);
+            assert_eq!(context, Some((vec![], (*name).to_owned(), vec![])));
+        }
+    }
+
+    #[test]
+    fn test_get_context_frames() {
+        let frame = |in_app| SymbolicatedFrame {
+            raw: RawFrame {
+                abs_path: Some("/build/foo.c".to_owned()),
+                lineno: Some(1),
+                in_app: Some(in_app),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let response = CompletedSymbolicationResponse {
+            stacktraces: vec![
+                CompleteStacktrace {
+                    frames: vec![frame(true)],
+                    ..Default::default()
+                },
+                CompleteStacktrace {
+                    is_requesting: Some(true),
+                    frames: vec![frame(false), frame(true), frame(true), frame(true)],
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        let options = RequestOptions::default();
+        assert_eq!(
+            get_context_frames(&response, &options),
+            [(0, 0), (1, 0), (1, 1), (1, 2), (1, 3)]
+        );
+
+        let options = RequestOptions {
+            context_in_app_only: true,
+            ..RequestOptions::default()
+        };
+        assert_eq!(
+            get_context_frames(&response, &options),
+            [(0, 0), (1, 1), (1, 2), (1, 3)]
+        );
+
+        let options = RequestOptions {
+            context_in_app_only: true,
+            context_max_frames: Some(2),
+            ..RequestOptions::default()
+        };
+        assert_eq!(get_context_frames(&response, &options), [(1, 1), (1, 2)]);
+    }
+
     fn create_object_info(has_id: bool, addr: u64, size: Option<u64>) -> CompleteObjectInfo {
         RawObjectInfo {
             ty: ObjectType::Elf,
@@ -2497,4 +3418,56 @@ mod tests {
         let valid = builder.build();
         assert_eq!(valid, vec![valid_object]);
     }
+
+    fn minidump_cache() -> crate::cache::Cache {
+        let config = crate::config::CacheConfig::Diagnostics(Default::default());
+        crate::cache::Cache::from_config("diagnostics", None, None, config).unwrap()
+    }
+
+    #[test]
+    fn test_join_procspawn_longer_than_interval() {
+        test::setup();
+
+        // The subprocess outlives several cancellation checks, each of which times out.
+        let pool = procspawn::Pool::new(1).unwrap();
+        let duration = PROCSPAWN_CANCEL_INTERVAL * 3;
+        let handle = pool.spawn(duration, |duration| -> Result<_, ProcessMinidumpError> {
+            std::thread::sleep(duration);
+            Ok(procspawn::serde::Json(42u32))
+        });
+
+        let cancelled = AtomicBool::new(false);
+        let result = SymbolicationActor::join_procspawn(
+            handle,
+            Duration::from_secs(30),
+            &cancelled,
+            "test.spawn.error",
+            &[],
+            minidump_cache(),
+        );
+        assert_eq!(result.unwrap(), 42);
+    }
+
+    #[test]
+    fn test_join_procspawn_cancelled() {
+        test::setup();
+
+        let pool = procspawn::Pool::new(1).unwrap();
+        let handle = pool.spawn((), |()| -> Result<_, ProcessMinidumpError> {
+            std::thread::sleep(Duration::from_secs(30));
+            Ok(procspawn::serde::Json(42u32))
+        });
+
+        let cancelled = AtomicBool::new(true);
+        let result = SymbolicationActor::join_procspawn(
+            handle,
+            Duration::from_secs(30),
+            &cancelled,
+            "test.spawn.error",
+            &[],
+            minidump_cache(),
+        );
+        let error = result.unwrap_err();
+        assert_eq!(error.to_string(), "minidump processing cancelled");
+    }
 }
@@ -1,3 +1,4 @@
+use std::fmt;
 use std::fs::File;
 use std::io::{self, BufWriter};
 use std::path::{Path, PathBuf};
@@ -8,18 +9,19 @@ use anyhow::Error;
 use futures::compat::Future01CompatExt;
 use futures::future::{FutureExt, TryFutureExt};
 use sentry::{configure_scope, Hub, SentryFutureExt};
-use symbolic::common::{Arch, ByteView};
+use symbolic::common::{Arch, ByteView, SelfCell};
 use symbolic::debuginfo::Object;
 use symbolic::symcache::{self, SymCache, SymCacheWriter};
 use thiserror::Error;
 
-use crate::cache::{Cache, CacheKey, CacheStatus};
+use crate::cache::{Cache, CacheKey, CacheStatus, CacheVersions};
 use crate::services::bitcode::{BcSymbolMapHandle, BitcodeService};
 use crate::services::cacher::{CacheItemRequest, CachePath, Cacher};
 use crate::services::objects::{
     FindObject, FoundObject, ObjectError, ObjectHandle, ObjectMetaHandle, ObjectPurpose,
     ObjectsActor,
 };
+use crate::services::shared_cache::SharedCacheService;
 use crate::sources::{FileType, SourceConfig};
 use crate::types::{
     AllObjectCandidates, ObjectFeatures, ObjectId, ObjectType, ObjectUseInfo, Scope,
@@ -58,36 +60,80 @@ pub enum SymCacheError {
     Canceled,
 }
 
+/// The format versions of symcaches.
+///
+/// Bump the current version when the format written by symbolic changes.  If symbolic can still
+/// read the previous format, add the previous version to the fallbacks, so that existing items
+/// are served while they are upgraded in place.
+///
+/// # Versions
+///
+/// - 0: Initial version, including items written before the version was recorded.
+/// - 1: Hidden symbol names are resolved with BCSymbolMaps.  Items of version 0 may still contain
+///   hidden names, but are otherwise valid and served until they are recomputed.
+pub const SYMCACHE_VERSIONS: CacheVersions = CacheVersions {
+    current: 1,
+    fallbacks: &[0],
+};
+
 #[derive(Clone, Debug)]
 pub struct SymCacheActor {
     symcaches: Arc<Cacher<FetchSymCacheInternal>>,
     objects: ObjectsActor,
     bitcode_svc: BitcodeService,
     threadpool: ThreadPool,
+    timeout: Duration,
 }
 
 impl SymCacheActor {
     pub fn new(
         cache: Cache,
+        shared_cache: Option<Arc<SharedCacheService>>,
         objects: ObjectsActor,
         bitcode_svc: BitcodeService,
         threadpool: ThreadPool,
+        timeout: Duration,
     ) -> Self {
         SymCacheActor {
-            symcaches: Arc::new(Cacher::new(cache)),
+            symcaches: Arc::new(Cacher::new(cache, shared_cache)),
             objects,
             bitcode_svc,
             threadpool,
+            timeout,
         }
     }
 }
 
+/// A symcache parsed from the data it borrows.
+struct ParsedSymCache(SelfCell<ByteView<'static>, SymCache<'static>>);
+
+impl ParsedSymCache {
+    fn parse(data: ByteView<'static>) -> Result<Self, SymCacheError> {
+        SelfCell::try_new(data, |data| SymCache::parse(unsafe { &*data }))
+            .map(ParsedSymCache)
+            .map_err(SymCacheError::Parsing)
+    }
+
+    fn data(&self) -> &[u8] {
+        self.0.owner()
+    }
+}
+
+impl fmt::Debug for ParsedSymCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ParsedSymCache")
+            .field("size", &self.data().len())
+            .finish()
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct SymCacheFile {
     object_type: ObjectType,
     identifier: ObjectId,
     scope: Scope,
-    data: ByteView<'static>,
+    /// The symcache, which is only parsed once when it is loaded.
+    symcache: Option<Arc<ParsedSymCache>>,
     features: ObjectFeatures,
     status: CacheStatus,
     arch: Arch,
@@ -95,13 +141,11 @@ pub struct SymCacheFile {
 }
 
 impl SymCacheFile {
-    pub fn parse(&self) -> Result<Option<SymCache<'_>>, SymCacheError> {
-        match self.status {
-            CacheStatus::Positive => Ok(Some(
-                SymCache::parse(&self.data).map_err(SymCacheError::Parsing)?,
-            )),
-            CacheStatus::Negative => Ok(None),
-            CacheStatus::Malformed => Err(SymCacheError::Malformed),
+    pub fn parse(&self) -> Result<Option<&SymCache<'_>>, SymCacheError> {
+        match (self.status, &self.symcache) {
+            (CacheStatus::Positive, Some(symcache)) => Ok(Some(symcache.0.get())),
+            (CacheStatus::Negative, _) => Ok(None),
+            _ => Err(SymCacheError::Malformed),
         }
     }
 
@@ -139,6 +183,9 @@ struct FetchSymCacheInternal {
     /// Thread pool on which to spawn the symcache computation.
     threadpool: ThreadPool,
 
+    /// Timeout for computing the symcache, including the download of its object files.
+    timeout: Duration,
+
     /// The object candidates from which [`FetchSymCacheInternal::object_meta`] was chosen.
     ///
     /// This needs to be returned back with the symcache result and is only being passed
@@ -198,6 +245,19 @@ async fn fetch_difs_and_compute_symcache(
         .unwrap_or(Err(SymCacheError::Canceled))
 }
 
+impl FetchSymCacheInternal {
+    /// Returns the candidates of this request, including the status of the symcache.
+    fn candidates(&self, status: CacheStatus) -> AllObjectCandidates {
+        let mut candidates = self.candidates.clone(); // yuk!
+        candidates.set_debug(
+            self.object_meta.source_id().clone(),
+            &self.object_meta.uri(),
+            ObjectUseInfo::from_derived_status(status, self.object_meta.status()),
+        );
+        candidates
+    }
+}
+
 impl CacheItemRequest for FetchSymCacheInternal {
     type Item = SymCacheFile;
     type Error = SymCacheError;
@@ -206,6 +266,10 @@ impl CacheItemRequest for FetchSymCacheInternal {
         self.object_meta.cache_key()
     }
 
+    fn get_source_key(&self) -> CacheKey {
+        self.object_meta.cache_key()
+    }
+
     fn compute(&self, path: &Path) -> BoxedFuture<Result<CacheStatus, Self::Error>> {
         let future = fetch_difs_and_compute_symcache(
             path.to_owned(),
@@ -221,7 +285,7 @@ impl CacheItemRequest for FetchSymCacheInternal {
         Box::pin(
             future_metrics!(
                 "symcaches",
-                Some((Duration::from_secs(1200), SymCacheError::Timeout)),
+                Some((self.timeout, SymCacheError::Timeout)),
                 future.boxed_local().compat(),
                 "num_sources" => &num_sources.to_string()
             )
@@ -229,12 +293,47 @@ impl CacheItemRequest for FetchSymCacheInternal {
         )
     }
 
+    fn versions(&self) -> Option<CacheVersions> {
+        Some(SYMCACHE_VERSIONS)
+    }
+
+    fn to_memory(item: &Self::Item) -> Option<(Self::Item, u64)> {
+        // Only hot modules with actual data are worth keeping in memory.  The copy shares the
+        // parsed symcache, and the candidates are rebuilt for every request in `from_memory`.
+        let size = match (item.status, &item.symcache) {
+            (CacheStatus::Positive, Some(symcache)) => symcache.data().len(),
+            _ => return None,
+        };
+
+        let copy = SymCacheFile {
+            candidates: AllObjectCandidates::default(),
+            ..item.clone()
+        };
+
+        Some((copy, (size + std::mem::size_of::<SymCacheFile>()) as u64))
+    }
+
+    fn from_memory(&self, item: Arc<Self::Item>) -> Option<Arc<Self::Item>> {
+        Some(Arc::new(SymCacheFile {
+            object_type: self.request.object_type,
+            identifier: self.request.identifier.clone(),
+            candidates: self.candidates(item.status),
+            ..SymCacheFile::clone(&item)
+        }))
+    }
+
     fn should_load(&self, data: &[u8]) -> bool {
         SymCache::parse(data)
             .map(|symcache| symcache.is_latest())
             .unwrap_or(false)
     }
 
+    fn should_load_fallback(&self, data: &[u8]) -> bool {
+        // Items of older versions are served while they are being upgraded, so this only checks
+        // whether the format can still be read.
+        verify_symcache(data).is_ok()
+    }
+
     fn load(
         &self,
         scope: Scope,
@@ -242,23 +341,29 @@ impl CacheItemRequest for FetchSymCacheInternal {
         data: ByteView<'static>,
         _: CachePath,
     ) -> Self::Item {
-        // TODO: Figure out if this double-parsing could be avoided
-        let arch = SymCache::parse(&data)
-            .map(|cache| cache.arch())
+        let (status, symcache) = match status {
+            CacheStatus::Positive => match ParsedSymCache::parse(data) {
+                Ok(symcache) => (status, Some(Arc::new(symcache))),
+                Err(e) => {
+                    log::warn!("Failed to parse symcache: {}", e);
+                    (CacheStatus::Malformed, None)
+                }
+            },
+            _ => (status, None),
+        };
+
+        let arch = symcache
+            .as_ref()
+            .map(|symcache| symcache.0.get().arch())
             .unwrap_or_default();
 
-        let mut candidates = self.candidates.clone(); // yuk!
-        candidates.set_debug(
-            self.object_meta.source_id().clone(),
-            &self.object_meta.uri(),
-            ObjectUseInfo::from_derived_status(status, self.object_meta.status()),
-        );
+        let candidates = self.candidates(status);
 
         SymCacheFile {
             object_type: self.request.object_type,
             identifier: self.request.identifier.clone(),
             scope,
-            data,
+            symcache,
             features: self.object_meta.features(),
             status,
             arch,
@@ -267,6 +372,12 @@ impl CacheItemRequest for FetchSymCacheInternal {
     }
 }
 
+/// Checks whether a symcache can be parsed.
+pub(crate) fn verify_symcache(data: &[u8]) -> Result<(), SymCacheError> {
+    SymCache::parse(data).map_err(SymCacheError::Parsing)?;
+    Ok(())
+}
+
 /// Information for fetching the symbols for this symcache
 #[derive(Debug, Clone)]
 pub struct FetchSymCache {
@@ -277,6 +388,14 @@ pub struct FetchSymCache {
 }
 
 impl SymCacheActor {
+    /// Removes symcaches of the given scope whose cache key matches the filter from memory.
+    pub fn remove_memory<F>(&self, scope: &Scope, filter: F) -> usize
+    where
+        F: FnMut(&str) -> bool,
+    {
+        self.symcaches.remove_memory(scope, filter)
+    }
+
     pub async fn fetch(
         &self,
         request: FetchSymCache,
@@ -302,6 +421,7 @@ impl SymCacheActor {
                         bitcode_svc: self.bitcode_svc.clone(),
                         object_meta: handle,
                         threadpool: self.threadpool.clone(),
+                        timeout: self.timeout,
                         candidates,
                     })
                     .await
@@ -310,7 +430,7 @@ impl SymCacheActor {
                 object_type: request.object_type,
                 identifier: request.identifier,
                 scope: request.scope,
-                data: ByteView::from_slice(b""),
+                symcache: None,
                 features: ObjectFeatures::default(),
                 status: CacheStatus::Negative,
                 arch: Arch::Unknown,
@@ -5,6 +5,7 @@ use std::collections::BTreeMap;
 use std::fmt;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
 use serde::{Deserialize, Deserializer, Serialize};
 use url::Url;
@@ -55,6 +56,8 @@ pub enum SourceConfig {
     Gcs(Arc<GcsSourceConfig>),
     /// Local file system.
     Filesystem(Arc<FilesystemSourceConfig>),
+    /// Source files in a directory or on a web server, used for source context.
+    SourceRoot(Arc<SourceRootSourceConfig>),
 }
 
 impl SourceConfig {
@@ -66,6 +69,7 @@ impl SourceConfig {
             SourceConfig::Gcs(ref x) => &x.id,
             SourceConfig::Sentry(ref x) => &x.id,
             SourceConfig::Filesystem(ref x) => &x.id,
+            SourceConfig::SourceRoot(ref x) => &x.id,
         }
     }
 
@@ -76,6 +80,68 @@ impl SourceConfig {
             SourceConfig::Gcs(..) => "gcs",
             SourceConfig::Http(..) => "http",
             SourceConfig::Filesystem(..) => "filesystem",
+            SourceConfig::SourceRoot(..) => "source_root",
+        }
+    }
+
+    /// Returns `true` if the source authenticates with credentials from the environment.
+    ///
+    /// These credentials belong to Symbolicator, so such sources may only be configured on the
+    /// server and not passed in requests.
+    pub fn uses_ambient_credentials(&self) -> bool {
+        match *self {
+            SourceConfig::S3(ref x) => {
+                x.source_key.credentials_provider != AwsCredentialsProvider::Static
+            }
+            SourceConfig::Gcs(ref x) => {
+                x.source_key.credentials_provider != GcsCredentialsProvider::Static
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the download limits configured on the source.
+    pub fn limits(&self) -> DownloadLimits {
+        match *self {
+            SourceConfig::Http(ref x) => x.files.limits,
+            SourceConfig::S3(ref x) => x.files.limits,
+            SourceConfig::Gcs(ref x) => x.files.limits,
+            SourceConfig::Filesystem(ref x) => x.files.limits,
+            SourceConfig::Sentry(_) | SourceConfig::SourceRoot(_) => DownloadLimits::default(),
+        }
+    }
+
+    /// Returns the retry policy configured on the source.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        match *self {
+            SourceConfig::Sentry(ref x) => x.retry,
+            SourceConfig::Http(ref x) => x.files.retry,
+            SourceConfig::S3(ref x) => x.files.retry,
+            SourceConfig::Gcs(ref x) => x.files.retry,
+            SourceConfig::Filesystem(ref x) => x.files.retry,
+            SourceConfig::SourceRoot(_) => RetryPolicy::default(),
+        }
+    }
+
+    /// Returns the download timeout configured on the source.
+    pub fn timeout(&self) -> Option<Duration> {
+        match *self {
+            SourceConfig::Http(ref x) => x.files.timeout,
+            SourceConfig::S3(ref x) => x.files.timeout,
+            SourceConfig::Gcs(ref x) => x.files.timeout,
+            SourceConfig::Filesystem(ref x) => x.files.timeout,
+            SourceConfig::Sentry(_) | SourceConfig::SourceRoot(_) => None,
+        }
+    }
+
+    /// Returns `true` if the source reads files from the local file system.
+    ///
+    /// Such sources could expose arbitrary files of the server, so they may only be configured on
+    /// the server and not passed in requests.
+    pub fn uses_local_files(&self) -> bool {
+        match *self {
+            SourceConfig::SourceRoot(ref x) => matches!(x.root, SourceRoot::Path(_)),
+            _ => false,
         }
     }
 }
@@ -91,6 +157,10 @@ pub struct SentrySourceConfig {
 
     /// Bearer authorization token.
     pub token: String,
+
+    /// Retries of failed downloads and file listings, overriding the global retry policy.
+    #[serde(default)]
+    pub retry: RetryPolicy,
 }
 
 /// Configuration for symbol server HTTP endpoints.
@@ -123,6 +193,46 @@ pub struct FilesystemSourceConfig {
     pub files: CommonSourceConfig,
 }
 
+/// Configuration for source files in a directory or on a web server.
+///
+/// As opposed to all other sources, source roots do not provide debug files.  Instead, they are
+/// used to look up source context for frames that have no source in a source bundle.  Source files
+/// are identified by the absolute path of the frame, which is mapped to the root by replacing the
+/// prefix.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SourceRootSourceConfig {
+    /// Unique source identifier.
+    pub id: SourceId,
+
+    /// Prefix of absolute source paths that are mapped to this root.
+    pub prefix: String,
+
+    /// Location of the source tree.
+    #[serde(flatten)]
+    pub root: SourceRoot,
+
+    /// Revision of the source tree, substituted for `{revision}` in the URL.
+    #[serde(default)]
+    pub revision: Option<String>,
+
+    /// Additional headers to be sent with every HTTP request.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+/// Location of the source tree of a [`SourceRootSourceConfig`].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceRoot {
+    /// Path to a directory on the local file system.
+    Path(PathBuf),
+    /// URL of the source tree on a web server.
+    ///
+    /// The URL may contain a `{revision}` placeholder.  The path of the source file relative to the
+    /// root is appended to the URL.
+    Url(String),
+}
+
 /// Local helper to deserializes an S3 region string in `S3SourceKey`.
 fn deserialize_region<'de, D>(deserializer: D) -> Result<rusoto_core::Region, D::Error>
 where
@@ -137,6 +247,42 @@ where
         .map_err(|e| D::Error::custom(format!("region: {}", e)))
 }
 
+/// Local helper to deserialize the rate in `DownloadLimits`.
+fn deserialize_rate<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    // A rate of zero or less cannot be enforced, and would otherwise disable the rate limit.
+    use serde::de::Error as _;
+    let rate = Option::<f64>::deserialize(deserializer)?;
+    match rate {
+        Some(rate) if !(rate > 0.0 && rate.is_finite()) => Err(D::Error::custom(format!(
+            "requests_per_second: must be positive, got {}",
+            rate
+        ))),
+        rate => Ok(rate),
+    }
+}
+
+/// Where to obtain credentials for an S3 bucket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AwsCredentialsProvider {
+    /// The `access_key` and `secret_key` of the source.
+    Static,
+    /// The standard credential chain of AWS.
+    ///
+    /// Credentials are read from the environment, the profile, a web identity token file or the
+    /// container and instance metadata, in this order.
+    Chain,
+}
+
+impl Default for AwsCredentialsProvider {
+    fn default() -> Self {
+        AwsCredentialsProvider::Static
+    }
+}
+
 /// Amazon S3 authorization information.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct S3SourceKey {
@@ -144,10 +290,16 @@ pub struct S3SourceKey {
     #[serde(deserialize_with = "deserialize_region")]
     pub region: rusoto_core::Region,
 
+    /// Where to obtain credentials.
+    #[serde(default)]
+    pub credentials_provider: AwsCredentialsProvider,
+
     /// S3 authorization key.
+    #[serde(default)]
     pub access_key: String,
 
     /// S3 secret key.
+    #[serde(default)]
     pub secret_key: String,
 }
 
@@ -156,6 +308,7 @@ impl PartialEq for S3SourceKey {
         self.access_key == other.access_key
             && self.secret_key == other.secret_key
             && self.region == other.region
+            && self.credentials_provider == other.credentials_provider
     }
 }
 
@@ -166,16 +319,44 @@ impl std::hash::Hash for S3SourceKey {
         self.access_key.hash(state);
         self.secret_key.hash(state);
         self.region.name().hash(state);
+        self.credentials_provider.hash(state);
+    }
+}
+
+/// Where to obtain credentials for a GCS bucket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GcsCredentialsProvider {
+    /// The `private_key` and `client_email` of the source.
+    Static,
+    /// The application default credentials file.
+    ///
+    /// The file is located with the `GOOGLE_APPLICATION_CREDENTIALS` environment variable, or
+    /// created by `gcloud auth application-default login`.
+    ApplicationDefault,
+    /// A token of the default service account from the metadata server.
+    Metadata,
+}
+
+impl Default for GcsCredentialsProvider {
+    fn default() -> Self {
+        GcsCredentialsProvider::Static
     }
 }
 
 /// GCS authorization information.
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
 pub struct GcsSourceKey {
+    /// Where to obtain credentials.
+    #[serde(default)]
+    pub credentials_provider: GcsCredentialsProvider,
+
     /// Gcs authorization key.
+    #[serde(default)]
     pub private_key: String,
 
     /// The client email.
+    #[serde(default)]
     pub client_email: String,
 }
 
@@ -217,6 +398,20 @@ pub struct S3SourceConfig {
     #[serde(flatten)]
     pub source_key: Arc<S3SourceKey>,
 
+    /// Endpoint of an S3-compatible service to use instead of AWS.
+    ///
+    /// Only endpoints listed in
+    /// [`allowed_s3_endpoints`](crate::config::Config::allowed_s3_endpoints) can be used.  The
+    /// region is still required to sign requests.
+    #[serde(default)]
+    pub endpoint: Option<Url>,
+
+    /// Whether to address the bucket in the path instead of the host name of the endpoint.
+    ///
+    /// This only applies to custom endpoints.
+    #[serde(default)]
+    pub path_style: bool,
+
     #[serde(flatten)]
     pub files: CommonSourceConfig,
 }
@@ -233,6 +428,16 @@ pub struct CommonSourceConfig {
 
     /// Whether debug files are shared across scopes.
     pub is_public: bool,
+
+    /// Limits for downloads from this source, overriding the global per-source limits.
+    pub limits: DownloadLimits,
+
+    /// Retries of failed downloads from this source, overriding the global retry policy.
+    pub retry: RetryPolicy,
+
+    /// Timeout for downloading a single file from this source, overriding the global timeout.
+    #[serde(with = "humantime_serde")]
+    pub timeout: Option<Duration>,
 }
 
 impl CommonSourceConfig {
@@ -248,6 +453,100 @@ impl CommonSourceConfig {
     }
 }
 
+/// Concurrency and rate limits for downloads from a source or host.
+///
+/// Limits that are not set do not restrict downloads.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DownloadLimits {
+    /// Maximum number of concurrent downloads.
+    pub max_concurrent: Option<usize>,
+
+    /// Maximum number of downloads started per second.
+    ///
+    /// Allows bursts of up to one second worth of downloads.  Must be positive.
+    #[serde(deserialize_with = "deserialize_rate")]
+    pub requests_per_second: Option<f64>,
+}
+
+impl DownloadLimits {
+    /// Returns these limits, falling back to `defaults` for limits that are not set.
+    pub fn or(self, defaults: DownloadLimits) -> Self {
+        DownloadLimits {
+            max_concurrent: self.max_concurrent.or(defaults.max_concurrent),
+            requests_per_second: self.requests_per_second.or(defaults.requests_per_second),
+        }
+    }
+
+    /// Returns `true` if neither the concurrency nor the rate is limited.
+    pub fn is_unlimited(&self) -> bool {
+        self.max_concurrent.is_none() && self.requests_per_second.is_none()
+    }
+
+    /// Returns these limits, restricted to at most the limits set in `max`.
+    pub fn clamp(self, max: DownloadLimits) -> Self {
+        DownloadLimits {
+            max_concurrent: match (self.max_concurrent, max.max_concurrent) {
+                (Some(limit), Some(max)) => Some(limit.min(max)),
+                (limit, max) => limit.or(max),
+            },
+            requests_per_second: match (self.requests_per_second, max.requests_per_second) {
+                (Some(limit), Some(max)) => Some(limit.min(max)),
+                (limit, max) => limit.or(max),
+            },
+        }
+    }
+}
+
+/// How failed downloads from a source are retried.
+///
+/// Downloads are retried on server errors, failed connections and when the server asks to
+/// retry later with status `429` or `503`.  Settings that are not set use the defaults.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts, including the first one.
+    pub max_attempts: Option<u32>,
+
+    /// Delay before the first retry, which doubles with every further retry.
+    #[serde(with = "humantime_serde")]
+    pub initial_backoff: Option<Duration>,
+
+    /// Maximum delay between two attempts, which also caps `Retry-After` headers.
+    #[serde(with = "humantime_serde")]
+    pub max_backoff: Option<Duration>,
+}
+
+impl RetryPolicy {
+    /// Returns this policy, falling back to `defaults` for settings that are not set.
+    pub fn or(self, defaults: RetryPolicy) -> Self {
+        RetryPolicy {
+            max_attempts: self.max_attempts.or(defaults.max_attempts),
+            initial_backoff: self.initial_backoff.or(defaults.initial_backoff),
+            max_backoff: self.max_backoff.or(defaults.max_backoff),
+        }
+    }
+
+    /// Returns this policy, restricted to at most the attempts and at least the backoffs set in
+    /// `limits`.
+    pub fn clamp(self, limits: RetryPolicy) -> Self {
+        RetryPolicy {
+            max_attempts: match (self.max_attempts, limits.max_attempts) {
+                (Some(attempts), Some(max)) => Some(attempts.min(max)),
+                (attempts, max) => attempts.or(max),
+            },
+            initial_backoff: match (self.initial_backoff, limits.initial_backoff) {
+                (Some(backoff), Some(min)) => Some(backoff.max(min)),
+                (backoff, min) => backoff.or(min),
+            },
+            max_backoff: match (self.max_backoff, limits.max_backoff) {
+                (Some(backoff), Some(min)) => Some(backoff.max(min)),
+                (backoff, min) => backoff.or(min),
+            },
+        }
+    }
+}
+
 /// Common attributes to make the symbolicator skip/consider sources by certain criteria.
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[serde(default)]
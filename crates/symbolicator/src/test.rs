@@ -26,9 +26,11 @@ use log::LevelFilter;
 use reqwest::Url;
 use warp::Filter;
 
+use crate::services::download::http::HttpRemoteDif;
+use crate::services::download::{RemoteDif, SourceLocation};
 use crate::sources::{
-    CommonSourceConfig, FileType, FilesystemSourceConfig, HttpSourceConfig, SourceConfig,
-    SourceFilters, SourceId,
+    CommonSourceConfig, DownloadLimits, FileType, FilesystemSourceConfig, HttpSourceConfig,
+    SourceConfig, SourceFilters, SourceId,
 };
 
 pub use actix_web::test::TestServer;
@@ -138,6 +140,11 @@ pub(crate) fn local_source() -> SourceConfig {
 
 /// Get bucket configuration for the microsoft symbol server.
 pub(crate) fn microsoft_symsrv() -> SourceConfig {
+    microsoft_symsrv_with_limits(DownloadLimits::default())
+}
+
+/// Like [`microsoft_symsrv`], but with the given download limits.
+pub(crate) fn microsoft_symsrv_with_limits(limits: DownloadLimits) -> SourceConfig {
     SourceConfig::Http(Arc::new(HttpSourceConfig {
         id: SourceId::new("microsoft"),
         url: "https://msdl.microsoft.com/download/symbols/"
@@ -149,11 +156,27 @@ pub(crate) fn microsoft_symsrv() -> SourceConfig {
                 filetypes: vec![FileType::Pe, FileType::Pdb],
                 ..Default::default()
             },
+            limits,
             ..Default::default()
         },
     }))
 }
 
+/// Returns a [`RemoteDif`] for `hello.txt` on the [`microsoft_symsrv`] source.
+pub(crate) fn remote_dif() -> RemoteDif {
+    remote_dif_with_limits(DownloadLimits::default())
+}
+
+/// Like [`remote_dif`], but with the given download limits on the source.
+pub(crate) fn remote_dif_with_limits(limits: DownloadLimits) -> RemoteDif {
+    match microsoft_symsrv_with_limits(limits) {
+        SourceConfig::Http(source) => {
+            HttpRemoteDif::new(source, SourceLocation::new("hello.txt")).into()
+        }
+        _ => unreachable!(),
+    }
+}
+
 /// Custom version of warp's sealed `IsReject` trait.
 ///
 /// This is required to allow the test [`Server`] to spawn a warp server.
@@ -10,6 +10,7 @@ use std::convert::Infallible;
 use std::fmt;
 use std::ops::Deref;
 use std::str::FromStr;
+use std::time::Duration;
 
 use chrono::{DateTime, Utc};
 use serde::{de, Deserialize, Deserializer, Serialize};
@@ -23,8 +24,10 @@ use crate::utils::hex::HexValue;
 use crate::utils::sentry::ConfigureScope;
 
 mod objects;
+mod text;
 
 pub use objects::{AllObjectCandidates, ObjectCandidate, ObjectDownloadInfo, ObjectUseInfo};
+pub use text::TextReport;
 
 /// Symbolication task identifier.
 #[derive(Debug, Clone, Copy, Serialize, Ord, PartialOrd, Eq, PartialEq)]
@@ -144,7 +147,7 @@ pub struct RequestData {
 ///
 /// These options control some features which control the symbolication and general request
 /// handling behaviour.
-#[derive(Clone, Debug, Default, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 pub struct RequestOptions {
     /// Whether to return detailed information on DIF object candidates.
     ///
@@ -155,6 +158,59 @@ pub struct RequestOptions {
     /// [`ObjectCandidate`] struct for which extra information is returned for DIF objects.
     #[serde(default)]
     pub dif_candidates: bool,
+
+    /// Number of lines of source context before and after the line of each frame.
+    ///
+    /// Defaults to `5`.  If set to `0`, no source context is applied and source bundles are not
+    /// fetched at all.  Larger values are capped at the server's configured maximum.
+    #[serde(default = "default_context_lines")]
+    pub context_lines: usize,
+
+    /// Whether to apply source context only to frames marked as [`in_app`](RawFrame::in_app).
+    #[serde(default)]
+    pub context_in_app_only: bool,
+
+    /// Apply source context only to the top frames of the crashing thread, up to this number.
+    ///
+    /// Frames of all other threads receive no source context.  The crashing thread is the one
+    /// marked as [`is_requesting`](RawStacktrace::is_requesting), or the first one if no thread is
+    /// marked.  Frames skipped by [`context_in_app_only`](Self::context_in_app_only) do not count
+    /// towards this limit.
+    #[serde(default)]
+    pub context_max_frames: Option<usize>,
+
+    /// Maximum duration of the request, such as `"30s"`.
+    ///
+    /// Downloads and stackwalking are cut short when the request runs out of time.  The request
+    /// never takes longer than the server's configured symbolication timeout.
+    #[serde(default, with = "humantime_serde")]
+    pub timeout: Option<Duration>,
+
+    /// Whether to return partial results when the request runs out of time.
+    ///
+    /// Instead of failing with a timeout, the request completes with the debug files that are
+    /// available at its deadline.  Modules whose files are still being fetched are marked as
+    /// [`ObjectFileStatus::Timeout`], and fetching continues in the background so that a retry of
+    /// the request finds them in the cache.
+    #[serde(default)]
+    pub partial_results: bool,
+}
+
+fn default_context_lines() -> usize {
+    5
+}
+
+impl Default for RequestOptions {
+    fn default() -> Self {
+        Self {
+            dif_candidates: false,
+            context_lines: default_context_lines(),
+            context_in_app_only: false,
+            context_max_frames: None,
+            timeout: None,
+            partial_results: false,
+        }
+    }
 }
 
 /// A map of register values.
@@ -230,6 +286,13 @@ pub struct RawFrame {
     /// Information about how the raw frame was created.
     #[serde(default, skip_serializing_if = "is_default_value")]
     pub trust: FrameTrust,
+
+    /// Whether this frame belongs to the application rather than a library.
+    ///
+    /// Symbolicator does not determine this by itself, but it can be passed in to limit source
+    /// context to in-app frames.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_app: Option<bool>,
 }
 
 /// A stack trace containing unsymbolicated stack frames.
@@ -292,7 +355,7 @@ pub struct RawObjectInfo {
 }
 
 /// The type of an object file.
-#[derive(Serialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Serialize, Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 #[serde(rename_all = "snake_case")]
 pub enum ObjectType {
     Elf,
@@ -591,6 +654,38 @@ pub enum SymbolicationResponse {
     InternalError,
 }
 
+/// The prefetch status of a module.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PrefetchModule {
+    /// Status of the symcache, or `None` while it is still being fetched.
+    pub debug_status: Option<ObjectFileStatus>,
+    /// Status of the CFI cache, or `None` while it is still being fetched.
+    pub unwind_status: Option<ObjectFileStatus>,
+    /// The original module information.
+    #[serde(flatten)]
+    pub raw: RawObjectInfo,
+}
+
+/// The response of a prefetch request or poll request.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum PrefetchResponse {
+    /// Some caches are still being fetched.
+    Pending {
+        /// The id with which further updates can be polled.
+        request_id: RequestId,
+        /// The modules in the order of the request.
+        modules: Vec<PrefetchModule>,
+    },
+    /// All caches have been fetched.
+    Completed {
+        /// The id of the prefetch request.
+        request_id: RequestId,
+        /// The modules in the order of the request.
+        modules: Vec<PrefetchModule>,
+    },
+}
+
 /// The symbolicated crash data.
 ///
 /// It contains the symbolicated stack frames, module information as well as other
@@ -60,8 +60,13 @@ pub enum ObjectDownloadInfo {
     /// The DIF object was downloaded successfully.
     ///
     /// The `features` field describes which [`ObjectFeatures`] the object is expected to
-    /// provide, though whether these are actually usable has not yet been verified.
-    Ok { features: ObjectFeatures },
+    /// provide, though whether these are actually usable has not yet been verified.  If the
+    /// download was retried, `retries` holds the number of retries.
+    Ok {
+        features: ObjectFeatures,
+        #[serde(default, skip_serializing_if = "is_zero")]
+        retries: u32,
+    },
     /// The DIF object could not be parsed after downloading.
     ///
     /// This is only a basic validity check of whether the container of the object file can
@@ -84,8 +89,16 @@ pub enum ObjectDownloadInfo {
     /// The next attempt to access this DIF object will retry the download.
     ///
     /// More details should be available in the `details` field, which is not meant to be
-    /// machine parsable.
-    Error { details: String },
+    /// machine parsable.  If the download was retried, `retries` holds the number of retries.
+    Error {
+        details: String,
+        #[serde(default, skip_serializing_if = "is_zero")]
+        retries: u32,
+    },
+}
+
+fn is_zero(value: &u32) -> bool {
+    *value == 0
 }
 
 /// Information about the use of a DIF object.
@@ -283,6 +296,7 @@ mod tests {
             location: RemoteDifUri::new("a"),
             download: ObjectDownloadInfo::Ok {
                 features: Default::default(),
+                retries: 0,
             },
             unwind: ObjectUseInfo::Ok,
             debug: ObjectUseInfo::Ok,
@@ -292,6 +306,7 @@ mod tests {
             location: RemoteDifUri::new("b"),
             download: ObjectDownloadInfo::Ok {
                 features: Default::default(),
+                retries: 0,
             },
             unwind: ObjectUseInfo::Ok,
             debug: ObjectUseInfo::Ok,
@@ -301,6 +316,7 @@ mod tests {
             location: RemoteDifUri::new("c"),
             download: ObjectDownloadInfo::Ok {
                 features: Default::default(),
+                retries: 0,
             },
             unwind: ObjectUseInfo::Ok,
             debug: ObjectUseInfo::Ok,
@@ -321,6 +337,7 @@ mod tests {
             location: RemoteDifUri::new("a"),
             download: ObjectDownloadInfo::Ok {
                 features: Default::default(),
+                retries: 0,
             },
             unwind: ObjectUseInfo::Ok,
             debug: ObjectUseInfo::None,
@@ -330,6 +347,7 @@ mod tests {
             location: RemoteDifUri::new("a"),
             download: ObjectDownloadInfo::Ok {
                 features: Default::default(),
+                retries: 0,
             },
             unwind: ObjectUseInfo::Malformed,
             debug: ObjectUseInfo::Ok,
@@ -352,6 +370,7 @@ mod tests {
             location: RemoteDifUri::new("uri://dummy"),
             download: ObjectDownloadInfo::Ok {
                 features: Default::default(),
+                retries: 0,
             },
             unwind: ObjectUseInfo::Ok,
             debug: ObjectUseInfo::Ok,
@@ -361,6 +380,7 @@ mod tests {
             location: RemoteDifUri::new("uri://dummy"),
             download: ObjectDownloadInfo::Ok {
                 features: Default::default(),
+                retries: 0,
             },
             unwind: ObjectUseInfo::None,
             debug: ObjectUseInfo::None,
@@ -375,4 +395,40 @@ mod tests {
         assert_eq!(all.0[0].unwind, ObjectUseInfo::Ok);
         assert_eq!(all.0[0].debug, ObjectUseInfo::Ok);
     }
+
+    #[test]
+    fn test_download_info_retries() {
+        let info = ObjectDownloadInfo::Error {
+            details: "failed".into(),
+            retries: 0,
+        };
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"status": "error", "details": "failed"})
+        );
+        assert_eq!(
+            serde_json::from_value::<ObjectDownloadInfo>(json).unwrap(),
+            info
+        );
+
+        let info = ObjectDownloadInfo::Error {
+            details: "failed".into(),
+            retries: 2,
+        };
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["retries"], 2);
+
+        let info = ObjectDownloadInfo::Ok {
+            features: Default::default(),
+            retries: 1,
+        };
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["status"], "ok");
+        assert_eq!(json["retries"], 1);
+        assert_eq!(
+            serde_json::from_value::<ObjectDownloadInfo>(json).unwrap(),
+            info
+        );
+    }
 }
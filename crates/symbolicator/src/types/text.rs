@@ -0,0 +1,295 @@
+//! Human readable text rendering of symbolication responses.
+
+use std::fmt;
+
+use symbolic::common::split_path;
+
+use crate::utils::addr::AddrMode;
+
+use super::{
+    CompleteObjectInfo, CompleteStacktrace, CompletedSymbolicationResponse, Signal,
+    SymbolicatedFrame,
+};
+
+/// Renders a [`CompletedSymbolicationResponse`] as a text crash report.
+///
+/// The report starts with the crash reason, followed by all threads with the crashing thread
+/// first, and a table of all modules.  Every frame is rendered on its own line as
+/// `#N function (file:line) [module+offset]`.
+#[derive(Debug)]
+pub struct TextReport<'a>(pub &'a CompletedSymbolicationResponse);
+
+impl fmt::Display for TextReport<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let response = self.0;
+
+        write_header(f, response)?;
+
+        // The crashing thread is the most interesting one, so it goes first.
+        let mut threads: Vec<_> = response.stacktraces.iter().enumerate().collect();
+        threads.sort_by_key(|(_, stacktrace)| stacktrace.is_requesting != Some(true));
+
+        for (index, stacktrace) in threads {
+            writeln!(f)?;
+            write_thread(f, index, stacktrace, &response.modules)?;
+        }
+
+        if !response.modules.is_empty() {
+            writeln!(f)?;
+            write_modules(f, &response.modules)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes the crash reason and information about the system.
+fn write_header(
+    f: &mut fmt::Formatter<'_>,
+    response: &CompletedSymbolicationResponse,
+) -> fmt::Result {
+    // Not all crash formats tell whether the process crashed.
+    match response.crashed {
+        Some(true) => writeln!(f, "Crashed:      Yes")?,
+        Some(false) => writeln!(f, "Crashed:      No")?,
+        None => (),
+    }
+
+    if let Some(ref reason) = response.crash_reason {
+        writeln!(f, "Crash reason: {}", reason)?;
+    }
+    if let Some(ref details) = response.crash_details {
+        writeln!(f, "Crash details: {}", details)?;
+    }
+    if let Some(ref assertion) = response.assertion {
+        writeln!(f, "Assertion:    {}", assertion)?;
+    }
+    if let Some(Signal(signal)) = response.signal {
+        writeln!(f, "Signal:       {}", signal)?;
+    }
+    if let Some(ref timestamp) = response.timestamp {
+        writeln!(f, "Timestamp:    {}", timestamp)?;
+    }
+    if let Some(ref info) = response.system_info {
+        writeln!(
+            f,
+            "System:       {} {} ({}) {}",
+            info.os_name, info.os_version, info.os_build, info.cpu_arch
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Writes the header and all frames of a thread.
+fn write_thread(
+    f: &mut fmt::Formatter<'_>,
+    index: usize,
+    stacktrace: &CompleteStacktrace,
+    modules: &[CompleteObjectInfo],
+) -> fmt::Result {
+    write!(f, "Thread {}", index)?;
+    if let Some(thread_id) = stacktrace.thread_id {
+        write!(f, " (id: {})", thread_id)?;
+    }
+    if stacktrace.is_requesting == Some(true) {
+        write!(f, " (crashed)")?;
+    }
+    writeln!(f)?;
+
+    let mut frames = stacktrace.frames.iter().enumerate().peekable();
+    while let Some((frame_index, frame)) = frames.next() {
+        // Inlined frames are followed by their caller, which has the same original index.
+        let inlined = match frames.peek() {
+            Some((_, next)) => {
+                frame.original_index.is_some() && frame.original_index == next.original_index
+            }
+            None => false,
+        };
+
+        write!(f, "  #{} ", frame_index)?;
+        write_frame(f, frame, modules)?;
+        if inlined {
+            write!(f, " (inlined)")?;
+        }
+        writeln!(f)?;
+    }
+
+    Ok(())
+}
+
+/// Writes a table of all modules with their address range and status.
+fn write_modules(f: &mut fmt::Formatter<'_>, modules: &[CompleteObjectInfo]) -> fmt::Result {
+    writeln!(f, "Modules")?;
+
+    for module in modules {
+        let start = module.raw.image_addr.0;
+        let end = start.saturating_add(module.raw.image_size.unwrap_or(0));
+        let name = module
+            .raw
+            .code_file
+            .as_deref()
+            .or_else(|| module.raw.debug_file.as_deref())
+            .map_or("<unknown>", |path| split_path(path).1);
+
+        write!(f, "  {:#018x} - {:#018x}  {}", start, end, name)?;
+        if let Some(ref debug_id) = module.raw.debug_id {
+            write!(f, " ({})", debug_id)?;
+        }
+        write!(f, "  debug_status: {}", module.debug_status.name())?;
+        if let Some(unwind_status) = module.unwind_status {
+            write!(f, ", unwind_status: {}", unwind_status.name())?;
+        }
+        writeln!(f)?;
+    }
+
+    Ok(())
+}
+
+/// Writes the function, source location and module of a single frame.
+fn write_frame(
+    f: &mut fmt::Formatter<'_>,
+    frame: &SymbolicatedFrame,
+    modules: &[CompleteObjectInfo],
+) -> fmt::Result {
+    let raw = &frame.raw;
+    match raw.function.as_deref().or_else(|| raw.symbol.as_deref()) {
+        Some(function) => write!(f, "{}", function)?,
+        None => write!(f, "{}", raw.instruction_addr)?,
+    }
+
+    if let Some(file) = raw.filename.as_deref().or_else(|| raw.abs_path.as_deref()) {
+        match raw.lineno {
+            Some(line) => write!(f, " ({}:{})", file, line)?,
+            None => write!(f, " ({})", file)?,
+        }
+    }
+
+    let addr = raw.instruction_addr.0;
+    let (module, offset) = match raw.addr_mode {
+        AddrMode::Abs => match find_module(modules, addr) {
+            Some(module) => (Some(module), module.abs_to_rel_addr(addr)),
+            None => (None, None),
+        },
+        AddrMode::Rel(index) => (modules.get(index), Some(addr)),
+    };
+
+    let name = module
+        .and_then(|module| module.raw.code_file.as_deref())
+        .or_else(|| raw.package.as_deref())
+        .map(|path| split_path(path).1);
+
+    match (name, offset) {
+        (Some(name), Some(offset)) => write!(f, " [{}+{:#x}]", name, offset),
+        (Some(name), None) => write!(f, " [{}]", name),
+        (None, _) => Ok(()),
+    }
+}
+
+/// Finds the module that contains the given absolute address.
+fn find_module(modules: &[CompleteObjectInfo], addr: u64) -> Option<&CompleteObjectInfo> {
+    modules.iter().find(|module| {
+        let start = module.raw.image_addr.0;
+        let size = module.raw.image_size.unwrap_or(0);
+        module.supports_absolute_addresses() && addr >= start && addr < start.saturating_add(size)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::types::{ObjectFileStatus, ObjectType, RawFrame, RawObjectInfo};
+    use crate::utils::hex::HexValue;
+
+    fn frame(
+        original_index: usize,
+        instruction_addr: u64,
+        function: Option<&str>,
+        lineno: Option<u32>,
+    ) -> SymbolicatedFrame {
+        SymbolicatedFrame {
+            original_index: Some(original_index),
+            raw: RawFrame {
+                instruction_addr: HexValue(instruction_addr),
+                function: function.map(str::to_owned),
+                filename: lineno.map(|_| "src/bar.rs".to_owned()),
+                lineno,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn response() -> CompletedSymbolicationResponse {
+        let mut module = CompleteObjectInfo::from(RawObjectInfo {
+            ty: ObjectType::Elf,
+            code_id: None,
+            code_file: Some("/usr/lib/libfoo.so".to_owned()),
+            debug_id: Some("502fc0a5-1ec1-3e47-9998-684fa139dca7".to_owned()),
+            debug_file: None,
+            image_addr: HexValue(0x1000),
+            image_size: Some(0x1000),
+        });
+        module.debug_status = ObjectFileStatus::Found;
+        module.unwind_status = Some(ObjectFileStatus::Missing);
+
+        let idle_thread = CompleteStacktrace {
+            thread_id: Some(1),
+            frames: vec![frame(0, 0x5678, None, None)],
+            ..Default::default()
+        };
+
+        let crashed_thread = CompleteStacktrace {
+            thread_id: Some(7),
+            is_requesting: Some(true),
+            frames: vec![
+                frame(0, 0x1234, Some("foo::inlined"), Some(12)),
+                frame(0, 0x1234, Some("foo::bar"), Some(42)),
+                frame(1, 0x1456, Some("main"), None),
+            ],
+            ..Default::default()
+        };
+
+        CompletedSymbolicationResponse {
+            signal: Some(Signal(11)),
+            crashed: Some(true),
+            crash_reason: Some("SIGSEGV".to_owned()),
+            stacktraces: vec![idle_thread, crashed_thread],
+            modules: vec![module],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_text_report() {
+        let response = response();
+        insta::assert_snapshot!(TextReport(&response).to_string(), @r###"
+        Crashed:      Yes
+        Crash reason: SIGSEGV
+        Signal:       11
+
+        Thread 1 (id: 7) (crashed)
+          #0 foo::inlined (src/bar.rs:12) [libfoo.so+0x234] (inlined)
+          #1 foo::bar (src/bar.rs:42) [libfoo.so+0x234]
+          #2 main [libfoo.so+0x456]
+
+        Thread 0 (id: 1)
+          #0 0x5678
+
+        Modules
+          0x0000000000001000 - 0x0000000000002000  libfoo.so (502fc0a5-1ec1-3e47-9998-684fa139dca7)  debug_status: found, unwind_status: missing
+        "###);
+    }
+
+    #[test]
+    fn test_text_report_unknown_crashed() {
+        let response = CompletedSymbolicationResponse {
+            crashed: None,
+            ..response()
+        };
+
+        let report = TextReport(&response).to_string();
+        assert!(report.starts_with("Crash reason: SIGSEGV\nSignal:       11\n"));
+    }
+}
@@ -5,6 +5,9 @@ use std::process::{Command, Stdio};
 use flate2::read::{MultiGzDecoder, ZlibDecoder};
 use tempfile::NamedTempFile;
 
+/// Magic bytes at the start of every zstd frame.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
 /// Decompresses an object file.
 ///
 /// Some compression methods are implemented by spawning an external tool and can only
@@ -32,7 +35,7 @@ pub fn decompress_object_file(src: &NamedTempFile, mut dst: File) -> io::Result<
     match magic_bytes {
         // Magic bytes for zstd
         // https://tools.ietf.org/id/draft-kucherawy-dispatch-zstd-00.html#rfc.section.2.1.1
-        [0x28, 0xb5, 0x2f, 0xfd] => {
+        ZSTD_MAGIC => {
             metric!(counter("compression") += 1, "type" => "zstd");
 
             zstd::stream::copy_decode(src.as_file(), &mut dst)?;
@@ -84,3 +87,67 @@ pub fn decompress_object_file(src: &NamedTempFile, mut dst: File) -> io::Result<
         }
     }
 }
+
+/// Checks whether the data starts with a zstd frame.
+pub fn is_zstd(data: &[u8]) -> bool {
+    data.starts_with(&ZSTD_MAGIC)
+}
+
+/// Compresses data with zstd into a new file and returns the number of bytes written.
+pub fn compress_zstd(data: &[u8], dst: &mut File, level: i32) -> io::Result<u64> {
+    zstd::stream::copy_encode(data, &mut *dst, level)?;
+    Ok(dst.metadata()?.len())
+}
+
+/// Decompresses zstd data written by [`compress_zstd`] into `dst`.
+///
+/// Skippable frames are ignored, so this also reads object files that were stored with a seek
+/// table by previous versions.
+pub fn decompress_zstd(data: &[u8], mut dst: File) -> io::Result<File> {
+    zstd::stream::copy_decode(data, &mut dst)?;
+    Ok(dst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use symbolic::common::ByteView;
+
+    #[test]
+    fn test_zstd_roundtrip() {
+        let data: Vec<u8> = (0..(1 << 20) * 2 + 1000).map(|i| (i % 251) as u8).collect();
+
+        let mut file = tempfile::tempfile().unwrap();
+        let written = compress_zstd(&data, &mut file, 3).unwrap();
+        let compressed = ByteView::map_file(file).unwrap();
+        assert_eq!(written, compressed.len() as u64);
+        assert!(is_zstd(&compressed));
+        assert!(compressed.len() < data.len());
+
+        let decompressed = decompress_zstd(&compressed, tempfile::tempfile().unwrap()).unwrap();
+        let view = ByteView::map_file(decompressed).unwrap();
+        assert_eq!(view.as_slice(), data.as_slice());
+    }
+
+    #[test]
+    fn test_zstd_skippable_frame() {
+        let data = b"some object file".to_vec();
+
+        // Object files stored by previous versions end with a seek table in a skippable frame.
+        let mut compressed = zstd::stream::encode_all(data.as_slice(), 3).unwrap();
+        let mut seek_table = Vec::new();
+        seek_table.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        seek_table.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        seek_table.extend_from_slice(&1u32.to_le_bytes());
+        seek_table.push(0);
+        seek_table.extend_from_slice(&0x8f92_eab1_u32.to_le_bytes());
+        compressed.extend_from_slice(&0x184d_2a5e_u32.to_le_bytes());
+        compressed.extend_from_slice(&(seek_table.len() as u32).to_le_bytes());
+        compressed.extend_from_slice(&seek_table);
+
+        let decompressed = decompress_zstd(&compressed, tempfile::tempfile().unwrap()).unwrap();
+        let view = ByteView::map_file(decompressed).unwrap();
+        assert_eq!(view.as_slice(), data.as_slice());
+    }
+}
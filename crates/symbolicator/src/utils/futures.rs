@@ -86,6 +86,11 @@ impl CallOnDrop {
             f: Some(Box::new(f)),
         }
     }
+
+    /// Drops the callback without calling it.
+    pub fn disarm(mut self) {
+        self.f = None;
+    }
 }
 
 impl Drop for CallOnDrop {
@@ -280,21 +285,3 @@ pub mod m {
         }
     }
 }
-
-/// Retry a future 3 times with 20 millisecond delays.
-pub async fn retry<G, F, T, E>(mut task_gen: G) -> Result<T, E>
-where
-    G: FnMut() -> F,
-    F: Future<Output = Result<T, E>>,
-{
-    let mut retries = 0;
-    loop {
-        let result = task_gen().await;
-        if result.is_ok() || retries >= 3 {
-            break result;
-        }
-
-        retries += 1;
-        delay(Duration::from_millis(20)).await;
-    }
-}
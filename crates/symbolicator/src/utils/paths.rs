@@ -441,7 +441,8 @@ pub fn get_directory_paths(
     paths
 }
 
-pub fn parse_symstore_path(path: &str) -> Option<(&'static [FileType], ObjectId)> {
+/// Parses a path in the symstore and SSQP layouts, see [`get_symstore_path`].
+fn parse_symstore_path(path: &str) -> Option<(&'static [FileType], ObjectId)> {
     let mut split = path.splitn(3, '/');
     let leading_fn = split.next()?;
     let signature = split.next()?;
@@ -523,6 +524,289 @@ pub fn parse_symstore_path(path: &str) -> Option<(&'static [FileType], ObjectId)
     }
 }
 
+/// Parses the build id of a debuginfod request into an ELF object identifier.
+///
+/// Since debuginfod only serves ELF files, the debug id is derived from the build id the same
+/// way it is for ELF files: the first 16 bytes are interpreted as little-endian GUID.
+pub fn parse_debuginfod_build_id(build_id: &str) -> Option<ObjectId> {
+    if build_id.is_empty()
+        || build_id.len() % 2 != 0
+        || !build_id.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        return None;
+    }
+
+    let mut data = [0u8; 16];
+    for (byte, chunk) in data.iter_mut().zip(build_id.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(chunk).ok()?, 16).ok()?;
+    }
+
+    data[0..4].reverse();
+    data[4..6].reverse();
+    data[6..8].reverse();
+
+    Some(ObjectId {
+        code_id: Some(CodeId::new(build_id.to_lowercase())),
+        code_file: None,
+        debug_id: Some(DebugId::from_uuid(Uuid::from_bytes(data))),
+        debug_file: None,
+        object_type: ObjectType::Elf,
+    })
+}
+
+/// Strips a suffix from the string, ignoring ASCII case.
+fn strip_suffix_ignore_case<'a>(s: &'a str, suffix: &str) -> Option<&'a str> {
+    let split = s.len().checked_sub(suffix.len())?;
+    if s.is_char_boundary(split) && s[split..].eq_ignore_ascii_case(suffix) {
+        Some(&s[..split])
+    } else {
+        None
+    }
+}
+
+/// Parses a path in the symstore index2 layout, see [`get_symstore_index2_path`].
+fn parse_symstore_index2_path(path: &str) -> Option<(&'static [FileType], ObjectId)> {
+    let mut split = path.splitn(2, '/');
+    let prefix = split.next()?;
+    let rest = split.next()?;
+
+    // The prefix is made of the first two characters of the file name, or only the first one if
+    // the second is a dot.
+    let file_name = rest.split('/').next()?;
+    let expected_prefix = match file_name.get(..2) {
+        Some(two) if !two.ends_with('.') => two,
+        _ => file_name.get(..1)?,
+    };
+    if !prefix.eq_ignore_ascii_case(expected_prefix) {
+        return None;
+    }
+
+    parse_symstore_path(rest).or_else(|| parse_breakpad_path(rest))
+}
+
+/// Parses a breakpad symbol path or the path of a source bundle stored next to it.
+///
+/// These paths have the form `<debug_file>/<BREAKPADID>/<stem>.sym` or `.src.zip`, where the
+/// stem is the debug file name, optionally without its `.pdb` extension.  Symstore uses the same
+/// form for source bundles of PDBs.
+fn parse_breakpad_path(path: &str) -> Option<(&'static [FileType], ObjectId)> {
+    let (debug_file, signature, file_name) = match *path.split('/').collect::<Vec<_>>() {
+        [debug_file, signature, file_name] => (debug_file, signature, file_name),
+        _ => return None,
+    };
+
+    let (filetypes, stem): (&'static [FileType], _) =
+        if let Some(stem) = strip_suffix_ignore_case(file_name, ".sym") {
+            (&[FileType::Breakpad], stem)
+        } else if let Some(stem) = strip_suffix_ignore_case(file_name, ".src.zip") {
+            (&[FileType::SourceBundle], stem)
+        } else {
+            return None;
+        };
+
+    let pdb_stem = strip_suffix_ignore_case(debug_file, ".pdb");
+    if !stem.eq_ignore_ascii_case(debug_file)
+        && !pdb_stem.map_or(false, |s| s.eq_ignore_ascii_case(stem))
+    {
+        return None;
+    }
+
+    let (debug_file, object_type) = match pdb_stem {
+        // The extension is only stripped when lowercase, so normalize it for path lookups.
+        Some(pdb_stem) => (format!("{}.pdb", pdb_stem), ObjectType::Pe),
+        None if filetypes == [FileType::SourceBundle] => {
+            // Source bundles are also looked up next to the debug file, which needs a type that
+            // allows to derive all paths from the debug id alone.
+            (debug_file.to_owned(), ObjectType::Macho)
+        }
+        None => (debug_file.to_owned(), ObjectType::Unknown),
+    };
+
+    Some((
+        filetypes,
+        ObjectId {
+            code_id: None,
+            code_file: None,
+            debug_id: Some(DebugId::from_breakpad(signature).ok()?),
+            debug_file: Some(debug_file),
+            object_type,
+        },
+    ))
+}
+
+/// Parses a path in the debuginfod layout, see [`get_debuginfod_path`].
+fn parse_debuginfod_path(path: &str) -> Option<(&'static [FileType], ObjectId)> {
+    let (build_id, kind) = match *path.split('/').collect::<Vec<_>>() {
+        [build_id, kind] => (build_id, kind),
+        _ => return None,
+    };
+
+    let filetypes: &'static [FileType] = if kind.eq_ignore_ascii_case("debuginfo") {
+        &[FileType::ElfDebug]
+    } else if kind.eq_ignore_ascii_case("executable") {
+        &[FileType::ElfCode]
+    } else {
+        return None;
+    };
+
+    Some((filetypes, parse_debuginfod_build_id(build_id)?))
+}
+
+/// Parses a native path of ELF and WASM files, see [`get_gdb_path`].
+fn parse_gdb_path(path: &str) -> Option<(&'static [FileType], ObjectId)> {
+    let (prefix, rest) = match *path.split('/').collect::<Vec<_>>() {
+        [prefix, rest] if prefix.len() == 2 => (prefix, rest),
+        _ => return None,
+    };
+
+    let (rest, filetypes): (_, &'static [FileType]) =
+        if let Some(rest) = strip_suffix_ignore_case(rest, ".debug") {
+            (rest, &[FileType::ElfDebug, FileType::WasmDebug])
+        } else if let Some(rest) = strip_suffix_ignore_case(rest, ".src.zip") {
+            (rest, &[FileType::SourceBundle])
+        } else {
+            (rest, &[FileType::ElfCode, FileType::WasmCode])
+        };
+
+    Some((
+        filetypes,
+        parse_debuginfod_build_id(&format!("{}{}", prefix, rest))?,
+    ))
+}
+
+/// Parses a native path of MachO files, see [`get_lldb_path`].
+fn parse_lldb_path(path: &str) -> Option<(&'static [FileType], ObjectId)> {
+    let (path, filetypes): (_, &'static [FileType]) =
+        if let Some(path) = strip_suffix_ignore_case(path, ".app") {
+            (path, &[FileType::MachCode])
+        } else if let Some(path) = strip_suffix_ignore_case(path, ".src.zip") {
+            (path, &[FileType::SourceBundle])
+        } else {
+            (path, &[FileType::MachDebug])
+        };
+
+    let segments: Vec<_> = path.split('/').collect();
+    let lengths: Vec<_> = segments.iter().map(|segment| segment.len()).collect();
+    if lengths != [4, 4, 4, 4, 4, 12] || !segments.iter().all(|s| is_hex(s)) {
+        return None;
+    }
+
+    let uuid: Uuid = segments.concat().parse().ok()?;
+    Some((
+        filetypes,
+        ObjectId {
+            code_id: Some(CodeId::new(uuid.to_simple_ref().to_string())),
+            code_file: None,
+            debug_id: Some(DebugId::from_uuid(uuid)),
+            debug_file: None,
+            object_type: ObjectType::Macho,
+        },
+    ))
+}
+
+/// Parses a path in the unified layout, see [`get_unified_path`].
+///
+/// The identifier in unified paths depends on the object type, so this returns an interpretation
+/// for every object type the identifier is valid for.
+fn parse_unified_path(path: &str) -> Vec<(&'static [FileType], ObjectId)> {
+    let (prefix, rest, suffix) = match *path.split('/').collect::<Vec<_>>() {
+        [prefix, rest, suffix] if prefix.len() == 2 => (prefix, rest, suffix),
+        _ => return Vec::new(),
+    };
+
+    let id = format!("{}{}", prefix, rest).to_ascii_lowercase();
+    if !is_hex(&id) {
+        return Vec::new();
+    }
+
+    let suffix = suffix.to_ascii_lowercase();
+    let mut candidates = Vec::new();
+
+    // ELF files are identified by their build id.
+    let elf_filetypes: Option<&'static [FileType]> = match suffix.as_str() {
+        "executable" => Some(&[FileType::ElfCode]),
+        "debuginfo" => Some(&[FileType::ElfDebug]),
+        "breakpad" => Some(&[FileType::Breakpad]),
+        "sourcebundle" => Some(&[FileType::SourceBundle]),
+        _ => None,
+    };
+    if let (Some(filetypes), Some(object_id)) = (elf_filetypes, parse_debuginfod_build_id(&id)) {
+        candidates.push((filetypes, object_id));
+    }
+
+    // MachO and WASM files are identified by their UUID.
+    let mach_filetypes: Option<&'static [FileType]> = match suffix.as_str() {
+        "executable" => Some(&[FileType::MachCode, FileType::WasmCode]),
+        "debuginfo" => Some(&[FileType::MachDebug, FileType::WasmDebug]),
+        "breakpad" => Some(&[FileType::Breakpad]),
+        "sourcebundle" => Some(&[FileType::SourceBundle]),
+        "plist" => Some(&[FileType::PList]),
+        "bcsymbolmap" => Some(&[FileType::BcSymbolMap]),
+        _ => None,
+    };
+    if let (Some(filetypes), 32, Ok(uuid)) = (mach_filetypes, id.len(), id.parse::<Uuid>()) {
+        candidates.push((
+            filetypes,
+            ObjectId {
+                code_id: Some(CodeId::new(id.clone())),
+                code_file: None,
+                debug_id: Some(DebugId::from_uuid(uuid)),
+                debug_file: None,
+                object_type: ObjectType::Macho,
+            },
+        ));
+    }
+
+    // PE files are identified by their breakpad debug id.
+    let pe_filetypes: Option<&'static [FileType]> = match suffix.as_str() {
+        "executable" => Some(&[FileType::Pe]),
+        "debuginfo" => Some(&[FileType::Pdb]),
+        "breakpad" => Some(&[FileType::Breakpad]),
+        "sourcebundle" => Some(&[FileType::SourceBundle]),
+        _ => None,
+    };
+    if let (Some(filetypes), 33..=40, Ok(debug_id)) =
+        (pe_filetypes, id.len(), DebugId::from_breakpad(&id))
+    {
+        candidates.push((
+            filetypes,
+            ObjectId {
+                code_id: None,
+                code_file: None,
+                debug_id: Some(debug_id),
+                debug_file: None,
+                object_type: ObjectType::Pe,
+            },
+        ));
+    }
+
+    candidates
+}
+
+fn is_hex(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Parses a path requested from the symbol proxy into file types and an object identifier.
+///
+/// The path can be in any of the layouts supported by [`get_directory_paths`], in any
+/// [`FilenameCasing`].  Since some paths are ambiguous, particularly across object types, this
+/// returns all possible interpretations in order of preference.  An empty list means that the
+/// path is not valid in any of the layouts.
+pub fn parse_proxy_path(path: &str) -> Vec<(&'static [FileType], ObjectId)> {
+    let path = path.trim_start_matches('/');
+
+    let mut candidates = Vec::new();
+    candidates.extend(parse_symstore_path(path));
+    candidates.extend(parse_symstore_index2_path(path));
+    candidates.extend(parse_breakpad_path(path));
+    candidates.extend(parse_debuginfod_path(path));
+    candidates.extend(parse_gdb_path(path));
+    candidates.extend(parse_lldb_path(path));
+    candidates.extend(parse_unified_path(path));
+    candidates
+}
+
 pub fn matches_path_patterns(object_id: &ObjectId, patterns: &[Glob]) -> bool {
     fn canonicalize_path(s: &str) -> String {
         s.replace(r"\", "/")
@@ -690,6 +974,100 @@ mod tests {
         path_test!(FileType::ElfDebug, ELF_OBJECT_ID, @"_/_.debug/elf-buildid-sym-dfb85de42daffd09640c8fe377d572de3e168920/_.debug");
     }
 
+    #[test]
+    fn test_parse_debuginfod_build_id() {
+        let object_id =
+            parse_debuginfod_build_id("DFB85DE42DAFFD09640C8FE377D572DE3E168920").unwrap();
+        assert_eq!(object_id.code_id, ELF_OBJECT_ID.code_id);
+        assert_eq!(object_id.debug_id, ELF_OBJECT_ID.debug_id);
+        assert_eq!(object_id.object_type, ObjectType::Elf);
+
+        assert!(parse_debuginfod_build_id("").is_none());
+        assert!(parse_debuginfod_build_id("abc").is_none());
+        assert!(parse_debuginfod_build_id("not-a-build-id").is_none());
+    }
+
+    /// Asserts that all paths of the object can be parsed into an identifier that yields the same
+    /// path again.
+    fn assert_proxy_roundtrip(object_id: &ObjectId, filetypes: &[FileType]) {
+        let layout_types = [
+            DirectoryLayoutType::Native,
+            DirectoryLayoutType::Symstore,
+            DirectoryLayoutType::SymstoreIndex2,
+            DirectoryLayoutType::Ssqp,
+            DirectoryLayoutType::Debuginfod,
+            DirectoryLayoutType::Unified,
+        ];
+        let casings = [
+            FilenameCasing::Default,
+            FilenameCasing::Lowercase,
+            FilenameCasing::Uppercase,
+        ];
+
+        for &ty in &layout_types {
+            for &casing in &casings {
+                let layout = DirectoryLayout { ty, casing };
+
+                for &filetype in filetypes {
+                    for path in get_directory_paths(layout, filetype, object_id) {
+                        // Compressed files are not served by the proxy.
+                        if path.ends_with('_') {
+                            continue;
+                        }
+
+                        let found = parse_proxy_path(&path).into_iter().any(|(types, id)| {
+                            types.contains(&filetype)
+                                && get_directory_paths(layout, filetype, &id)
+                                    .iter()
+                                    .any(|p| p.eq_ignore_ascii_case(&path))
+                        });
+
+                        assert!(found, "{:?} in {:?}: {}", filetype, layout, path);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_proxy_path_roundtrip() {
+        use FileType::*;
+
+        assert_proxy_roundtrip(&PE_OBJECT_ID, &[Pdb, Pe, Breakpad, SourceBundle]);
+        assert_proxy_roundtrip(
+            &MACHO_OBJECT_ID,
+            &[
+                MachCode,
+                MachDebug,
+                Breakpad,
+                SourceBundle,
+                PList,
+                BcSymbolMap,
+            ],
+        );
+        assert_proxy_roundtrip(&ELF_OBJECT_ID, &[ElfCode, ElfDebug, Breakpad, SourceBundle]);
+        assert_proxy_roundtrip(&WASM_OBJECT_ID, &[WasmCode, WasmDebug, SourceBundle]);
+    }
+
+    #[test]
+    fn test_parse_proxy_path() {
+        let candidates = parse_proxy_path("/CRASH.PDB/3249D99D0C4049318610F4E4FB0B69361/CRASH.SYM");
+        assert_eq!(candidates.len(), 1);
+        let (filetypes, object_id) = &candidates[0];
+        assert_eq!(*filetypes, [FileType::Breakpad]);
+        assert_eq!(object_id.debug_id, PE_OBJECT_ID.debug_id);
+        assert_eq!(object_id.debug_file.as_deref(), Some("CRASH.pdb"));
+
+        // Unified paths with UUIDs can refer to both ELF and MachO files.
+        let candidates = parse_proxy_path("67/e9247c814e392ba027dbde6748fcbf/executable");
+        let object_types: Vec<_> = candidates.iter().map(|(_, id)| id.object_type).collect();
+        assert_eq!(object_types, [ObjectType::Elf, ObjectType::Macho]);
+
+        assert!(parse_proxy_path("crash.pdb/not-an-id/crash.pdb").is_empty());
+        assert!(parse_proxy_path("foo/bar").is_empty());
+        assert!(parse_proxy_path("").is_empty());
+    }
+
     #[test]
     fn test_matches_path_patterns_empty() {
         assert!(matches_path_patterns(
@@ -0,0 +1,371 @@
+//! Verification of the integrity of local caches.
+//!
+//! Corrupt cache files, for instance after a crash during a write or disk corruption, are
+//! otherwise only noticed when they are loaded during symbolication.  Verification walks all
+//! caches and parses every positive cache item the same way it is loaded by its service, as well as
+//! the metadata stored next to versioned cache items.  It also
+//! finds files that are not cache items: orphaned temporary files and files outside of the
+//! expected directory layout.
+
+use std::fs::{self, read_dir};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use symbolic::common::ByteView;
+
+use crate::cache::{item_path, safe_path_segment, Cache, CacheItemMeta, CacheStatus, Caches};
+use crate::config::Config;
+use crate::services::bitcode::verify_auxdif;
+use crate::services::cficaches::verify_cficache;
+use crate::services::objects::{verify_object, verify_object_meta};
+use crate::services::requests::verify_request;
+use crate::services::symcaches::verify_symcache;
+
+/// Minimum age of a temporary file to be considered orphaned.
+///
+/// Younger files may still be written by a running symbolicator.
+const ORPHAN_AGE: Duration = Duration::from_secs(3600);
+
+/// Parses the data of a positive cache item.
+type Verifier = fn(ByteView<'static>) -> Result<()>;
+
+/// The directory layout of a cache.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Layout {
+    /// Items are stored in a directory per scope.
+    Scoped,
+    /// Items are stored directly in the cache directory.
+    Flat,
+}
+
+/// Verification results of a single cache.
+#[derive(Debug, Default, Eq, PartialEq)]
+struct VerifySummary {
+    /// Number of cache items.
+    items: usize,
+    /// Number of positive cache items that could not be parsed.
+    corrupt: usize,
+    /// Number of files or directories that are not cache items.
+    unknown: usize,
+    /// Number of removed files and directories.
+    deleted: usize,
+}
+
+impl VerifySummary {
+    /// Reports a problem and removes the file or directory if requested.
+    fn report(&mut self, path: &Path, problem: &str, delete: bool) {
+        println!("{}: {}", path.display(), problem);
+
+        if !delete {
+            return;
+        }
+
+        let result = if path.is_dir() {
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        };
+
+        match result {
+            Ok(()) => self.deleted += 1,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => self.deleted += 1,
+            Err(e) => println!("{}: failed to remove: {}", path.display(), e),
+        }
+    }
+
+    /// Reports a file or directory that is not a cache item.
+    fn unknown(&mut self, path: &Path, problem: &str, delete: bool) {
+        self.unknown += 1;
+        self.report(path, problem, delete);
+    }
+
+    /// Returns whether problems were found that have not been removed.
+    fn has_problems(&self) -> bool {
+        self.corrupt + self.unknown > self.deleted
+    }
+}
+
+/// Verifies a single cache item.
+fn verify_item(path: &Path, verifier: Option<Verifier>) -> Result<()> {
+    let data = ByteView::open(path)?;
+    if CacheStatus::from_content(&data) != CacheStatus::Positive {
+        return Ok(());
+    }
+
+    match verifier {
+        Some(verifier) => verifier(data),
+        None => Ok(()),
+    }
+}
+
+/// Verifies the metadata of a cache item, which is stored next to the item.
+fn verify_meta(path: &Path, item: &Path) -> Result<()> {
+    serde_json::from_slice::<CacheItemMeta>(&fs::read(path)?)?;
+    anyhow::ensure!(item.is_file(), "metadata of missing item");
+    Ok(())
+}
+
+/// Verifies all items of a cache.
+fn verify_cache(
+    cache: &Cache,
+    layout: Layout,
+    verifier: Option<Verifier>,
+    delete: bool,
+) -> Result<VerifySummary> {
+    let mut summary = VerifySummary::default();
+    let cache_dir = match cache.cache_dir() {
+        Some(cache_dir) => cache_dir,
+        None => return Ok(summary),
+    };
+
+    let mut items = Vec::new();
+    for entry in read_dir(cache_dir)? {
+        let path = entry?.path();
+
+        match (layout, path.is_dir()) {
+            (Layout::Flat, false) => items.push(path),
+            (Layout::Flat, true) => summary.unknown(&path, "unknown directory", delete),
+            (Layout::Scoped, false) => summary.unknown(&path, "file outside of scope", delete),
+            (Layout::Scoped, true) => {
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                if safe_path_segment(&name) != name {
+                    summary.unknown(&path, "unknown scope directory", delete);
+                    continue;
+                }
+
+                for entry in read_dir(&path)? {
+                    let path = entry?.path();
+                    if path.is_dir() {
+                        summary.unknown(&path, "unknown directory", delete);
+                    } else {
+                        items.push(path);
+                    }
+                }
+            }
+        }
+    }
+
+    // Metadata is verified after the items, so that metadata of deleted items is removed as well.
+    let mut metas = Vec::new();
+    for path in items {
+        if let Some(item) = item_path(&path) {
+            metas.push((path, item));
+            continue;
+        }
+
+        summary.items += 1;
+        if let Err(error) = verify_item(&path, verifier) {
+            summary.corrupt += 1;
+            summary.report(&path, &format!("corrupt: {:#}", error), delete);
+        }
+    }
+
+    for (path, item) in metas {
+        if let Err(error) = verify_meta(&path, &item) {
+            summary.corrupt += 1;
+            summary.report(&path, &format!("corrupt: {:#}", error), delete);
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Finds temporary files that have been left behind.
+fn verify_tmp(tmp_dir: Option<PathBuf>, delete: bool) -> Result<VerifySummary> {
+    let mut summary = VerifySummary::default();
+    let tmp_dir = match tmp_dir {
+        Some(tmp_dir) if tmp_dir.exists() => tmp_dir,
+        _ => return Ok(summary),
+    };
+
+    let now = SystemTime::now();
+    for entry in read_dir(tmp_dir)? {
+        let path = entry?.path();
+        let modified = path.metadata()?.modified()?;
+        summary.items += 1;
+
+        if now.duration_since(modified).unwrap_or_default() >= ORPHAN_AGE {
+            summary.unknown(&path, "orphaned temporary file", delete);
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Verifies all caches and returns a summary for each cache.
+fn verify_caches(config: &Config, delete: bool) -> Result<Vec<(&'static str, VerifySummary)>> {
+    let caches = Caches::from_config(config).context("failed to create local caches")?;
+
+    let checks: [(&Cache, Layout, Option<Verifier>); 7] = [
+        (
+            &caches.objects,
+            Layout::Scoped,
+            Some(|data| Ok(verify_object(data)?)),
+        ),
+        (
+            &caches.object_meta,
+            Layout::Scoped,
+            Some(|data| Ok(verify_object_meta(&data)?)),
+        ),
+        (
+            &caches.auxdifs,
+            Layout::Scoped,
+            Some(|data| verify_auxdif(&data)),
+        ),
+        (
+            &caches.symcaches,
+            Layout::Scoped,
+            Some(|data| Ok(verify_symcache(&data)?)),
+        ),
+        (
+            &caches.cficaches,
+            Layout::Scoped,
+            Some(|data| Ok(verify_cficache(&data)?)),
+        ),
+        (&caches.diagnostics, Layout::Flat, None),
+        (
+            &caches.requests,
+            Layout::Flat,
+            Some(|data| Ok(verify_request(&data)?)),
+        ),
+    ];
+
+    let mut summaries = Vec::new();
+    for &(cache, layout, verifier) in checks.iter() {
+        let summary = verify_cache(cache, layout, verifier, delete)
+            .with_context(|| format!("failed to verify {}", cache.name()))?;
+        summaries.push((cache.name(), summary));
+    }
+
+    let tmp = verify_tmp(config.cache_dir("tmp"), delete).context("failed to verify tmp")?;
+    summaries.push(("tmp", tmp));
+
+    Ok(summaries)
+}
+
+/// Verifies all caches and prints a summary.
+///
+/// If `delete` is set, corrupt cache items and unknown files are removed.  Returns an error if
+/// problems remain.
+pub fn verify(config: Config, delete: bool) -> Result<()> {
+    let summaries = verify_caches(&config, delete)?;
+
+    println!();
+    println!(
+        "{:<12} {:>10} {:>10} {:>10} {:>10}",
+        "cache", "items", "corrupt", "unknown", "deleted"
+    );
+    for (name, summary) in &summaries {
+        println!(
+            "{:<12} {:>10} {:>10} {:>10} {:>10}",
+            name, summary.items, summary.corrupt, summary.unknown, summary.deleted
+        );
+    }
+
+    let problems = summaries.iter().any(|(_, summary)| summary.has_problems());
+    anyhow::ensure!(!problems, "found problems in caches");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    use filetime::FileTime;
+    use symbolic::debuginfo::Object;
+    use symbolic::symcache::SymCacheWriter;
+
+    use crate::cache::CacheKey;
+    use crate::test;
+    use crate::types::Scope;
+
+    #[test]
+    fn test_verify() {
+        test::setup();
+
+        let cache_dir = test::tempdir();
+        let config = Config {
+            cache_dir: Some(cache_dir.path().to_owned()),
+            ..Default::default()
+        };
+
+        let symcaches = cache_dir.path().join("symcaches");
+        fs::create_dir_all(symcaches.join("global")).unwrap();
+        fs::create_dir_all(symcaches.join("foo.bar")).unwrap();
+        fs::write(symcaches.join("global/negative"), b"").unwrap();
+        fs::write(symcaches.join("global/malformed"), b"malformed").unwrap();
+        fs::write(symcaches.join("global/corrupt"), b"garbage").unwrap();
+        fs::write(symcaches.join("outside"), b"").unwrap();
+
+        // A valid symcache along with its metadata.
+        let object_data =
+            test::read_fixture("symbols/crash.pdb/3249D99D0C4049318610F4E4FB0B69361/crash.pdb");
+        let object = Object::parse(&object_data).unwrap();
+        let mut symcache = Cursor::new(Vec::new());
+        SymCacheWriter::write_object(&object, &mut symcache).unwrap();
+        fs::write(symcaches.join("global/valid"), symcache.into_inner()).unwrap();
+        let meta = CacheItemMeta {
+            source: CacheKey {
+                cache_key: "valid".to_owned(),
+                scope: Scope::Global,
+            },
+            version: 1,
+        };
+        meta.write(&symcaches.join("global/valid")).unwrap();
+
+        // Metadata of a missing item, and metadata that cannot be parsed.
+        meta.write(&symcaches.join("global/missing")).unwrap();
+        fs::write(symcaches.join("global/negative.meta"), b"garbage").unwrap();
+
+        let tmp = cache_dir.path().join("tmp");
+        fs::create_dir_all(&tmp).unwrap();
+        fs::write(tmp.join("recent"), b"").unwrap();
+        fs::write(tmp.join("orphan"), b"").unwrap();
+        let old = FileTime::from_unix_time(0, 0);
+        filetime::set_file_times(tmp.join("orphan"), old, old).unwrap();
+
+        let summaries = verify_caches(&config, false).unwrap();
+        let summary = |name| &summaries.iter().find(|(n, _)| *n == name).unwrap().1;
+
+        assert_eq!(
+            summary("symcaches"),
+            &VerifySummary {
+                items: 4,
+                corrupt: 3,
+                unknown: 2,
+                deleted: 0,
+            }
+        );
+        assert_eq!(
+            summary("tmp"),
+            &VerifySummary {
+                items: 2,
+                corrupt: 0,
+                unknown: 1,
+                deleted: 0,
+            }
+        );
+        assert!(summary("symcaches").has_problems());
+        assert!(!summary("objects").has_problems());
+
+        let summaries = verify_caches(&config, true).unwrap();
+        assert!(summaries.iter().all(|(_, summary)| !summary.has_problems()));
+
+        assert!(symcaches.join("global/valid").exists());
+        assert!(symcaches.join("global/valid.meta").exists());
+        assert!(!symcaches.join("global/missing.meta").exists());
+        assert!(!symcaches.join("global/negative.meta").exists());
+        assert!(symcaches.join("global/negative").exists());
+        assert!(symcaches.join("global/malformed").exists());
+        assert!(!symcaches.join("global/corrupt").exists());
+        assert!(!symcaches.join("foo.bar").exists());
+        assert!(!symcaches.join("outside").exists());
+        assert!(tmp.join("recent").exists());
+        assert!(!tmp.join("orphan").exists());
+    }
+}